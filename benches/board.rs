@@ -0,0 +1,196 @@
+// Benchmarks of the board and move validation, run with cargo bench --features fixtures.
+// The server is a binary crate, modules the game depends on are compiled into the benchmark.
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/account.rs"]
+mod account;
+#[path = "../src/board/mod.rs"]
+mod board;
+#[path = "../src/bot.rs"]
+mod bot;
+#[path = "../src/canary.rs"]
+mod canary;
+#[path = "../src/cluster.rs"]
+mod cluster;
+#[path = "../src/conformance.rs"]
+mod conformance;
+#[path = "../src/engine.rs"]
+mod engine;
+#[path = "../src/fairplay.rs"]
+mod fairplay;
+#[path = "../src/filter.rs"]
+mod filter;
+#[path = "../src/flags.rs"]
+mod flags;
+#[path = "../src/locale.rs"]
+mod locale;
+#[path = "../src/metrics.rs"]
+mod metrics;
+#[path = "../src/pool.rs"]
+mod pool;
+#[path = "../src/proto.rs"]
+mod proto;
+#[path = "../src/rating.rs"]
+mod rating;
+#[path = "../src/storage.rs"]
+mod storage;
+#[path = "../src/tenant.rs"]
+mod tenant;
+#[path = "../src/tournament.rs"]
+mod tournament;
+#[path = "../src/vault.rs"]
+mod vault;
+
+use board::{Board, Figure, RawMove};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use proto::Move;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use vault::{fixtures, Color};
+
+const COLORS: [Color; 4] = [Color::Red, Color::Blue, Color::Yellow, Color::Green];
+// every player moved this many times, pieces are developed and some traded
+const MIDDLEGAME_ROUNDS: usize = 10;
+const SEEDS: [u64; 3] = [1, 2, 3];
+
+// Random legal moves from the initial position in turn order, the same for the seed.
+// Promotions and king captures are left out, positions stay playable for every color.
+fn middlegame(seed: u64) -> Board {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut board = Board::new();
+    for _ in 0..MIDDLEGAME_ROUNDS {
+        for color in COLORS {
+            let moves = quiet_moves(&mut board, color);
+            if let Some(mv) = moves.choose(&mut rng) {
+                board.piece_move(mv.from, mv.to);
+            }
+        }
+    }
+    board
+}
+
+fn quiet_moves(board: &mut Board, color: Color) -> Vec<RawMove> {
+    board
+        .legal_moves(color)
+        .into_iter()
+        .filter(|mv| {
+            let promotes = board.piece(mv.from).is_some_and(|piece| {
+                piece.figure.is(Figure::Pawn)
+                    && piece
+                        .promotion_line()
+                        .is_some_and(|line| line.contains(mv.to))
+            });
+            let takes_king = board
+                .piece(mv.to)
+                .is_some_and(|piece| piece.figure.is(Figure::King));
+            !promotes && !takes_king
+        })
+        .collect()
+}
+
+// legal move of red as sent by the client
+fn red_move(board: &mut Board) -> Move {
+    let mv = quiet_moves(board, Color::Red)[0];
+    match board.piece(mv.to) {
+        Some(_) => Move::Capture {
+            from: mv.from,
+            to: mv.to,
+        },
+        None => Move::Basic {
+            from: mv.from,
+            to: mv.to,
+        },
+    }
+}
+
+fn positions() -> Vec<(String, Board)> {
+    let mut positions = vec![("initial".to_string(), Board::new())];
+    for seed in SEEDS {
+        positions.push((format!("middlegame_{}", seed), middlegame(seed)));
+    }
+    positions
+}
+
+fn attackers_on_position(c: &mut Criterion) {
+    let mut group = c.benchmark_group("attackers_on_position");
+    for (name, board) in positions() {
+        let kings = COLORS
+            .iter()
+            .filter_map(|color| Some((board.find_king(*color)?.position(), *color)))
+            .collect::<Vec<_>>();
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for (pos, color) in &kings {
+                    black_box(board.attackers_on_position(*pos, *color));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn legal_moves(c: &mut Criterion) {
+    let mut group = c.benchmark_group("legal_moves");
+    for (name, mut board) in positions() {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for color in COLORS {
+                    black_box(board.legal_moves(color));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn validate_move(c: &mut Criterion) {
+    let mut group = c.benchmark_group("validate_move");
+    for (name, mut board) in positions() {
+        let mv = red_move(&mut board);
+        let mut game = fixtures::game(board);
+        group.bench_function(name, |b| b.iter(|| black_box(game.validate_move(&mv))));
+    }
+    group.finish();
+}
+
+// the steps of the game session with the complete move, fresh game for every run
+fn apply_move(c: &mut Criterion) {
+    let mut group = c.benchmark_group("apply_move");
+    for (name, mut board) in positions() {
+        let mv = red_move(&mut board);
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || fixtures::game(board.clone()),
+                |mut game| {
+                    black_box(game.apply_move(&mv)).unwrap();
+                    game.update_players_states();
+                    game
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+// the whole move tree from the position, three plies deep
+fn perft(c: &mut Criterion) {
+    let mut group = c.benchmark_group("perft_3");
+    for (name, mut board) in positions() {
+        group.bench_function(name, |b| {
+            b.iter(|| black_box(board::perft::perft(&mut board, Color::Red, &[], 3)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    attackers_on_position,
+    legal_moves,
+    validate_move,
+    apply_move,
+    perft
+);
+criterion_main!(benches);
@@ -0,0 +1,525 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const NAME_MAX_LEN: usize = 32;
+const PASSWORD_MIN_LEN: usize = 8;
+// bytes of the random salt of the password hash
+const SALT_LEN: usize = 16;
+const TOKEN_LEN: usize = 32;
+// oldest tokens are forgotten, the player is logged out on the oldest device
+const MAX_TOKENS: usize = 8;
+// deleted players are known by this prefix and their account id in game records
+const DELETED_PREFIX: &str = "deleted-";
+const MAX_BLOCKED: usize = 256;
+const MAX_PRIVATE_POOLS: usize = 64;
+const MAX_FRIENDS: usize = 256;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub id: u64,
+    pub name: String,
+    // unix seconds, 0 for accounts registered before it was stored
+    #[serde(default)]
+    pub created: u64,
+    // PHC string of the argon2 hash with its salt and parameters
+    password_hash: String,
+    // hashes of issued login tokens, the token itself is known only to the client
+    tokens: Vec<String>,
+    // ids of accounts the player does not want to meet or hear from
+    #[serde(default)]
+    blocked: Vec<u64>,
+    // pools whose games the player keeps out of public history
+    #[serde(default)]
+    private_pools: Vec<String>,
+    // ids of accounts the player added as friends, the other side does not have to agree
+    #[serde(default)]
+    friends: Vec<u64>,
+}
+
+pub enum AccountError {
+    BadName,
+    NameTaken,
+    WeakPassword,
+    BadCredentials,
+    NotFound,
+    TooMany,
+}
+
+// Registered players by name, persisted by storage
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Accounts {
+    accounts: HashMap<String, Account>,
+    next_id: u64,
+    // owner name by hash of every issued token, rebuilt once loaded
+    #[serde(skip)]
+    token_owners: HashMap<String, String>,
+    // not yet persisted changes
+    #[serde(skip)]
+    changed: bool,
+}
+
+impl Accounts {
+    // Name and password of the new account, the name is checked again on register
+    pub fn check_new(&self, name: &str, password: &str) -> Result<(), AccountError> {
+        self.check_name(name)?;
+        if password.chars().count() < PASSWORD_MIN_LEN {
+            return Err(AccountError::WeakPassword);
+        }
+        Ok(())
+    }
+
+    fn check_name(&self, name: &str) -> Result<(), AccountError> {
+        if name.is_empty()
+            || name.chars().count() > NAME_MAX_LEN
+            || !name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+            || name.starts_with(DELETED_PREFIX)
+        {
+            return Err(AccountError::BadName);
+        }
+        if self.accounts.contains_key(name) {
+            return Err(AccountError::NameTaken);
+        }
+        Ok(())
+    }
+
+    // Create account with the password hashed by hash_password and issue login token
+    pub fn register(
+        &mut self,
+        name: &str,
+        password_hash: String,
+    ) -> Result<(u64, String), AccountError> {
+        self.check_name(name)?;
+        let account = Account {
+            id: self.next_id,
+            name: name.to_string(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            password_hash,
+            tokens: Vec::new(),
+            blocked: Vec::new(),
+            private_pools: Vec::new(),
+            friends: Vec::new(),
+        };
+        self.next_id += 1;
+        self.accounts.insert(name.to_string(), account);
+        let token = self.issue_token(name);
+        Ok((self.next_id - 1, token))
+    }
+
+    // Hash the password of the account is verified against by verify_password
+    pub fn password_hash(&self, name: &str) -> Result<String, AccountError> {
+        self.accounts
+            .get(name)
+            .map(|account| account.password_hash.clone())
+            .ok_or(AccountError::BadCredentials)
+    }
+
+    // Issue login token once the password matched the hash, the account may be gone meanwhile
+    pub fn login(&mut self, name: &str, verified: &str) -> Result<(u64, String), AccountError> {
+        let id = self.verified(name, verified)?.id;
+        Ok((id, self.issue_token(name)))
+    }
+
+    // Returns id and name of the token owner
+    pub fn login_token(&self, token: &str) -> Result<(u64, String), AccountError> {
+        self.token_owners
+            .get(&token_hash(token))
+            .and_then(|name| self.accounts.get(name))
+            .map(|account| (account.id, account.name.clone()))
+            .ok_or(AccountError::BadCredentials)
+    }
+
+    // Tokens of the loaded accounts
+    pub fn index_tokens(&mut self) {
+        self.token_owners = self
+            .accounts
+            .values()
+            .flat_map(|account| {
+                account
+                    .tokens
+                    .iter()
+                    .map(move |hash| (hash.clone(), account.name.clone()))
+            })
+            .collect();
+    }
+
+    // account still having the hash the password was verified against
+    fn verified(&self, name: &str, verified: &str) -> Result<&Account, AccountError> {
+        self.accounts
+            .get(name)
+            .filter(|account| account.password_hash == verified)
+            .ok_or(AccountError::BadCredentials)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Account> {
+        self.accounts.get(name)
+    }
+
+    // Remove account once the password matched the hash, returns the removed one
+    pub fn delete(&mut self, name: &str, verified: &str) -> Result<Account, AccountError> {
+        self.verified(name, verified)?;
+        self.changed = true;
+        let account = self
+            .accounts
+            .remove(name)
+            .ok_or(AccountError::BadCredentials)?;
+        for hash in &account.tokens {
+            self.token_owners.remove(hash);
+        }
+        Ok(account)
+    }
+
+    // ids of accounts blocked by the player
+    pub fn blocked(&self, name: &str) -> Vec<u64> {
+        self.accounts
+            .get(name)
+            .map_or_else(Vec::new, |account| account.blocked.clone())
+    }
+
+    // Block or unblock other player, returns ids blocked by the player after the change
+    pub fn set_blocked(
+        &mut self,
+        name: &str,
+        other: &str,
+        blocked: bool,
+    ) -> Result<Vec<u64>, AccountError> {
+        let other_id = match self.accounts.get(other) {
+            Some(account) if other != name => account.id,
+            _ => return Err(AccountError::NotFound),
+        };
+        let account = self
+            .accounts
+            .get_mut(name)
+            .ok_or(AccountError::BadCredentials)?;
+        let known = account.blocked.contains(&other_id);
+        if blocked && !known {
+            if account.blocked.len() >= MAX_BLOCKED {
+                return Err(AccountError::TooMany);
+            }
+            account.blocked.push(other_id);
+            self.changed = true;
+        } else if !blocked && known {
+            account.blocked.retain(|id| *id != other_id);
+            self.changed = true;
+        }
+        Ok(account.blocked.clone())
+    }
+
+    // ids of accounts the player added as friends
+    pub fn friends(&self, name: &str) -> Vec<u64> {
+        self.accounts
+            .get(name)
+            .map_or_else(Vec::new, |account| account.friends.clone())
+    }
+
+    // Add or remove other player as a friend, returns ids of the friends after the change
+    pub fn set_friend(
+        &mut self,
+        name: &str,
+        other: &str,
+        friend: bool,
+    ) -> Result<Vec<u64>, AccountError> {
+        let other_id = match self.accounts.get(other) {
+            Some(account) if other != name => account.id,
+            _ => return Err(AccountError::NotFound),
+        };
+        let account = self
+            .accounts
+            .get_mut(name)
+            .ok_or(AccountError::BadCredentials)?;
+        let known = account.friends.contains(&other_id);
+        if friend && !known {
+            if account.friends.len() >= MAX_FRIENDS {
+                return Err(AccountError::TooMany);
+            }
+            account.friends.push(other_id);
+            self.changed = true;
+        } else if !friend && known {
+            account.friends.retain(|id| *id != other_id);
+            self.changed = true;
+        }
+        Ok(account.friends.clone())
+    }
+
+    // pools whose games the player keeps out of public history
+    pub fn private_pools(&self, name: &str) -> Vec<String> {
+        self.accounts
+            .get(name)
+            .map_or_else(Vec::new, |account| account.private_pools.clone())
+    }
+
+    // Keep games of the pool out of public history or publish them again,
+    // returns private pools of the player after the change
+    pub fn set_private(
+        &mut self,
+        name: &str,
+        pool: &str,
+        private: bool,
+    ) -> Result<Vec<String>, AccountError> {
+        let account = self
+            .accounts
+            .get_mut(name)
+            .ok_or(AccountError::BadCredentials)?;
+        let known = account.private_pools.iter().any(|p| p == pool);
+        if private && !known {
+            if account.private_pools.len() >= MAX_PRIVATE_POOLS {
+                return Err(AccountError::TooMany);
+            }
+            account.private_pools.push(pool.to_string());
+            self.changed = true;
+        } else if !private && known {
+            account.private_pools.retain(|p| p != pool);
+            self.changed = true;
+        }
+        Ok(account.private_pools.clone())
+    }
+
+    // names of the accounts, deleted ones are skipped
+    pub fn names(&self, ids: &[u64]) -> Vec<String> {
+        let mut names = self
+            .accounts
+            .values()
+            .filter(|account| ids.contains(&account.id))
+            .map(|account| account.name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn issue_token(&mut self, name: &str) -> String {
+        let token = random_string(TOKEN_LEN);
+        if let Some(account) = self.accounts.get_mut(name) {
+            let hash = token_hash(&token);
+            account.tokens.push(hash.clone());
+            self.token_owners.insert(hash, name.to_string());
+            if account.tokens.len() > MAX_TOKENS {
+                let oldest = account.tokens.remove(0);
+                self.token_owners.remove(&oldest);
+            }
+            self.changed = true;
+        }
+        token
+    }
+
+    // true once after every change
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+    pub fn mark_changed(&mut self) {
+        self.changed = true;
+    }
+}
+
+// name of the deleted player in game records and past leaderboards
+pub fn deleted_name(account_id: u64) -> String {
+    format!("{}{}", DELETED_PREFIX, account_id)
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// PHC string of the argon2 hash with a random salt. Slow on purpose, so never on the async
+// workers nor under a lock.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::encode_b64(&rand::thread_rng().gen::<[u8; SALT_LEN]>())
+        .expect("salt of the password hash");
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("argon2 password hash")
+        .to_string()
+}
+
+// Password against the hash of hash_password, as slow
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+// tokens are random enough to be stored without salt
+fn token_hash(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register(accounts: &mut Accounts, name: &str, password: &str) -> (u64, String) {
+        accounts.check_new(name, password).ok().unwrap();
+        accounts
+            .register(name, hash_password(password))
+            .ok()
+            .unwrap()
+    }
+
+    // hash the password was verified against, none if it does not match
+    fn verify(accounts: &Accounts, name: &str, password: &str) -> String {
+        let hash = accounts.password_hash(name).ok().unwrap_or_default();
+        match verify_password(password, &hash) {
+            true => hash,
+            false => String::new(),
+        }
+    }
+
+    #[test]
+    fn register_and_login() {
+        let mut accounts = Accounts::default();
+        let (id, token) = register(&mut accounts, "alice", "password1");
+        let verified = verify(&accounts, "alice", "password1");
+        assert!(matches!(accounts.login("alice", &verified), Ok((i, _)) if i == id));
+        assert!(
+            matches!(accounts.login_token(&token), Ok((i, name)) if i == id && name == "alice")
+        );
+        assert!(matches!(
+            accounts.login("alice", &verify(&accounts, "alice", "password2")),
+            Err(AccountError::BadCredentials)
+        ));
+        assert!(matches!(
+            accounts.login_token("forged"),
+            Err(AccountError::BadCredentials)
+        ));
+    }
+
+    #[test]
+    fn register_rejected() {
+        let mut accounts = Accounts::default();
+        let hash = hash_password("password1");
+        assert!(accounts.register("bob", hash.clone()).is_ok());
+        assert!(matches!(
+            accounts.check_new("bob", "password1"),
+            Err(AccountError::NameTaken)
+        ));
+        // taken while the password was hashed
+        assert!(matches!(
+            accounts.register("bob", hash),
+            Err(AccountError::NameTaken)
+        ));
+        assert!(matches!(
+            accounts.check_new("bob smith", "password1"),
+            Err(AccountError::BadName)
+        ));
+        assert!(matches!(
+            accounts.check_new("carol", "short"),
+            Err(AccountError::WeakPassword)
+        ));
+    }
+
+    #[test]
+    fn oldest_token_forgotten() {
+        let mut accounts = Accounts::default();
+        let (_, first) = register(&mut accounts, "dave", "password1");
+        for _ in 0..MAX_TOKENS {
+            let verified = verify(&accounts, "dave", "password1");
+            accounts.login("dave", &verified).ok().unwrap();
+        }
+        assert!(accounts.login_token(&first).is_err());
+    }
+
+    #[test]
+    fn delete_needs_password() {
+        let mut accounts = Accounts::default();
+        let (id, token) = register(&mut accounts, "erin", "password1");
+        assert!(accounts
+            .delete("erin", &verify(&accounts, "erin", "password2"))
+            .is_err());
+        let verified = verify(&accounts, "erin", "password1");
+        assert!(matches!(accounts.delete("erin", &verified), Ok(a) if a.id == id));
+        assert!(accounts.get("erin").is_none());
+        assert!(accounts.login_token(&token).is_err());
+        assert!(matches!(
+            accounts.check_new(&deleted_name(id), "password1"),
+            Err(AccountError::BadName)
+        ));
+    }
+
+    #[test]
+    fn password_hash_in_phc_format() {
+        let hash = hash_password("password1");
+        assert!(hash.starts_with("$argon2id$"));
+        assert_ne!(hash, hash_password("password1"));
+        assert!(verify_password("password1", &hash));
+        assert!(!verify_password("password2", &hash));
+        assert!(!verify_password("password1", "not a hash"));
+    }
+
+    #[test]
+    fn tokens_indexed_once_loaded() {
+        let mut accounts = Accounts::default();
+        let (id, token) = register(&mut accounts, "ivan", "password1");
+        let json = serde_json::to_vec(&accounts).unwrap();
+        let mut loaded: Accounts = serde_json::from_slice(&json).unwrap();
+        assert!(loaded.login_token(&token).is_err());
+        loaded.index_tokens();
+        assert!(matches!(loaded.login_token(&token), Ok((i, _)) if i == id));
+    }
+
+    #[test]
+    fn block_by_name() {
+        let mut accounts = Accounts::default();
+        register(&mut accounts, "frank", "password1");
+        let (id, _) = register(&mut accounts, "grace", "password1");
+        assert!(matches!(accounts.set_blocked("frank", "grace", true), Ok(b) if b == vec![id]));
+        assert!(matches!(accounts.set_blocked("frank", "grace", true), Ok(b) if b.len() == 1));
+        assert_eq!(accounts.names(&accounts.blocked("frank")), vec!["grace"]);
+        assert!(matches!(
+            accounts.set_blocked("frank", "frank", true),
+            Err(AccountError::NotFound)
+        ));
+        assert!(matches!(
+            accounts.set_blocked("frank", "nobody", true),
+            Err(AccountError::NotFound)
+        ));
+        assert!(matches!(accounts.set_blocked("frank", "grace", false), Ok(b) if b.is_empty()));
+    }
+
+    #[test]
+    fn friends_by_name() {
+        let mut accounts = Accounts::default();
+        register(&mut accounts, "frank", "password1");
+        let (id, _) = register(&mut accounts, "grace", "password1");
+        assert!(matches!(accounts.set_friend("frank", "grace", true), Ok(f) if f == vec![id]));
+        assert!(accounts.friends("grace").is_empty());
+        assert!(matches!(
+            accounts.set_friend("frank", "nobody", true),
+            Err(AccountError::NotFound)
+        ));
+        assert!(matches!(accounts.set_friend("frank", "grace", false), Ok(f) if f.is_empty()));
+    }
+
+    #[test]
+    fn private_pools_by_name() {
+        let mut accounts = Accounts::default();
+        register(&mut accounts, "heidi", "password1");
+        assert!(
+            matches!(accounts.set_private("heidi", "blitz", true), Ok(p) if p == vec!["blitz"])
+        );
+        assert!(matches!(accounts.set_private("heidi", "blitz", true), Ok(p) if p.len() == 1));
+        assert_eq!(accounts.private_pools("heidi"), vec!["blitz"]);
+        assert!(accounts.private_pools("nobody").is_empty());
+        assert!(matches!(
+            accounts.set_private("nobody", "blitz", true),
+            Err(AccountError::BadCredentials)
+        ));
+        assert!(matches!(accounts.set_private("heidi", "blitz", false), Ok(p) if p.is_empty()));
+    }
+}
@@ -0,0 +1,40 @@
+use crate::board::Board;
+use crate::vault::Color;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+// Run f until the duration passes, returns runs per second
+fn rate(duration: Duration, mut f: impl FnMut()) -> u64 {
+    let start = Instant::now();
+    let mut runs = 0u64;
+    while start.elapsed() < duration {
+        f();
+        runs += 1;
+    }
+    (runs as f64 / start.elapsed().as_secs_f64()) as u64
+}
+
+// move generations of the whole position per second
+pub fn movegen_rate(board: &Board, duration: Duration) -> u64 {
+    rate(duration, || {
+        black_box(board.pseudo_legal_moves());
+    })
+}
+
+// check scans of the kings of all four colors per second
+pub fn attack_rate(board: &Board, duration: Duration) -> u64 {
+    rate(duration, || {
+        for color in [Color::Red, Color::Blue, Color::Yellow, Color::Green] {
+            black_box(board.is_check(color));
+        }
+    })
+}
+
+// board serializations and serialized bytes per second
+pub fn serialize_rate(board: &Board, duration: Duration) -> (u64, u64) {
+    let len = serde_json::to_vec(board).map_or(0, |json| json.len() as u64);
+    let runs = rate(duration, || {
+        black_box(serde_json::to_vec(board).ok());
+    });
+    (runs, runs * len)
+}
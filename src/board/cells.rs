@@ -0,0 +1,111 @@
+use super::Position;
+use enum_iterator::IntoEnumIterator;
+use once_cell::sync::Lazy;
+use std::convert::TryFrom;
+
+// cells of the cross board, the corners of the 14x14 square are not part of it
+pub const CELLS: usize = 160;
+
+const KNIGHT_SHIFTS: [(isize, isize); 8] = [
+    (2, 1),
+    (1, 2),
+    (2, -1),
+    (1, -2),
+    (-2, 1),
+    (-1, 2),
+    (-2, -1),
+    (-1, -2),
+];
+// straight lines first, then diagonals
+const RAY_SHIFTS: [(isize, isize); 8] = [
+    (0, 1),
+    (0, -1),
+    (1, 0),
+    (-1, 0),
+    (1, 1),
+    (1, -1),
+    (-1, 1),
+    (-1, -1),
+];
+
+type Rays = [Vec<Position>; 8];
+
+static POSITIONS: Lazy<Vec<Position>> = Lazy::new(|| Position::into_enum_iter().collect());
+
+static KNIGHT_JUMPS: Lazy<Vec<Vec<Position>>> = Lazy::new(|| {
+    POSITIONS
+        .iter()
+        .map(|pos| {
+            let (col, row) = pos.col_row_idx();
+            KNIGHT_SHIFTS
+                .iter()
+                .filter_map(|(c, r)| Position::try_from((col + c, row + r)).ok())
+                .collect()
+        })
+        .collect()
+});
+
+// cells seen from the cell in every direction, the nearest first
+static RAYS: Lazy<Vec<Rays>> = Lazy::new(|| {
+    POSITIONS
+        .iter()
+        .map(|pos| {
+            let (col, row) = pos.col_row_idx();
+            let mut rays: Rays = Default::default();
+            for (ray, (c, r)) in rays.iter_mut().zip(RAY_SHIFTS.iter()) {
+                let mut distance = 1;
+                while let Ok(to) = Position::try_from((col + c * distance, row + r * distance)) {
+                    ray.push(to);
+                    distance += 1;
+                }
+            }
+            rays
+        })
+        .collect()
+});
+
+pub fn index(pos: Position) -> usize {
+    pos as usize
+}
+
+pub fn position(index: usize) -> Position {
+    POSITIONS[index]
+}
+
+pub fn knight_jumps(pos: Position) -> &'static [Position] {
+    &KNIGHT_JUMPS[index(pos)]
+}
+
+pub fn straight_rays(pos: Position) -> &'static [Vec<Position>] {
+    &RAYS[index(pos)][..4]
+}
+
+pub fn diagonal_rays(pos: Position) -> &'static [Vec<Position>] {
+    &RAYS[index(pos)][4..]
+}
+
+pub fn all_rays(pos: Position) -> &'static [Vec<Position>] {
+    &RAYS[index(pos)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_follow_the_cross_shape() {
+        assert_eq!(Position::into_enum_iter().count(), CELLS);
+        assert!(Position::into_enum_iter().all(|pos| position(index(pos)) == pos));
+
+        // the corner of the square is not jumped to
+        assert_eq!(knight_jumps(Position::d1), [Position::f2, Position::e3]);
+        assert_eq!(knight_jumps(Position::g7).len(), 8);
+        // diagonals of the corner cell end at the cut corners
+        let diagonals: Vec<usize> = diagonal_rays(Position::d1)
+            .iter()
+            .map(|r| r.len())
+            .collect();
+        assert_eq!(diagonals, [10, 0, 0, 0]);
+        assert_eq!(straight_rays(Position::a4)[2].len(), 13);
+    }
+}
@@ -0,0 +1,685 @@
+use super::{Board, EnPassant, Figure, Piece, StoredBoard};
+use crate::board::position::{Column, Line, Position, Row};
+use crate::vault::Color;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+// FEN4 lists per player values in this order
+const COLORS: [Color; 4] = [Color::Red, Color::Blue, Color::Yellow, Color::Green];
+
+// en passant field wraps the per player targets
+const EN_PASSANT_PREFIX: &str = "{'enPassant':(";
+const EN_PASSANT_SUFFIX: &str = ")}";
+
+// Compact snapshot layout, bumped on any change of it
+pub const COMPACT_VERSION: u8 = 1;
+// cell outside of the board (corners)
+const COMPACT_OFF_BOARD: u8 = 0xff;
+// header is version, to move and dead flags
+pub const COMPACT_LEN: usize = 3 + 14 * 14 + 4 * 4;
+
+// Position in FEN4 notation used by four-player chess clients:
+// <to move>-<dead>-<king side castling>-<queen side castling>-<points>-<halfmove clock>-<en passant>-<board>
+// Board is described from row 14 to row 1, column a to n, empty cells (corners too) as numbers.
+pub struct Fen4 {
+    pub board: Board,
+    pub to_move: Color,
+    pub dead: Vec<Color>,
+    pub points: [u32; 4],
+    pub halfmove_clock: u32,
+}
+
+fn color_letter(color: Color) -> char {
+    match color {
+        Color::Red => 'r',
+        Color::Blue => 'b',
+        Color::Yellow => 'y',
+        Color::Green => 'g',
+    }
+}
+
+fn letter_color(letter: char) -> Result<Color> {
+    match letter.to_ascii_lowercase() {
+        'r' => Ok(Color::Red),
+        'b' => Ok(Color::Blue),
+        'y' => Ok(Color::Yellow),
+        'g' => Ok(Color::Green),
+        _ => bail!("unknown color \"{}\"", letter),
+    }
+}
+
+fn figure_letter(figure: Figure) -> char {
+    match figure {
+        Figure::Pawn => 'P',
+        Figure::Knight => 'N',
+        Figure::Bishop => 'B',
+        Figure::Rook => 'R',
+        Figure::Queen => 'Q',
+        Figure::King => 'K',
+    }
+}
+
+fn letter_figure(letter: char) -> Result<Figure> {
+    match letter {
+        'P' => Ok(Figure::Pawn),
+        'N' => Ok(Figure::Knight),
+        'B' => Ok(Figure::Bishop),
+        'R' => Ok(Figure::Rook),
+        'Q' => Ok(Figure::Queen),
+        'K' => Ok(Figure::King),
+        _ => bail!("unknown figure \"{}\"", letter),
+    }
+}
+
+fn home_line(figure: Figure, color: Color) -> Line {
+    match (color, figure) {
+        (Color::Red, Figure::Pawn) => Line::Row(Row::R2),
+        (Color::Red, _) => Line::Row(Row::R1),
+        (Color::Blue, Figure::Pawn) => Line::Column(Column::b),
+        (Color::Blue, _) => Line::Column(Column::a),
+        (Color::Yellow, Figure::Pawn) => Line::Row(Row::R13),
+        (Color::Yellow, _) => Line::Row(Row::R14),
+        (Color::Green, Figure::Pawn) => Line::Column(Column::m),
+        (Color::Green, _) => Line::Column(Column::n),
+    }
+}
+
+fn color_index(color: Color) -> u8 {
+    COLORS.iter().position(|c| *c == color).unwrap_or(0) as u8
+}
+
+// figure in bits 0-2 (1 based), color in bits 3-4, stone in bit 5, zero is an empty cell
+fn compact_piece(piece: &Piece) -> u8 {
+    let figure = match piece.figure {
+        Figure::Pawn => 1,
+        Figure::Knight => 2,
+        Figure::Bishop => 3,
+        Figure::Rook => 4,
+        Figure::Queen => 5,
+        Figure::King => 6,
+    };
+    figure | color_index(piece.color) << 3 | (piece.stone as u8) << 5
+}
+
+fn join_flags(flags: impl Iterator<Item = bool>) -> String {
+    flags
+        .map(|f| if f { "1" } else { "0" })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// target and the pawn which stepped over it as 'e3:e4', empty when there is no double step
+fn en_passant_to_string(board: &Board) -> String {
+    let targets = COLORS
+        .iter()
+        .map(|c| match board.en_passant(*c) {
+            Some(ep) => format!("'{}:{}'", ep.target, ep.pawn),
+            None => "''".to_string(),
+        })
+        .collect::<Vec<_>>();
+    format!(
+        "{}{}{}",
+        EN_PASSANT_PREFIX,
+        targets.join(","),
+        EN_PASSANT_SUFFIX
+    )
+}
+
+fn parse_en_passant(field: &str) -> Result<[Option<EnPassant>; 4]> {
+    let targets = field
+        .strip_prefix(EN_PASSANT_PREFIX)
+        .and_then(|f| f.strip_suffix(EN_PASSANT_SUFFIX))
+        .context(format!("bad en passant \"{}\"", field))?;
+    let targets = targets
+        .split(',')
+        .map(|target| {
+            let target = target
+                .strip_prefix('\'')
+                .and_then(|t| t.strip_suffix('\''))
+                .context(format!("bad en passant target \"{}\"", target))?;
+            if target.is_empty() {
+                return Ok(None);
+            }
+            let (target, pawn) = target
+                .split_once(':')
+                .context(format!("en passant target \"{}\" has no pawn", target))?;
+            let cell = |cell: &str| {
+                Position::try_from(cell)
+                    .map_err(|_| anyhow::anyhow!("bad en passant cell \"{}\"", cell))
+            };
+            Ok(Some(EnPassant {
+                target: cell(target)?,
+                pawn: cell(pawn)?,
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    <[Option<EnPassant>; 4]>::try_from(targets)
+        .map_err(|_| anyhow::anyhow!("en passant \"{}\" must have 4 targets", field))
+}
+
+fn parse_list<T: std::str::FromStr>(field: &str) -> Result<[T; 4]> {
+    let values = field
+        .split(',')
+        .map(|v| v.parse::<T>().ok())
+        .collect::<Option<Vec<_>>>()
+        .context(format!("bad list \"{}\"", field))?;
+    <[T; 4]>::try_from(values).map_err(|_| anyhow::anyhow!("list \"{}\" must have 4 values", field))
+}
+
+impl Fen4 {
+    // FNV-1a of the FEN4 string, lets clients verify their state is exactly the server one
+    pub fn checksum(&self) -> String {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in self.to_string().bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        format!("{:016x}", hash)
+    }
+
+    // Byte per cell in the FEN4 board order followed by remaining milliseconds
+    // of every player as big endian u32, colors are in the FEN4 order.
+    pub fn compact(&self, clocks: [u32; 4]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(COMPACT_LEN);
+        bytes.push(COMPACT_VERSION);
+        bytes.push(color_index(self.to_move));
+        bytes.push(
+            COLORS
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| self.dead.contains(c))
+                .fold(0, |flags, (i, _)| flags | 1 << i),
+        );
+        for row_idx in (0..14).rev() {
+            for col_idx in 0..14 {
+                let cell = match Position::try_from((col_idx, row_idx)) {
+                    Ok(pos) => self.board.piece(pos).map_or(0, compact_piece),
+                    Err(_) => COMPACT_OFF_BOARD,
+                };
+                bytes.push(cell);
+            }
+        }
+        for clock in clocks.iter() {
+            bytes.extend_from_slice(&clock.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn board_to_string(&self) -> String {
+        let mut rows = Vec::new();
+        for row_idx in (0..14).rev() {
+            let mut cells = Vec::new();
+            let mut empty = 0;
+            for col_idx in 0..14 {
+                let piece = Position::try_from((col_idx, row_idx))
+                    .ok()
+                    .and_then(|pos| self.board.piece(pos));
+                match piece {
+                    Some(piece) => {
+                        if empty > 0 {
+                            cells.push(empty.to_string());
+                            empty = 0;
+                        }
+                        cells.push(format!(
+                            "{}{}",
+                            color_letter(piece.color),
+                            figure_letter(piece.figure)
+                        ));
+                    }
+                    None => empty += 1,
+                }
+            }
+            if empty > 0 {
+                cells.push(empty.to_string());
+            }
+            rows.push(cells.join(","));
+        }
+        rows.join("/")
+    }
+
+    // pieces of dead players still on the board are stones
+    fn parse_board(
+        board: &str,
+        castling: &HashMap<Position, bool>,
+        dead: &[Color],
+    ) -> Result<Board> {
+        let rows = board.split('/').collect::<Vec<_>>();
+        if rows.len() != 14 {
+            bail!("board must have 14 rows");
+        }
+
+        let mut pieces = HashMap::new();
+        for (row_idx, row) in (0..14).rev().zip(rows) {
+            let mut col_idx: usize = 0;
+            for cell in row.split(',') {
+                if let Ok(empty) = cell.parse::<usize>() {
+                    if empty == 0 || empty > 14 {
+                        bail!("bad empty cells count \"{}\"", cell);
+                    }
+                    col_idx = col_idx
+                        .checked_add(empty)
+                        .filter(|col_idx| *col_idx <= 14)
+                        .context(format!("row \"{}\" has more than 14 cells", row))?;
+                    continue;
+                }
+                let mut chars = cell.chars();
+                let (color, figure) = match (chars.next(), chars.next(), chars.next()) {
+                    (Some(c), Some(f), None) => (letter_color(c)?, letter_figure(f)?),
+                    _ => bail!("bad cell \"{}\"", cell),
+                };
+                let pos = Position::try_from((col_idx as isize, row_idx))
+                    .map_err(|_| anyhow::anyhow!("cell \"{}\" is out of board", cell))?;
+                let home_line = home_line(figure, color);
+                let have_not_move_yet = match figure {
+                    Figure::Pawn => home_line.contains(pos),
+                    Figure::King | Figure::Rook => *castling.get(&pos).unwrap_or(&false),
+                    _ => true,
+                };
+                pieces.insert(
+                    pos,
+                    Piece {
+                        figure,
+                        color,
+                        have_not_move_yet,
+                        home_line,
+                        stone: dead.contains(&color),
+                    },
+                );
+                col_idx += 1;
+            }
+            if col_idx != 14 {
+                bail!("row \"{}\" must have 14 cells", row);
+            }
+        }
+
+        Ok(Board::from(StoredBoard {
+            pieces,
+            en_passant: [None; 4],
+        }))
+    }
+}
+
+impl fmt::Display for Fen4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rights = COLORS
+            .iter()
+            .map(|c| self.board.castling_rights(*c))
+            .collect::<Vec<_>>();
+        write!(
+            f,
+            "{}-{}-{}-{}-{}-{}-{}-{}",
+            color_letter(self.to_move).to_ascii_uppercase(),
+            join_flags(COLORS.iter().map(|c| self.dead.contains(c))),
+            join_flags(rights.iter().map(|r| r.king_side)),
+            join_flags(rights.iter().map(|r| r.queen_side)),
+            self.points
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            self.halfmove_clock,
+            en_passant_to_string(&self.board),
+            self.board_to_string()
+        )
+    }
+}
+
+impl TryFrom<&str> for Fen4 {
+    type Error = anyhow::Error;
+
+    fn try_from(fen: &str) -> Result<Self> {
+        let fields = fen.splitn(8, '-').collect::<Vec<_>>();
+        if fields.len() != 8 {
+            bail!("FEN4 must have 8 fields");
+        }
+
+        let mut to_move_chars = fields[0].chars();
+        let to_move = match (to_move_chars.next(), to_move_chars.next()) {
+            (Some(c), None) => letter_color(c)?,
+            _ => bail!("bad side to move \"{}\"", fields[0]),
+        };
+
+        let dead = parse_list::<u8>(fields[1])?;
+        let king_side = parse_list::<u8>(fields[2])?;
+        let queen_side = parse_list::<u8>(fields[3])?;
+        let points = parse_list::<u32>(fields[4])?;
+        let halfmove_clock = fields[5]
+            .parse()
+            .context(format!("bad halfmove clock \"{}\"", fields[5]))?;
+        let en_passant = parse_en_passant(fields[6])?;
+
+        // king and rooks which still can castle are the unmoved ones
+        let mut castling = HashMap::new();
+        for (i, color) in COLORS.iter().enumerate() {
+            let squares = super::castling_squares(*color);
+            let (ks, qs) = (king_side[i] == 1, queen_side[i] == 1);
+            castling.insert(squares.king, ks || qs);
+            castling.insert(squares.king_side_rook, ks);
+            castling.insert(squares.queen_side_rook, qs);
+        }
+
+        let dead = COLORS
+            .iter()
+            .zip(dead.iter())
+            .filter(|(_, d)| **d == 1)
+            .map(|(c, _)| *c)
+            .collect::<Vec<_>>();
+        let mut board = Fen4::parse_board(fields[7], &castling, &dead)?;
+        // the pawn which stepped over the target stands behind it
+        for (color, ep) in COLORS.iter().zip(en_passant.iter()) {
+            if let Some(ep) = ep {
+                let pawn = board.piece(ep.pawn);
+                if !pawn.is_some_and(|p| p.color == *color && p.figure.is(Figure::Pawn)) {
+                    bail!(
+                        "en passant pawn of {} is not on {}",
+                        color.to_string(),
+                        ep.pawn
+                    );
+                }
+                if board.piece(ep.target).is_some() {
+                    bail!("en passant target {} is occupied", ep.target);
+                }
+            }
+            board.set_en_passant(*color, *ep);
+        }
+        for (i, color) in COLORS.iter().enumerate() {
+            let rights = board.castling_rights(*color);
+            if rights.king_side != (king_side[i] == 1) || rights.queen_side != (queen_side[i] == 1)
+            {
+                bail!(
+                    "castling rights of {} do not match the board",
+                    color.to_string()
+                );
+            }
+        }
+
+        Ok(Fen4 {
+            board,
+            to_move,
+            dead,
+            points,
+            halfmove_clock,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::Move;
+    use crate::vault::{fixtures::game, Game};
+
+    fn fen4(board: Board) -> Fen4 {
+        Fen4 {
+            board,
+            to_move: Color::Red,
+            dead: Vec::new(),
+            points: [0; 4],
+            halfmove_clock: 0,
+        }
+    }
+
+    fn round_trip(fen: &Fen4) -> Fen4 {
+        Fen4::try_from(fen.to_string().as_str()).unwrap()
+    }
+
+    #[test]
+    fn initial_position_round_trip() {
+        let fen = fen4(Board::new());
+        let restored = round_trip(&fen);
+        assert_eq!(fen.to_string(), restored.to_string());
+        assert_eq!(fen.checksum(), restored.checksum());
+        for color in COLORS.iter() {
+            assert_eq!(
+                restored.board.castling_rights(*color),
+                super::super::CastlingRights {
+                    king_side: true,
+                    queen_side: true
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn castling_rights_round_trip_after_rook_move() {
+        let mut board = Board::new();
+        board.piece_move(Position::j1, Position::i3);
+        board.piece_move(Position::k1, Position::j1);
+        board.piece_move(Position::n11, Position::l10);
+
+        let restored = round_trip(&fen4(board));
+        let red = restored.board.castling_rights(Color::Red);
+        assert!(!red.king_side);
+        assert!(red.queen_side);
+        let green = restored.board.castling_rights(Color::Green);
+        assert!(green.king_side);
+        assert!(!green.queen_side);
+        assert_eq!(restored.to_string(), round_trip(&restored).to_string());
+    }
+
+    #[test]
+    fn castling_rights_lost_after_king_move() {
+        let mut board = Board::new();
+        board.piece_move(Position::a9, Position::c11);
+        board.piece_move(Position::a8, Position::a9);
+
+        let restored = round_trip(&fen4(board));
+        let blue = restored.board.castling_rights(Color::Blue);
+        assert!(!blue.king_side);
+        assert!(!blue.queen_side);
+    }
+
+    #[test]
+    fn checksum_depends_on_castling_rights() {
+        // rook goes away and comes back, pieces placement is the same as initial
+        let mut board = Board::new();
+        board.piece_move(Position::d13, Position::d11);
+        board.piece_move(Position::d14, Position::d12);
+        board.piece_move(Position::d12, Position::d14);
+        board.piece_move(Position::d11, Position::d13);
+
+        let initial = fen4(Board::new());
+        let moved = fen4(board);
+        assert_eq!(initial.board_to_string(), moved.board_to_string());
+        assert_ne!(initial.checksum(), moved.checksum());
+        assert_eq!(moved.checksum(), round_trip(&moved).checksum());
+    }
+
+    #[test]
+    fn compact_snapshot_layout() {
+        let mut fen = fen4(Board::new());
+        fen.to_move = Color::Blue;
+        fen.dead = vec![Color::Green];
+        let bytes = fen.compact([1000, 2000, 3000, 4000]);
+        assert_eq!(bytes.len(), COMPACT_LEN);
+        assert_eq!(&bytes[..3], &[COMPACT_VERSION, 1, 0b1000]);
+        // a14 is a corner, d14 is the yellow rook, d1 is the red rook
+        assert_eq!(bytes[3], COMPACT_OFF_BOARD);
+        assert_eq!(bytes[3 + 3], 4 | 2 << 3);
+        assert_eq!(bytes[3 + 13 * 14 + 3], 4);
+        assert_eq!(bytes[3 + 14 * 7 + 6], 0);
+        assert_eq!(&bytes[COMPACT_LEN - 4..], &4000u32.to_be_bytes());
+    }
+
+    // the move is made by the given color, as the game session does it in turn
+    fn play(game: &mut Game, color: Color, mv: Move) {
+        game.who_move.as_mut().unwrap().color = color;
+        game.apply_move(&mv).unwrap();
+    }
+
+    fn assert_round_trip(game: &Game) -> Fen4 {
+        assert_eq!(game.board.key(), game.board.full_key());
+        let fen = game.fen4();
+        let restored = round_trip(&fen);
+        assert_eq!(fen.to_string(), restored.to_string());
+        assert_eq!(fen.checksum(), restored.checksum());
+        assert_eq!(restored.board.key(), restored.board.full_key());
+        restored
+    }
+
+    // blue pawn waits on d4 next to the red double step
+    fn en_passant_game() -> Game {
+        let mut board = Board::new();
+        board.piece_move(Position::b4, Position::d4);
+        let mut game = game(board);
+        play(
+            &mut game,
+            Color::Red,
+            Move::Basic {
+                from: Position::e2,
+                to: Position::e4,
+            },
+        );
+        game
+    }
+
+    #[test]
+    fn en_passant_target_round_trip() {
+        let fen = en_passant_game().fen4();
+        assert!(fen.to_string().contains("{'enPassant':('e3:e4','','','')}"));
+        let restored = assert_round_trip(&en_passant_game());
+        assert_eq!(
+            restored.board.en_passant(Color::Red),
+            Some(EnPassant {
+                target: Position::e3,
+                pawn: Position::e4
+            })
+        );
+        // the target is position data only, en passant is forbidden
+        let mut restored = game(restored.board);
+        restored.who_move.as_mut().unwrap().color = Color::Blue;
+        assert!(!restored
+            .legal_moves(Color::Blue, Position::d4)
+            .iter()
+            .any(|mv| matches!(
+                mv,
+                Move::Capture {
+                    to: Position::e3,
+                    ..
+                }
+            )));
+
+        // the same placement without the double step
+        let mut board = Board::new();
+        board.piece_move(Position::b4, Position::d4);
+        board.piece_move(Position::e2, Position::e4);
+        let stepped = fen4(board);
+        assert_eq!(fen.board_to_string(), stepped.board_to_string());
+        assert_ne!(fen.checksum(), stepped.checksum());
+        assert_ne!(fen.board.key(), stepped.board.key());
+    }
+
+    #[test]
+    fn en_passant_capture_rejected() {
+        let mut game = en_passant_game();
+        game.who_move.as_mut().unwrap().color = Color::Blue;
+        assert!(game
+            .apply_move(&Move::Capture {
+                from: Position::d4,
+                to: Position::e3,
+            })
+            .is_err());
+        assert!(game.board.piece(Position::e4).is_some());
+        assert_round_trip(&game);
+    }
+
+    #[test]
+    fn en_passant_target_expires_with_the_next_move() {
+        let mut game = en_passant_game();
+        play(
+            &mut game,
+            Color::Red,
+            Move::Basic {
+                from: Position::f2,
+                to: Position::f3,
+            },
+        );
+        assert_eq!(game.board.en_passant(Color::Red), None);
+        let restored = assert_round_trip(&game);
+        assert!(restored.to_string().contains("{'enPassant':('','','','')}"));
+    }
+
+    #[test]
+    fn castling_and_en_passant_round_trip() {
+        let mut board = Board::new();
+        for pos in [Position::e1, Position::f1, Position::g1] {
+            board.piece_move(pos, Position::h7);
+        }
+        let mut game = game(board);
+        play(&mut game, Color::Red, Move::Castling { rook: Position::d1 });
+        play(
+            &mut game,
+            Color::Yellow,
+            Move::Basic {
+                from: Position::e13,
+                to: Position::e11,
+            },
+        );
+        play(
+            &mut game,
+            Color::Green,
+            Move::Basic {
+                from: Position::m10,
+                to: Position::k10,
+            },
+        );
+        let restored = assert_round_trip(&game);
+        let red = restored.board.castling_rights(Color::Red);
+        assert!(!red.queen_side && !red.king_side);
+        assert!(restored
+            .to_string()
+            .contains("{'enPassant':('','','e12:e11','l10:k10')}"));
+        // the next move of green lets its double step expire
+        play(
+            &mut game,
+            Color::Green,
+            Move::Basic {
+                from: Position::m9,
+                to: Position::l9,
+            },
+        );
+        assert_eq!(game.board.en_passant(Color::Green), None);
+        assert_round_trip(&game);
+    }
+
+    #[test]
+    fn en_passant_pawn_must_be_on_the_board() {
+        let fen = en_passant_game().fen4().to_string();
+        assert!(Fen4::try_from(fen.as_str()).is_ok());
+        let moved = fen.replace("'e3:e4'", "'e3:e5'");
+        assert!(Fen4::try_from(moved.as_str()).is_err());
+        let malformed = fen.replace("'e3:e4'", "'e3'");
+        assert!(Fen4::try_from(malformed.as_str()).is_err());
+    }
+
+    #[test]
+    fn bad_empty_cells_counts_rejected() {
+        let fen = fen4(Board::new()).to_string();
+        // yN over yR with the row still adding up to 14
+        let negative = fen.replacen("3,yR,yN,", "3,yR,-1,yN,yN,", 1);
+        assert!(Fen4::try_from(negative.as_str()).is_err());
+        let huge = fen.replacen("3,yR,", &format!("{},yR,", usize::MAX), 1);
+        assert!(Fen4::try_from(huge.as_str()).is_err());
+        let zero = fen.replacen("3,yR,", "0,3,yR,", 1);
+        assert!(Fen4::try_from(zero.as_str()).is_err());
+        let wide = fen.replacen("/14/", "/15/", 1);
+        assert!(Fen4::try_from(wide.as_str()).is_err());
+    }
+
+    #[test]
+    fn malformed_fen_rejected() {
+        assert!(Fen4::try_from("R-0,0,0,0").is_err());
+        let fen = fen4(Board::new()).to_string();
+        assert!(Fen4::try_from(fen.replace("rK", "rX").as_str()).is_err());
+        // castling right claimed for a rook which is not on its square
+        let mut board = Board::new();
+        board.piece_move(Position::j1, Position::i3);
+        board.piece_move(Position::k1, Position::j1);
+        let fen = fen4(board)
+            .to_string()
+            .replacen("-0,1,1,1-", "-1,1,1,1-", 1);
+        assert!(Fen4::try_from(fen.as_str()).is_err());
+    }
+}
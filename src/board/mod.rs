@@ -1,13 +1,18 @@
+mod cells;
+pub mod fen4;
+#[cfg(any(test, feature = "fixtures"))]
+pub mod perft;
 pub mod position;
+pub mod zobrist;
 
 use crate::vault::Color;
 use anyhow::{Context, Result};
 use enum_iterator::IntoEnumIterator;
+pub use fen4::Fen4;
 use once_cell::sync::Lazy;
 pub use position::{Column, Direction, Line, Position, Row};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::convert::TryFrom;
 
 pub struct CastlingPattern {
     pub space_between: Vec<Position>,
@@ -94,6 +99,46 @@ pub static CASTLING_PATTERNS: Lazy<HashMap<(Position, Position), CastlingPattern
         m
     });
 
+// colors in the order of the board arrays
+const COLORS: [Color; 4] = [Color::Red, Color::Green, Color::Blue, Color::Yellow];
+
+pub struct CastlingSquares {
+    pub king: Position,
+    pub king_side_rook: Position,
+    pub queen_side_rook: Position,
+}
+
+pub fn castling_squares(color: Color) -> CastlingSquares {
+    match color {
+        Color::Red => CastlingSquares {
+            king: Position::h1,
+            king_side_rook: Position::k1,
+            queen_side_rook: Position::d1,
+        },
+        Color::Blue => CastlingSquares {
+            king: Position::a8,
+            king_side_rook: Position::a11,
+            queen_side_rook: Position::a4,
+        },
+        Color::Yellow => CastlingSquares {
+            king: Position::g14,
+            king_side_rook: Position::d14,
+            queen_side_rook: Position::k14,
+        },
+        Color::Green => CastlingSquares {
+            king: Position::n7,
+            king_side_rook: Position::n4,
+            queen_side_rook: Position::n11,
+        },
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CastlingRights {
+    pub king_side: bool,
+    pub queen_side: bool,
+}
+
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
 pub enum Figure {
     Pawn,
@@ -105,19 +150,29 @@ pub enum Figure {
 }
 
 impl Figure {
+    // pawn may be promoted into
+    pub fn is_promotion(&self) -> bool {
+        matches!(
+            self,
+            Figure::Queen | Figure::Rook | Figure::Bishop | Figure::Knight
+        )
+    }
     pub fn is(&self, figure: Figure) -> bool {
-        matches!(self, figure)
+        *self == figure
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Piece {
-    figure: Figure,
+    pub figure: Figure,
     pub color: Color,
     // need for king rook castling
     have_not_move_yet: bool,
     // need for pawn direction determine
     pub home_line: Line,
+    // piece of the lost player left as immovable blocker, can not be captured
+    #[serde(default)]
+    pub stone: bool,
 }
 
 impl Piece {
@@ -127,11 +182,22 @@ impl Piece {
             color,
             home_line,
             have_not_move_yet: true,
+            stone: false,
         }
     }
     pub fn already_move(&self) -> bool {
         return !self.have_not_move_yet;
     }
+    // pawns are promoted on the 8th line counting from the player's side
+    pub fn promotion_line(&self) -> Option<Line> {
+        match self.home_line {
+            Line::Row(Row::R1) | Line::Row(Row::R2) => Some(Line::Row(Row::R8)),
+            Line::Row(Row::R13) | Line::Row(Row::R14) => Some(Line::Row(Row::R7)),
+            Line::Column(Column::a) | Line::Column(Column::b) => Some(Line::Column(Column::h)),
+            Line::Column(Column::m) | Line::Column(Column::n) => Some(Line::Column(Column::g)),
+            _ => None,
+        }
+    }
 }
 
 pub enum CheckMate {
@@ -140,19 +206,78 @@ pub enum CheckMate {
     Checkmate,
 }
 
-struct Restore {
-    from: CellPos,
-    to: CellPos,
+// cells as they were before the change, any number of them
+type Restore = Vec<CellPos>;
+
+// Pawn of the color stepped over the target cell on its first move, kept until the color
+// moves again. En passant is forbidden, the target only tells positions apart.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EnPassant {
+    pub target: Position,
+    pub pawn: Position,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "StoredBoard", into = "StoredBoard")]
 pub struct Board {
+    // mailbox of the cells, indexed by cells::index
+    pieces: Vec<Option<Piece>>,
+    // cell of the king of every color, indexed by the color
+    kings: [Option<Position>; 4],
+    // double step of every color, indexed by the color
+    en_passant: [Option<EnPassant>; 4],
+    // changes to undo, the last one first
+    #[serde(skip)]
+    restore: Vec<Restore>,
+    // zobrist key of the pieces, kept up to date by every change of the board
+    #[serde(skip)]
+    key: u64,
+}
+
+// boards are stored as pieces by position without keys, they are computed again on load
+#[derive(Serialize, Deserialize)]
+struct StoredBoard {
     pieces: HashMap<Position, Piece>,
-    restore: Option<Restore>,
+    #[serde(default)]
+    en_passant: [Option<EnPassant>; 4],
 }
 
-struct RawMove {
-    from: Position,
-    to: Position,
+impl From<StoredBoard> for Board {
+    fn from(stored: StoredBoard) -> Board {
+        let mut board = Board {
+            pieces: vec![None; cells::CELLS],
+            kings: [None; 4],
+            en_passant: [None; 4],
+            restore: Vec::new(),
+            key: 0,
+        };
+        for (pos, piece) in stored.pieces {
+            board.insert(pos, piece);
+        }
+        for (color, en_passant) in COLORS.iter().zip(stored.en_passant) {
+            board.set_en_passant(*color, en_passant);
+        }
+        board
+    }
+}
+
+impl From<Board> for StoredBoard {
+    fn from(board: Board) -> StoredBoard {
+        StoredBoard {
+            pieces: board
+                .pieces()
+                .map(|(pos, piece)| (pos, piece.clone()))
+                .collect(),
+            en_passant: board.en_passant,
+        }
+    }
+}
+
+// step of the piece, promotion and castling are told apart by the game
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RawMove {
+    pub from: Position,
+    pub to: Position,
 }
 
 impl<'a> Board {
@@ -174,7 +299,7 @@ impl<'a> Board {
         let mut pieces = HashMap::new();
 
         for position in Position::into_enum_iter() {
-            let position_col_row = (position.column(), position.row());
+            let position_col_row = position.col_row();
 
             match position_col_row {
                 (_, Row::R2) => pieces.insert(
@@ -228,231 +353,276 @@ impl<'a> Board {
                 _ => None,
             };
         }
-        return Board {
+        Board::from(StoredBoard {
             pieces,
-            restore: None,
-        };
+            en_passant: [None; 4],
+        })
     }
 
     pub fn piece(&self, pos: Position) -> Option<&Piece> {
-        self.pieces.get(&pos)
+        self.pieces[cells::index(pos)].as_ref()
     }
 
-    pub fn attackers_on_position(&self, target_pos: Position) -> Option<Vec<PiecePos>> {
-        let mut attackers = Vec::new();
+    fn pieces(&self) -> impl Iterator<Item = (Position, &Piece)> {
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter_map(|(index, cell)| cell.as_ref().map(|piece| (cells::position(index), piece)))
+    }
 
-        let row_idx = target_pos.row().get_index();
-        let col_idx = target_pos.column().get_index();
-
-        let knights_shifts = [
-            (2, 1),
-            (1, 2),
-            (2, -1),
-            (1, -2),
-            (-2, 1),
-            (-1, 2),
-            (-2, -1),
-            (-1, -2),
-        ];
-        for knight_shift in &knights_shifts {
-            if let Ok(attacker_pos) =
-                Position::try_from((col_idx + knight_shift.0, row_idx + knight_shift.1))
-            {
-                if let Some(attacker_piece) = self.piece(attacker_pos) {
-                    if attacker_piece.figure == Figure::Knight {
-                        attackers.push(PiecePos {
-                            position: attacker_pos,
-                            piece: attacker_piece,
-                        });
-                    }
+    // fingerprint of the position, the player to move is not included
+    pub fn key(&self) -> u64 {
+        self.key
+    }
+
+    // keys are updated on every change, computed anew only to check them
+    #[cfg(test)]
+    fn full_key(&self) -> u64 {
+        let key = self
+            .pieces()
+            .fold(0, |key, (pos, piece)| key ^ zobrist::piece_key(pos, piece));
+        COLORS
+            .iter()
+            .filter_map(|color| self.en_passant(*color).map(|ep| (color, ep)))
+            .fold(key, |key, (color, ep)| {
+                key ^ zobrist::en_passant_key(*color, ep.target)
+            })
+    }
+
+    pub fn en_passant(&self, color: Color) -> Option<EnPassant> {
+        self.en_passant[color as usize]
+    }
+
+    pub fn set_en_passant(&mut self, color: Color, en_passant: Option<EnPassant>) {
+        let old = std::mem::replace(&mut self.en_passant[color as usize], en_passant);
+        for ep in old.iter().chain(en_passant.iter()) {
+            self.key ^= zobrist::en_passant_key(color, ep.target);
+        }
+    }
+
+    // Move of the piece in the game: the double step of its color expires, the double step
+    // leaves its target. Returns the captured piece.
+    pub fn play(&mut self, from: Position, to: Position) -> Option<Piece> {
+        let piece = self.piece(from)?.clone();
+        self.set_en_passant(piece.color, None);
+        let captured = self.piece_move(from, to);
+        if piece.figure.is(Figure::Pawn) && !piece.already_move() {
+            let forward = Direction::try_all_from_home_line(piece.home_line)
+                .map(|directions| directions.forward);
+            if let Ok(forward) = forward {
+                if from.step(&forward, 2) == Ok(to) {
+                    let target = from.step(&forward, 1).ok();
+                    let double_step = target.map(|target| EnPassant { target, pawn: to });
+                    self.set_en_passant(piece.color, double_step);
                 }
             }
         }
+        captured
+    }
 
-        let diagonals = [(1, 1), (1, -1), (-1, 1), (-1, -1)];
-        for shift in &diagonals {
-            let mut distance = 0;
-            while let Ok(attacker_pos) = Position::try_from((col_idx + shift.0, row_idx + shift.1))
-            {
-                if let Some(attacker_piece) = self.piece(attacker_pos) {
-                    match attacker_piece.figure {
-                        Figure::Rook | Figure::Knight => break,
-                        Figure::Queen | Figure::Bishop => {
-                            attackers.push(PiecePos {
-                                position: attacker_pos,
-                                piece: attacker_piece,
-                            });
-                            break;
-                        }
-                        Figure::Pawn => {
-                            if distance == 0 {
-                                match &attacker_piece.home_line {
-                                    Line::Column(attacker_starting_col) => {
-                                        if attacker_starting_col.get_index() == 1 {
-                                            if attacker_pos.column().get_index()
-                                                < target_pos.column().get_index()
-                                            {
-                                                attackers.push(PiecePos {
-                                                    position: attacker_pos,
-                                                    piece: attacker_piece,
-                                                });
-                                            }
-                                        } else {
-                                            if attacker_pos.column().get_index()
-                                                > target_pos.column().get_index()
-                                            {
-                                                attackers.push(PiecePos {
-                                                    position: attacker_pos,
-                                                    piece: attacker_piece,
-                                                });
-                                            }
-                                        }
-                                    }
-                                    Line::Row(attacker_starting_row) => {
-                                        if attacker_starting_row.get_index() == 1 {
-                                            if attacker_pos.row().get_index()
-                                                < target_pos.row().get_index()
-                                            {
-                                                attackers.push(PiecePos {
-                                                    position: attacker_pos,
-                                                    piece: attacker_piece,
-                                                });
-                                            }
-                                        } else {
-                                            if attacker_pos.row().get_index()
-                                                > target_pos.row().get_index()
-                                            {
-                                                attackers.push(PiecePos {
-                                                    position: attacker_pos,
-                                                    piece: attacker_piece,
-                                                });
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            break;
-                        }
-                        Figure::King => {
-                            if distance == 0 {
-                                attackers.push(PiecePos {
-                                    position: attacker_pos,
-                                    piece: attacker_piece,
-                                });
-                            }
-                            break;
-                        }
-                    }
+    // every change of the pieces goes through insert and remove to keep the key and kings
+    fn insert(&mut self, pos: Position, piece: Piece) -> Option<Piece> {
+        let replaced = self.remove(pos);
+        self.key ^= zobrist::piece_key(pos, &piece);
+        if piece.figure.is(Figure::King) {
+            self.kings[piece.color as usize] = Some(pos);
+        }
+        self.pieces[cells::index(pos)] = Some(piece);
+        replaced
+    }
+
+    fn remove(&mut self, pos: Position) -> Option<Piece> {
+        let removed = self.pieces[cells::index(pos)].take();
+        if let Some(removed) = &removed {
+            self.key ^= zobrist::piece_key(pos, removed);
+            if self.kings[removed.color as usize] == Some(pos) {
+                self.kings[removed.color as usize] = None;
+            }
+        }
+        removed
+    }
+
+    fn unmoved(&self, pos: Position, figure: Figure, color: Color) -> bool {
+        match self.piece(pos) {
+            Some(piece) => piece.figure == figure && piece.color == color && !piece.already_move(),
+            None => false,
+        }
+    }
+
+    // castling still possible some time later, both king and rook never moved
+    pub fn castling_rights(&self, color: Color) -> CastlingRights {
+        let squares = castling_squares(color);
+        let king = self.unmoved(squares.king, Figure::King, color);
+        CastlingRights {
+            king_side: king && self.unmoved(squares.king_side_rook, Figure::Rook, color),
+            queen_side: king && self.unmoved(squares.queen_side_rook, Figure::Rook, color),
+        }
+    }
+
+    // pieces of the other colors attacking the cell of the defender
+    pub fn attackers_on_position(
+        &self,
+        target_pos: Position,
+        defender: Color,
+    ) -> Vec<PiecePos<'_>> {
+        let mut attackers = Vec::new();
+
+        for attacker_pos in cells::knight_jumps(target_pos) {
+            if let Some(attacker_piece) = self.piece(*attacker_pos) {
+                if attacker_piece.figure == Figure::Knight {
+                    attackers.push(PiecePos {
+                        position: *attacker_pos,
+                        piece: attacker_piece,
+                    });
                 }
-                distance += 1;
             }
         }
 
-        let vertizontals = [(0, 1), (0, -1), (1, 0), (-1, 0)];
-        for shift in &vertizontals {
-            let mut distance = 0;
-            while let Ok(attacker_pos) = Position::try_from((col_idx + shift.0, row_idx + shift.1))
-            {
-                if let Some(attacker_piece) = self.piece(attacker_pos) {
-                    match attacker_piece.figure {
-                        Figure::Pawn | Figure::Knight | Figure::Bishop => break,
-                        Figure::Queen | Figure::Rook => {
-                            attackers.push(PiecePos {
-                                position: attacker_pos,
-                                piece: attacker_piece,
-                            });
-                            break;
-                        }
-                        Figure::King => {
-                            if distance == 0 {
-                                attackers.push(PiecePos {
-                                    position: attacker_pos,
-                                    piece: attacker_piece,
-                                });
-                            }
-                            break;
-                        }
+        for ray in cells::diagonal_rays(target_pos) {
+            if let Some((distance, attacker_pos, attacker_piece)) = self.first_piece(ray) {
+                let attacks = match attacker_piece.figure {
+                    Figure::Rook | Figure::Knight => false,
+                    Figure::Queen | Figure::Bishop => true,
+                    Figure::Pawn => {
+                        distance == 0 && pawn_faces(attacker_piece, attacker_pos, target_pos)
                     }
+                    Figure::King => distance == 0,
+                };
+                if attacks {
+                    attackers.push(PiecePos {
+                        position: attacker_pos,
+                        piece: attacker_piece,
+                    });
                 }
-                distance += 1;
             }
         }
 
-        if attackers.len() > 0 {
-            return Some(attackers);
-        } else {
-            return None;
+        for ray in cells::straight_rays(target_pos) {
+            if let Some((distance, attacker_pos, attacker_piece)) = self.first_piece(ray) {
+                let attacks = match attacker_piece.figure {
+                    Figure::Pawn | Figure::Knight | Figure::Bishop => false,
+                    Figure::Queen | Figure::Rook => true,
+                    Figure::King => distance == 0,
+                };
+                if attacks {
+                    attackers.push(PiecePos {
+                        position: attacker_pos,
+                        piece: attacker_piece,
+                    });
+                }
+            }
         }
+
+        // own pieces defend the cell, stones block the lines but attack nothing
+        attackers.retain(|attacker| attacker.piece.color != defender && !attacker.piece.stone);
+        attackers
+    }
+
+    // nearest piece on the ray and its distance, 0 for the adjacent cell
+    fn first_piece(&self, ray: &[Position]) -> Option<(usize, Position, &Piece)> {
+        ray.iter()
+            .enumerate()
+            .find_map(|(distance, pos)| self.piece(*pos).map(|piece| (distance, *pos, piece)))
     }
 
     pub fn find_king(&self, color: Color) -> Option<PiecePos> {
-        for (position, piece) in &self.pieces {
-            if piece.figure.is(Figure::King) && piece.color == color {
-                return Some(PiecePos {
-                    position: *position,
-                    piece: &piece,
-                });
+        let position = self.kings[color as usize]?;
+        self.piece(position)
+            .map(|piece| PiecePos { piece, position })
+    }
+
+    // pieces of the lost player are removed
+    pub fn clear(&mut self, color: Color) {
+        self.set_en_passant(color, None);
+        for pos in self.positions_of(color) {
+            self.remove(pos);
+        }
+    }
+
+    // pieces of the lost player stay on the board as blockers
+    pub fn turn_to_stone(&mut self, color: Color) {
+        self.set_en_passant(color, None);
+        for pos in self.positions_of(color) {
+            if let Some(mut piece) = self.remove(pos) {
+                piece.stone = true;
+                self.insert(pos, piece);
             }
         }
-        None
+    }
+
+    fn positions_of(&self, color: Color) -> Vec<Position> {
+        self.pieces()
+            .filter(|(_, piece)| piece.color == color)
+            .map(|(pos, _)| pos)
+            .collect()
     }
 
     pub fn piece_move(&mut self, from: Position, to: Position) -> Option<Piece> {
-        if let Some(mut piece) = self.pieces.remove(&from) {
+        if let Some(mut piece) = self.remove(from) {
             piece.have_not_move_yet = false;
-            return self.pieces.insert(to, piece);
+            return self.insert(to, piece);
         }
         None
     }
 
-    pub fn restorable_piece_move(&mut self, from: Position, to: Position) -> Option<Piece> {
-        self.restore = Some(Restore {
-            from: CellPos {
-                cell: match self.pieces.get(&from) {
-                    Some(piece) => Some(piece.clone()),
-                    None => None,
-                },
-                position: from,
-            },
-            to: CellPos {
-                cell: match self.pieces.get(&to) {
-                    Some(piece) => Some(piece.clone()),
-                    None => None,
-                },
-                position: to,
-            },
-        });
+    // replace the piece keeping its color and home line
+    pub fn promote(&mut self, pos: Position, figure: Figure) -> bool {
+        match self.remove(pos) {
+            Some(mut piece) => {
+                piece.figure = figure;
+                self.insert(pos, piece);
+                true
+            }
+            None => false,
+        }
+    }
 
+    pub fn restorable_piece_move(&mut self, from: Position, to: Position) -> Option<Piece> {
+        self.remember(&[from, to]);
         self.piece_move(from, to)
     }
 
-    pub fn restore_move(&mut self) -> bool {
-        let restore = match self.restore.as_ref() {
-            Some(restore) => restore,
-            None => return false,
-        };
+    // several pieces moved in order as one change, e.g. rook and king of the castling
+    pub fn restorable_piece_moves(&mut self, moves: &[(Position, Position)]) {
+        let cells = moves
+            .iter()
+            .flat_map(|(from, to)| [*from, *to])
+            .collect::<Vec<_>>();
+        self.remember(&cells);
+        for (from, to) in moves {
+            self.piece_move(*from, *to);
+        }
+    }
 
-        let from = &restore.from;
-        match &from.cell {
-            Some(piece) => {
-                self.pieces.insert(from.position, piece.clone());
-            }
-            None => {
-                self.pieces.remove(&from.position);
+    fn remember(&mut self, cells: &[Position]) {
+        let mut restore: Restore = Vec::with_capacity(cells.len());
+        for position in cells {
+            if restore.iter().all(|cell| cell.position != *position) {
+                restore.push(CellPos {
+                    cell: self.piece(*position).cloned(),
+                    position: *position,
+                });
             }
         }
+        self.restore.push(restore);
+    }
 
-        let to = &restore.to;
-        match &to.cell {
-            Some(piece) => {
-                self.pieces.insert(to.position, piece.clone());
-            }
-            None => {
-                self.pieces.remove(&to.position);
+    // undo the last restorable change, false if there is none
+    pub fn restore_move(&mut self) -> bool {
+        let restore = match self.restore.pop() {
+            Some(restore) => restore,
+            None => return false,
+        };
+        for cell in restore {
+            match cell.cell {
+                Some(piece) => {
+                    self.insert(cell.position, piece);
+                }
+                None => {
+                    self.remove(cell.position);
+                }
             }
         }
-
-        self.restore = None;
         true
     }
 
@@ -467,12 +637,16 @@ impl<'a> Board {
         let mut moves = Vec::new();
 
         if have_not_move_yet {
-            let step_to = pos.step(&directions.forward, 2).unwrap();
-            if self.piece(step_to).is_none() {
-                moves.push(RawMove {
-                    from: pos,
-                    to: step_to,
-                });
+            if let (Ok(step_over), Ok(step_to)) = (
+                pos.step(&directions.forward, 1),
+                pos.step(&directions.forward, 2),
+            ) {
+                if self.piece(step_over).is_none() && self.piece(step_to).is_none() {
+                    moves.push(RawMove {
+                        from: pos,
+                        to: step_to,
+                    });
+                }
             }
         }
 
@@ -487,7 +661,7 @@ impl<'a> Board {
 
         if let Ok(eat_to) = pos.step(&directions.forward_left, 1) {
             if let Some(piece) = self.piece(eat_to) {
-                if piece.color != our_color {
+                if piece.color != our_color && !piece.stone {
                     moves.push(RawMove {
                         from: pos,
                         to: eat_to,
@@ -498,7 +672,7 @@ impl<'a> Board {
 
         if let Ok(eat_to) = pos.step(&directions.forward_right, 1) {
             if let Some(piece) = self.piece(eat_to) {
-                if piece.color != our_color {
+                if piece.color != our_color && !piece.stone {
                     moves.push(RawMove {
                         from: pos,
                         to: eat_to,
@@ -509,132 +683,54 @@ impl<'a> Board {
         return moves;
     }
 
-    fn moves_lines(
+    fn moves_rays(
         &self,
-        directions: &Vec<&Direction>,
+        rays: &[Vec<Position>],
         max_distance: usize,
         pos: Position,
         our_color: Color,
     ) -> Vec<RawMove> {
         let mut moves = Vec::new();
 
-        for direction in directions {
-            for step_dist in 1..=max_distance {
-                if let Ok(step_to) = pos.step(direction, step_dist as usize) {
-                    if let Some(piece) = self.piece(step_to) {
-                        if piece.color != our_color {
-                            moves.push(RawMove {
-                                from: pos,
-                                to: step_to,
-                            });
-                        }
-                        break;
-                    } else {
+        for ray in rays {
+            for step_to in ray.iter().take(max_distance) {
+                if let Some(piece) = self.piece(*step_to) {
+                    if piece.color != our_color && !piece.stone {
                         moves.push(RawMove {
                             from: pos,
-                            to: step_to,
+                            to: *step_to,
                         });
                     }
-                } else {
                     break;
                 }
-            }
-        }
-        moves
-    }
-
-    fn moves_rook(&self, pos: Position, our_color: Color, home_line: Line) -> Vec<RawMove> {
-        let directions = Direction::try_all_from_home_line(home_line).unwrap();
-        let rook_dirs = vec![
-            &directions.forward,
-            &directions.right,
-            &directions.backward,
-            &directions.left,
-        ];
-
-        let max_distance = Row::R13.get_index() as usize;
-        self.moves_lines(&rook_dirs, max_distance, pos, our_color)
-    }
-
-    fn moves_knight(&self, pos: Position, our_color: Color, home_line: Line) -> Vec<RawMove> {
-        let mut moves = Vec::new();
-        let knights_shifts = [
-            (2, 1),
-            (1, 2),
-            (2, -1),
-            (1, -2),
-            (-2, 1),
-            (-1, 2),
-            (-2, -1),
-            (-1, -2),
-        ];
-
-        for knight_shift in knights_shifts.iter() {
-            let position_to = Position::try_from((
-                pos.column().get_index() + knight_shift.0,
-                pos.row().get_index() + knight_shift.1,
-            ));
-            if let Ok(step_to) = position_to {
-                if let Some(piece) = self.piece(step_to) {
-                    if piece.color == our_color {
-                        continue;
-                    }
-                }
                 moves.push(RawMove {
                     from: pos,
-                    to: step_to,
+                    to: *step_to,
                 });
             }
         }
         moves
     }
 
-    fn moves_bishop(&self, pos: Position, our_color: Color, home_line: Line) -> Vec<RawMove> {
-        let directions = Direction::try_all_from_home_line(home_line).unwrap();
-        let bishop_dirs = vec![
-            &directions.forward_right,
-            &directions.backward_right,
-            &directions.backward_left,
-            &directions.forward_left,
-        ];
-        let max_distance = Row::R13.get_index() as usize;
-        self.moves_lines(&bishop_dirs, max_distance, pos, our_color)
-    }
-
-    fn moves_queen(&self, pos: Position, our_color: Color, home_line: Line) -> Vec<RawMove> {
-        let directions = Direction::try_all_from_home_line(home_line).unwrap();
-        let bishop_dirs = vec![
-            &directions.forward,
-            &directions.forward_right,
-            &directions.right,
-            &directions.backward_right,
-            &directions.backward,
-            &directions.backward_left,
-            &directions.left,
-            &directions.forward_left,
-        ];
-        let max_distance = Row::R13.get_index() as usize;
-        self.moves_lines(&bishop_dirs, max_distance, pos, our_color)
-    }
-
-    fn moves_king(&self, pos: Position, our_color: Color, home_line: Line) -> Vec<RawMove> {
-        let directions = Direction::try_all_from_home_line(home_line).unwrap();
-        let bishop_dirs = vec![
-            &directions.forward,
-            &directions.forward_right,
-            &directions.right,
-            &directions.backward_right,
-            &directions.backward,
-            &directions.backward_left,
-            &directions.left,
-            &directions.forward_left,
-        ];
-        let max_distance = 1;
-        self.moves_lines(&bishop_dirs, max_distance, pos, our_color)
+    fn moves_knight(&self, pos: Position, our_color: Color) -> Vec<RawMove> {
+        cells::knight_jumps(pos)
+            .iter()
+            .filter(|step_to| match self.piece(**step_to) {
+                Some(piece) => piece.color != our_color && !piece.stone,
+                None => true,
+            })
+            .map(|step_to| RawMove {
+                from: pos,
+                to: *step_to,
+            })
+            .collect()
     }
 
     fn moves(&self, piece_pos: Position) -> Result<Vec<RawMove>> {
         let piece = self.piece(piece_pos).context("no piece")?;
+        if piece.stone {
+            return Ok(Vec::new());
+        }
 
         return match piece.figure {
             Figure::Pawn => Ok(self.moves_pawn(
@@ -643,43 +739,159 @@ impl<'a> Board {
                 piece.home_line,
                 piece.have_not_move_yet,
             )),
-            Figure::Rook => Ok(self.moves_rook(piece_pos, piece.color, piece.home_line)),
-            Figure::Knight => Ok(self.moves_knight(piece_pos, piece.color, piece.home_line)),
-            Figure::Bishop => Ok(self.moves_bishop(piece_pos, piece.color, piece.home_line)),
-            Figure::Queen => Ok(self.moves_queen(piece_pos, piece.color, piece.home_line)),
-            Figure::King => Ok(self.moves_king(piece_pos, piece.color, piece.home_line)),
+            Figure::Rook => Ok(self.moves_rays(
+                cells::straight_rays(piece_pos),
+                usize::MAX,
+                piece_pos,
+                piece.color,
+            )),
+            Figure::Knight => Ok(self.moves_knight(piece_pos, piece.color)),
+            Figure::Bishop => Ok(self.moves_rays(
+                cells::diagonal_rays(piece_pos),
+                usize::MAX,
+                piece_pos,
+                piece.color,
+            )),
+            Figure::Queen => Ok(self.moves_rays(
+                cells::all_rays(piece_pos),
+                usize::MAX,
+                piece_pos,
+                piece.color,
+            )),
+            Figure::King => {
+                Ok(self.moves_rays(cells::all_rays(piece_pos), 1, piece_pos, piece.color))
+            }
         };
     }
 
-    pub fn is_checkmate(&mut self, player_color: Color) -> CheckMate {
+    // moves of every piece on the board, king safety is not checked
+    pub fn pseudo_legal_moves(&self) -> usize {
+        self.pieces()
+            .filter_map(|(pos, _)| self.moves(pos).ok())
+            .map(|moves| moves.len())
+            .sum()
+    }
+
+    // piece on from can step or capture on to, king safety is not checked
+    pub fn is_reachable(&self, from: Position, to: Position) -> bool {
+        match self.moves(from) {
+            Ok(moves) => moves.iter().any(|mv| mv.to == to),
+            Err(_) => false,
+        }
+    }
+
+    // king of player_color attacked by any other color
+    pub fn is_check(&self, player_color: Color) -> bool {
+        !self.checkers(player_color).is_empty()
+    }
+
+    // enemy pieces attacking the king of the player
+    pub fn checkers(&self, player_color: Color) -> Vec<PiecePos<'_>> {
         let king_pos = match self.find_king(player_color) {
             Some(k) => k.position(),
-            None => return CheckMate::No,
+            None => return Vec::new(),
         };
+        self.attackers_on_position(king_pos, player_color)
+    }
 
-        if self.attackers_on_position(king_pos).is_none() {
+    pub fn is_checkmate(&mut self, player_color: Color) -> CheckMate {
+        if !self.is_check(player_color) {
             return CheckMate::No;
         }
 
-        let our_pieces_pos = self
-            .pieces
-            .iter()
-            .filter(|(pos, piece)| piece.color == player_color)
-            .map(|(pos, piece)| *pos)
-            .collect::<Vec<_>>();
+        if self.has_legal_move(player_color) {
+            CheckMate::Check
+        } else {
+            CheckMate::Checkmate
+        }
+    }
 
-        for piece_pos in our_pieces_pos {
-            for mv in self.moves(piece_pos).unwrap() {
-                self.restorable_piece_move(mv.from, mv.to);
-                if self.attackers_on_position(king_pos).is_none() {
-                    self.restore_move();
-                    return CheckMate::Check;
-                }
-                self.restore_move();
+    // player is not in check but every his move leaves the king attacked
+    pub fn is_stalemate(&mut self, player_color: Color) -> bool {
+        !self.is_check(player_color) && !self.has_legal_move(player_color)
+    }
+
+    // any move of player_color after which his king is not attacked
+    fn has_legal_move(&mut self, player_color: Color) -> bool {
+        let safety = self.king_safety(player_color);
+        self.positions_of(player_color)
+            .into_iter()
+            .any(|from| !self.legal_moves_of(from, safety).is_empty())
+    }
+
+    // moves of all pieces of the color after which his king is not attacked
+    pub fn legal_moves(&mut self, color: Color) -> Vec<RawMove> {
+        let safety = self.king_safety(color);
+        self.positions_of(color)
+            .into_iter()
+            .flat_map(|from| self.legal_moves_of(from, safety))
+            .collect()
+    }
+
+    // moves of the piece on from after which the king of its color is not attacked
+    pub fn legal_moves_from(&mut self, from: Position) -> Vec<RawMove> {
+        match self.piece(from) {
+            Some(piece) => {
+                let safety = self.king_safety(piece.color);
+                self.legal_moves_of(from, safety)
             }
+            None => Vec::new(),
+        }
+    }
+
+    // cell of the king and whether he is in check now
+    fn king_safety(&self, color: Color) -> (Option<Position>, bool) {
+        let king = self.find_king(color).map(|king| king.position());
+        (king, self.is_check(color))
+    }
+
+    // A piece off the lines through its king is pinned by nobody, out of check only
+    // its king has to be tried on the board
+    fn legal_moves_of(
+        &mut self,
+        from: Position,
+        (king, in_check): (Option<Position>, bool),
+    ) -> Vec<RawMove> {
+        let color = match self.piece(from) {
+            Some(piece) => piece.color,
+            None => return Vec::new(),
+        };
+        let moves = self.moves(from).unwrap_or_default();
+        let king = match king {
+            Some(king) => king,
+            None => return moves,
+        };
+        if !in_check && from != king && !aligned(from, king) {
+            return moves;
         }
+        moves
+            .into_iter()
+            .filter(|mv| {
+                self.restorable_piece_move(mv.from, mv.to);
+                let attacked = self.is_check(color);
+                self.restore_move();
+                !attacked
+            })
+            .collect()
+    }
+}
 
-        CheckMate::Checkmate
+// cells on one row, column or diagonal
+fn aligned(a: Position, b: Position) -> bool {
+    let (a_col, a_row) = a.col_row_idx();
+    let (b_col, b_row) = b.col_row_idx();
+    a_col == b_col || a_row == b_row || (a_col - b_col).abs() == (a_row - b_row).abs()
+}
+
+// pawn looks towards the cell, the opposite of its home line
+fn pawn_faces(pawn: &Piece, pawn_pos: Position, target_pos: Position) -> bool {
+    let (pawn_col, pawn_row) = pawn_pos.col_row_idx();
+    let (target_col, target_row) = target_pos.col_row_idx();
+    match pawn.home_line {
+        Line::Column(column) if column.get_index() == 1 => pawn_col < target_col,
+        Line::Column(_) => pawn_col > target_col,
+        Line::Row(row) if row.get_index() == 1 => pawn_row < target_row,
+        Line::Row(_) => pawn_row > target_row,
     }
 }
 
@@ -688,6 +900,7 @@ pub struct PiecePos<'a> {
     position: Position,
 }
 
+#[derive(Clone)]
 pub struct CellPos {
     cell: Option<Piece>,
     position: Position,
@@ -700,7 +913,88 @@ impl<'a> PiecePos<'a> {
     pub fn position(&self) -> Position {
         self.position
     }
-    pub fn piece(&self) -> &Piece {
-        self.piece
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Board, Position};
+    use crate::vault::Color;
+
+    #[test]
+    fn attackers_are_of_other_living_colors() {
+        let mut board = Board::new();
+        // queen and bishop next to the king are his own
+        assert!(board
+            .attackers_on_position(Position::h1, Color::Red)
+            .is_empty());
+
+        board.piece_move(Position::j14, Position::g3);
+        let attackers = board.attackers_on_position(Position::h1, Color::Red);
+        assert_eq!(attackers.len(), 1);
+        let (piece, position) = attackers[0].piece_pos();
+        assert_eq!((piece.color, position), (Color::Yellow, Position::g3));
+        assert!(board.is_check(Color::Red));
+        // the knight defends the cell of yellow
+        let attackers = board.attackers_on_position(Position::h1, Color::Yellow);
+        assert!(attackers
+            .iter()
+            .all(|a| a.piece_pos().0.color != Color::Yellow));
+
+        board.turn_to_stone(Color::Yellow);
+        assert!(board
+            .attackers_on_position(Position::h1, Color::Red)
+            .is_empty());
+        assert!(!board.is_check(Color::Red));
+    }
+
+    #[test]
+    fn legal_moves_skip_only_moves_exposing_the_king() {
+        let mut board = Board::new();
+        // blue queen pins the red pawn on i2, yellow knight checks nobody yet
+        board.piece_move(Position::a7, Position::k4);
+        board.piece_move(Position::j14, Position::f5);
+        board.piece_move(Position::h2, Position::h4);
+
+        for color in [Color::Red, Color::Blue, Color::Yellow, Color::Green] {
+            let mut tried = Vec::new();
+            for from in board.positions_of(color) {
+                for mv in board.moves(from).unwrap() {
+                    board.restorable_piece_move(mv.from, mv.to);
+                    if !board.is_check(color) {
+                        tried.push(mv);
+                    }
+                    board.restore_move();
+                }
+            }
+            let legal = board.legal_moves(color);
+            assert_eq!(legal.len(), tried.len());
+            assert!(legal.iter().all(|mv| tried.contains(mv)));
+        }
+        assert!(board.legal_moves_from(Position::i2).is_empty());
+        assert!(board
+            .legal_moves(Color::Red)
+            .iter()
+            .all(|mv| mv.from != Position::i2));
+    }
+
+    #[test]
+    fn restore_stack_undoes_changes_of_many_cells() {
+        let mut board = Board::new();
+        let start = board.key();
+        // king side castling of red, the cells between are cleared first
+        board.restorable_piece_moves(&[(Position::i1, Position::i6), (Position::j1, Position::j6)]);
+        board.restorable_piece_moves(&[(Position::k1, Position::i1), (Position::h1, Position::j1)]);
+        board.restorable_piece_move(Position::j1, Position::k1);
+        assert!(board.piece(Position::h1).is_none());
+
+        assert!(board.restore_move());
+        assert!(board.piece(Position::j1).is_some());
+        assert!(board.restore_move());
+        assert!(board.piece(Position::k1).is_some());
+        assert!(board.piece(Position::h1).is_some());
+        assert!(board.restore_move());
+        assert!(!board.restore_move());
+        assert_eq!(board.key(), start);
+        assert_eq!(board.key(), board.full_key());
     }
 }
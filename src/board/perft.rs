@@ -0,0 +1,141 @@
+use super::{Board, RawMove};
+use crate::vault::Color;
+
+// turn order of the game
+const TURNS: [Color; 4] = [Color::Red, Color::Blue, Color::Yellow, Color::Green];
+
+// Leaf nodes of the move tree depth plies deep, the players move in turn order from to_move
+// and the dead ones are skipped. Moves are the ones of the board: a promotion counts once,
+// castling is checked by the game and is not counted. Branches end where the player has no move.
+pub fn perft(board: &mut Board, to_move: Color, dead: &[Color], depth: usize) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+    let moves = board.legal_moves(to_move);
+    if depth == 1 {
+        return moves.len() as u64;
+    }
+    let next = next_alive(to_move, dead);
+    moves
+        .into_iter()
+        .map(|mv| {
+            board.restorable_piece_move(mv.from, mv.to);
+            let nodes = perft(board, next, dead, depth - 1);
+            board.restore_move();
+            nodes
+        })
+        .sum()
+}
+
+// Leaf nodes below every move of to_move, sorted by move, tells which move the counts differ on
+pub fn divide(
+    board: &mut Board,
+    to_move: Color,
+    dead: &[Color],
+    depth: usize,
+) -> Vec<(RawMove, u64)> {
+    let next = next_alive(to_move, dead);
+    let mut nodes = board
+        .legal_moves(to_move)
+        .into_iter()
+        .map(|mv| {
+            board.restorable_piece_move(mv.from, mv.to);
+            let nodes = perft(board, next, dead, depth.saturating_sub(1));
+            board.restore_move();
+            (mv, nodes)
+        })
+        .collect::<Vec<_>>();
+    nodes.sort_by_key(|(mv, _)| (mv.from as usize, mv.to as usize));
+    nodes
+}
+
+fn next_alive(color: Color, dead: &[Color]) -> Color {
+    let turn = TURNS.iter().position(|c| *c == color).unwrap_or(0);
+    (1..=4)
+        .map(|i| TURNS[(turn + i) % 4])
+        .find(|c| !dead.contains(c))
+        .unwrap_or(color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Fen4;
+    use std::convert::TryFrom;
+
+    // red king in check by the yellow knight on g3, only the pawns can take it
+    const CHECK: &str = "R-0,0,0,0-1,1,1,1-1,1,1,1-0,0,0,0-0-{'enPassant':('','','','')}-3,yR,yN,yB,yK,yQ,yB,1,yR,3/3,yP,yP,yP,yP,yP,yP,yP,yP,3/14/bR,bP,10,gP,gR/bN,bP,10,gP,gN/bB,bP,10,gP,gB/bK,bP,10,gP,gQ/bQ,bP,10,gP,gK/bB,bP,10,gP,gB/bN,bP,10,gP,gN/bR,bP,10,gP,gR/6,yN,7/3,rP,rP,rP,rP,rP,rP,rP,rP,3/3,rR,rN,rB,rQ,rK,rB,rN,rR,3";
+    // blue queen on k4 pins the red pawn on i2, yellow knight on f5
+    const PIN: &str = "R-0,0,0,0-1,1,1,1-1,1,1,1-0,0,0,0-0-{'enPassant':('','','','')}-3,yR,yN,yB,yK,yQ,yB,1,yR,3/3,yP,yP,yP,yP,yP,yP,yP,yP,3/14/bR,bP,10,gP,gR/bN,bP,10,gP,gN/bB,bP,10,gP,gB/bK,bP,10,gP,gQ/1,bP,10,gP,gK/bB,bP,10,gP,gB/bN,bP,3,yN,6,gP,gN/bR,bP,5,rP,2,bQ,1,gP,gR/14/3,rP,rP,rP,rP,1,rP,rP,rP,3/3,rR,rN,rB,rQ,rK,rB,rN,rR,3";
+    // yellow is dead, blue moves and green follows
+    const DEAD: &str = "B-0,0,1,0-1,1,1,1-1,1,1,1-0,0,0,0-0-{'enPassant':('','','','')}-3,yR,yN,yB,yK,yQ,yB,yN,yR,3/3,yP,yP,yP,yP,yP,yP,yP,yP,3/14/bR,bP,10,gP,gR/bN,bP,10,gP,gN/bB,bP,10,gP,gB/bK,bP,10,gP,gQ/bQ,bP,10,gP,gK/bB,bP,10,gP,gB/bN,bP,10,gP,gN/bR,bP,10,gP,gR/14/3,rP,rP,rP,rP,rP,rP,rP,rP,3/3,rR,rN,rB,rQ,rK,rB,rN,rR,3";
+    // lone kings and the red rook on d1, 5 king and 16 rook moves of red
+    const KINGS: &str = "R-0,0,0,0-0,0,0,0-0,0,0,0-0,0,0,0-0-{'enPassant':('','','','')}-6,yK,7/14/14/14/14/14/13,gK/bK,13/14/14/14/14/14/3,rR,3,rK,6";
+
+    fn counts(fen: &str, depth: usize) -> Vec<u64> {
+        let mut fen = Fen4::try_from(fen).unwrap();
+        (1..=depth)
+            .map(|d| perft(&mut fen.board, fen.to_move, &fen.dead, d))
+            .collect()
+    }
+
+    // every pseudo-legal move tried on the board, independent of the pin shortcuts
+    fn brute_force(board: &mut Board, to_move: Color, dead: &[Color], depth: usize) -> u64 {
+        if depth == 0 {
+            return 1;
+        }
+        let mut nodes = 0;
+        for from in board.positions_of(to_move) {
+            for mv in board.moves(from).unwrap_or_default() {
+                board.restorable_piece_move(mv.from, mv.to);
+                if !board.is_check(to_move) {
+                    nodes += brute_force(board, next_alive(to_move, dead), dead, depth - 1);
+                }
+                board.restore_move();
+            }
+        }
+        nodes
+    }
+
+    #[test]
+    fn initial_position_counts() {
+        // red pawn on d4 blocks the double step of the blue pawn on b4
+        let mut board = Board::new();
+        let counts = (1..=4)
+            .map(|depth| perft(&mut board, Color::Red, &[], depth))
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![20, 399, 7960, 158402]);
+        assert_eq!(board.key(), Board::new().key());
+    }
+
+    #[test]
+    fn curated_position_counts() {
+        assert_eq!(counts(CHECK, 4), vec![2, 40, 758, 15120]);
+        assert_eq!(counts(PIN, 3), vec![31, 1476, 39578]);
+        assert_eq!(counts(DEAD, 4), vec![20, 400, 7960, 185074]);
+        assert_eq!(counts(KINGS, 4), vec![21, 100, 460, 2195]);
+    }
+
+    #[test]
+    fn divide_sums_up_to_perft() {
+        let mut fen = Fen4::try_from(PIN).unwrap();
+        let divided = divide(&mut fen.board, fen.to_move, &fen.dead, 2);
+        assert_eq!(divided.len(), 31);
+        assert_eq!(divided.iter().map(|(_, nodes)| nodes).sum::<u64>(), 1476);
+        assert!(divided
+            .iter()
+            .all(|(mv, _)| mv.from != crate::board::Position::i2));
+    }
+
+    #[test]
+    fn legal_moves_agree_with_brute_force() {
+        for fen in [CHECK, PIN, DEAD, KINGS] {
+            let mut fen = Fen4::try_from(fen).unwrap();
+            let (to_move, dead) = (fen.to_move, fen.dead.clone());
+            assert_eq!(
+                perft(&mut fen.board, to_move, &dead, 3),
+                brute_force(&mut fen.board, to_move, &dead, 3)
+            );
+        }
+    }
+}
@@ -1,9 +1,12 @@
+use anyhow::{anyhow, bail};
 use enum_iterator::IntoEnumIterator;
-use serde::{Deserialize, Serialize};
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 //use std::ops::Index;
 use std::convert::TryFrom;
+use std::fmt;
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub enum Row {
     R1,
     R2,
@@ -88,7 +91,7 @@ impl TryFrom<isize> for Row {
     }
 }*/
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize)]
 #[allow(non_camel_case_types)]
 pub enum Column {
     a,
@@ -151,7 +154,9 @@ impl TryFrom<isize> for Column {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, IntoEnumIterator, PartialEq, Eq, Hash)]
+// Cell of the board, written as column letter and row number like "h10" on the wire,
+// in SAN, FEN4 and logs
+#[derive(Debug, Clone, Copy, IntoEnumIterator, PartialEq, Eq, Hash)]
 #[allow(non_camel_case_types)]
 pub enum Position {
     a4,
@@ -645,11 +650,11 @@ impl Position {
             Position::n11 => Row::R11,
         }
     }
-    pub fn col_row(&self) -> (Row, Column) {
-        (self.row(), self.column())
+    pub fn col_row(&self) -> (Column, Row) {
+        (self.column(), self.row())
     }
     pub fn col_row_idx(&self) -> (isize, isize) {
-        (self.row().get_index(), self.column().get_index())
+        (self.column().get_index(), self.row().get_index())
     }
     pub fn line_between(pos_one: Position, pos_two: Position) -> Result<Vec<Position>, ()> {
         let (pos_one_col, pos_one_row) = pos_one.col_row_idx();
@@ -699,6 +704,73 @@ impl Position {
     }
 }
 
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (col, row) = self.col_row_idx();
+        write!(f, "{}{}", (b'a' + col as u8) as char, row + 1)
+    }
+}
+
+// "h10", the column letter in any case
+impl TryFrom<&str> for Position {
+    type Error = anyhow::Error;
+    fn try_from(cell: &str) -> anyhow::Result<Self> {
+        let mut chars = cell.chars();
+        let col = match chars.next().map(|c| c.to_ascii_lowercase()) {
+            Some(c @ 'a'..='n') => c as isize - 'a' as isize,
+            _ => bail!("bad column of cell \"{}\"", cell),
+        };
+        let row = chars.as_str();
+        if row.is_empty() || row.starts_with('0') || !row.chars().all(|c| c.is_ascii_digit()) {
+            bail!("bad row of cell \"{}\"", cell);
+        }
+        let row = row.parse::<isize>().map_or(-1, |r| r - 1);
+        Position::try_from((col, row)).map_err(|_| anyhow!("no cell \"{}\" on the board", cell))
+    }
+}
+
+impl Serialize for Position {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// Protocol version 1 reads the cell as "h10" in any case or as [column, row] indexes from 0,
+// the names of version 0 are the same strings
+impl<'de> Deserialize<'de> for Position {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(PositionVisitor)
+    }
+}
+
+struct PositionVisitor;
+
+impl<'de> Visitor<'de> for PositionVisitor {
+    type Value = Position;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("cell like \"h10\" or [column, row]")
+    }
+
+    fn visit_str<E: de::Error>(self, cell: &str) -> Result<Position, E> {
+        Position::try_from(cell).map_err(E::custom)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Position, A::Error> {
+        let col: isize = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let row: isize = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        if seq.next_element::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(3, &self));
+        }
+        Position::try_from((col, row))
+            .map_err(|_| de::Error::custom(format!("no cell at [{}, {}]", col, row)))
+    }
+}
+
 impl TryFrom<(isize, isize)> for Position {
     type Error = ();
     fn try_from(value: (isize, isize)) -> Result<Self, Self::Error> {
@@ -880,12 +952,21 @@ impl TryFrom<(Column, Row)> for Position {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub enum Line {
     Column(Column),
     Row(Row),
 }
 
+impl Line {
+    pub fn contains(&self, pos: Position) -> bool {
+        match self {
+            Line::Row(row) => pos.row() == *row,
+            Line::Column(column) => pos.column() == *column,
+        }
+    }
+}
+
 pub enum DecNoneInc {
     Dec,
     None,
@@ -962,3 +1043,34 @@ impl Direction {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cells_written_and_read_as_strings() {
+        for pos in Position::into_enum_iter() {
+            let cell = pos.to_string();
+            assert_eq!(cell, format!("{:?}", pos));
+            assert_eq!(Position::try_from(cell.as_str()).unwrap(), pos);
+            assert_eq!(
+                serde_json::to_string(&pos).unwrap(),
+                format!("\"{}\"", cell)
+            );
+        }
+        assert_eq!(Position::try_from("H10").unwrap(), Position::h10);
+        for bad in ["", "h", "h0", "h01", "h+1", "h15", "o5", "a1", "n14", "10h"] {
+            assert!(Position::try_from(bad).is_err(), "{:?} accepted", bad);
+        }
+
+        let read = |json: &str| serde_json::from_str::<Position>(json);
+        assert_eq!(read("\"h10\"").unwrap(), Position::h10);
+        assert_eq!(read("\"H10\"").unwrap(), Position::h10);
+        assert_eq!(read("[7, 9]").unwrap(), Position::h10);
+        assert!(read("[0, 0]").is_err());
+        assert!(read("[7]").is_err());
+        assert!(read("[7, 9, 1]").is_err());
+        assert!(read("79").is_err());
+    }
+}
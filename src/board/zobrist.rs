@@ -0,0 +1,97 @@
+use super::{Figure, Piece, Position};
+use crate::vault::Color;
+use once_cell::sync::Lazy;
+
+const CELLS: usize = 14 * 14;
+// figure, color, stone and king or rook which may still castle
+const KINDS: usize = 6 * 4 * 2 * 2;
+// keys are the same on every run, logged keys of servers can be compared
+const SEED: u64 = 0x4650_4334_5a6f_6272;
+
+static KEYS: Lazy<Vec<u64>> = Lazy::new(|| {
+    let mut state = SEED;
+    (0..KINDS * CELLS + 4 + 4 * CELLS)
+        .map(|_| splitmix(&mut state))
+        .collect()
+});
+
+fn splitmix(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn color_index(color: Color) -> usize {
+    match color {
+        Color::Red => 0,
+        Color::Blue => 1,
+        Color::Yellow => 2,
+        Color::Green => 3,
+    }
+}
+
+// Moved pawns never come back to their home line, only kings and rooks
+// differ by moving when they stand on the same cell
+pub fn piece_key(pos: Position, piece: &Piece) -> u64 {
+    let figure = match piece.figure {
+        Figure::Pawn => 0,
+        Figure::Knight => 1,
+        Figure::Bishop => 2,
+        Figure::Rook => 3,
+        Figure::Queen => 4,
+        Figure::King => 5,
+    };
+    let castler = matches!(piece.figure, Figure::King | Figure::Rook) && piece.have_not_move_yet;
+    let kind =
+        ((figure * 4 + color_index(piece.color)) * 2 + piece.stone as usize) * 2 + castler as usize;
+    let (col, row) = pos.col_row_idx();
+    KEYS[kind * CELLS + col as usize * 14 + row as usize]
+}
+
+pub fn to_move_key(color: Color) -> u64 {
+    KEYS[KINDS * CELLS + color_index(color)]
+}
+
+// target of the double step of the color
+pub fn en_passant_key(color: Color, target: Position) -> u64 {
+    let (col, row) = target.col_row_idx();
+    KEYS[KINDS * CELLS + 4 + color_index(color) * CELLS + col as usize * 14 + row as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::board::{Board, Position};
+    use crate::vault::Color;
+
+    #[test]
+    fn key_follows_moves_and_repeats() {
+        let start = Board::new().key();
+        let mut board = Board::new();
+        board.piece_move(Position::e1, Position::f3);
+        assert_ne!(board.key(), start);
+        assert_eq!(board.key(), board.full_key());
+        board.piece_move(Position::f3, Position::e1);
+        assert_eq!(board.key(), start);
+
+        // castling rights are lost once the king moved
+        board.piece_move(Position::h2, Position::h4);
+        let pawn_moved = board.key();
+        board.piece_move(Position::h1, Position::h2);
+        board.piece_move(Position::h2, Position::h1);
+        assert_ne!(board.key(), pawn_moved);
+        assert_eq!(board.key(), board.full_key());
+
+        board.restorable_piece_move(Position::e2, Position::e4);
+        board.restore_move();
+        board.promote(Position::d2, crate::board::Figure::Queen);
+        board.turn_to_stone(Color::Blue);
+        board.clear(Color::Green);
+        assert_eq!(board.key(), board.full_key());
+
+        let stored = serde_json::to_string(&board).unwrap();
+        let restored: Board = serde_json::from_str(&stored).unwrap();
+        assert_eq!(restored.key(), board.key());
+    }
+}
@@ -0,0 +1,102 @@
+use crate::board::{Figure, Position, CASTLING_PATTERNS};
+use crate::engine::parse_move;
+use crate::proto::Move;
+use crate::vault::{Color, Game};
+use enum_iterator::IntoEnumIterator;
+use rand::seq::SliceRandom;
+
+fn value(figure: Figure) -> u32 {
+    match figure {
+        Figure::Pawn => 1,
+        Figure::Knight | Figure::Bishop => 3,
+        Figure::Rook => 5,
+        Figure::Queen => 9,
+        Figure::King => 20,
+    }
+}
+
+// Material of the color on the board in pawns, kings are not counted
+pub fn material(game: &Game, color: Color) -> u32 {
+    Position::into_enum_iter()
+        .filter_map(|pos| game.board.piece(pos))
+        .filter(|piece| piece.color == color && piece.figure != Figure::King)
+        .map(|piece| value(piece.figure))
+        .sum()
+}
+
+// Greedy move of the color: the most valuable capture or promotion, random among equals.
+// Pawns are promoted into queens only. None if the color has no legal move.
+pub fn choose_move(game: &mut Game, color: Color) -> Option<Move> {
+    let mut best = Vec::new();
+    let mut best_score = 0;
+    for mv in game.all_legal_moves(color) {
+        let score = match &mv {
+            Move::Capture { to, .. } => game.board.piece(*to).map_or(0, |p| value(p.figure)),
+            Move::Promotion { to, into, .. } if *into == Figure::Queen => {
+                value(Figure::Queen) + game.board.piece(*to).map_or(0, |p| value(p.figure))
+            }
+            Move::Promotion { .. } => continue,
+            _ => 0,
+        };
+        if best.is_empty() || score > best_score {
+            best.clear();
+            best_score = score;
+        }
+        if score == best_score {
+            best.push(mv);
+        }
+    }
+    best.choose(&mut rand::thread_rng()).cloned()
+}
+
+// Legal move of the color matching the engine answer, none if the engine is wrong.
+// Promotions are into queens unless the engine says otherwise.
+pub fn engine_move(game: &mut Game, color: Color, answer: &str) -> Option<Move> {
+    let (from, to, into) = parse_move(answer)?;
+    if game
+        .board
+        .piece(from)
+        .is_none_or(|piece| piece.color != color)
+    {
+        return None;
+    }
+    game.legal_moves(color, from)
+        .into_iter()
+        .find(|mv| match mv {
+            Move::Basic { to: t, .. } | Move::Capture { to: t, .. } => *t == to && into.is_none(),
+            Move::Promotion { to: t, into: i, .. } => {
+                *t == to && *i == into.unwrap_or(Figure::Queen)
+            }
+            // king moves to its castling square or onto the rook
+            Move::Castling { rook } => {
+                into.is_none()
+                    && CASTLING_PATTERNS
+                        .get(&(*rook, from))
+                        .is_some_and(|pattern| to == pattern.king_end_pos || to == *rook)
+            }
+            _ => false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Board;
+
+    #[test]
+    fn takes_the_most_valuable_piece() {
+        let mut board = Board::new();
+        // red queen can take the blue and green pawns or the yellow queen
+        board.piece_move(Position::g1, Position::g7);
+        board.piece_move(Position::h14, Position::g9);
+        let mut game = crate::vault::fixtures::game(board);
+
+        assert_eq!(
+            choose_move(&mut game, Color::Red),
+            Some(Move::Capture {
+                from: Position::g7,
+                to: Position::g9,
+            })
+        );
+    }
+}
@@ -0,0 +1,310 @@
+use crate::board::{Board, Position};
+use crate::conformance::Client;
+use crate::proto::{
+    Auth, AuthError, Connect, DrawVote, GameSession, Handshake, LegalMoves, Move, MoveCall, Pdu,
+    PlayerRegister, ProtoVersion, Protocol, Variant,
+};
+use crate::vault::{Color, Vault};
+use anyhow::{anyhow, bail, Context, Result};
+use enum_iterator::IntoEnumIterator;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time;
+
+const PROTO_VER: &str = ProtoVersion::LATEST.as_str();
+// moves of the canary before it leaves the game to the bots
+const MOVES: usize = 3;
+static WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn default_name() -> String {
+    "canary".to_string()
+}
+
+fn default_pool() -> String {
+    "canary".to_string()
+}
+
+fn default_interval() -> u64 {
+    300
+}
+
+// Client of the server's own public endpoint probing the whole path from the listener to the
+// game loop every interval seconds. The account plays a few moves in the pool, the pool should
+// be casual with group size 1 so that bots take the other seats and nobody waits for it.
+// Webhook on "http://host:port/path" is posted the failures and the first pass after them.
+#[derive(Clone, Deserialize)]
+pub struct CanaryConfig {
+    // "ws://host:port" as the players connect to it
+    pub url: String,
+    #[serde(default = "default_name")]
+    pub name: String,
+    // the account is registered on the first probe
+    pub password: String,
+    #[serde(default = "default_pool")]
+    pub pool: String,
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    #[serde(default)]
+    pub webhook: Option<String>,
+}
+
+// body of the webhook post
+#[derive(Serialize)]
+struct Report<'a> {
+    tenant: Option<&'a str>,
+    url: &'a str,
+    passed: bool,
+    error: Option<String>,
+    // unix seconds
+    at: u64,
+}
+
+// Probes counted by the metrics samples
+pub async fn canary_dispatcher(vault: Arc<Vault>, config: CanaryConfig) {
+    let mut interval = time::interval(Duration::from_secs(config.interval.max(1)));
+    let mut failing = false;
+    loop {
+        interval.tick().await;
+        let result = probe(&config).await;
+        vault.count_canary_probe(result.is_ok());
+        match &result {
+            Ok(()) => debug!("canary probe of \"{}\" passed", config.url),
+            Err(e) => error!("canary probe of \"{}\" failed \"{:#}\"", config.url, e),
+        }
+        if result.is_err() || failing {
+            if let Some(webhook) = &config.webhook {
+                let report = Report {
+                    tenant: vault.tenant().name.as_deref(),
+                    url: &config.url,
+                    passed: result.is_ok(),
+                    error: result.as_ref().err().map(|e| format!("{:#}", e)),
+                    at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs()),
+                };
+                let body = serde_json::to_string(&report).unwrap();
+                match time::timeout(WEBHOOK_TIMEOUT, post(webhook, &body)).await {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => error!("canary webhook \"{}\" failed \"{:#}\"", webhook, e),
+                    Err(_) => error!("canary webhook \"{}\" timed out", webhook),
+                }
+            }
+        }
+        failing = result.is_err();
+    }
+}
+
+async fn probe(config: &CanaryConfig) -> Result<()> {
+    let client = Client::open(&config.url, &config.name).await?;
+    play(client, config).await
+}
+
+// Handshake, login, queue into the pool, a few legal moves of the canary's pieces and the agreed
+// draw. The probe passes as well if the game ends sooner.
+pub async fn play<S: AsyncRead + AsyncWrite + Unpin>(
+    mut client: Client<S>,
+    config: &CanaryConfig,
+) -> Result<()> {
+    client
+        .send(Pdu::Handshake(Handshake::Connect(Connect::Client {
+            name: "canary".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol: Protocol::Version(PROTO_VER.to_string()),
+            capabilities: Vec::new(),
+            locale: None,
+        })))
+        .await?;
+    match client
+        .expect("connect", |pdu| match pdu {
+            Pdu::Handshake(Handshake::Connect(c)) => Some(c),
+            _ => None,
+        })
+        .await?
+    {
+        Connect::Ok { .. } => (),
+        other => bail!("handshake failed {:?}", other),
+    }
+
+    let credentials = |register| {
+        let (name, password) = (config.name.clone(), config.password.clone());
+        match register {
+            true => Auth::Register { name, password },
+            false => Auth::Login { name, password },
+        }
+    };
+    for register in [false, true] {
+        client.send(Pdu::Auth(credentials(register))).await?;
+        match client
+            .expect("auth", |pdu| match pdu {
+                Pdu::Auth(a) => Some(a),
+                _ => None,
+            })
+            .await?
+        {
+            Auth::Ok { .. } => break,
+            Auth::Error(AuthError::BadCredentials { .. }) if !register => (),
+            other => bail!("authentication failed {:?}", other),
+        }
+    }
+
+    let init = client
+        .join(PlayerRegister::Join {
+            name: config.name.clone(),
+            pool: config.pool.clone(),
+            variant: Variant::default(),
+            time_control: None,
+        })
+        .await?;
+    let positions = &init.start_positions;
+    let color = [
+        (Color::Red, &positions.red),
+        (Color::Blue, &positions.blue),
+        (Color::Yellow, &positions.yellow),
+        (Color::Green, &positions.green),
+    ]
+    .iter()
+    .find(|(_, p)| p.player_name == config.name)
+    .map(|(color, _)| *color)
+    .context("canary is not seated")?;
+    client.send(Pdu::GameSession(GameSession::Ready {})).await?;
+
+    // the canary never castles, only its own moves take its pieces elsewhere
+    let board = Board::new();
+    let mut pieces = Position::into_enum_iter()
+        .filter(|pos| board.piece(*pos).is_some_and(|p| p.color == color))
+        .collect::<HashSet<_>>();
+    let player = format!("{:?}", color);
+    for _ in 0..MOVES {
+        let called = client
+            .expect("turn", |pdu| match pdu {
+                Pdu::GameSession(GameSession::Update(u)) => match u.move_call {
+                    MoveCall::Call { player: p, .. } if p == player => Some(true),
+                    _ => None,
+                },
+                Pdu::GameSession(GameSession::Finish(_)) => Some(false),
+                _ => None,
+            })
+            .await?;
+        if !called {
+            return Ok(());
+        }
+        let mut chosen = None;
+        for from in pieces.iter().copied() {
+            client
+                .send(Pdu::GameSession(GameSession::LegalMoves(
+                    LegalMoves::Request { position: from },
+                )))
+                .await?;
+            let moves = client
+                .expect("legal moves", |pdu| match pdu {
+                    Pdu::GameSession(GameSession::LegalMoves(LegalMoves::Moves {
+                        position,
+                        moves,
+                    })) if position == from => Some(Ok(moves)),
+                    Pdu::GameSession(GameSession::LegalMoves(LegalMoves::Error(e))) => Some(Err(e)),
+                    _ => None,
+                })
+                .await?
+                .map_err(|e| anyhow!("legal moves failed {:?}", e))?;
+            chosen = moves
+                .into_iter()
+                .find(|mv| matches!(mv, Move::Basic { .. } | Move::Capture { .. }));
+            if chosen.is_some() {
+                break;
+            }
+        }
+        let mv = chosen.context("canary has no move")?;
+        if let Move::Basic { from, to } | Move::Capture { from, to } = mv {
+            pieces.remove(&from);
+            pieces.insert(to);
+        }
+        client.make_move(mv.clone()).await?;
+        client.update(&mv).await?;
+    }
+
+    // bots agree with the draw, the account is free for the next probe
+    client
+        .send(Pdu::GameSession(GameSession::DrawVote(DrawVote::Vote {
+            agree: true,
+        })))
+        .await?;
+    let finish = client.finish().await?;
+    if finish.game_id != init.game_id {
+        bail!(
+            "finish of game {} instead of {}",
+            finish.game_id,
+            init.game_id
+        );
+    }
+    Ok(())
+}
+
+// Post of the json body, plain http only
+async fn post(url: &str, body: &str) -> Result<()> {
+    let rest = url
+        .strip_prefix("http://")
+        .context("only http:// webhooks are supported")?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let addr = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:80", host),
+    };
+    let mut stream = TcpStream::connect(&addr)
+        .await
+        .context(format!("connect to {} failed", addr))?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line).await?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => bail!("webhook answered {:?}", status_line.trim_end()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn webhook_posted_as_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hooks/canary", listener.local_addr().unwrap());
+        let hook = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !String::from_utf8_lossy(&request).ends_with("}") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        post(&url, r#"{"passed":false}"#).await.unwrap();
+        let request = hook.await.unwrap();
+        assert!(request.starts_with("POST /hooks/canary HTTP/1.1\r\n"));
+        assert!(request.ends_with("\r\n\r\n{\"passed\":false}"));
+
+        assert!(post("https://example.com/hook", "{}").await.is_err());
+    }
+}
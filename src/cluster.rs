@@ -0,0 +1,197 @@
+use anyhow::{Context, Result};
+use redis::aio::ConnectionManager;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::sync::OnceCell;
+
+// seconds a node is taken for alive after it published, its entries are stale afterwards
+pub const NODE_TTL: u64 = 15;
+
+// Distributed mode of the tenant, set by FPC_REDIS_URL and FPC_NODE_ADDR for the only tenant
+#[derive(Clone, Deserialize)]
+pub struct ClusterConfig {
+    pub redis_url: String,
+    // "ws://host:port" of this node, clients routed here connect to it
+    pub node_addr: String,
+}
+
+// queued player of the node, unix seconds since
+pub struct QueueEntry {
+    pub conn_id: u64,
+    pub queued_at: u64,
+}
+
+// Nodes of the tenant sharing one Redis server. Every node publishes the reconnect ids of its
+// games, games are pinned to the node which created them. Matchmaking queues of all nodes are
+// kept per pool, the pool is gathered on the node of the player queued longest.
+pub struct Cluster {
+    client: redis::Client,
+    conn: OnceCell<ConnectionManager>,
+    // keys of the tenant start with it
+    prefix: String,
+    pub node: String,
+}
+
+impl Cluster {
+    pub fn new(config: &ClusterConfig, tenant: Option<&str>) -> Result<Cluster> {
+        let client = redis::Client::open(config.redis_url.as_str())
+            .context(format!("bad redis url {:?}", config.redis_url))?;
+        Ok(Cluster {
+            client,
+            conn: OnceCell::new(),
+            prefix: format!("fpc:{}", tenant.unwrap_or("server")),
+            node: config.node_addr.clone(),
+        })
+    }
+
+    // connected on first use, the manager reconnects by itself
+    async fn conn(&self) -> Result<ConnectionManager> {
+        let conn = self
+            .conn
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await?;
+        Ok(conn.clone())
+    }
+
+    fn node_key(&self, node: &str) -> String {
+        format!("{}:node:{}", self.prefix, node)
+    }
+    fn reconnect_key(&self, reconnect_id: &str) -> String {
+        format!("{}:reconnect:{}", self.prefix, reconnect_id)
+    }
+    fn queue_key(&self, pool: &str) -> String {
+        format!("{}:queue:{}", self.prefix, pool)
+    }
+
+    // The node is alive with the games of the reconnect ids and the players queued in the pools,
+    // entries of the node which are not there anymore are removed. Called every tick.
+    pub async fn publish(
+        &self,
+        reconnect_ids: &[String],
+        queues: &HashMap<String, Vec<QueueEntry>>,
+    ) -> Result<()> {
+        let mut conn = self.conn().await?;
+        let mut pipe = redis::pipe();
+        pipe.set_ex(self.node_key(&self.node), 1, NODE_TTL).ignore();
+        for reconnect_id in reconnect_ids {
+            pipe.set_ex(self.reconnect_key(reconnect_id), &self.node, NODE_TTL)
+                .ignore();
+        }
+        for (pool, entries) in queues {
+            let key = self.queue_key(pool);
+            let members: Vec<String> = redis::cmd("ZRANGE")
+                .arg(&key)
+                .arg(0)
+                .arg(-1)
+                .query_async(&mut conn)
+                .await?;
+            for member in members {
+                let gone = match parse_member(&member) {
+                    Some((node, conn_id)) => {
+                        node == self.node && !entries.iter().any(|e| e.conn_id == conn_id)
+                    }
+                    None => true,
+                };
+                if gone {
+                    pipe.zrem(&key, member).ignore();
+                }
+            }
+            for entry in entries {
+                let member = format!("{} {}", self.node, entry.conn_id);
+                pipe.zadd(&key, member, entry.queued_at).ignore();
+            }
+        }
+        pipe.query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+
+    // node the game of the reconnect id is pinned to
+    pub async fn reconnect_node(&self, reconnect_id: &str) -> Result<Option<String>> {
+        let mut conn = self.conn().await?;
+        let node = redis::cmd("GET")
+            .arg(self.reconnect_key(reconnect_id))
+            .query_async(&mut conn)
+            .await?;
+        Ok(node)
+    }
+
+    // Node gathering the pool, none while nobody is queued in it.
+    // Entries of the nodes which are gone are removed.
+    pub async fn pool_home(&self, pool: &str) -> Result<Option<String>> {
+        let mut conn = self.conn().await?;
+        let key = self.queue_key(pool);
+        let entries: Vec<(String, u64)> = redis::cmd("ZRANGE")
+            .arg(&key)
+            .arg(0)
+            .arg(-1)
+            .arg("WITHSCORES")
+            .query_async(&mut conn)
+            .await?;
+        let mut alive = HashMap::new();
+        for (member, _) in &entries {
+            let node = match parse_member(member) {
+                Some((node, _)) => node,
+                None => continue,
+            };
+            if !alive.contains_key(node) {
+                let exists: bool = redis::cmd("EXISTS")
+                    .arg(self.node_key(node))
+                    .query_async(&mut conn)
+                    .await?;
+                alive.insert(node.to_string(), exists);
+            }
+        }
+        let (home, stale) = home(&entries, |node| alive.get(node).copied().unwrap_or(false));
+        if !stale.is_empty() {
+            redis::cmd("ZREM")
+                .arg(&key)
+                .arg(stale)
+                .query_async::<()>(&mut conn)
+                .await?;
+        }
+        Ok(home)
+    }
+}
+
+// node and connection id of the queue member "ws://host:port 12"
+fn parse_member(member: &str) -> Option<(&str, u64)> {
+    let (node, conn_id) = member.rsplit_once(' ')?;
+    Some((node, conn_id.parse().ok()?))
+}
+
+// node of the longest queued player of the live nodes, and the members of the other nodes
+fn home(entries: &[(String, u64)], alive: impl Fn(&str) -> bool) -> (Option<String>, Vec<&str>) {
+    let mut home: Option<(&str, u64)> = None;
+    let mut stale = Vec::new();
+    for (member, queued_at) in entries {
+        match parse_member(member) {
+            Some((node, _)) if alive(node) => {
+                if home.is_none_or(|(_, since)| *queued_at < since) {
+                    home = Some((node, *queued_at));
+                }
+            }
+            _ => stale.push(member.as_str()),
+        }
+    }
+    (home.map(|(node, _)| node.to_string()), stale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_gathered_on_the_live_node_queued_longest() {
+        let entries = [
+            ("ws://b:8080 7".to_string(), 90),
+            ("ws://a:8080 3".to_string(), 100),
+            ("ws://dead:8080 1".to_string(), 50),
+            ("garbage".to_string(), 10),
+        ];
+        let (node, stale) = home(&entries, |node| node != "ws://dead:8080");
+        assert_eq!(node.as_deref(), Some("ws://b:8080"));
+        assert_eq!(stale, ["ws://dead:8080 1", "garbage"]);
+        assert_eq!(parse_member("ws://a:8080 3"), Some(("ws://a:8080", 3)));
+        assert_eq!(home(&[], |_| true).0, None);
+    }
+}
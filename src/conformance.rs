@@ -0,0 +1,424 @@
+use crate::board::Position;
+use crate::proto::{
+    Auth, Connect, ConnectError, DrawVote, Finish, GameSession, Handshake, IllegalMoveReason, Init,
+    MatchmakingQueue, Move, MoveCall, MoveError, Pdu, PlayerRegister, PlayerRegisterError,
+    ProtoVersion, Protocol, Reconnect, ReconnectError,
+};
+use crate::vault::GameEnd;
+use anyhow::{anyhow, bail, Context, Result};
+use futures::future::try_join_all;
+use futures_util::{SinkExt, StreamExt};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async, WebSocketStream};
+use tungstenite::protocol::Message;
+
+const PROTO_VER: &str = ProtoVersion::LATEST.as_str();
+const PASSWORD: &str = "conformance";
+// covers the pause before the first move
+static STEP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Player of the scripted session, the canary plays through the same client
+pub struct Client<S = TcpStream> {
+    ws: WebSocketStream<S>,
+    name: String,
+}
+
+impl Client {
+    pub async fn open(url: &str, name: &str) -> Result<Client> {
+        let (ws, _) = connect_async(url)
+            .await
+            .context(format!("connect to {} failed", url))?;
+        Ok(Client {
+            ws,
+            name: name.to_string(),
+        })
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    // client of the server on the other end of the stream, used by the in-memory tests
+    #[cfg(test)]
+    pub async fn over(stream: S, url: &str, name: &str) -> Result<Client<S>> {
+        let (ws, _) = tokio_tungstenite::client_async(url, stream)
+            .await
+            .context(format!("connect to {} failed", url))?;
+        Ok(Client {
+            ws,
+            name: name.to_string(),
+        })
+    }
+
+    pub async fn send(&mut self, pdu: Pdu) -> Result<()> {
+        self.ws.send(pdu.to_message()?).await?;
+        Ok(())
+    }
+
+    // Next pdu accepted by f, others are skipped. Pings are answered while reading.
+    pub async fn expect<T>(
+        &mut self,
+        what: &str,
+        mut f: impl FnMut(Pdu) -> Option<T>,
+    ) -> Result<T> {
+        let name = self.name.clone();
+        let wait = async {
+            while let Some(msg) = self.ws.next().await {
+                if let Message::Text(text) = msg? {
+                    let pdu = serde_json::from_str::<Pdu>(&text)
+                        .context(format!("{} got undecodable pdu {}", name, text))?;
+                    if let Some(found) = f(pdu) {
+                        return Ok(found);
+                    }
+                }
+            }
+            bail!("{} was disconnected", name)
+        };
+        tokio::time::timeout(STEP_TIMEOUT, wait)
+            .await
+            .context(format!("{} waited for {} too long", self.name, what))?
+    }
+
+    async fn connect(&mut self, version: &str) -> Result<Connect> {
+        self.send(Pdu::Handshake(Handshake::Connect(Connect::Client {
+            name: "conformance".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol: Protocol::Version(version.to_string()),
+            capabilities: Vec::new(),
+            locale: None,
+        })))
+        .await?;
+        self.expect("connect", |pdu| match pdu {
+            Pdu::Handshake(Handshake::Connect(c)) => Some(c),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn register(&mut self) -> Result<()> {
+        let name = self.name.clone();
+        self.send(Pdu::Auth(Auth::Register {
+            name,
+            password: PASSWORD.to_string(),
+        }))
+        .await?;
+        match self
+            .expect("auth", |pdu| match pdu {
+                Pdu::Auth(a) => Some(a),
+                _ => None,
+            })
+            .await?
+        {
+            Auth::Ok { .. } => Ok(()),
+            other => bail!("{} registration failed {:?}", self.name, other),
+        }
+    }
+
+    async fn queue(&mut self) -> Result<Init> {
+        let name = self.name.clone();
+        self.join(PlayerRegister::Name(name)).await
+    }
+
+    // queued by the request, heartbeat checks are answered until the game starts
+    pub async fn join(&mut self, request: PlayerRegister) -> Result<Init> {
+        self.send(Pdu::MatchmakingQueue(MatchmakingQueue::PlayerRegister(
+            request,
+        )))
+        .await?;
+        loop {
+            let pdu = self
+                .expect("game init", |pdu| match pdu {
+                    Pdu::MatchmakingQueue(_) | Pdu::GameSession(GameSession::Init(_)) => Some(pdu),
+                    _ => None,
+                })
+                .await?;
+            match pdu {
+                Pdu::MatchmakingQueue(MatchmakingQueue::HeartbeatCheck {}) => {
+                    self.send(Pdu::MatchmakingQueue(MatchmakingQueue::HeartbeatCheck {}))
+                        .await?;
+                }
+                Pdu::MatchmakingQueue(MatchmakingQueue::PlayerRegister(PlayerRegister::Error(
+                    e,
+                ))) => bail!("{} was not queued {:?}", self.name, e),
+                Pdu::GameSession(GameSession::Init(init)) => return Ok(init),
+                _ => (),
+            }
+        }
+    }
+
+    pub async fn make_move(&mut self, mv: Move) -> Result<()> {
+        self.send(Pdu::GameSession(GameSession::Move(mv))).await
+    }
+
+    async fn move_error(&mut self) -> Result<MoveError> {
+        self.expect("move error", |pdu| match pdu {
+            Pdu::GameSession(GameSession::Move(Move::Error(e))) => Some(e),
+            _ => None,
+        })
+        .await
+    }
+
+    // update with the given previous move, no move for the first one
+    pub async fn update(&mut self, previous: &Move) -> Result<MoveCall> {
+        self.expect("update", |pdu| match pdu {
+            Pdu::GameSession(GameSession::Update(u)) if u.move_previous == *previous => {
+                Some(u.move_call)
+            }
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn finish(&mut self) -> Result<Finish> {
+        self.expect("finish", |pdu| match pdu {
+            Pdu::GameSession(GameSession::Finish(f)) => Some(f),
+            _ => None,
+        })
+        .await
+    }
+}
+
+struct Report {
+    passed: usize,
+    failed: Option<String>,
+}
+
+impl Report {
+    fn check<T>(&mut self, step: &str, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                println!("PASS {}", step);
+                self.passed += 1;
+                Some(value)
+            }
+            Err(e) => {
+                println!("FAIL {}: {:#}", step, e);
+                self.failed = Some(step.to_string());
+                None
+            }
+        }
+    }
+}
+
+// Plays one scripted game with four clients against the server on url.
+// Steps stop at the first failure, later ones depend on it. Returns true if all passed.
+pub async fn run(url: &str) -> bool {
+    let mut report = Report {
+        passed: 0,
+        failed: None,
+    };
+    let tag = rand::thread_rng().gen_range(0..1_000_000);
+    let names = (0..4)
+        .map(|i| format!("conformance{}_{}", i, tag))
+        .collect::<Vec<_>>();
+    steps(url, &names, &mut report).await;
+    match &report.failed {
+        Some(step) => println!("{} passed, failed at \"{}\"", report.passed, step),
+        None => println!("{} passed", report.passed),
+    }
+    report.failed.is_none()
+}
+
+async fn steps(url: &str, names: &[String], report: &mut Report) -> Option<()> {
+    let result = async {
+        let mut probe = Client::open(url, "probe").await?;
+        probe
+            .send(Pdu::MatchmakingQueue(MatchmakingQueue::PlayerRegister(
+                PlayerRegister::Name("probe".to_string()),
+            )))
+            .await?;
+        match probe
+            .expect("register error", |pdu| match pdu {
+                Pdu::MatchmakingQueue(MatchmakingQueue::PlayerRegister(r)) => Some(r),
+                _ => None,
+            })
+            .await?
+        {
+            PlayerRegister::Error(PlayerRegisterError::Handshake { .. }) => Ok(probe),
+            other => bail!("expected handshake error, got {:?}", other),
+        }
+    }
+    .await;
+    let mut probe = report.check("queue before handshake is rejected", result)?;
+
+    let result = match probe.connect("unsupported").await {
+        Ok(Connect::Error(ConnectError::UnsupportedProtocolVersion { .. })) => Ok(()),
+        Ok(other) => Err(anyhow!("expected version error, got {:?}", other)),
+        Err(e) => Err(e),
+    };
+    report.check("unsupported protocol version is rejected", result)?;
+
+    let result = async {
+        let mut clients = Vec::new();
+        for name in names {
+            let mut client = Client::open(url, name).await?;
+            match client.connect(PROTO_VER).await? {
+                Connect::Ok { .. } => (),
+                other => bail!("{} handshake failed {:?}", name, other),
+            }
+            client.register().await?;
+            clients.push(client);
+        }
+        Ok(clients)
+    }
+    .await;
+    let mut clients = report.check("handshake and registration", result)?;
+
+    let result = async {
+        let inits = try_join_all(clients.iter_mut().map(|c| c.queue())).await?;
+        if inits.iter().any(|init| init.game_id != inits[0].game_id) {
+            bail!("players got different games");
+        }
+        Ok(inits)
+    }
+    .await;
+    let inits = report.check("four players are matched", result)?;
+    let game_id = inits[0].game_id;
+    let seat = |name: &str| names.iter().position(|n| n == name);
+    let positions = &inits[0].start_positions;
+    let (red, blue) = match (
+        seat(&positions.red.player_name),
+        seat(&positions.blue.player_name),
+    ) {
+        (Some(red), Some(blue)) => (red, blue),
+        _ => {
+            report.check::<()>("players are seated", Err(anyhow!("unknown player names")))?;
+            return None;
+        }
+    };
+
+    let result = async {
+        for client in clients.iter_mut() {
+            match client.update(&Move::NoMove {}).await? {
+                MoveCall::Call { player, .. } if player == "Red" => (),
+                other => bail!("expected red to move first, got {:?}", other),
+            }
+        }
+        Ok(())
+    }
+    .await;
+    report.check("red is called to move first", result)?;
+
+    let e2e4 = Move::Basic {
+        from: Position::e2,
+        to: Position::e4,
+    };
+    let result = async {
+        clients[blue].make_move(e2e4.clone()).await?;
+        match clients[blue].move_error().await? {
+            MoveError::IllegalMove {
+                reason: IllegalMoveReason::NotYourTurn,
+                ..
+            } => Ok(()),
+            other => bail!("expected not your turn, got {:?}", other),
+        }
+    }
+    .await;
+    report.check("move out of turn is rejected", result)?;
+
+    let result = async {
+        clients[red]
+            .make_move(Move::Basic {
+                from: Position::e2,
+                to: Position::e5,
+            })
+            .await?;
+        match clients[red].move_error().await? {
+            MoveError::IllegalMove { .. } => Ok(()),
+            other => bail!("expected illegal move, got {:?}", other),
+        }
+    }
+    .await;
+    report.check("illegal move is rejected", result)?;
+
+    let result = async {
+        clients[red].make_move(e2e4.clone()).await?;
+        for client in clients.iter_mut() {
+            match client.update(&e2e4).await? {
+                MoveCall::Call { player, .. } if player == "Blue" => (),
+                other => bail!("expected blue to move next, got {:?}", other),
+            }
+        }
+        Ok(())
+    }
+    .await;
+    report.check("legal move is applied", result)?;
+
+    let result = async {
+        clients[blue].ws.close(None).await?;
+        let mut client = Client::open(url, &names[blue]).await?;
+        match client.connect(PROTO_VER).await? {
+            Connect::Ok { .. } => (),
+            other => bail!("handshake failed {:?}", other),
+        }
+        client
+            .send(Pdu::GameSession(GameSession::Reconnect(
+                Reconnect::Request {
+                    reconnect_id: "unknown".to_string(),
+                },
+            )))
+            .await?;
+        match client
+            .expect("reconnect error", |pdu| match pdu {
+                Pdu::GameSession(GameSession::Reconnect(r)) => Some(r),
+                _ => None,
+            })
+            .await?
+        {
+            Reconnect::Error(ReconnectError::BadReconnectId { .. }) => Ok(client),
+            other => bail!("expected bad reconnect id, got {:?}", other),
+        }
+    }
+    .await;
+    let mut client = report.check("unknown reconnect id is rejected", result)?;
+
+    let result = async {
+        client
+            .send(Pdu::GameSession(GameSession::Reconnect(
+                Reconnect::Request {
+                    reconnect_id: inits[blue].reconnect_id.clone(),
+                },
+            )))
+            .await?;
+        let init = client
+            .expect("game init", |pdu| match pdu {
+                Pdu::GameSession(GameSession::Init(init)) => Some(init),
+                _ => None,
+            })
+            .await?;
+        if init.game_id != game_id {
+            bail!(
+                "reconnected to game {} instead of {}",
+                init.game_id,
+                game_id
+            );
+        }
+        match client.update(&Move::NoMove {}).await? {
+            MoveCall::Call { player, .. } if player == "Blue" => Ok(()),
+            other => bail!("expected blue to move, got {:?}", other),
+        }
+    }
+    .await;
+    report.check("player reconnects to the game", result)?;
+    clients[blue] = client;
+
+    let result = async {
+        for client in clients.iter_mut() {
+            client
+                .send(Pdu::GameSession(GameSession::DrawVote(DrawVote::Vote {
+                    agree: true,
+                })))
+                .await?;
+        }
+        for client in clients.iter_mut() {
+            let finish = client.finish().await?;
+            if finish.game_id != game_id || finish.end != GameEnd::DrawAgreed {
+                bail!("unexpected finish {:?}", finish);
+            }
+        }
+        Ok(())
+    }
+    .await;
+    report.check("agreed draw finishes the game", result)
+}
@@ -0,0 +1,317 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use futures_util::{future, pin_mut};
+use log::debug;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tungstenite::handshake::server::{Request, Response};
+use tungstenite::http::header::{HeaderValue, SEC_WEBSOCKET_EXTENSIONS};
+
+// permessage-deflate (RFC 7692) tungstenite 0.12 does not know. Every message is compressed
+// on its own, so neither side keeps the window between messages.
+const AGREED: &str = "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+// shorter messages are sent plain, deflate would only add to them
+const MIN_LEN: usize = 64;
+// longest message of the client, compressed or inflated
+const MAX_MESSAGE: usize = 16 << 20;
+// end of the sync flush, stripped from the compressed messages
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+// control frames have the highest bit of the opcode set
+const OP_CONTROL: u8 = 0x8;
+
+// Accepts the first offer of permessage-deflate the server can serve, the response gets the
+// agreed parameters. The server window is always the largest one, offers limiting it are
+// declined, the window of the client is whatever it uses.
+pub fn negotiate(request: &Request, response: &mut Response) -> bool {
+    let accepted = request
+        .headers()
+        .get_all(SEC_WEBSOCKET_EXTENSIONS)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(acceptable);
+    if accepted {
+        response
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_EXTENSIONS, HeaderValue::from_static(AGREED));
+    }
+    accepted
+}
+
+fn acceptable(offer: &str) -> bool {
+    let mut params = offer.split(';').map(str::trim);
+    if params.next() != Some("permessage-deflate") {
+        return false;
+    }
+    params.all(|param| {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (param, None),
+        };
+        match (name, value) {
+            ("server_no_context_takeover", None) | ("client_no_context_takeover", None) => true,
+            ("client_max_window_bits", _) => true,
+            ("server_max_window_bits", Some(bits)) => bits == "15",
+            _ => false,
+        }
+    })
+}
+
+pub struct Frame {
+    pub fin: bool,
+    // the message is compressed, set on its first frame only
+    pub rsv1: bool,
+    pub opcode: u8,
+    pub mask: Option<[u8; 4]>,
+    // masked as it goes over the wire
+    pub payload: Vec<u8>,
+}
+
+fn invalid(description: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, description.to_string())
+}
+
+// masking is its own inverse
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+pub async fn read_frame<R: AsyncRead + Unpin>(from: &mut R) -> io::Result<Frame> {
+    let mut head = [0u8; 2];
+    from.read_exact(&mut head).await?;
+    if head[0] & 0x30 != 0 {
+        return Err(invalid("reserved bits of the frame are set"));
+    }
+    let len = match head[1] & 0x7f {
+        126 => from.read_u16().await? as u64,
+        127 => from.read_u64().await?,
+        len => len as u64,
+    };
+    if len > MAX_MESSAGE as u64 {
+        return Err(invalid("frame is too long"));
+    }
+    let mask = match head[1] & 0x80 {
+        0 => None,
+        _ => {
+            let mut mask = [0u8; 4];
+            from.read_exact(&mut mask).await?;
+            Some(mask)
+        }
+    };
+    let mut payload = vec![0; len as usize];
+    from.read_exact(&mut payload).await?;
+    Ok(Frame {
+        fin: head[0] & 0x80 != 0,
+        rsv1: head[0] & 0x40 != 0,
+        opcode: head[0] & 0x0f,
+        mask,
+        payload,
+    })
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(to: &mut W, frame: &Frame) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(frame.payload.len() + 14);
+    bytes.push((frame.fin as u8) << 7 | (frame.rsv1 as u8) << 6 | frame.opcode);
+    let masked = (frame.mask.is_some() as u8) << 7;
+    match frame.payload.len() {
+        len if len < 126 => bytes.push(masked | len as u8),
+        len if len <= u16::MAX as usize => {
+            bytes.push(masked | 126);
+            bytes.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            bytes.push(masked | 127);
+            bytes.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    if let Some(mask) = frame.mask {
+        bytes.extend_from_slice(&mask);
+    }
+    bytes.extend_from_slice(&frame.payload);
+    to.write_all(&bytes).await?;
+    to.flush().await
+}
+
+// raw deflate with the sync flush, the tail of the flush is not sent
+pub fn deflate(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut compress = Compress::new(Compression::default(), false);
+    let mut out = Vec::with_capacity(payload.len() / 2 + 64);
+    loop {
+        let consumed = compress.total_in() as usize;
+        compress
+            .compress_vec(&payload[consumed..], &mut out, FlushCompress::Sync)
+            .map_err(io::Error::other)?;
+        // flushed once the output did not fill the buffer
+        if compress.total_in() as usize == payload.len() && out.len() < out.capacity() {
+            break;
+        }
+        out.reserve(out.capacity());
+    }
+    if out.ends_with(&TAIL) {
+        out.truncate(out.len() - TAIL.len());
+    }
+    Ok(out)
+}
+
+pub fn inflate(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let input = [payload, &TAIL].concat();
+    let mut decompress = Decompress::new(false);
+    let mut out = Vec::with_capacity(input.len() * 4);
+    loop {
+        let (consumed, produced) = (decompress.total_in(), decompress.total_out());
+        let status = decompress
+            .decompress_vec(&input[consumed as usize..], &mut out, FlushDecompress::Sync)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if out.len() > MAX_MESSAGE {
+            return Err(invalid("inflated message is too long"));
+        }
+        let done = decompress.total_in() as usize == input.len() && out.len() < out.capacity();
+        if status == Status::StreamEnd || done {
+            return Ok(out);
+        }
+        if (decompress.total_in(), decompress.total_out()) == (consumed, produced) {
+            return Err(invalid("compressed message is truncated"));
+        }
+        out.reserve(out.capacity());
+    }
+}
+
+// Compressed messages of the client go to tungstenite as a single plain frame masked
+// by the key of the first one, everything else goes as it came.
+async fn inflate_incoming<R, W>(mut from: R, mut to: W) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // first frame of the compressed message with the unmasked payload of all its frames
+    let mut compressed: Option<Frame> = None;
+    loop {
+        let mut frame = read_frame(&mut from).await?;
+        let starts = frame.opcode != OP_CONTINUATION && frame.opcode & OP_CONTROL == 0;
+        if frame.rsv1 && !starts {
+            return Err(invalid(
+                "compressed flag is set on a frame not starting a message",
+            ));
+        }
+        if frame.rsv1 || (frame.opcode == OP_CONTINUATION && compressed.is_some()) {
+            if let Some(mask) = frame.mask {
+                apply_mask(&mut frame.payload, mask);
+            }
+            let fin = frame.fin;
+            let mut message = match compressed.take() {
+                Some(mut message) => {
+                    message.payload.extend_from_slice(&frame.payload);
+                    message
+                }
+                None => frame,
+            };
+            if message.payload.len() > MAX_MESSAGE {
+                return Err(invalid("compressed message is too long"));
+            }
+            if !fin {
+                compressed = Some(message);
+                continue;
+            }
+            message.payload = inflate(&message.payload)?;
+            if let Some(mask) = message.mask {
+                apply_mask(&mut message.payload, mask);
+            }
+            message.fin = true;
+            message.rsv1 = false;
+            write_frame(&mut to, &message).await?;
+            continue;
+        }
+        write_frame(&mut to, &frame).await?;
+    }
+}
+
+// Whole data messages of tungstenite long enough are compressed, fragmented ones and
+// control frames are sent as they are.
+async fn deflate_outgoing<R, W>(mut from: R, mut to: W) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let mut frame = match read_frame(&mut from).await {
+            Ok(frame) => frame,
+            // the session is over
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return to.shutdown().await,
+            Err(e) => return Err(e),
+        };
+        let data = frame.opcode == OP_TEXT || frame.opcode == OP_BINARY;
+        if data && frame.fin && frame.payload.len() >= MIN_LEN {
+            frame.payload = deflate(&frame.payload)?;
+            frame.rsv1 = true;
+        }
+        write_frame(&mut to, &frame).await?;
+    }
+}
+
+// Frames between the client socket and tungstenite on the other end of the pipe, until
+// either side is closed
+pub async fn relay<S, T>(socket: S, tungstenite: T)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let (socket_rx, socket_tx) = tokio::io::split(socket);
+    let (ws_rx, ws_tx) = tokio::io::split(tungstenite);
+    let incoming = inflate_incoming(BufReader::new(socket_rx), ws_tx);
+    let outgoing = deflate_outgoing(BufReader::new(ws_rx), socket_tx);
+    pin_mut!(incoming, outgoing);
+    if let (Err(e), _) = future::select(incoming, outgoing).await.factor_first() {
+        if e.kind() != io::ErrorKind::UnexpectedEof {
+            debug!("permessage-deflate relay stopped \"{}\"", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(extensions: &str) -> (bool, Response) {
+        let request = Request::builder()
+            .header(SEC_WEBSOCKET_EXTENSIONS, extensions)
+            .body(())
+            .unwrap();
+        let mut response = Response::default();
+        (negotiate(&request, &mut response), response)
+    }
+
+    #[test]
+    fn deflate_offer_accepted() {
+        let (accepted, response) = offer("permessage-deflate; client_max_window_bits");
+        assert!(accepted);
+        assert_eq!(response.headers()[SEC_WEBSOCKET_EXTENSIONS], AGREED);
+        assert!(offer("permessage-deflate; server_max_window_bits=15").0);
+        assert!(!offer("x-webkit-deflate-frame").0);
+        assert!(!Response::default()
+            .headers()
+            .contains_key(SEC_WEBSOCKET_EXTENSIONS));
+    }
+
+    #[test]
+    fn smaller_server_window_declined() {
+        assert!(!offer("permessage-deflate; server_max_window_bits=10").0);
+        assert!(!offer("permessage-deflate; unknown_param").0);
+        // the fallback offer of the client
+        assert!(offer("permessage-deflate; server_max_window_bits=10, permessage-deflate").0);
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let update = br#"{"game":{"update":{"board":"3,yR,yN,yB,yK,yQ,yB,yN,yR,3"}}}"#.repeat(20);
+        let compressed = deflate(&update).unwrap();
+        assert!(compressed.len() < update.len() / 4);
+        assert!(!compressed.ends_with(&TAIL));
+        assert_eq!(inflate(&compressed).unwrap(), update);
+        assert!(inflate(b"\xff\xff\xff").is_err());
+    }
+}
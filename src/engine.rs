@@ -0,0 +1,150 @@
+use crate::board::{Fen4, Figure, Position};
+use anyhow::{bail, Context, Result};
+use std::convert::TryFrom;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+// thinking time of every search
+const MOVE_TIME: Duration = Duration::from_millis(500);
+// engine not answering in time is considered broken
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// External four-player chess engine process speaking a UCI-like protocol over stdin/stdout.
+// "uci" is answered by "uciok" once the engine is up, every search is
+// "setoption name Skill Level value <level>", "position fen4 <fen4>", "go movetime <ms>"
+// answered by "bestmove <move>". Other lines of the engine are ignored.
+pub struct Engine {
+    child: Child,
+    stdin: ChildStdin,
+    // stdout lines, read by a thread until the engine exits
+    lines: UnboundedReceiver<String>,
+}
+
+impl Engine {
+    pub async fn start(command: &str) -> Result<Engine> {
+        let mut child = Command::new(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .context(format!("start engine {:?} failed", command))?;
+        let stdin = child.stdin.take().context("engine stdin is not piped")?;
+        let stdout = child.stdout.take().context("engine stdout is not piped")?;
+        let (tx, lines) = unbounded_channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut engine = Engine {
+            child,
+            stdin,
+            lines,
+        };
+        engine.send("uci")?;
+        engine.answer("uciok").await?;
+        Ok(engine)
+    }
+
+    fn send(&mut self, command: &str) -> Result<()> {
+        writeln!(self.stdin, "{}", command).context("write to engine failed")?;
+        self.stdin.flush().context("write to engine failed")
+    }
+
+    // rest of the first line starting with the keyword
+    async fn answer(&mut self, keyword: &str) -> Result<String> {
+        let lines = &mut self.lines;
+        let wait = async {
+            while let Some(line) = lines.recv().await {
+                if let Some(rest) = line.strip_prefix(keyword) {
+                    return Ok(rest.trim().to_string());
+                }
+            }
+            bail!("engine exited")
+        };
+        tokio::time::timeout(ANSWER_TIMEOUT, wait)
+            .await
+            .context(format!("engine did not answer {} in time", keyword))?
+    }
+
+    // Best move of the side to move in the engine notation, level 1 is the weakest
+    pub async fn best_move(&mut self, fen: &Fen4, level: u8) -> Result<String> {
+        self.send(&format!("setoption name Skill Level value {}", level))?;
+        self.send(&format!("position fen4 {}", fen))?;
+        self.send(&format!("go movetime {}", MOVE_TIME.as_millis()))?;
+        let answer = self.answer("bestmove").await?;
+        answer
+            .split_whitespace()
+            .next()
+            .map(str::to_string)
+            .context("engine answered empty best move")
+    }
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Squares and promotion of the engine move like "e2e4", "e2-e4", "d13xd14=Q" or "d13d14q".
+// Castling is the king move.
+pub fn parse_move(text: &str) -> Option<(Position, Position, Option<Figure>)> {
+    if !text.is_ascii() {
+        return None;
+    }
+    let text = text.to_lowercase().replace(['-', 'x', '='], "");
+    let mut squares = Vec::new();
+    let mut rest = text.as_str();
+    while !rest.is_empty() {
+        let digits = rest[1..].chars().take_while(char::is_ascii_digit).count();
+        if digits == 0 {
+            break;
+        }
+        let name = &rest[..1 + digits];
+        squares.push(Position::try_from(name).ok()?);
+        rest = &rest[1 + digits..];
+    }
+    let into = match rest {
+        "" => None,
+        "q" => Some(Figure::Queen),
+        "r" => Some(Figure::Rook),
+        "b" => Some(Figure::Bishop),
+        "n" => Some(Figure::Knight),
+        _ => return None,
+    };
+    match squares[..] {
+        [from, to] => Some((from, to, into)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn engine_notation_parsed() {
+        assert_eq!(parse_move("e2e4"), Some((Position::e2, Position::e4, None)));
+        assert_eq!(
+            parse_move("h13-h14"),
+            Some((Position::h13, Position::h14, None))
+        );
+        assert_eq!(
+            parse_move("d13xd14=Q"),
+            Some((Position::d13, Position::d14, Some(Figure::Queen)))
+        );
+        assert_eq!(
+            parse_move("k7l8n"),
+            Some((Position::k7, Position::l8, Some(Figure::Knight)))
+        );
+        assert!(parse_move("a1a2").is_none());
+        assert!(parse_move("e2").is_none());
+        assert!(parse_move("e2e4k").is_none());
+    }
+}
@@ -0,0 +1,203 @@
+use crate::proto::Suspect;
+use crate::vault::GameSnapshot;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// latest games of the player looked at by every review
+const REVIEW_GAMES: usize = 20;
+// engine evaluated moves needed before the match rate tells anything
+const MIN_EVALUATED: usize = 30;
+// share of the evaluated moves matching the engine best move, in percent
+const ENGINE_MATCH_PERCENT: u8 = 85;
+// moves needed before the spread of the think times tells anything
+const MIN_TIMED: usize = 30;
+// standard deviation of the think times in percent of their mean, people vary more than that
+const TIME_SPREAD_PERCENT: u8 = 15;
+
+// Move quality and think time figures of the latest games of the player
+pub struct Review {
+    // all games of the player kept in records
+    pub games: u32,
+    pub moves: u32,
+    pub engine_match: Option<u8>,
+    pub time_spread: Option<u8>,
+}
+
+impl Review {
+    // the player is suspicious for every reason, none of them proves anything
+    pub fn reasons(&self) -> Vec<String> {
+        let mut reasons = Vec::new();
+        if let Some(engine_match) = self.engine_match.filter(|m| *m >= ENGINE_MATCH_PERCENT) {
+            reasons.push(format!("{}% of the moves matched the engine", engine_match));
+        }
+        if let Some(time_spread) = self.time_spread.filter(|s| *s < TIME_SPREAD_PERCENT) {
+            reasons.push(format!(
+                "think times spread by {}% of their mean only",
+                time_spread
+            ));
+        }
+        reasons
+    }
+}
+
+// Review of the moves the player made in the records, oldest record first
+pub fn review(name: &str, records: &[&GameSnapshot]) -> Review {
+    let moves = records
+        .iter()
+        .rev()
+        .take(REVIEW_GAMES)
+        .filter_map(|record| {
+            let color = record.player_color(name)?;
+            Some(record.moves.iter().filter(move |m| m.color == color))
+        })
+        .flatten()
+        .collect::<Vec<_>>();
+
+    let evaluated = moves.iter().filter_map(|m| m.best).collect::<Vec<_>>();
+    let engine_match = (evaluated.len() >= MIN_EVALUATED)
+        .then(|| (evaluated.iter().filter(|b| **b).count() * 100 / evaluated.len()) as u8);
+
+    let times = moves
+        .iter()
+        .map(|m| m.time_used.as_secs_f64())
+        .collect::<Vec<_>>();
+    let mean = times.iter().sum::<f64>() / times.len().max(1) as f64;
+    let time_spread = (times.len() >= MIN_TIMED && mean > 0.0).then(|| {
+        let variance = times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / times.len() as f64;
+        (variance.sqrt() * 100.0 / mean).min(255.0) as u8
+    });
+
+    Review {
+        games: records.len() as u32,
+        moves: moves.len() as u32,
+        engine_match,
+        time_spread,
+    }
+}
+
+// Players flagged for review by the admins, persisted by storage
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct FairPlay {
+    suspects: HashMap<String, Suspect>,
+    // games of the player when the admin cleared them, flagged again only after more games
+    cleared: HashMap<String, u32>,
+    // not yet persisted changes
+    #[serde(skip)]
+    changed: bool,
+}
+
+impl FairPlay {
+    // Flag the player with reasons, the flag is kept until an admin clears it.
+    // Returns true if the player was flagged now.
+    pub fn update(&mut self, name: &str, review: Review, now: u64) -> bool {
+        let reasons = review.reasons();
+        if reasons.is_empty() || self.cleared.get(name).is_some_and(|g| *g >= review.games) {
+            return false;
+        }
+        let flagged_at = self.suspects.get(name).map_or(now, |s| s.flagged_at);
+        let suspect = Suspect {
+            player: name.to_string(),
+            games: review.games,
+            moves: review.moves,
+            engine_match: review.engine_match,
+            time_spread: review.time_spread,
+            reasons,
+            flagged_at,
+        };
+        let flagged = self.suspects.insert(name.to_string(), suspect).is_none();
+        self.changed = true;
+        flagged
+    }
+
+    // longest flagged first
+    pub fn suspects(&self) -> Vec<Suspect> {
+        let mut suspects = self.suspects.values().cloned().collect::<Vec<_>>();
+        suspects.sort_by(|a, b| (a.flagged_at, &a.player).cmp(&(b.flagged_at, &b.player)));
+        suspects
+    }
+
+    // reviewed by an admin, false if the player was not flagged
+    pub fn clear(&mut self, name: &str) -> bool {
+        match self.suspects.remove(name) {
+            Some(suspect) => {
+                self.cleared.insert(name.to_string(), suspect.games);
+                self.changed = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // the account is deleted
+    pub fn forget(&mut self, name: &str) {
+        let flagged = self.suspects.remove(name).is_some();
+        let cleared = self.cleared.remove(name).is_some();
+        if flagged || cleared {
+            self.changed = true;
+        }
+    }
+
+    // true once after every change
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+    pub fn mark_changed(&mut self) {
+        self.changed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Board;
+    use crate::board::Position;
+    use crate::proto::Move;
+    use crate::vault::{fixtures, Color, MoveRecord};
+    use std::time::Duration;
+
+    fn record(best: bool, millis: &[u64]) -> GameSnapshot {
+        let mut record = fixtures::game(Board::new()).record();
+        record.red.name = "mallory".to_string();
+        record.moves = millis
+            .iter()
+            .map(|ms| MoveRecord {
+                color: Color::Red,
+                mv: Move::Basic {
+                    from: Position::e2,
+                    to: Position::e4,
+                },
+                time_used: Duration::from_millis(*ms),
+                captured: None,
+                checks: Vec::new(),
+                best: Some(best),
+                figure: None,
+                at: 0,
+            })
+            .collect();
+        record
+    }
+
+    #[test]
+    fn engine_moves_at_a_steady_pace_flagged() {
+        let steady = record(true, &[2000; 40]);
+        let steady_review = review("mallory", &[&steady]);
+        assert_eq!(
+            (steady_review.engine_match, steady_review.time_spread),
+            (Some(100), Some(0))
+        );
+        let mut fair_play = FairPlay::default();
+        assert!(fair_play.update("mallory", steady_review, 100));
+        assert_eq!(fair_play.suspects()[0].reasons.len(), 2);
+        assert!(fair_play.clear("mallory"));
+        assert!(!fair_play.update("mallory", review("mallory", &[&steady]), 200));
+        assert!(fair_play.suspects().is_empty());
+
+        let varied = (0..40).map(|i| 500 + i * 300).collect::<Vec<_>>();
+        let human_review = review("mallory", &[&record(false, &varied)]);
+        assert_eq!(human_review.engine_match, Some(0));
+        assert!(human_review.reasons().is_empty());
+        // too few moves to tell
+        let short = record(true, &[2000; 10]);
+        assert!(review("mallory", &[&short]).reasons().is_empty());
+    }
+}
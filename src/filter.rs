@@ -0,0 +1,231 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+fn default_mute_after() -> u32 {
+    3
+}
+
+fn default_mute_secs() -> u64 {
+    300
+}
+
+// What happens to the text with the word, from the mildest.
+// Dropped messages are counted, the sender is muted after mute_after of them.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Censor,
+    Drop,
+    Mute,
+}
+
+// list without locale applies to every client
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WordList {
+    #[serde(default)]
+    pub locale: Option<String>,
+    pub severity: Severity,
+    pub words: Vec<String>,
+}
+
+// Operator supplied word lists, words match whole words case insensitively
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FilterConfig {
+    #[serde(default)]
+    pub lists: Vec<WordList>,
+    #[serde(default = "default_mute_after")]
+    pub mute_after: u32,
+    #[serde(default = "default_mute_secs")]
+    pub mute_secs: u64,
+}
+
+impl Default for FilterConfig {
+    fn default() -> FilterConfig {
+        FilterConfig {
+            lists: Vec::new(),
+            mute_after: default_mute_after(),
+            mute_secs: default_mute_secs(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Clean,
+    // text with the words replaced by asterisks
+    Censored(String),
+    Dropped,
+    Muted,
+}
+
+// hits since the start of the server, kept over reloads
+#[derive(Default)]
+pub struct FilterStats {
+    pub checked: u64,
+    pub censored: u64,
+    pub dropped: u64,
+    // escalated mutes are counted by the caller
+    pub muted: u64,
+    pub names_rejected: u64,
+    pub words: HashMap<(String, Severity), u64>,
+}
+
+#[derive(Default)]
+pub struct ChatFilter {
+    config: FilterConfig,
+    // locale, none for every client => word => severity
+    words: HashMap<Option<String>, HashMap<String, Severity>>,
+    pub stats: FilterStats,
+}
+
+impl ChatFilter {
+    pub fn reload(&mut self, config: FilterConfig) {
+        self.words.clear();
+        for list in &config.lists {
+            let words = self.words.entry(list.locale.clone()).or_default();
+            for word in &list.words {
+                let severity = words.entry(word.to_lowercase()).or_insert(list.severity);
+                *severity = (*severity).max(list.severity);
+            }
+        }
+        self.config = config;
+    }
+
+    pub fn mute_after(&self) -> u32 {
+        self.config.mute_after
+    }
+
+    pub fn mute_for(&self) -> Duration {
+        Duration::from_secs(self.config.mute_secs)
+    }
+
+    // listed words of the text with their positions, global lists and lists of the locale
+    fn hits(&self, text: &str, locale: Option<&str>) -> Vec<(usize, usize, Severity)> {
+        let lists = [
+            self.words.get(&None),
+            locale.and_then(|l| self.words.get(&Some(l.to_string()))),
+        ];
+        let mut hits = Vec::new();
+        let mut start = None;
+        for (i, c) in text
+            .char_indices()
+            .chain(std::iter::once((text.len(), ' ')))
+        {
+            match (c.is_alphanumeric(), start) {
+                (true, None) => start = Some(i),
+                (false, Some(s)) => {
+                    let word = text[s..i].to_lowercase();
+                    if let Some(severity) =
+                        lists.iter().flatten().filter_map(|l| l.get(&word)).max()
+                    {
+                        hits.push((s, i, *severity));
+                    }
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+        hits
+    }
+
+    fn count(&mut self, text: &str, hits: &[(usize, usize, Severity)]) {
+        for (from, to, severity) in hits {
+            *self
+                .stats
+                .words
+                .entry((text[*from..*to].to_lowercase(), *severity))
+                .or_insert(0) += 1;
+        }
+    }
+
+    // Verdict of the most severe word of the chat message
+    pub fn check(&mut self, text: &str, locale: Option<&str>) -> Verdict {
+        self.stats.checked += 1;
+        let hits = self.hits(text, locale);
+        self.count(text, &hits);
+        match hits.iter().map(|h| h.2).max() {
+            None => Verdict::Clean,
+            Some(Severity::Censor) => {
+                self.stats.censored += 1;
+                let mut censored = text.to_string();
+                for (from, to, _) in hits.iter().rev() {
+                    let stars = "*".repeat(text[*from..*to].chars().count());
+                    censored.replace_range(*from..*to, &stars);
+                }
+                Verdict::Censored(censored)
+            }
+            Some(Severity::Drop) => {
+                self.stats.dropped += 1;
+                Verdict::Dropped
+            }
+            Some(Severity::Mute) => {
+                self.stats.muted += 1;
+                Verdict::Muted
+            }
+        }
+    }
+
+    // names with any listed word are rejected
+    pub fn allows_name(&mut self, name: &str, locale: Option<&str>) -> bool {
+        self.stats.checked += 1;
+        let hits = self.hits(name, locale);
+        self.count(name, &hits);
+        if !hits.is_empty() {
+            self.stats.names_rejected += 1;
+        }
+        hits.is_empty()
+    }
+}
+
+pub fn load_filter(path: &str) -> Result<FilterConfig> {
+    let json = std::fs::read_to_string(path).context(format!("read {:?} failed", path))?;
+    serde_json::from_str(&json).context(format!("parse {:?} failed", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> ChatFilter {
+        let mut filter = ChatFilter::default();
+        filter.reload(
+            serde_json::from_str(
+                r#"{"lists": [
+                    {"severity": "censor", "words": ["darn"]},
+                    {"severity": "drop", "words": ["Rude"]},
+                    {"locale": "de", "severity": "mute", "words": ["mist"]}
+                ]}"#,
+            )
+            .unwrap(),
+        );
+        filter
+    }
+
+    #[test]
+    fn most_severe_word_decides() {
+        let mut filter = filter();
+        assert_eq!(filter.check("hello there", None), Verdict::Clean);
+        assert_eq!(
+            filter.check("Darn, darnation. DARN", None),
+            Verdict::Censored("****, darnation. ****".to_string())
+        );
+        assert_eq!(filter.check("darn rude move", None), Verdict::Dropped);
+        assert_eq!(
+            filter.stats.words[&("darn".to_string(), Severity::Censor)],
+            3
+        );
+        assert_eq!(filter.stats.dropped, 1);
+    }
+
+    #[test]
+    fn locale_lists_apply_to_their_locale() {
+        let mut filter = filter();
+        assert_eq!(filter.check("so ein mist", Some("en")), Verdict::Clean);
+        assert_eq!(filter.check("so ein mist", Some("de")), Verdict::Muted);
+        assert!(!filter.allows_name("rude_player", None));
+        assert!(filter.allows_name("rudolph", None));
+        assert_eq!(filter.stats.names_rejected, 1);
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+// moves sent before the turn are kept and played when it comes
+pub const FLAG_PREMOVES: &str = "premoves";
+// malformed moves are rejected instead of being skipped
+pub const FLAG_STRICT_VALIDATION: &str = "strict_validation";
+// updates carry the changes of the turn only
+pub const FLAG_DELTA_UPDATES: &str = "delta_updates";
+const KNOWN_FLAGS: &[&str] = &[FLAG_PREMOVES, FLAG_STRICT_VALIDATION, FLAG_DELTA_UPDATES];
+
+fn default_percent() -> u8 {
+    100
+}
+
+// Experimental feature of the games, limited to pools and rolled out to a share of games
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Flag {
+    pub name: String,
+    pub enabled: bool,
+    // all pools if empty, lobby games are of no pool
+    #[serde(default)]
+    pub pools: Vec<String>,
+    // share of the games, the same games are chosen as long as it is not changed
+    #[serde(default = "default_percent")]
+    pub percent: u8,
+}
+
+// Feature flags loaded from the config, toggled by the operators until restart
+#[derive(Default)]
+pub struct Flags {
+    flags: Vec<Flag>,
+}
+
+impl Flags {
+    pub fn list(&self) -> Vec<Flag> {
+        self.flags.clone()
+    }
+
+    // replaces the flag of the same name
+    pub fn set(&mut self, flag: Flag) -> Result<()> {
+        check(&flag)?;
+        self.flags.retain(|f| f.name != flag.name);
+        self.flags.push(flag);
+        self.flags.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(())
+    }
+
+    pub fn enabled(&self, name: &str, pool: Option<&str>, game_id: u64) -> bool {
+        self.flags.iter().any(|f| {
+            f.name == name
+                && f.enabled
+                && (f.pools.is_empty() || pool.is_some_and(|p| f.pools.iter().any(|fp| fp == p)))
+                && game_share(name, game_id) < f.percent as u64
+        })
+    }
+
+    // names of the flags the game is played with
+    pub fn of_game(&self, pool: Option<&str>, game_id: u64) -> Vec<String> {
+        self.flags
+            .iter()
+            .filter(|f| self.enabled(&f.name, pool, game_id))
+            .map(|f| f.name.clone())
+            .collect()
+    }
+}
+
+fn check(flag: &Flag) -> Result<()> {
+    if !KNOWN_FLAGS.contains(&flag.name.as_str()) {
+        bail!("unknown flag \"{}\"", flag.name);
+    }
+    if flag.percent > 100 {
+        bail!("flag \"{}\" percent must be 0 to 100", flag.name);
+    }
+    Ok(())
+}
+
+// 0-99, games are spread by id differently for every flag
+fn game_share(name: &str, game_id: u64) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in name.bytes().chain(game_id.to_le_bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash % 100
+}
+
+// Load flags from json file
+pub fn load_flags(path: &str) -> Result<Flags> {
+    let json = std::fs::read_to_string(path).context(format!("read {:?} failed", path))?;
+    let list: Vec<Flag> =
+        serde_json::from_str(&json).context(format!("parse {:?} failed", path))?;
+    let mut flags = Flags::default();
+    for flag in list {
+        flags.set(flag)?;
+    }
+    Ok(flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flag(name: &str, pools: &[&str], percent: u8) -> Flag {
+        Flag {
+            name: name.to_string(),
+            enabled: true,
+            pools: pools.iter().map(|p| p.to_string()).collect(),
+            percent,
+        }
+    }
+
+    #[test]
+    fn flags_limited_to_pools_and_share_of_games() {
+        let mut flags = Flags::default();
+        flags.set(flag(FLAG_PREMOVES, &["blitz"], 100)).unwrap();
+        flags.set(flag(FLAG_DELTA_UPDATES, &[], 30)).unwrap();
+        assert!(flags.set(flag("teleport", &[], 100)).is_err());
+        assert!(flags.set(flag(FLAG_PREMOVES, &[], 101)).is_err());
+
+        assert!(flags.enabled(FLAG_PREMOVES, Some("blitz"), 1));
+        assert!(!flags.enabled(FLAG_PREMOVES, Some("default"), 1));
+        assert!(!flags.enabled(FLAG_PREMOVES, None, 1));
+        assert!(!flags.enabled(FLAG_STRICT_VALIDATION, Some("blitz"), 1));
+
+        let delta = (0..1000)
+            .filter(|id| flags.enabled(FLAG_DELTA_UPDATES, None, *id))
+            .count();
+        assert!((200..400).contains(&delta));
+
+        let mut disabled = flag(FLAG_PREMOVES, &["blitz"], 100);
+        disabled.enabled = false;
+        flags.set(disabled).unwrap();
+        assert!(!flags.enabled(FLAG_PREMOVES, Some("blitz"), 1));
+        assert_eq!(flags.list().len(), 2);
+    }
+}
@@ -0,0 +1,45 @@
+use serde_json::json;
+
+// Status and json body of the probe with the request line like "GET /readyz HTTP/1.1".
+// /healthz answers while the process does, /readyz once nothing keeps the tenants from
+// serving players, problems tell what does.
+pub fn route(request_line: &str, problems: &[String]) -> (u16, String) {
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return (400, json!({ "error": "bad request" }).to_string()),
+    };
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+    if path != "/healthz" && path != "/readyz" {
+        return (404, json!({ "error": "unknown resource" }).to_string());
+    }
+    if method != "GET" {
+        return (405, json!({ "error": "only GET is allowed" }).to_string());
+    }
+    match path {
+        "/healthz" => (200, json!({ "status": "alive" }).to_string()),
+        _ if problems.is_empty() => (200, json!({ "status": "ready" }).to_string()),
+        _ => (
+            503,
+            json!({ "status": "not_ready", "problems": problems }).to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_without_problems() {
+        let problems = vec!["storage is unreachable".to_string()];
+        assert_eq!(route("GET /healthz HTTP/1.1", &problems).0, 200);
+        let (status, body) = route("GET /readyz HTTP/1.1", &problems);
+        assert_eq!(status, 503);
+        assert!(body.contains("storage is unreachable"));
+        assert_eq!(route("GET /readyz?verbose HTTP/1.1", &[]).0, 200);
+        assert_eq!(route("POST /readyz HTTP/1.1", &[]).0, 405);
+        assert_eq!(route("GET /metrics HTTP/1.1", &[]).0, 404);
+        assert_eq!(route("", &[]).0, 400);
+    }
+}
@@ -0,0 +1,362 @@
+use serde_json::{Map, Value};
+
+// Locales of the catalog, english is the language of the server
+pub const LOCALES: [&str; 3] = ["en", "de", "es"];
+
+// Error descriptions by stable code, in the order of LOCALES. The server sends the english one,
+// "{}" stands for a value of the description which is kept in the translation.
+const CATALOG: &[(&str, [&str; 3])] = &[
+    (
+        "pass_handshake_first",
+        [
+            "pass handshake first",
+            "zuerst den Handshake abschließen",
+            "primero completa el handshake",
+        ],
+    ),
+    (
+        "unsupported_protocol",
+        [
+            "Unsupported client version",
+            "nicht unterstützte Client-Version",
+            "versión de cliente no compatible",
+        ],
+    ),
+    (
+        "handshake_timeout",
+        [
+            "no handshake in {} seconds",
+            "kein Handshake in {} Sekunden",
+            "sin handshake en {} segundos",
+        ],
+    ),
+    (
+        "log_in_first",
+        ["log in first", "zuerst anmelden", "inicia sesión primero"],
+    ),
+    (
+        "already_logged_in",
+        [
+            "you are already logged in",
+            "du bist bereits angemeldet",
+            "ya has iniciado sesión",
+        ],
+    ),
+    (
+        "login_failed",
+        [
+            "login failed",
+            "Anmeldung fehlgeschlagen",
+            "error al iniciar sesión",
+        ],
+    ),
+    (
+        "wrong_password",
+        [
+            "wrong password",
+            "falsches Passwort",
+            "contraseña incorrecta",
+        ],
+    ),
+    (
+        "password_too_short",
+        [
+            "password must be at least 8 characters",
+            "das Passwort muss mindestens 8 Zeichen haben",
+            "la contraseña debe tener al menos 8 caracteres",
+        ],
+    ),
+    (
+        "name_registered",
+        [
+            "name is already registered",
+            "der Name ist bereits registriert",
+            "el nombre ya está registrado",
+        ],
+    ),
+    (
+        "name_not_allowed",
+        [
+            "name is not allowed",
+            "der Name ist nicht erlaubt",
+            "el nombre no está permitido",
+        ],
+    ),
+    (
+        "account_not_found",
+        [
+            "account not found",
+            "Konto nicht gefunden",
+            "cuenta no encontrada",
+        ],
+    ),
+    (
+        "bad_admin_token",
+        [
+            "bad admin token",
+            "ungültiges Admin-Token",
+            "token de administrador no válido",
+        ],
+    ),
+    (
+        "leave_queue_lobby_or_game",
+        [
+            "leave matchmaking queue, lobby or active game session first",
+            "zuerst die Warteschlange, die Lobby oder das laufende Spiel verlassen",
+            "primero sal de la cola, del lobby o de la partida activa",
+        ],
+    ),
+    (
+        "no_such_game",
+        [
+            "no such game",
+            "dieses Spiel gibt es nicht",
+            "no existe esa partida",
+        ],
+    ),
+    (
+        "no_record",
+        [
+            "no record of game {}",
+            "keine Aufzeichnung der Partie {}",
+            "no hay registro de la partida {}",
+        ],
+    ),
+    (
+        "no_such_tournament",
+        [
+            "no such tournament",
+            "dieses Turnier gibt es nicht",
+            "no existe ese torneo",
+        ],
+    ),
+    (
+        "game_crashed",
+        [
+            "game session crashed",
+            "die Spielsitzung ist abgestürzt",
+            "la sesión de juego falló",
+        ],
+    ),
+    (
+        "time_over",
+        ["time over", "die Zeit ist abgelaufen", "se acabó el tiempo"],
+    ),
+    (
+        "not_your_turn",
+        ["not your turn", "du bist nicht am Zug", "no es tu turno"],
+    ),
+    (
+        "game_paused",
+        [
+            "the game is paused",
+            "das Spiel ist pausiert",
+            "la partida está en pausa",
+        ],
+    ),
+    (
+        "empty_from_cell",
+        [
+            "empty from cell",
+            "das Ausgangsfeld ist leer",
+            "la casilla de origen está vacía",
+        ],
+    ),
+    (
+        "piece_of_another_player",
+        [
+            "piece of another player",
+            "Figur eines anderen Spielers",
+            "pieza de otro jugador",
+        ],
+    ),
+    (
+        "destination_occupied",
+        [
+            "destination cell is not empty",
+            "das Zielfeld ist nicht leer",
+            "la casilla de destino no está vacía",
+        ],
+    ),
+    (
+        "nothing_to_capture",
+        [
+            "nothing to capture",
+            "nichts zu schlagen",
+            "no hay nada que capturar",
+        ],
+    ),
+    (
+        "unreachable",
+        [
+            "piece can not reach destination cell",
+            "die Figur kann das Zielfeld nicht erreichen",
+            "la pieza no puede llegar a la casilla de destino",
+        ],
+    ),
+    (
+        "king_remains_in_check",
+        [
+            "king would remain in check from {}",
+            "der König bliebe im Schach von {}",
+            "el rey seguiría en jaque desde {}",
+        ],
+    ),
+    (
+        "king_into_check",
+        [
+            "king would be in check from {}",
+            "der König stünde im Schach von {}",
+            "el rey quedaría en jaque desde {}",
+        ],
+    ),
+    (
+        "chat_players_only",
+        [
+            "only players of the game can chat",
+            "nur Spieler der Partie können chatten",
+            "solo los jugadores de la partida pueden chatear",
+        ],
+    ),
+    (
+        "chat_message_length",
+        [
+            "message must be 1-{} characters",
+            "die Nachricht muss 1-{} Zeichen haben",
+            "el mensaje debe tener entre 1 y {} caracteres",
+        ],
+    ),
+    (
+        "chat_message_not_allowed",
+        [
+            "message is not allowed",
+            "die Nachricht ist nicht erlaubt",
+            "el mensaje no está permitido",
+        ],
+    ),
+    (
+        "chat_muted_for",
+        [
+            "you are muted for {} seconds",
+            "du bist für {} Sekunden stummgeschaltet",
+            "estás silenciado durante {} segundos",
+        ],
+    ),
+];
+
+// Catalog locale of the client locale, "de-AT" takes "de". Locales without catalog get english.
+pub fn find(locale: Option<&str>) -> usize {
+    let language = locale
+        .and_then(|l| l.split(['-', '_']).next())
+        .map(str::to_lowercase);
+    LOCALES
+        .iter()
+        .position(|l| Some(*l) == language.as_deref())
+        .unwrap_or(0)
+}
+
+// values in place of "{}" if the text is the template
+fn values<'t>(template: &str, text: &'t str) -> Option<Vec<&'t str>> {
+    let mut parts = template.split("{}");
+    let mut rest = text.strip_prefix(parts.next()?)?;
+    let parts = parts.collect::<Vec<_>>();
+    let mut values = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        let end = match i + 1 == parts.len() {
+            true => rest.strip_suffix(part)?.len(),
+            false => rest.find(part)?,
+        };
+        values.push(&rest[..end]);
+        rest = &rest[end + part.len()..];
+    }
+    rest.is_empty().then_some(values)
+}
+
+// Code and description in the locale of the english description. Descriptions missing from
+// the catalog stay english, the kind of the error is their code.
+pub fn localize(kind: &str, description: &str, locale: usize) -> (String, String) {
+    for (code, texts) in CATALOG {
+        if let Some(values) = values(texts[0], description) {
+            let mut values = values.into_iter();
+            let localized = texts[locale]
+                .split("{}")
+                .enumerate()
+                .map(|(i, part)| match i {
+                    0 => part.to_string(),
+                    _ => format!("{}{}", values.next().unwrap_or_default(), part),
+                })
+                .collect();
+            return (code.to_string(), localized);
+        }
+    }
+    (kind.to_string(), description.to_string())
+}
+
+// Every error of the pdu gets the code next to its description in the locale.
+// Errors are the variants in "error" objects with a description.
+pub fn localize_errors(value: &mut Value, locale: usize) {
+    let Value::Object(map) = value else {
+        return;
+    };
+    match map.get_mut("error") {
+        Some(Value::Object(error)) => error.iter_mut().for_each(|(kind, fields)| {
+            if let Value::Object(fields) = fields {
+                localize_fields(kind, fields, locale);
+            }
+        }),
+        _ => map
+            .values_mut()
+            .for_each(|value| localize_errors(value, locale)),
+    }
+}
+
+fn localize_fields(kind: &str, fields: &mut Map<String, Value>, locale: usize) {
+    let Some(Value::String(description)) = fields.get("description") else {
+        return;
+    };
+    let (code, description) = localize(kind, description, locale);
+    fields.insert("code".to_string(), Value::String(code));
+    fields.insert("description".to_string(), Value::String(description));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn errors_coded_and_translated() {
+        let de = find(Some("de-AT"));
+        assert_eq!(LOCALES[de], "de");
+        assert_eq!(find(Some("fr")), 0);
+        assert_eq!(
+            localize("forbidden", "you are muted for 30 seconds", de),
+            (
+                "chat_muted_for".to_string(),
+                "du bist für 30 Sekunden stummgeschaltet".to_string()
+            )
+        );
+        assert_eq!(
+            localize(
+                "illegal_move",
+                "king would be in check from Blue",
+                find(Some("es"))
+            )
+            .1,
+            "el rey quedaría en jaque desde Blue"
+        );
+        // unknown descriptions stay as they are
+        assert_eq!(
+            localize("unspecified_error", "no pool \"x\"", de),
+            ("unspecified_error".to_string(), "no pool \"x\"".to_string())
+        );
+
+        let mut pdu = json!({"auth": {"error": {"forbidden": {"description": "log in first"}}}});
+        localize_errors(&mut pdu, 0);
+        assert_eq!(
+            pdu,
+            json!({"auth": {"error": {"forbidden": {
+                "code": "log_in_first", "description": "log in first"}}}})
+        );
+    }
+}
@@ -1,33 +1,100 @@
+mod account;
+mod bench;
 mod board;
+mod bot;
+mod canary;
+mod cluster;
+mod conformance;
+mod deflate;
+mod engine;
+mod fairplay;
+mod filter;
+mod flags;
+mod health;
+mod locale;
+mod metrics;
+mod pgn4;
+mod pool;
 mod proto;
+mod rating;
+mod storage;
+mod telemetry;
+mod tenant;
+mod tls;
+mod tournament;
 mod vault;
 
 use proto::{
-    Connect, ConnectError, GameSession, GetInfo, Handshake, Init, MatchmakingQueue, Move, MoveCall,
-    Pdu, PlayerRegister, PlayerRegisterError, PlayersStates, Protocol, Server, StartPosition,
-    StartPositions, Update,
+    Account, AccountData, AccountDataError, AccountProfile, AccountStats, Adjudication,
+    AdjudicationError, Admin, AdminError, Analysis, Auth, AuthError, Benchmark, BenchmarkReport,
+    Broadcast, ChallengeInfo, Chat, ChatError, ChatKind, ChatMute, Connect, ConnectError,
+    CreateTournament, Disconnect, DisconnectReason, DrawVote, DrawVoteError, Evaluation,
+    FairPlayReview, FeatureFlags, FilterReport, FilterStats, Finish, Friend, GameDiff, GameHistory,
+    GameInfo, GameList, GameReplay, GameResult, GameSession, GameState, GetInfo, Handshake,
+    HistoryMove, IllegalMoveReason, Init, Kick, Leaderboard, LeaderboardError, LegalMoves,
+    LegalMovesError, Lobby, LobbyError, MatchmakingQueue, Moderation, ModerationError, Move,
+    MoveCall, MoveEffects, Notice, Pause, PauseError, Pdu, PeerInfo, PeerList, Pgn4, PlayerRank,
+    PlayerRegister, PlayerRegisterError, PlayerSummary, PlayersClocks, PlayersNames, PlayersPoints,
+    PlayersScores, PlayersStates, PlayersSummaries, Pools, ProtoVersion, Protocol, RateLimited,
+    Rating, Reconnect, ReconnectError, RemainingPieces, Replay, ReplayError, ReplayMove, Route,
+    RulesDescriptor, Season, SeasonError, Seats, Server, ServerMeta, Social, SocialError, Spectate,
+    SpectateError, StartPosition, StartPositions, StateSync, StateSyncError, Subscribe,
+    SubscribeError, Terminate, TimingReport, Tournament, TournamentError, TournamentFormat, Update,
+    Variant, WordHits, CAPABILITY_BINARY_SNAPSHOT, CAPABILITY_MSGPACK, CAPABILITY_PONG_READINESS,
+    CAPABILITY_SEAT_LABELS,
 };
 
-use board::{Board, Position};
-use vault::{ClientInfo, Color, Complete, Game, Peer, PeerState, Player, PlayerState};
+use account::{deleted_name, AccountError, Accounts};
+use board::{Board, Fen4, Position};
+use canary::CanaryConfig;
+use cluster::{Cluster, ClusterConfig, QueueEntry};
+use engine::Engine;
+use filter::{load_filter, Verdict};
+use flags::Flag;
+use metrics::{MoveTimings, Sample};
+use pool::{group_apart, Pool, DEFAULT_POOL, DEFAULT_TIME_CONTROL};
+use rating::Ratings;
+use storage::{Fsync, Storage, WalEntry};
+use tenant::Tenant;
+use vault::{
+    peer_channel, redact_chat, AccountInfo, Admission, AfkPolicy, Challenge, ClientInfo, Color,
+    Complete, ConnectionId, DisconnectPolicy, Fanout, Game, GameEnd, GamePause, GameSnapshot,
+    LobbyMember, Peer, PeerState, Player, PlayerState, PrivateLobby, RateLimit, ShedPolicy,
+    StalemateRule, SubscriberKind, TokenBucket, Tx,
+};
 
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{Mutex, MutexGuard};
 use tokio::time::{self};
 
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use env_logger::Builder;
 use log::LevelFilter;
 use log::{debug, error, info};
+use tracing::{info_span, instrument, Instrument, Span};
 
-use std::{env, io::Error as IoError, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    env,
+    io::Error as IoError,
+    net::SocketAddr,
+    sync::Arc,
+};
 
 use futures::future::Either;
 use futures_channel::mpsc::{unbounded, UnboundedReceiver};
-use futures_util::{future, pin_mut, StreamExt};
-use tokio::net::{TcpListener, TcpStream};
+use futures_util::{future, pin_mut, Sink, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::handshake::server as ws_server;
+use tungstenite::protocol::{Message, Role};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use enum_iterator::IntoEnumIterator;
+use once_cell::sync::Lazy;
 
 use std::string::ToString;
 
@@ -36,63 +103,377 @@ use crate::vault::WhoMove;
 use rand::{distributions::Alphanumeric, Rng};
 use tokio::sync::mpsc::UnboundedSender;
 
-type Vault = Arc<RwLock<vault::Vault>>;
+type Vault = Arc<vault::Vault>;
 
-const PROTO_VER: &str = "0";
 const SERV_NAME: &str = "fpc-server-rs";
 const SERV_VER: &str = "0.0.1";
+const SERV_CAPABILITIES: &[&str] = &[
+    CAPABILITY_PONG_READINESS,
+    CAPABILITY_BINARY_SNAPSHOT,
+    CAPABILITY_SEAT_LABELS,
+    CAPABILITY_MSGPACK,
+];
+const SERVER_META_VER: u32 = 1;
 static HB_DISP_TICK_PERIOD: Duration = Duration::from_secs(1);
 static HB_WAIT_TIMEOUT: Duration = Duration::from_secs(2);
 static HB_READY_TIMEOUT: Duration = Duration::from_secs(5);
 static GS_INIT_PAUSE: Duration = Duration::from_secs(10);
-static PLAYER_TIMER: Duration = Duration::from_secs(60);
+static GS_RECOVERY_PAUSE: Duration = Duration::from_secs(3);
+static GS_MAX_RECOVERIES: usize = 3;
+// games check this often whether the player to move is disconnected
+static GS_DISCONNECT_CHECK_PERIOD: Duration = Duration::from_secs(1);
+// players of games restored after restart need time to reconnect
+static GS_RESTORE_PAUSE: Duration = Duration::from_secs(30);
+static SNAPSHOT_PERIOD: Duration = Duration::from_secs(5);
+static METRICS_PERIOD: Duration = Duration::from_secs(60);
+// samples older than this are forgotten by the server, the storage keeps them
+static METRICS_RETENTION: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+// bytes in flight between tungstenite and the permessage-deflate relay
+static DEFLATE_PIPE_CAPACITY: usize = 64 * 1024;
+// longest request head of the observer API
+static OBSERVER_HEAD_LIMIT: usize = 8192;
+static OBSERVER_TIMEOUT: Duration = Duration::from_secs(5);
+// matchmaking which has not run for this long is stuck, the tenant is not ready
+static MATCHMAKING_STALL: Duration = Duration::from_secs(30);
+// FPC_WAL_FSYNC=never leaves flushing of the turn log to the system, storage of every tenant
+static WAL_FSYNC: Lazy<Fsync> = Lazy::new(|| {
+    env::var("FPC_WAL_FSYNC")
+        .map(|fsync| fsync.parse().expect("bad FPC_WAL_FSYNC"))
+        .unwrap_or(Fsync::Always)
+});
+// FPC_WS_DEFLATE=true compresses the messages to clients offering permessage-deflate
+static WS_DEFLATE: Lazy<bool> = Lazy::new(|| {
+    env::var("FPC_WS_DEFLATE")
+        .map(|deflate| deflate.parse().expect("bad FPC_WS_DEFLATE"))
+        .unwrap_or(false)
+});
+// every connection is pinged, silent longer than the timeout is closed as half-open
+static KEEPALIVE_PERIOD: Duration = Duration::from_secs(15);
+static KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(45);
+static GC_PERIOD: Duration = Duration::from_secs(30);
+// FPC_HANDSHAKE_TIMEOUT seconds, connections talking without the handshake are closed after it
+static HANDSHAKE_TIMEOUT: Lazy<Duration> = Lazy::new(|| {
+    let secs = env::var("FPC_HANDSHAKE_TIMEOUT")
+        .map(|secs| secs.parse().expect("bad FPC_HANDSHAKE_TIMEOUT"))
+        .unwrap_or(60);
+    Duration::from_secs(secs)
+});
+// FPC_IDLE_TIMEOUT seconds the idle peer may stay connected without requests, 0 never closes.
+// Spectators and engines following a game are never idle.
+static IDLE_TIMEOUT: Lazy<Option<Duration>> = Lazy::new(|| {
+    let secs = env::var("FPC_IDLE_TIMEOUT")
+        .map(|secs| secs.parse().expect("bad FPC_IDLE_TIMEOUT"))
+        .unwrap_or(1800);
+    (secs > 0).then(|| Duration::from_secs(secs))
+});
+// closed connection still in the vault after it lost its task, e.g. to a panic
+static DEAD_PEER_TIMEOUT: Duration = Duration::from_secs(60);
+// operator metadata file is checked for changes with this period
+static SERVER_META_RELOAD_PERIOD: Duration = Duration::from_secs(10);
+static CHAT_FILTER_RELOAD_PERIOD: Duration = Duration::from_secs(10);
+// players of aborted games are grouped first when they queue again within this period
+static QUEUE_PRIORITY_PERIOD: Duration = Duration::from_secs(600);
+static LOBBY_CODE_LEN: usize = 6;
+// challenges not accepted by all players within this period are dropped
+static CHALLENGE_TIMEOUT: Duration = Duration::from_secs(300);
+static SEASON_CHECK_PERIOD: Duration = Duration::from_secs(60);
+// finished tournament games are picked up and the next rounds started with this period
+static TOURNAMENT_CHECK_PERIOD: Duration = Duration::from_secs(5);
+// distributed mode, the node publishes its games and queues with this period
+static CLUSTER_PERIOD: Duration = Duration::from_secs(5);
+// rating season length in days
+static SEASON_LENGTH: Lazy<Duration> = Lazy::new(|| {
+    let days = env::var("FPC_SEASON_DAYS")
+        .map(|days| days.parse().expect("bad FPC_SEASON_DAYS"))
+        .unwrap_or(30);
+    Duration::from_secs(days * 24 * 60 * 60)
+});
+// players on the all time leaderboard unless the request tells how many
+static LEADERBOARD_TOP: usize = 10;
+// latest results in the form of the player
+static FORM_GAMES: usize = 5;
+// seconds the disconnected player may reconnect to the game
+static RECONNECT_GRACE: Lazy<Duration> = Lazy::new(|| {
+    let secs = env::var("FPC_RECONNECT_GRACE")
+        .map(|secs| secs.parse().expect("bad FPC_RECONNECT_GRACE"))
+        .unwrap_or(600);
+    Duration::from_secs(secs)
+});
+// seconds the seat of the disconnected tournament player is held unless the tournament tells
+static TOURNAMENT_RECONNECT_GRACE: u64 = 900;
+static PLAYER_TIMER: Duration = Duration::from_secs(DEFAULT_TIME_CONTROL);
+// clock of the armageddon player with draw odds, percents of the time control
+static DRAW_ODDS_TIME_PERCENT: u32 = 80;
 static PLAYER_TIME_2: Duration = Duration::from_secs(5);
+static AFK_TURN_TIMEOUT: Duration = Duration::from_secs(30);
+static AFK_WARN_AFTER: usize = 2;
+static AFK_RESIGN_AFTER: usize = 3;
+// turns in a row skipped by at least two players before the others are offered adjudication
+static ADJUDICATION_AFTER: usize = 2;
+// clear or turn_to_stone, pieces of lost player are removed if unset
+static REMAINING_PIECES: Lazy<RemainingPieces> = Lazy::new(|| {
+    env::var("FPC_REMAINING_PIECES")
+        .map(|mode| mode.parse().expect("bad FPC_REMAINING_PIECES"))
+        .unwrap_or(RemainingPieces::Clear)
+});
+// spectators of a game are shed only when there are at least this many of them
+static SHED_MIN_SPECTATORS: usize = 16;
+// spectator with this many messages waiting in its queue is switched to clocks only
+static SHED_DOWNGRADE_QUEUED: usize = 32;
+static SHED_DISCONNECT_QUEUED: usize = 128;
+// peer with this many messages waiting in its queue is disconnected as too slow
+static PEER_QUEUE_LIMIT: usize = 256;
+// messages per second and bucket size of every peer, FPC_RATE_LIMIT and FPC_RATE_BURST
+static RATE_LIMIT: Lazy<RateLimit> = Lazy::new(|| {
+    let env_f64 = |name: &str, default: f64| {
+        env::var(name)
+            .map(|v| v.parse().unwrap_or_else(|_| panic!("bad {}", name)))
+            .unwrap_or(default)
+    };
+    let burst = env_f64("FPC_RATE_BURST", 40.0);
+    RateLimit {
+        per_second: env_f64("FPC_RATE_LIMIT", 20.0),
+        burst,
+        disconnect_after: burst as usize,
+    }
+});
+// analysis engines authenticate with this token, analysis disabled if unset
+static ANALYSIS_TOKEN: Lazy<Option<String>> = Lazy::new(|| env::var("FPC_ANALYSIS_TOKEN").ok());
+// every benchmark runs this long
+static BENCHMARK_DURATION: Duration = Duration::from_millis(200);
+// connections of the handshake benchmark asking at once
+static BENCHMARK_HANDSHAKES: usize = 2000;
+// history stream waits while so many messages are queued to the peer
+static HISTORY_QUEUE_MAX: usize = 32;
+static HISTORY_PACE: Duration = Duration::from_millis(20);
+static CHAT_MAX_LEN: usize = 500;
+static REACTION_MAX_LEN: usize = 16;
+// eliminate, skip or draw, stalemated player loses if unset
+static STALEMATE_RULE: Lazy<StalemateRule> = Lazy::new(|| {
+    env::var("FPC_STALEMATE_RULE")
+        .map(|rule| rule.parse().expect("bad FPC_STALEMATE_RULE"))
+        .unwrap_or(StalemateRule::Eliminate)
+});
+
+// seconds in the queue after which incomplete groups are filled with bots, never if unset
+static BOT_FILL_AFTER: Lazy<Option<Duration>> = Lazy::new(|| {
+    env::var("FPC_BOT_FILL_AFTER")
+        .ok()
+        .map(|secs| Duration::from_secs(secs.parse().expect("bad FPC_BOT_FILL_AFTER")))
+});
+// bots do not move at once, humans would miss their moves
+static BOT_MOVE_DELAY: Duration = Duration::from_secs(1);
+// command of the engine playing bots of pools with bot_level, built-in bots only if unset
+static BOT_ENGINE: Lazy<Option<String>> = Lazy::new(|| env::var("FPC_BOT_ENGINE").ok());
+// games an account may play at once over all its connections, it is queued once at most
+static ACCOUNT_GAME_LIMIT: Lazy<usize> = Lazy::new(|| {
+    env::var("FPC_ACCOUNT_GAME_LIMIT")
+        .map(|limit| limit.parse().expect("bad FPC_ACCOUNT_GAME_LIMIT"))
+        .unwrap_or(1)
+});
+// milliseconds between the accepted moves of a player, faster moves wait for their turn
+static MIN_MOVE_INTERVAL: Lazy<Duration> = Lazy::new(|| {
+    let millis = env::var("FPC_MIN_MOVE_INTERVAL")
+        .map(|millis| millis.parse().expect("bad FPC_MIN_MOVE_INTERVAL"))
+        .unwrap_or(100);
+    Duration::from_millis(millis)
+});
+// milliseconds of the measured round trip not charged to the player, 0 disables
+static LATENCY_CAP: Lazy<Duration> = Lazy::new(|| {
+    let millis = env::var("FPC_LATENCY_CAP")
+        .map(|millis| millis.parse().expect("bad FPC_LATENCY_CAP"))
+        .unwrap_or(500);
+    Duration::from_millis(millis)
+});
+// started on the first use, restarted after it failed
+static ENGINE: Lazy<Mutex<Option<Engine>>> = Lazy::new(|| Mutex::new(None));
 
 macro_rules! send_msg_to {
-    ($peers:expr, $addr:expr, $msg:expr) => {
-        $peers
-            .read()
-            .await
-            .get_peers()
-            .await
-            .get($addr)
-            .context(format!("get({}) from peer_map failed", $addr))?
-            .lock()
-            .await
-            .tx
-            .unbounded_send($msg)?;
+    ($peers:expr, $conn_id:expr, $msg:expr) => {
+        $peers.reply($conn_id, $msg)?;
     };
 }
 
 macro_rules! game_init_pdu {
-    ($pause_time:expr, $reconnect_id:expr, $red:expr,
-    $green:expr, $blue:expr, $yellow:expr) => {
+    ($game_id:expr, $pause_time:expr, $reconnect_id:expr, $red:expr,
+    $green:expr, $blue:expr, $yellow:expr, $draw_odds:expr, $features:expr, $start:expr,
+    $seats:expr) => {
         Pdu::GameSession(proto::GameSession::Init(Init {
+            game_id: $game_id,
             countdown: $pause_time,
             reconnect_id: $reconnect_id,
             start_positions: StartPositions {
                 red: StartPosition {
                     player_name: $red,
                     left_rook: Position::d1,
+                    seat: $seats.then(|| proto::Seat::of(Color::Red)),
                 },
                 blue: StartPosition {
-                    player_name: $green,
+                    player_name: $blue,
                     left_rook: Position::a11,
+                    seat: $seats.then(|| proto::Seat::of(Color::Blue)),
                 },
                 yellow: StartPosition {
-                    player_name: $blue,
+                    player_name: $yellow,
                     left_rook: Position::k14,
+                    seat: $seats.then(|| proto::Seat::of(Color::Yellow)),
                 },
                 green: StartPosition {
-                    player_name: $yellow,
+                    player_name: $green,
                     left_rook: Position::n4,
+                    seat: $seats.then(|| proto::Seat::of(Color::Green)),
                 },
             },
+            draw_odds: $draw_odds.map(|color: Color| color.to_string()),
+            features: $features,
+            start: $start,
         }))
         .to_message()
     };
 }
 
+fn default_disconnect_policy() -> DisconnectPolicy {
+    DisconnectPolicy {
+        grace: *RECONNECT_GRACE,
+        hold_seat: false,
+    }
+}
+
+// tournament games stop the clock of the disconnected player and keep the seat for the grace
+fn held_seat_policy(grace: Duration) -> DisconnectPolicy {
+    DisconnectPolicy {
+        grace,
+        hold_seat: true,
+    }
+}
+
+fn default_afk_policy() -> AfkPolicy {
+    AfkPolicy {
+        turn_timeout: AFK_TURN_TIMEOUT,
+        warn_after: AFK_WARN_AFTER,
+        resign_after: AFK_RESIGN_AFTER,
+    }
+}
+
+fn default_shed_policy() -> ShedPolicy {
+    ShedPolicy {
+        min_spectators: SHED_MIN_SPECTATORS,
+        downgrade_queued: SHED_DOWNGRADE_QUEUED,
+        disconnect_queued: SHED_DISCONNECT_QUEUED,
+    }
+}
+
+fn player_state(game: &Game, color: Color) -> proto::PlayerState {
+    proto::PlayerState::new(game.player(&color).state.clone(), game.remaining_pieces)
+}
+
+fn players_states(game: &Game) -> PlayersStates {
+    PlayersStates {
+        red: player_state(game, Color::Red),
+        blue: player_state(game, Color::Blue),
+        yellow: player_state(game, Color::Yellow),
+        green: player_state(game, Color::Green),
+        seats: None,
+    }
+}
+
+fn move_call(game: &Game) -> MoveCall {
+    match game.current_move_player() {
+        Some(player) => MoveCall::Call {
+            player: player.color.to_string(),
+            timer: player.time_remaining.as_secs(),
+            timer_2: PLAYER_TIME_2.as_secs(),
+            seat: None,
+        },
+        None => MoveCall::NoCall {},
+    }
+}
+
+fn players_clocks(game: &Game) -> PlayersClocks {
+    PlayersClocks {
+        red: game.player(&Color::Red).time_remaining.as_millis() as u64,
+        blue: game.player(&Color::Blue).time_remaining.as_millis() as u64,
+        yellow: game.player(&Color::Yellow).time_remaining.as_millis() as u64,
+        green: game.player(&Color::Green).time_remaining.as_millis() as u64,
+    }
+}
+
+fn players_points(game: &Game) -> Option<PlayersPoints> {
+    if game.variant != Variant::Points {
+        return None;
+    }
+    Some(PlayersPoints {
+        red: game.player(&Color::Red).points,
+        blue: game.player(&Color::Blue).points,
+        yellow: game.player(&Color::Yellow).points,
+        green: game.player(&Color::Green).points,
+    })
+}
+
+fn player_summary(game: &Game, color: Color, rating_delta: Option<i32>) -> PlayerSummary {
+    let player = game.player(&color);
+    let moves = game
+        .moves
+        .iter()
+        .filter(|m| m.color == color)
+        .collect::<Vec<_>>();
+    let evaluated = moves.iter().filter_map(|m| m.best).collect::<Vec<_>>();
+    let accuracy = if evaluated.is_empty() {
+        None
+    } else {
+        let best = evaluated.iter().filter(|b| **b).count();
+        Some((best * 100 / evaluated.len()) as u8)
+    };
+    PlayerSummary {
+        player: player.name.clone(),
+        state: player_state(game, color),
+        time_used: moves.iter().map(|m| m.time_used.as_millis() as u64).sum(),
+        moves: moves.len() as u64,
+        captures: game.captures_by(color).len() as u64,
+        checks_given: moves.iter().map(|m| m.checks.len() as u64).sum(),
+        accuracy,
+        rating_delta,
+        points: Some(player.points).filter(|_| game.variant == Variant::Points),
+    }
+}
+
+fn players_summaries(game: &Game, rating_deltas: &[(Color, i32)]) -> PlayersSummaries {
+    let summary = |color| {
+        let rating_delta = rating_deltas
+            .iter()
+            .find(|(c, _)| *c == color)
+            .map(|(_, delta)| *delta);
+        player_summary(game, color, rating_delta)
+    };
+    PlayersSummaries {
+        red: summary(Color::Red),
+        blue: summary(Color::Blue),
+        yellow: summary(Color::Yellow),
+        green: summary(Color::Green),
+    }
+}
+
+// update ratings of players by the final standings of the game, casual games are not rated
+fn rate_game(game: &Game, ratings: &mut Ratings) -> Vec<(Color, i32)> {
+    if game.casual {
+        return Vec::new();
+    }
+    let players = game.players();
+    let standings = players
+        .iter()
+        .map(|p| (p.name.as_str(), game.place(p.color)))
+        .collect::<Vec<_>>();
+    let deltas = ratings.rate(&standings);
+    players.iter().map(|p| p.color).zip(deltas).collect()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 fn random_string() -> String {
     rand::thread_rng()
         .sample_iter(&Alphanumeric)
@@ -101,50 +482,76 @@ fn random_string() -> String {
         .collect()
 }
 
-async fn process_hs_get_info(vault: &Vault, addr: &SocketAddr) -> Result<()> {
+async fn process_hs_get_info(vault: &Vault, conn_id: &ConnectionId) -> Result<()> {
     let resp = Pdu::Handshake(Handshake::GetInfo(GetInfo::Ok {
-        protocol: Protocol::SupportedVersion(vec![String::from(PROTO_VER)]),
+        protocol: Protocol::SupportedVersion(
+            ProtoVersion::SUPPORTED
+                .iter()
+                .map(|v| v.as_str().to_string())
+                .collect(),
+        ),
     }))
     .to_message()?;
-    send_msg_to!(vault, addr, resp);
+    send_msg_to!(vault, conn_id, resp);
     Ok(())
 }
 
 async fn process_hs_connect(
     vault: &Vault,
-    addr: &SocketAddr,
+    conn_id: &ConnectionId,
     name: &str,
     version: &str,
-    proto_ver: &str,
+    protocol: &Protocol,
+    capabilities: &[String],
+    locale: Option<&str>,
 ) -> Result<()> {
-    if proto_ver == PROTO_VER {
+    if let Some(proto_ver) = ProtoVersion::negotiate(protocol) {
+        let supported = capabilities
+            .iter()
+            .filter(|c| SERV_CAPABILITIES.contains(&c.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+        let pong_readiness = supported.iter().any(|c| c == CAPABILITY_PONG_READINESS);
+        let binary_snapshot = supported.iter().any(|c| c == CAPABILITY_BINARY_SNAPSHOT);
+        let seat_labels = supported.iter().any(|c| c == CAPABILITY_SEAT_LABELS);
+        let msgpack = supported.iter().any(|c| c == CAPABILITY_MSGPACK);
+        let catalog_locale = locale::find(locale);
         let resp = Pdu::Handshake(Handshake::Connect(Connect::Ok {
-            server: Server {
+            server: Box::new(Server {
                 name: String::from(SERV_NAME),
                 version: String::from(SERV_VER),
-            },
+                meta: vault.get_server_meta().await.clone(),
+                capabilities: supported,
+                rules: seat_labels.then(|| RulesDescriptor {
+                    seats: Seats::table(),
+                }),
+                tenant: vault.tenant().name.clone(),
+                protocol: Some(proto_ver.as_str().to_string()),
+                locale: locale.map(|_| locale::LOCALES[catalog_locale].to_string()),
+            }),
         }))
         .to_message()?;
 
-        let lock = vault.write().await;
-        let peers_lock = lock.get_peers().await;
-        let peer = peers_lock
-            .get(addr)
-            .context(format!("get({}) from peer_map failed", addr))?;
+        let peer = vault
+            .get_peer(conn_id)
+            .context(format!("get({}) from peer_map failed", conn_id))?;
         let mut peer_lock = peer.lock().await;
 
         if peer_lock.state.is_unknown() {
-            peer_lock.tx.unbounded_send(resp)?;
+            peer_lock.tx.send(resp)?;
+            peer_lock.tx.set_msgpack(msgpack);
+            peer_lock.tx.set_locale(catalog_locale);
 
             peer_lock.state = PeerState::Idle;
             peer_lock.client_info = Some(ClientInfo {
                 name: String::from(name),
                 version: String::from(version),
-                protocol: String::from(proto_ver),
+                protocol: proto_ver,
+                pong_readiness,
+                binary_snapshot,
+                seat_labels,
+                locale: locale.map(str::to_string),
             });
-
-            let mut idle_lock = lock.get_idle().await;
-            idle_lock.insert(*addr, peer.clone());
         }
     } else {
         let resp = Pdu::Handshake(Handshake::Connect(Connect::Error(
@@ -153,32 +560,118 @@ async fn process_hs_connect(
             },
         )))
         .to_message()?;
-        send_msg_to!(vault, addr, resp);
+        send_msg_to!(vault, conn_id, resp);
     }
     Ok(())
 }
 
-async fn process_mm_player_reg(vault: &Vault, addr: &SocketAddr, name: &str) -> Result<()> {
-    let lock = vault.write().await;
-    let peers_lock = lock.get_peers().await;
-    let peer = peers_lock
-        .get(addr)
-        .context(format!("get({}) from peer_map failed", addr))?;
+async fn process_mm_player_reg(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    name: &str,
+    pool: &str,
+    variant: Variant,
+    time_control: Option<u64>,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    // other connections of the account are counted before this one is locked
+    let account = peer
+        .lock()
+        .await
+        .account
+        .as_ref()
+        .map(|a| (a.id, a.name.clone()));
+    let (queued, games) = match &account {
+        Some((id, name)) => account_entries(vault, conn_id, *id, name).await,
+        None => (0, 0),
+    };
+    // distributed mode, the pool is gathered on one node
+    let home = match &vault.tenant().cluster {
+        Some(cluster) => routed_home(cluster, pool).await,
+        None => None,
+    };
     let mut peer_lock = peer.lock().await;
     match peer_lock.state {
         PeerState::Idle => {
+            let now = unix_now();
+            let error = match vault.tenant().pool(Some(pool)) {
+                _ if peer_lock.account.is_none() => Some(PlayerRegisterError::Unauthorized {
+                    description: "log in first".to_string(),
+                }),
+                _ if !peer_lock.owns_name(name) => Some(PlayerRegisterError::BadName {
+                    description: format!("\"{}\" is not the name of your account", name),
+                }),
+                _ if queued > 0 => Some(PlayerRegisterError::AccountLimit {
+                    description: "your account is already queued from another connection"
+                        .to_string(),
+                }),
+                _ if games >= *ACCOUNT_GAME_LIMIT => Some(PlayerRegisterError::AccountLimit {
+                    description: format!(
+                        "your account plays {} of {} games allowed at once",
+                        games, *ACCOUNT_GAME_LIMIT
+                    ),
+                }),
+                None => Some(PlayerRegisterError::UnknownPool {
+                    description: format!("no pool \"{}\"", pool),
+                }),
+                Some(p) if !p.is_open(now) => Some(PlayerRegisterError::PoolClosed {
+                    description: format!("pool \"{}\" is closed now", pool),
+                    opens_at: p.opens_at(now),
+                }),
+                Some(p) if !p.offers(variant) || p.time_control(time_control).is_none() => {
+                    Some(PlayerRegisterError::UnsupportedQueue {
+                        description: format!(
+                            "pool \"{}\" does not offer {:?} with time control {:?}",
+                            pool, variant, time_control
+                        ),
+                    })
+                }
+                Some(_) => None,
+            };
+            if let Some(e) = error {
+                let resp = Pdu::MatchmakingQueue(MatchmakingQueue::PlayerRegister(
+                    PlayerRegister::Error(e),
+                ))
+                .to_message()?;
+                peer_lock.tx.send(resp)?;
+                return Ok(());
+            }
+            if let Some(route) = home.map(|node| pool_route(node, pool)) {
+                if peer_lock.protocol().understands(&route) {
+                    peer_lock.tx.send(route.to_message()?)?;
+                    return Ok(());
+                }
+            }
+
+            let (rating, badges) = {
+                let ratings_lock = vault.get_ratings().await;
+                (ratings_lock.get(name), ratings_lock.badges(name))
+            };
             let resp =
-                Pdu::MatchmakingQueue(MatchmakingQueue::PlayerRegister(PlayerRegister::Ok {}))
-                    .to_message()?;
-            peer_lock.tx.unbounded_send(resp)?;
+                Pdu::MatchmakingQueue(MatchmakingQueue::PlayerRegister(PlayerRegister::Ok {
+                    rating,
+                    badges,
+                }))
+                .to_message()?;
+            peer_lock.tx.send(resp)?;
             peer_lock.player_name = Some(name.to_string());
+            peer_lock.pool = Some(pool.to_string());
+            peer_lock.variant = variant;
+            peer_lock.time_control = vault
+                .tenant()
+                .pool(Some(pool))
+                .and_then(|p| p.time_control(time_control))
+                .unwrap_or(DEFAULT_TIME_CONTROL);
+            peer_lock.queued_at = Some(Instant::now());
+            peer_lock.merged = None;
             peer_lock.state = PeerState::MMQueue;
-            let mut mm_queue_lock = lock.get_mm_queue().await;
-            mm_queue_lock.insert(*addr, peer.clone());
         }
         PeerState::HeartbeatReady(_)
         | PeerState::HeartbeatWait(_)
         | PeerState::MMQueue
+        | PeerState::Lobby(_)
         | PeerState::Game { .. } => {
             let resp = Pdu::MatchmakingQueue(MatchmakingQueue::PlayerRegister(
                 PlayerRegister::Error(PlayerRegisterError::AlreadyRegistered {
@@ -187,7 +680,7 @@ async fn process_mm_player_reg(vault: &Vault, addr: &SocketAddr, name: &str) ->
                 }),
             ))
             .to_message()?;
-            peer_lock.tx.unbounded_send(resp)?;
+            peer_lock.tx.send(resp)?;
         }
         PeerState::Unknown(_) => {
             let resp = Pdu::MatchmakingQueue(MatchmakingQueue::PlayerRegister(
@@ -196,18 +689,69 @@ async fn process_mm_player_reg(vault: &Vault, addr: &SocketAddr, name: &str) ->
                 }),
             ))
             .to_message()?;
-            peer_lock.tx.unbounded_send(resp)?;
+            peer_lock.tx.send(resp)?;
+        }
+    }
+    Ok(())
+}
+
+// Queue and lobby entries of the account held by its other connections, and the games
+// it is seated in, seats held for reconnect included
+async fn account_entries(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    account_id: u64,
+    name: &str,
+) -> (usize, usize) {
+    let mut queued = 0;
+    for (key, peer) in vault.get_peers() {
+        if key == *conn_id {
+            continue;
+        }
+        let peer_lock = peer.lock().await;
+        let same_account = peer_lock
+            .account
+            .as_ref()
+            .is_some_and(|a| a.id == account_id);
+        let waiting = matches!(
+            peer_lock.state,
+            PeerState::MMQueue
+                | PeerState::HeartbeatWait(_)
+                | PeerState::HeartbeatReady(_)
+                | PeerState::Lobby(_)
+        );
+        if same_account && waiting {
+            queued += 1;
         }
     }
+    let mut games = 0;
+    for game in vault.get_games() {
+        let game_lock = game.lock().await;
+        let seated = game_lock
+            .players()
+            .iter()
+            .any(|p| !p.bot && p.name == name && p.state.in_game());
+        if !game_lock.terminated && seated {
+            games += 1;
+        }
+    }
+    (queued, games)
+}
+
+async fn process_mm_pools(vault: &Vault, conn_id: &ConnectionId) -> Result<()> {
+    let now = unix_now();
+    let resp = Pdu::MatchmakingQueue(MatchmakingQueue::Pools(Pools::Ok {
+        pools: vault.tenant().pools.iter().map(|p| p.info(now)).collect(),
+    }))
+    .to_message()?;
+    send_msg_to!(vault, conn_id, resp);
     Ok(())
 }
 
-async fn process_mm_player_leave(vault: &Vault, addr: &SocketAddr) -> Result<()> {
-    let lock = vault.read().await;
-    let peers_lock = lock.get_peers().await;
-    let peer = peers_lock
-        .get(addr)
-        .context(format!("get({}) from peer_map failed", addr))?;
+async fn process_mm_player_leave(vault: &Vault, conn_id: &ConnectionId) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
     let mut peer_lock = peer.lock().await;
     match peer_lock.state {
         PeerState::MMQueue | PeerState::HeartbeatWait(_) | PeerState::HeartbeatReady(_) => {
@@ -218,27 +762,38 @@ async fn process_mm_player_leave(vault: &Vault, addr: &SocketAddr) -> Result<()>
     Ok(())
 }
 
-async fn process_mm_heartbeat_check(vault: &Vault, addr: &SocketAddr) -> Result<()> {
-    let lock = vault.write().await;
-    let peers_lock = lock.get_peers().await;
-    let peer = peers_lock
-        .get(addr)
-        .context(format!("get({}) from peer_map failed", addr))?;
+async fn process_mm_heartbeat_check(vault: &Vault, conn_id: &ConnectionId) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
     let mut peer_lock = peer.lock().await;
     if peer_lock.state.is_hb_wait() {
         peer_lock.state = PeerState::HeartbeatReady(Instant::now());
-        let mut hb_ready_lock = lock.get_hb_ready().await;
-        hb_ready_lock.insert(*addr, peer.clone());
     }
     Ok(())
 }
 
-async fn process_move_make(vault: &Vault, addr: &SocketAddr, mv: &Move) -> Result<()> {
+// Time the moves of the peer spend on the way, given back to the player
+fn latency_allowance(peer: &Peer) -> Duration {
+    peer.rtt.map_or(Duration::ZERO, |rtt| rtt.min(*LATENCY_CAP))
+}
+
+// Color, game and sender of the player, the peer is unlocked again before the game is locked
+async fn game_seat(peer: &Arc<Mutex<Peer>>) -> Option<(Color, Arc<Mutex<Game>>, Tx)> {
+    let peer_lock = peer.lock().await;
+    match &peer_lock.state {
+        PeerState::Game { color, game } => Some((*color, game.clone(), peer_lock.tx.clone())),
+        _ => None,
+    }
+}
+
+async fn process_move_make(vault: &Vault, conn_id: &ConnectionId, mv: &Move) -> Result<()> {
     let now = tokio::time::Instant::now();
 
     let forbidden_move_pdu =
-        Pdu::GameSession(GameSession::Move(Move::Error(MoveError::ForbiddenMove {
-            description: "not allowed move".to_string(),
+        Pdu::GameSession(GameSession::Move(Move::Error(MoveError::IllegalMove {
+            reason: IllegalMoveReason::NotYourTurn,
+            description: "not your turn".to_string(),
         })))
         .to_message()?;
 
@@ -247,673 +802,7262 @@ async fn process_move_make(vault: &Vault, addr: &SocketAddr, mv: &Move) -> Resul
         | Move::Capture { .. }
         | Move::Promotion { .. }
         | Move::Castling { .. } => {
-            let lock = vault.write().await;
-            let peers_lock = lock.get_peers().await;
-            let peer = peers_lock
-                .get(addr)
-                .context(format!("get({}) from peer_map failed", addr))?;
-            let peer_lock = peer.lock().await;
-            match &peer_lock.state {
-                PeerState::Game { color, game } => {
-                    let mut game_lock = game.lock().await;
-                    if game_lock.validate_player_move(&mv, &color) {
-                        game_lock.who_move.as_mut().unwrap().complete = Some(Complete {
-                            mv: mv.clone(),
-                            at: now,
-                        });
-                        game_lock.move_happen_signal.unbounded_send(())?;
-                    } else {
-                        peer_lock.tx.unbounded_send(forbidden_move_pdu)?;
-                    }
-                }
-                _ => (),
+            let peer = vault
+                .get_peer(conn_id)
+                .context(format!("get({}) from peer_map failed", conn_id))?;
+            let (color, game, tx) = match game_seat(&peer).await {
+                Some(seat) => seat,
+                None => return Ok(()),
             };
+            let allowance = latency_allowance(&*peer.lock().await);
+            let mut game_lock = game.lock().await;
+            // the seat was taken over by the reconnected player meanwhile
+            if !Arc::ptr_eq(&game_lock.player(&color).peer, &peer) {
+                return Ok(());
+            }
+            if game_lock.pause != GamePause::Running {
+                let resp =
+                    Pdu::GameSession(GameSession::Move(Move::Error(MoveError::IllegalMove {
+                        reason: IllegalMoveReason::GamePaused,
+                        description: "the game is paused".to_string(),
+                    })))
+                    .to_message()?;
+                tx.send(resp)?;
+                return Ok(());
+            }
+            game_lock.mark_input(&color);
+            if !game_lock.validate_player_move(mv, &color) {
+                tx.send(forbidden_move_pdu)?;
+            } else if let Err(e) = game_lock.validate_move(mv) {
+                let resp = Pdu::GameSession(GameSession::Move(Move::Error(e))).to_message()?;
+                tx.send(resp)?;
+            } else {
+                // the move was made before it reached us, not before the turn began
+                let who_move = game_lock.who_move.as_mut().unwrap();
+                let at = now
+                    .checked_sub(allowance)
+                    .map_or(who_move.since, |at| at.max(who_move.since));
+                who_move.complete = Some(Complete {
+                    mv: mv.clone(),
+                    at,
+                    span: Span::current(),
+                });
+                game_lock.move_happen_signal.unbounded_send(())?;
+                let ack = Pdu::GameSession(GameSession::Move(Move::Ok {
+                    seq: game_lock.turn + 1,
+                }));
+                if peer.lock().await.protocol().understands(&ack) {
+                    tx.send(ack.to_message()?)?;
+                }
+            }
+        }
+        // no move is a sign of presence for afk detection
+        Move::NoMove {} => {
+            let peer = vault
+                .get_peer(conn_id)
+                .context(format!("get({}) from peer_map failed", conn_id))?;
+            if let Some((color, game, _)) = game_seat(&peer).await {
+                game.lock().await.mark_input(&color);
+            }
         }
-        Move::NoMove {} | Move::Error(_) => (),
+        Move::Ok { .. } | Move::Error(_) => (),
     };
 
     Ok(())
+}
+
+async fn process_gs_reconnect(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    reconnect_id: &str,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let peer_lock = peer.lock().await;
+
+    let error = |e: ReconnectError| {
+        Pdu::GameSession(GameSession::Reconnect(Reconnect::Error(e))).to_message()
+    };
+
+    match peer_lock.state {
+        PeerState::Idle => (),
+        PeerState::Unknown(_) => {
+            let resp = error(ReconnectError::Handshake {
+                description: "pass handshake first".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+        _ => {
+            let resp = error(ReconnectError::AlreadyInGame {
+                description: "leave matchmaking queue or active game session first".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    }
+    // idle peer changes its state only by its own requests, it is locked again after the game
+    let tx = peer_lock.tx.clone();
+    let protocol = peer_lock.protocol();
+    drop(peer_lock);
+
+    let game = match vault.get_reconnect(reconnect_id) {
+        Some(game) => game,
+        None => {
+            // distributed mode, the game may be pinned to another node
+            let node = match &vault.tenant().cluster {
+                Some(cluster) => pinned_node(cluster, reconnect_id).await,
+                None => None,
+            };
+            let route = node.map(|node| {
+                Pdu::Route(Route {
+                    node,
+                    description: "the game is played on another node".to_string(),
+                })
+            });
+            let resp = match route {
+                Some(route) if protocol.understands(&route) => route.to_message()?,
+                _ => error(ReconnectError::BadReconnectId {
+                    description: "no active game session with this reconnect id".to_string(),
+                })?,
+            };
+            tx.send(resp)?;
+            return Ok(());
+        }
+    };
+    let mut game_lock = game.lock().await;
+
+    let color = match game_lock
+        .players()
+        .iter()
+        .find(|p| p.reconnect_id == reconnect_id)
+    {
+        Some(player) => player.color,
+        None => return Ok(()),
+    };
+
+    // the seat is taken over, old connection if still alive is no longer a player
+    let old_peer = game_lock.player(&color).peer.clone();
+    if !Arc::ptr_eq(&old_peer, &peer) {
+        let mut old_peer_lock = old_peer.lock().await;
+        if old_peer_lock.state.is_game() {
+            old_peer_lock.state = PeerState::Idle;
+        }
+    }
 
-    /*let now = tokio::time::Instant::now();
-    let lock = vault.write().await;
-    let peers_lock = lock.get_peers().await;
-    let peer = peers_lock
-        .get(addr)
-        .context(format!("get({}) from peer_map failed", addr))?;
     let mut peer_lock = peer.lock().await;
-    if let PeerState::Game { color, game } = &mut peer_lock.state {
-        let mut game_lock = game.lock().await;
-        let player = game_lock.player_mut(&color);
-        if let PlayerState::MoveCallWait {
-            since,
-            timeout_dispatcher,
-        } = &player.state
-        {
-            /*match game_lock.make_turn(make) {
+    let player = game_lock.player_mut(&color);
+    player.peer = peer.clone();
+    peer_lock.player_name = Some(player.name.clone());
+    peer_lock.state = PeerState::Game {
+        color,
+        game: game.clone(),
+    };
 
+    let init = game_init_pdu!(
+        game_lock.id,
+        0,
+        reconnect_id.to_string(),
+        game_lock.player(&Color::Red).name.clone(),
+        game_lock.player(&Color::Green).name.clone(),
+        game_lock.player(&Color::Blue).name.clone(),
+        game_lock.player(&Color::Yellow).name.clone(),
+        game_lock.draw_odds,
+        game_lock.features.clone(),
+        game_lock.start.clone(),
+        peer_lock.seat_labels()
+    )?;
+    peer_lock.tx.send(init)?;
+
+    let mut update = Update {
+        move_call: move_call(&game_lock),
+        move_previous: Move::NoMove {},
+        move_effects: MoveEffects::default(),
+        players_states: players_states(&game_lock),
+        players_clocks: players_clocks(&game_lock),
+        checksum: game_lock.fen4().checksum(),
+        players_points: players_points(&game_lock),
+        seq: game_lock.turn,
+    };
+    if peer_lock.seat_labels() {
+        update = update.with_seats();
+    }
+    let update = Pdu::GameSession(GameSession::Update(update)).to_message()?;
+    peer_lock.tx.send(update)?;
+    if peer_lock
+        .client_info
+        .as_ref()
+        .is_some_and(|c| c.binary_snapshot)
+    {
+        let clocks = [Color::Red, Color::Blue, Color::Yellow, Color::Green]
+            .map(|c| game_lock.player(&c).time_remaining.as_millis() as u32);
+        peer_lock
+            .tx
+            .send(Message::Binary(game_lock.fen4().compact(clocks)))?;
+    }
+    drop(peer_lock);
+    notify_connection(&game_lock, color, true).await?;
+    Ok(())
+}
+
+// other players learn that the player left or came back
+async fn notify_connection(game: &Game, color: Color, connected: bool) -> Result<()> {
+    let pdu = Pdu::GameSession(GameSession::PlayerConnection {
+        player: color.to_string(),
+        connected,
+    })
+    .to_message()?;
+    for player in game.players().into_iter().filter(|p| p.color != color) {
+        if let Err(e) = player.peer.lock().await.tx.send(pdu.clone()) {
+            if !e.is_disconnected() {
+                return Err(e.into());
             }
-            let turn_duration = now - *since;
-            if turn_duration > PLAYER_TIME_2 {
-                player.time_remaining -= turn_duration - PLAYER_TIME_2;
-            }
-            timeout_dispatcher.abort();*/
         }
-
-        //peer_lock.state = PeerState::HeartbeatReady(Instant::now());
-        //let mut hb_ready_lock = lock.get_hb_ready().await;
-        //hb_ready_lock.insert(*addr, peer.clone());
     }
-    Ok(())*/
+    Ok(())
 }
 
-async fn process_msg(pdu: &Pdu, vault: &Vault, addr: &SocketAddr) -> Result<()> {
-    match pdu {
-        Pdu::Handshake(hs) => match hs {
-            Handshake::GetInfo(gi) => match gi {
-                GetInfo::Request {} => process_hs_get_info(vault, addr).await,
-                _ => Ok(()),
-            },
-            Handshake::Connect(c) => match c {
-                Connect::Client {
-                    name,
-                    version,
-                    protocol,
-                } => match protocol {
-                    Protocol::Version(proto_ver) => {
-                        process_hs_connect(vault, addr, name, version, proto_ver).await
-                    }
-                    _ => Ok(()),
-                },
-                _ => Ok(()),
-            },
-        },
-        Pdu::MatchmakingQueue(mq) => match mq {
-            MatchmakingQueue::PlayerRegister(pr) => match pr {
-                PlayerRegister::Name(name) => process_mm_player_reg(vault, addr, name).await,
-                _ => Ok(()),
-            },
-            MatchmakingQueue::PlayerLeave {} => process_mm_player_leave(vault, addr).await,
-            MatchmakingQueue::HeartbeatCheck {} => process_mm_heartbeat_check(vault, addr).await,
-            _ => Ok(()),
-        },
-        Pdu::GameSession(gs) => match gs {
-            GameSession::Move(mv) => process_move_make(vault, addr, mv).await,
-            GameSession::Init(_) | GameSession::Update(_) => Ok(()),
-        },
+// also called when the peer disconnects, the seat waits for reconnect
+async fn process_gs_disconnect(vault: &Vault, conn_id: &ConnectionId) -> Result<()> {
+    let (color, game) = {
+        let peer = match vault.get_peer(conn_id) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        let peer_lock = peer.lock().await;
+        match &peer_lock.state {
+            PeerState::Game { color, game } => (*color, game.clone()),
+            _ => return Ok(()),
+        }
+    };
+    let mut game_lock = game.lock().await;
+    info!(
+        "{} of game {} disconnected",
+        color.to_string(),
+        game_lock.id
+    );
+    // nobody moved yet, the others are not kept waiting for the player
+    if game_lock.who_move.is_none() && !game_lock.terminated {
+        return abort_game(&mut game_lock, color).await;
     }
+    notify_connection(&game_lock, color, false).await
 }
 
-async fn handle_connection(vault: Vault, raw_stream: TcpStream, addr: SocketAddr) {
-    debug!("Incoming TCP connection from: {}", addr);
+// Call off the game during the countdown, the session stops on the next wake up
+// and the remaining players are queued again once the game is released
+async fn abort_game(game: &mut Game, color: Color) -> Result<()> {
+    game.terminated = true;
+    game.aborted_by = Some(color);
+    let pdu = Pdu::GameSession(GameSession::Aborted {
+        game_id: game.id,
+        player: color.to_string(),
+        description: format!("{} left before the first move", color.to_string()),
+    })
+    .to_message()?;
+    game.broadcast(pdu.clone()).await?;
+    game.publish(pdu.clone(), Some(pdu), None).await;
+    let _ = game.move_happen_signal.unbounded_send(());
+    info!("Game {} aborted", game.id);
+    Ok(())
+}
 
-    let ws_stream = tokio_tungstenite::accept_async(raw_stream).await;
+async fn process_gs_chat(vault: &Vault, conn_id: &ConnectionId, chat: &Chat) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let mut peer_lock = peer.lock().await;
+    let error = |e: ChatError| Pdu::GameSession(GameSession::Chat(Chat::Error(e))).to_message();
 
-    let ws_stream = match ws_stream {
-        Ok(s) => s,
-        Err(e) => {
-            error!(
-                "Error during the websocket handshake occurred from \"{}\" \"{}\"",
-                addr, e
-            );
-            return;
+    let sender = peer_lock.account.as_ref().map(|a| a.id);
+    let (color, game) = match &peer_lock.state {
+        PeerState::Game { color, game } => (*color, game.clone()),
+        _ => {
+            let resp = error(ChatError::Forbidden {
+                description: "only players of the game can chat".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
         }
     };
-
-    debug!("WebSocket connection established from: {}", addr);
-
-    let (tx, rx) = unbounded();
-    let peer = Peer {
-        tx,
-        player_name: None,
-        state: PeerState::Unknown(Instant::now()),
-        client_info: None,
+    let (kind, text, max_len) = match chat {
+        Chat::Send { text } => (ChatKind::Text, text, CHAT_MAX_LEN),
+        Chat::React { reaction } => (ChatKind::Reaction, reaction, REACTION_MAX_LEN),
+        Chat::Record { allow } => {
+            drop(peer_lock);
+            let mut game_lock = game.lock().await;
+            game_lock.chat_unrecorded.retain(|c| *c != color);
+            if !allow {
+                game_lock.chat_unrecorded.push(color);
+            }
+            return Ok(());
+        }
+        Chat::Mute { player } | Chat::Unmute { player } => {
+            drop(peer_lock);
+            let mute = matches!(chat, Chat::Mute { .. });
+            let mut game_lock = game.lock().await;
+            let other = game_lock
+                .players()
+                .iter()
+                .find(|p| p.color.to_string() == *player && p.color != color)
+                .map(|p| p.color);
+            let resp = match other {
+                Some(other) => {
+                    game_lock.chat_mutes.retain(|m| *m != (color, other));
+                    if mute {
+                        game_lock.chat_mutes.push((color, other));
+                    }
+                    let players = game_lock
+                        .chat_mutes
+                        .iter()
+                        .filter(|(by, _)| *by == color)
+                        .map(|(_, muted)| muted.to_string())
+                        .collect();
+                    Pdu::GameSession(GameSession::Chat(Chat::Muted { players })).to_message()?
+                }
+                None => error(ChatError::BadMessage {
+                    description: "no other player of the game with this color".to_string(),
+                })?,
+            };
+            send_msg_to!(vault, conn_id, resp);
+            return Ok(());
+        }
+        _ => return Ok(()),
     };
-    //peer_map.lock().unwrap().insert(addr, peer);
-    if let Err(_) = vault.read().await.try_insert_peer(addr, peer).await {
-        error!("Duplicate address insert \"{}\"", addr);
+    if text.trim().is_empty() || text.chars().count() > max_len {
+        let resp = error(ChatError::BadMessage {
+            description: format!("message must be 1-{} characters", max_len),
+        })?;
+        peer_lock.tx.send(resp)?;
+        return Ok(());
     }
-
-    let (outgoing, incoming) = ws_stream.split();
-
-    let broadcast_incoming = incoming.fold((&addr, &vault), |arg, msg| async move {
-        let msg = msg.unwrap();
-        let pdu = serde_json::from_str::<Pdu>(msg.to_text().unwrap());
-        debug!(
-            "Received raw message from {}: \"{}\"",
-            addr,
-            msg.to_text().unwrap()
-        );
-        match pdu {
-            Ok(p) => {
-                debug!("Parsed pdu: {:?}", p);
-                if let Err(e) = process_msg(&p, arg.1, arg.0).await {
-                    error!("Error while process_msg() {}", e);
+    let now = Instant::now();
+    // muted by an admin, the sender is not told
+    if peer_lock
+        .player_name
+        .as_deref()
+        .is_some_and(|name| vault.is_muted(name, now))
+    {
+        return Ok(());
+    }
+    if peer_lock.is_muted(now) {
+        let resp = error(ChatError::Forbidden {
+            description: "you are muted".to_string(),
+        })?;
+        peer_lock.tx.send(resp)?;
+        return Ok(());
+    }
+    let locale = peer_lock
+        .client_info
+        .as_ref()
+        .and_then(|c| c.locale.clone());
+    let (verdict, mute_after, mute_for) = {
+        let mut filter_lock = vault.get_chat_filter().await;
+        (
+            filter_lock.check(text, locale.as_deref()),
+            filter_lock.mute_after(),
+            filter_lock.mute_for(),
+        )
+    };
+    let text = match verdict {
+        Verdict::Clean => text.clone(),
+        Verdict::Censored(censored) => censored,
+        Verdict::Dropped | Verdict::Muted => {
+            // repeated drops escalate to mute
+            peer_lock.filter_strikes += 1;
+            let resp = if verdict == Verdict::Muted || peer_lock.filter_strikes >= mute_after {
+                peer_lock.filter_strikes = 0;
+                peer_lock.muted_until = Some(now + mute_for);
+                if verdict == Verdict::Dropped {
+                    vault.get_chat_filter().await.stats.muted += 1;
                 }
-            }
-            Err(e) => {
-                error!(
-                    "Parsing received message from peer {} failed with message \"{}\"",
-                    addr, e
-                );
-            }
+                info!("{} muted by chat filter", conn_id);
+                error(ChatError::Forbidden {
+                    description: format!("you are muted for {} seconds", mute_for.as_secs()),
+                })?
+            } else {
+                error(ChatError::BadMessage {
+                    description: "message is not allowed".to_string(),
+                })?
+            };
+            peer_lock.tx.send(resp)?;
+            return Ok(());
         }
-        arg
-    });
-
-    let receive_from_others = rx.map(Ok).forward(outgoing);
-
-    pin_mut!(broadcast_incoming, receive_from_others);
-    future::select(broadcast_incoming, receive_from_others).await;
+    };
+    // the sender is locked, broadcast would deadlock on its peer
+    drop(peer_lock);
 
-    debug!("{} disconnected", &addr);
-    vault.read().await.remove_peer(&addr).await;
+    let mut game_lock = game.lock().await;
+    let message = game_lock.post_chat(color, kind, &text);
+    let pdu = Pdu::GameSession(GameSession::Chat(Chat::Message(message))).to_message()?;
+    // players who blocked or muted the sender do not get the message, it is recorded anyway
+    for player in game_lock.players() {
+        if game_lock.chat_mutes.contains(&(player.color, color)) {
+            continue;
+        }
+        let player_lock = player.peer.lock().await;
+        if !player_lock.has_blocked(sender) {
+            let _ = player_lock.tx.send(pdu.clone());
+        }
+    }
+    Ok(())
 }
 
-async fn move_call_dispatch(
-    vault: Vault,
-    mut move_received: UnboundedReceiver<()>,
-    game_id: u64,
+// Legal moves of the player's piece, any time during the game
+async fn process_gs_legal_moves(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    position: Position,
 ) -> Result<()> {
-    let mut player_time_remaining = Duration::from_secs(0);
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
 
-    // after GS_INIT_PAUSE broadcast first update
-    {
-        tokio::time::sleep(GS_INIT_PAUSE).await;
+    let resp = match game_seat(&peer).await {
+        Some((color, game, _)) => {
+            let moves = game.lock().await.legal_moves(color, position);
+            LegalMoves::Moves { position, moves }
+        }
+        None => LegalMoves::Error(LegalMovesError::Forbidden {
+            description: "only players of the game can ask for legal moves".to_string(),
+        }),
+    };
+    let resp = Pdu::GameSession(GameSession::LegalMoves(resp)).to_message()?;
+    peer.lock().await.tx.send(resp)?;
+    Ok(())
+}
 
-        let lock = vault.write().await;
-        let games_lock = lock.get_games().await;
-        let game = games_lock
-            .get(&game_id)
-            .context("game_session game lookup failed")?;
-        let mut game_lock = game.lock().await;
+fn game_state(game: &Game) -> GameState {
+    let fen = game.fen4();
+    GameState {
+        game_id: game.id,
+        position: fen.to_string(),
+        move_call: move_call(game),
+        players_states: players_states(game),
+        players_clocks: players_clocks(game),
+        turn_elapsed: game
+            .who_move
+            .as_ref()
+            .map_or(0, |wm| wm.since.elapsed().as_millis() as u64),
+        checksum: fen.checksum(),
+        players_points: players_points(game),
+        moves: game.moves.len(),
+        seq: game.turn,
+    }
+}
 
-        let first_moved_player = game_lock.next_moved_player_mut().unwrap();
+// Players get the state of their game, spectators and engines of the game they follow
+async fn process_gs_state_sync(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    game_id: Option<u64>,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let error = |e: StateSyncError| {
+        Pdu::GameSession(GameSession::StateSync(StateSync::Error(e))).to_message()
+    };
+    let (seat, tx, seat_labels) = {
+        let peer_lock = peer.lock().await;
+        let seat = match &peer_lock.state {
+            PeerState::Game { game, .. } => Some(game.clone()),
+            _ => None,
+        };
+        (seat, peer_lock.tx.clone(), peer_lock.seat_labels())
+    };
 
-        let call = Pdu::GameSession(GameSession::Update(Update {
-            move_call: MoveCall::Call {
-                player: first_moved_player.color.clone().to_string(),
-                timer: PLAYER_TIMER.as_secs(),
-                timer_2: PLAYER_TIME_2.as_secs(),
-            },
-            move_previous: Move::NoMove {},
-            players_states: PlayersStates {
-                red: proto::PlayerState::NoState {},
-                blue: proto::PlayerState::NoState {},
-                yellow: proto::PlayerState::NoState {},
-                green: proto::PlayerState::NoState {},
+    let game = match (seat, game_id) {
+        (Some(game), None) => game,
+        (seat, Some(game_id)) => match vault.get_game(game_id) {
+            Some(game) => {
+                let own = seat.is_some_and(|seat| Arc::ptr_eq(&seat, &game));
+                if !own && !game.lock().await.subscribers.contains_key(conn_id) {
+                    tx.send(error(StateSyncError::Forbidden {
+                        description: "spectate or subscribe to the game first".to_string(),
+                    })?)?;
+                    return Ok(());
+                }
+                game
+            }
+            None => {
+                tx.send(error(StateSyncError::GameNotFound {
+                    description: "no such game".to_string(),
+                })?)?;
+                return Ok(());
+            }
+        },
+        (None, None) => {
+            tx.send(error(StateSyncError::Forbidden {
+                description: "only players of a game may omit game id".to_string(),
+            })?)?;
+            return Ok(());
+        }
+    };
+    let mut state = game_state(&*game.lock().await);
+    if seat_labels {
+        state = state.with_seats();
+    }
+    tx.send(Pdu::GameSession(GameSession::StateSync(StateSync::State(state))).to_message()?)?;
+    Ok(())
+}
+
+// Player confirmed the start, ignored once the countdown is over
+async fn process_gs_ready(vault: &Vault, conn_id: &ConnectionId) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    if let Some((color, game, _)) = game_seat(&peer).await {
+        let mut game_lock = game.lock().await;
+        let fresh = match &mut game_lock.ready {
+            Some(ready) if !ready.contains(&color) => {
+                ready.push(color);
+                true
+            }
+            _ => false,
+        };
+        if fresh {
+            let _ = game_lock.move_happen_signal.unbounded_send(());
+        }
+    }
+    Ok(())
+}
+
+// Vote of the survivor for the draw, the game ends once all survivors agree
+async fn process_gs_draw_vote(vault: &Vault, conn_id: &ConnectionId, agree: bool) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let peer_lock = peer.lock().await;
+
+    let (color, game) = match &peer_lock.state {
+        PeerState::Game { color, game } => (*color, game.clone()),
+        _ => {
+            let resp = Pdu::GameSession(GameSession::DrawVote(DrawVote::Error(
+                DrawVoteError::Forbidden {
+                    description: "only players of the game can vote".to_string(),
+                },
+            )))
+            .to_message()?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    };
+    // the voter is locked, broadcast would deadlock on its peer
+    drop(peer_lock);
+
+    let mut game_lock = game.lock().await;
+    if game_lock.who_move.is_none()
+        || game_lock.end != GameEnd::Standings
+        || !game_lock.player(&color).state.in_game()
+    {
+        let resp = Pdu::GameSession(GameSession::DrawVote(DrawVote::Error(
+            DrawVoteError::Forbidden {
+                description: "only survivors of the active game can vote".to_string(),
             },
-        }))
+        )))
         .to_message()?;
+        drop(game_lock);
+        peer.lock().await.tx.send(resp)?;
+        return Ok(());
+    }
+    game_lock.draw_votes.retain(|c| *c != color);
+    if agree {
+        game_lock.draw_votes.push(color);
+    }
+    let votes = Pdu::GameSession(GameSession::DrawVote(DrawVote::Votes {
+        agreed: game_lock.draw_votes.iter().map(|c| c.to_string()).collect(),
+    }))
+    .to_message()?;
+    game_lock.broadcast(votes).await?;
+
+    // bots agree with the humans
+    let unanimous = game_lock
+        .players()
+        .iter()
+        .filter(|p| p.state.in_game() && !p.bot)
+        .all(|p| game_lock.draw_votes.contains(&p.color));
+    if unanimous {
+        game_lock.end = GameEnd::DrawAgreed;
+        // wake up the session to finish the game
+        let _ = game_lock.move_happen_signal.unbounded_send(());
+        info!("Game {} drawn by agreement", game_lock.id);
+    }
+    Ok(())
+}
+
+// Vote of the survivor to pause the running game or to resume the paused one,
+// the session is woken up once all survivors agree
+async fn process_gs_pause(vault: &Vault, conn_id: &ConnectionId, agree: bool) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let error = |description: &str| {
+        Pdu::GameSession(GameSession::Pause(Pause::Error(PauseError::Forbidden {
+            description: description.to_string(),
+        })))
+        .to_message()
+    };
+    let (color, game, tx) = match game_seat(&peer).await {
+        Some(seat) => seat,
+        None => {
+            let tx = peer.lock().await.tx.clone();
+            tx.send(error("only players of the game can vote")?)?;
+            return Ok(());
+        }
+    };
+
+    let mut game_lock = game.lock().await;
+    let forbidden = match &game_lock.who_move {
+        Some(wm)
+            if game_lock.end == GameEnd::Standings && game_lock.player(&color).state.in_game() =>
+        {
+            match game_lock.pause {
+                GamePause::Running if wm.complete.is_some() => {
+                    Some("the move of the turn is already made")
+                }
+                GamePause::Resuming => Some("the game is about to go on"),
+                _ => None,
+            }
+        }
+        _ => Some("only survivors of the active game can vote"),
+    };
+    if let Some(description) = forbidden {
+        drop(game_lock);
+        tx.send(error(description)?)?;
+        return Ok(());
+    }
+    game_lock.pause_votes.retain(|c| *c != color);
+    if agree {
+        game_lock.pause_votes.push(color);
+    }
+    let votes = Pdu::GameSession(GameSession::Pause(Pause::Votes {
+        agreed: game_lock
+            .pause_votes
+            .iter()
+            .map(|c| c.to_string())
+            .collect(),
+    }))
+    .to_message()?;
+    game_lock.broadcast(votes).await?;
+
+    // bots agree with the humans
+    let unanimous = game_lock
+        .players()
+        .iter()
+        .filter(|p| p.state.in_game() && !p.bot)
+        .all(|p| game_lock.pause_votes.contains(&p.color));
+    if unanimous {
+        game_lock.pause_votes.clear();
+        if game_lock.pause == GamePause::Running {
+            game_lock.pause = GamePause::Paused;
+            let paused =
+                Pdu::GameSession(GameSession::Pause(Pause::State { paused: true })).to_message()?;
+            game_lock.broadcast(paused).await?;
+            info!("Game {} paused by agreement", game_lock.id);
+        } else {
+            // the session counts down and tells the players
+            game_lock.pause = GamePause::Resuming;
+        }
+        let _ = game_lock.move_happen_signal.unbounded_send(());
+    }
+    Ok(())
+}
+
+// humans still moving, they decide about the adjudication
+fn active_players(game: &Game) -> Vec<Color> {
+    game.players()
+        .iter()
+        .filter(|p| p.state.in_game() && !p.bot && p.afk_count < ADJUDICATION_AFTER)
+        .map(|p| p.color)
+        .collect()
+}
+
+// material of every player in centipawns
+fn material_scores(game: &Game) -> PlayersScores {
+    let score = |color| bot::material(game, color) as i32 * 100;
+    PlayersScores {
+        red: score(Color::Red),
+        blue: score(Color::Blue),
+        yellow: score(Color::Yellow),
+        green: score(Color::Green),
+    }
+}
+
+// Offer the adjudication once several players time out turn after turn,
+// withdraw it when they are back
+async fn update_adjudication(game: &mut Game) -> Result<()> {
+    let stalling = game
+        .players()
+        .iter()
+        .filter(|p| p.state.in_game() && !p.bot && p.afk_count >= ADJUDICATION_AFTER)
+        .count();
+    let active = active_players(game);
+    let offer = stalling >= 2 && !active.is_empty();
+    let adjudication = match (&game.adjudication, offer) {
+        (None, true) => {
+            game.adjudication = Some(Vec::new());
+            Adjudication::Offer {
+                players: active.iter().map(|c| c.to_string()).collect(),
+                scores: material_scores(game),
+            }
+        }
+        (Some(_), false) => {
+            game.adjudication = None;
+            Adjudication::Withdrawn {}
+        }
+        _ => return Ok(()),
+    };
+    let pdu = Pdu::GameSession(GameSession::Adjudication(adjudication)).to_message()?;
+    game.broadcast(pdu).await
+}
+
+// Consent of the active player to the adjudication, the game ends once all of them agree
+async fn process_gs_adjudication(vault: &Vault, conn_id: &ConnectionId, agree: bool) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let peer_lock = peer.lock().await;
+
+    let forbidden = |description: &str| {
+        Pdu::GameSession(GameSession::Adjudication(Adjudication::Error(
+            AdjudicationError::Forbidden {
+                description: description.to_string(),
+            },
+        )))
+        .to_message()
+    };
+    let (color, game) = match &peer_lock.state {
+        PeerState::Game { color, game } => (*color, game.clone()),
+        _ => {
+            peer_lock
+                .tx
+                .send(forbidden("only players of the game can consent")?)?;
+            return Ok(());
+        }
+    };
+    // the player is locked, broadcast would deadlock on its peer
+    drop(peer_lock);
+
+    let mut game_lock = game.lock().await;
+    let active = active_players(&game_lock);
+    if game_lock.adjudication.is_none()
+        || game_lock.end != GameEnd::Standings
+        || !active.contains(&color)
+    {
+        drop(game_lock);
+        peer.lock()
+            .await
+            .tx
+            .send(forbidden("adjudication is not offered to you")?)?;
+        return Ok(());
+    }
+    let consents = game_lock.adjudication.get_or_insert_with(Vec::new);
+    consents.retain(|c| *c != color);
+    if agree {
+        consents.push(color);
+    }
+    let unanimous = active.iter().all(|c| consents.contains(c));
+    let pdu = Pdu::GameSession(GameSession::Adjudication(Adjudication::Consents {
+        agreed: consents.iter().map(|c| c.to_string()).collect(),
+    }))
+    .to_message()?;
+    game_lock.broadcast(pdu).await?;
+
+    if unanimous {
+        game_lock.end = GameEnd::Adjudicated;
+        // wake up the session to finish the game
+        let _ = game_lock.move_happen_signal.unbounded_send(());
+        info!("Game {} adjudicated", game_lock.id);
+    }
+    Ok(())
+}
+
+async fn process_analysis_subscribe(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    game_id: u64,
+    token: &str,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let peer_lock = peer.lock().await;
+
+    let error =
+        |e: SubscribeError| Pdu::Analysis(Analysis::Subscribe(Subscribe::Error(e))).to_message();
+
+    let engine = match (&peer_lock.state, &peer_lock.client_info) {
+        (PeerState::Unknown(_), _) | (_, None) => {
+            let resp = error(SubscribeError::Handshake {
+                description: "pass handshake first".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+        (PeerState::Idle, Some(client_info)) => client_info.name.clone(),
+        (_, Some(_)) => {
+            let resp = error(SubscribeError::Forbidden {
+                description: "players can not subscribe to analysis".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    };
+
+    if ANALYSIS_TOKEN.as_deref() != Some(token) {
+        let resp = error(SubscribeError::Unauthorized {
+            description: "bad analysis token".to_string(),
+        })?;
+        peer_lock.tx.send(resp)?;
+        return Ok(());
+    }
+    // games are locked before peers
+    let tx = peer_lock.tx.clone();
+    let seat_labels = peer_lock.seat_labels();
+    drop(peer_lock);
+
+    let game = match vault.get_game(game_id) {
+        Some(game) => game,
+        None => {
+            let resp = error(SubscribeError::GameNotFound {
+                description: "no such game".to_string(),
+            })?;
+            tx.send(resp)?;
+            return Ok(());
+        }
+    };
+    let mut game_lock = game.lock().await;
+
+    if game_lock.analysis_allowed() {
+        game_lock.subscribe(*conn_id, tx.clone(), SubscriberKind::Engine, seat_labels);
+        let resp = Pdu::Analysis(Analysis::Subscribe(Subscribe::Ok { game_id })).to_message()?;
+        tx.send(resp)?;
+    } else {
+        // ask the players, engine should retry once they agree
+        let request = Pdu::Analysis(Analysis::ConsentRequest { engine }).to_message()?;
+        game_lock.broadcast(request).await?;
+        let resp = error(SubscribeError::ConsentRequired {
+            description: "all players must allow analysis".to_string(),
+        })?;
+        tx.send(resp)?;
+    }
+    Ok(())
+}
+
+async fn process_analysis_spectate(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    game_id: u64,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let peer_lock = peer.lock().await;
+
+    let error =
+        |e: SpectateError| Pdu::Analysis(Analysis::Spectate(Spectate::Error(e))).to_message();
+
+    match peer_lock.state {
+        PeerState::Idle => (),
+        PeerState::Unknown(_) => {
+            let resp = error(SpectateError::Handshake {
+                description: "pass handshake first".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+        _ => {
+            let resp = error(SpectateError::Forbidden {
+                description: "leave matchmaking queue or game first".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    }
+    // games are locked before peers
+    let tx = peer_lock.tx.clone();
+    let seat_labels = peer_lock.seat_labels();
+    drop(peer_lock);
+
+    let game = match vault.get_game(game_id) {
+        Some(game) => game,
+        None => {
+            let resp = error(SpectateError::GameNotFound {
+                description: "no such game".to_string(),
+            })?;
+            tx.send(resp)?;
+            return Ok(());
+        }
+    };
+    game.lock()
+        .await
+        .subscribe(*conn_id, tx.clone(), SubscriberKind::Spectator, seat_labels);
+    let resp = Pdu::Analysis(Analysis::Spectate(Spectate::Ok { game_id })).to_message()?;
+    tx.send(resp)?;
+    Ok(())
+}
+
+fn game_replay(record: &GameSnapshot, with_chat: bool) -> GameReplay {
+    GameReplay {
+        game_id: record.id,
+        players: PlayersNames {
+            red: record.red.name.clone(),
+            blue: record.blue.name.clone(),
+            yellow: record.yellow.name.clone(),
+            green: record.green.name.clone(),
+        },
+        moves: record
+            .moves
+            .iter()
+            .map(|m| ReplayMove {
+                player: m.color.to_string(),
+                mv: m.mv.clone(),
+                move_effects: m.effects(),
+            })
+            .collect(),
+        chat: if with_chat {
+            Some(record.chat.clone())
+        } else {
+            None
+        },
+    }
+}
+
+// chat is exported to players of the game and moderators only
+async fn process_analysis_replay(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    game_id: u64,
+    token: Option<&str>,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let peer_lock = peer.lock().await;
+
+    let resp = match vault.get_records().await.get(game_id) {
+        Some(record) => {
+            let moderator = token.is_some_and(|t| vault.tenant().is_moderator(t));
+            let player = played_by(record, &peer_lock);
+            if record.private && !moderator && !player {
+                Replay::Error(ReplayError::GameNotFound {
+                    description: format!("no public record of game {}", game_id),
+                })
+            } else {
+                Replay::Ok(game_replay(record, moderator || player))
+            }
+        }
+        None => Replay::Error(ReplayError::GameNotFound {
+            description: format!("no record of game {}", game_id),
+        }),
+    };
+    let resp = Pdu::Analysis(Analysis::Replay(resp)).to_message()?;
+    peer_lock.tx.send(resp)?;
+    Ok(())
+}
+
+// private games are exported to their players only
+async fn process_analysis_pgn4(vault: &Vault, conn_id: &ConnectionId, game_id: u64) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let peer_lock = peer.lock().await;
+
+    let resp = match vault.get_records().await.get(game_id) {
+        Some(record) if record.private && !played_by(record, &peer_lock) => {
+            Pgn4::Error(ReplayError::GameNotFound {
+                description: format!("no public record of game {}", game_id),
+            })
+        }
+        Some(record) => Pgn4::Ok {
+            game_id,
+            pgn4: pgn4::export(record),
+        },
+        None => Pgn4::Error(ReplayError::GameNotFound {
+            description: format!("no record of game {}", game_id),
+        }),
+    };
+    let resp = Pdu::Analysis(Analysis::Pgn4(resp)).to_message()?;
+    peer_lock.tx.send(resp)?;
+    Ok(())
+}
+
+// player of the game logged in on the peer
+fn played_by(record: &GameSnapshot, peer: &Peer) -> bool {
+    [&record.red, &record.green, &record.blue, &record.yellow]
+        .iter()
+        .any(|p| peer.owns_name(&p.name))
+}
+
+// Stream the record of the finished game, the peer keeps playing meanwhile.
+// Private games are streamed to their players only.
+async fn process_game_history(vault: &Vault, conn_id: &ConnectionId, game_id: u64) -> Result<()> {
+    let (record, tx) = {
+        let record = vault.get_records().await.get(game_id).cloned();
+        let peer = vault
+            .get_peer(conn_id)
+            .context(format!("get({}) from peer_map failed", conn_id))?;
+        let peer_lock = peer.lock().await;
+        let record = record.filter(|r| !r.private || played_by(r, &peer_lock));
+        (record, peer_lock.tx.clone())
+    };
+    match record {
+        Some(record) => {
+            tokio::spawn(async move {
+                if let Err(e) = stream_game_history(&tx, &record).await {
+                    debug!("game {} history not delivered \"{}\"", record.id, e);
+                }
+            });
+        }
+        None => {
+            let resp = Pdu::GameHistory(GameHistory::Error(ReplayError::GameNotFound {
+                description: format!("no record of game {}", game_id),
+            }))
+            .to_message()?;
+            tx.send(resp)?;
+        }
+    }
+    Ok(())
+}
+
+// moves are paced by the peer queue, long games never overflow it
+async fn stream_game_history(tx: &Tx, record: &GameSnapshot) -> Result<()> {
+    let start = GameHistory::Start {
+        game_id: record.id,
+        players: PlayersNames {
+            red: record.red.name.clone(),
+            blue: record.blue.name.clone(),
+            yellow: record.yellow.name.clone(),
+            green: record.green.name.clone(),
+        },
+        started: record.started,
+        moves: record.moves.len(),
+    };
+    tx.send(Pdu::GameHistory(start).to_message()?)?;
+
+    for (i, mv) in record.moves.iter().enumerate() {
+        while tx.queued() >= HISTORY_QUEUE_MAX {
+            if tx.is_closed() {
+                return Ok(());
+            }
+            time::sleep(HISTORY_PACE).await;
+        }
+        let history_move = GameHistory::Move(HistoryMove {
+            number: i + 1,
+            player: mv.color.to_string(),
+            move_previous: mv.mv.clone(),
+            move_effects: mv.effects(),
+            time_used: mv.time_used.as_millis() as u64,
+            at: mv.at,
+        });
+        tx.send(Pdu::GameHistory(history_move).to_message()?)?;
+    }
+
+    let state =
+        |p: &vault::PlayerSnapshot| proto::PlayerState::new(p.state.clone(), *REMAINING_PIECES);
+    let end = GameHistory::End {
+        game_id: record.id,
+        players_states: PlayersStates {
+            red: state(&record.red),
+            blue: state(&record.blue),
+            yellow: state(&record.yellow),
+            green: state(&record.green),
+            seats: None,
+        },
+    };
+    tx.send(Pdu::GameHistory(end).to_message()?)?;
+    Ok(())
+}
+
+async fn process_analysis_unsubscribe(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    game_id: u64,
+) -> Result<()> {
+    if let Some(game) = vault.get_game(game_id) {
+        game.lock().await.subscribers.remove(conn_id);
+    }
+    Ok(())
+}
+
+async fn process_analysis_consent(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    allow: bool,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    if let Some((color, game, _)) = game_seat(&peer).await {
+        let mut game_lock = game.lock().await;
+        if allow {
+            if !game_lock.analysis_consent.contains(&color) {
+                game_lock.analysis_consent.push(color);
+            }
+        } else {
+            game_lock.analysis_consent.retain(|c| *c != color);
+            let game_id = game_lock.id;
+            let resp = Pdu::Analysis(Analysis::Unsubscribe { game_id }).to_message()?;
+            game_lock.drop_engines(resp).await;
+        }
+    }
+    Ok(())
+}
+
+async fn process_analysis_evaluation(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    evaluation: &Evaluation,
+) -> Result<()> {
+    if let Some(game) = vault.get_game(evaluation.game_id) {
+        let mut game_lock = game.lock().await;
+        // only subscribed engines may post, relayed to other subscribers only
+        let engine = game_lock
+            .subscribers
+            .get(conn_id)
+            .is_some_and(|s| s.kind == SubscriberKind::Engine);
+        if engine {
+            game_lock.best_move = Some(evaluation.best_move.clone());
+            let relay = Pdu::Analysis(Analysis::Evaluation(evaluation.clone())).to_message()?;
+            game_lock.publish(relay, None, Some(conn_id)).await;
+        }
+    }
+    Ok(())
+}
+
+fn lobby_error(e: LobbyError) -> Result<Message> {
+    Pdu::Lobby(Lobby::Error(e)).to_message()
+}
+
+// only idle players of their own accounts may create or join a lobby
+fn lobby_state_error(peer: &Peer, name: &str) -> Result<Option<Message>> {
+    match peer.state {
+        PeerState::Unknown(_) => lobby_error(LobbyError::Handshake {
+            description: "pass handshake first".to_string(),
+        })
+        .map(Some),
+        _ if peer.account.is_none() => lobby_error(LobbyError::Unauthorized {
+            description: "log in first".to_string(),
+        })
+        .map(Some),
+        _ if !peer.owns_name(name) => lobby_error(LobbyError::Forbidden {
+            description: format!("\"{}\" is not the name of your account", name),
+        })
+        .map(Some),
+        PeerState::Idle => Ok(None),
+        _ => lobby_error(LobbyError::Forbidden {
+            description: "leave matchmaking queue, lobby or active game session first".to_string(),
+        })
+        .map(Some),
+    }
+}
+
+// members list to every member except the one already locked by the caller
+async fn lobby_notify(
+    code: &str,
+    lobby: &PrivateLobby,
+    except: &Arc<Mutex<Peer>>,
+) -> Result<Message> {
+    let update = Pdu::Lobby(Lobby::Ok {
+        code: code.to_string(),
+        players: lobby.members.iter().map(|m| m.name.clone()).collect(),
+    })
+    .to_message()?;
+    for member in &lobby.members {
+        if Arc::ptr_eq(&member.peer, except) {
+            continue;
+        }
+        if let Err(e) = member.peer.lock().await.tx.send(update.clone()) {
+            error!("send failed \"{}\"", e);
+        }
+    }
+    Ok(update)
+}
+
+fn lobby_code() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(LOBBY_CODE_LEN)
+        .map(|c| char::from(c).to_ascii_uppercase())
+        .collect()
+}
+
+// Position of the lobby game, every player is alive and has a king
+fn lobby_start(fen: &str) -> Result<Fen4> {
+    let fen = Fen4::try_from(fen)?;
+    if !fen.dead.is_empty() {
+        bail!("every player must be alive");
+    }
+    for color in [Color::Red, Color::Blue, Color::Yellow, Color::Green].iter() {
+        if fen.board.find_king(*color).is_none() {
+            bail!("{} has no king", color.to_string());
+        }
+    }
+    Ok(fen)
+}
+
+async fn process_lobby_create(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    name: &str,
+    start: Option<&str>,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let mut lobbies_lock = vault.get_lobbies().await;
+    let mut peer_lock = peer.lock().await;
+    if let Some(resp) = lobby_state_error(&peer_lock, name)? {
+        peer_lock.tx.send(resp)?;
+        return Ok(());
+    }
+    let start = match start.map(lobby_start).transpose() {
+        Ok(start) => start,
+        Err(e) => {
+            let resp = lobby_error(LobbyError::BadPosition {
+                description: format!("{:#}", e),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    };
+
+    let code = loop {
+        let code = lobby_code();
+        if !lobbies_lock.contains_key(&code) {
+            break code;
+        }
+    };
+    let lobby = PrivateLobby {
+        members: vec![LobbyMember {
+            conn_id: *conn_id,
+            peer: peer.clone(),
+            name: name.to_string(),
+        }],
+        start,
+    };
+    let resp = lobby_notify(&code, &lobby, &peer).await?;
+    peer_lock.tx.send(resp)?;
+    peer_lock.player_name = Some(name.to_string());
+    peer_lock.state = PeerState::Lobby(code.clone());
+    lobbies_lock.insert(code, lobby);
+    Ok(())
+}
+
+async fn process_lobby_join(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    code: &str,
+    name: &str,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let mut lobbies_lock = vault.get_lobbies().await;
+    let mut peer_lock = peer.lock().await;
+    if let Some(resp) = lobby_state_error(&peer_lock, name)? {
+        peer_lock.tx.send(resp)?;
+        return Ok(());
+    }
+
+    let lobby = match lobbies_lock.get_mut(code) {
+        Some(lobby) => lobby,
+        None => {
+            let resp = lobby_error(LobbyError::NotFound {
+                description: "no lobby with this code".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    };
+    // members play under the names of their accounts, one account takes one seat
+    if lobby.members.iter().any(|m| m.name == name) {
+        let resp = lobby_error(LobbyError::Forbidden {
+            description: "your account is already in this lobby".to_string(),
+        })?;
+        peer_lock.tx.send(resp)?;
+        return Ok(());
+    }
+    lobby.members.push(LobbyMember {
+        conn_id: *conn_id,
+        peer: peer.clone(),
+        name: name.to_string(),
+    });
+    peer_lock.player_name = Some(name.to_string());
+    // lobby games are not of any pool, the players are not queued if it is called off
+    peer_lock.pool = None;
+    peer_lock.state = PeerState::Lobby(code.to_string());
+    let resp = lobby_notify(code, lobby, &peer).await?;
+    peer_lock.tx.send(resp)?;
+
+    if lobby.members.len() < 4 {
+        return Ok(());
+    }
+    let lobby = lobbies_lock.remove(code).unwrap();
+    let mut guards = Vec::new();
+    for member in &lobby.members[..3] {
+        guards.push(member.peer.lock().await);
+    }
+    let mut iter = lobby
+        .members
+        .iter()
+        .zip(guards.iter_mut())
+        .map(|(member, guard)| (member.peer.clone(), &mut **guard));
+    let players = [
+        iter.next(),
+        iter.next(),
+        iter.next(),
+        Some((peer.clone(), &mut *peer_lock)),
+    ];
+    start_game(
+        vault,
+        players,
+        false,
+        0,
+        PLAYER_TIMER,
+        None,
+        lobby.start,
+        default_disconnect_policy(),
+    )
+    .await;
+    Ok(())
+}
+
+// also called when the peer disconnects
+async fn process_lobby_leave(vault: &Vault, conn_id: &ConnectionId) -> Result<()> {
+    let peer = match vault.get_peer(conn_id) {
+        Some(peer) => peer,
+        None => return Ok(()),
+    };
+    let mut lobbies_lock = vault.get_lobbies().await;
+    let mut peer_lock = peer.lock().await;
+    let code = match peer_lock.state.get_lobby_code() {
+        Some(code) => code.to_string(),
+        None => return Ok(()),
+    };
+    peer_lock.state = PeerState::Idle;
+    peer_lock.player_name = None;
+
+    if let Some(lobby) = lobbies_lock.get_mut(&code) {
+        lobby.members.retain(|m| m.conn_id != *conn_id);
+        if lobby.members.is_empty() {
+            lobbies_lock.remove(&code);
+        } else {
+            lobby_notify(&code, lobby, &peer).await?;
+        }
+    }
+    Ok(())
+}
+
+// Redact the message in the active game or in the record of the finished one
+async fn process_moderation_redact(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    token: &str,
+    game_id: u64,
+    message_id: u64,
+) -> Result<()> {
+    let error = |e: ModerationError| Pdu::Moderation(Moderation::Error(e)).to_message();
+    if !vault.tenant().is_moderator(token) {
+        let resp = error(ModerationError::Forbidden {
+            description: "bad moderator token".to_string(),
+        })?;
+        send_msg_to!(vault, conn_id, resp);
+        return Ok(());
+    }
+
+    let redacted = {
+        match vault.get_game(game_id) {
+            Some(game) => {
+                let mut game_lock = game.lock().await;
+                let message = game_lock.redact_chat(message_id);
+                if let Some(message) = &message {
+                    let pdu = Pdu::GameSession(GameSession::Chat(Chat::Message(message.clone())))
+                        .to_message()?;
+                    game_lock.broadcast(pdu).await?;
+                }
+                message
+            }
+            None => vault
+                .get_records()
+                .await
+                .get_mut(game_id)
+                .and_then(|record| redact_chat(&mut record.chat, message_id)),
+        }
+    };
+
+    let resp = match redacted {
+        Some(_) => {
+            info!("message {} of game {} redacted", message_id, game_id);
+            Pdu::Moderation(Moderation::Ok {}).to_message()?
+        }
+        None => error(ModerationError::NotFound {
+            description: format!("no message {} in game {}", message_id, game_id),
+        })?,
+    };
+    send_msg_to!(vault, conn_id, resp);
+    Ok(())
+}
+
+async fn process_rating_season(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    number: Option<u32>,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let peer_lock = peer.lock().await;
+
+    let resp = if peer_lock.state.is_unknown() {
+        Season::Error(SeasonError::Handshake {
+            description: "pass handshake first".to_string(),
+        })
+    } else {
+        match vault
+            .get_ratings()
+            .await
+            .season(number, SEASON_LENGTH.as_secs())
+        {
+            Some(season) => Season::Ok(season),
+            None => Season::Error(SeasonError::NotFound {
+                description: "no such season".to_string(),
+            }),
+        }
+    };
+    let resp = Pdu::Rating(Rating::Season(resp)).to_message()?;
+    peer_lock.tx.send(resp)?;
+    Ok(())
+}
+
+// Best players of all time and the rank of the player with the results of the rated games
+async fn process_rating_leaderboard(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    top: Option<u32>,
+    player: Option<&str>,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let peer_lock = peer.lock().await;
+    let error = |e| Pdu::Rating(Rating::Leaderboard(Leaderboard::Error(e))).to_message();
+
+    if peer_lock.state.is_unknown() {
+        let resp = error(LeaderboardError::Handshake {
+            description: "pass handshake first".to_string(),
+        })?;
+        peer_lock.tx.send(resp)?;
+        return Ok(());
+    }
+    let (top, rank) = {
+        let ratings_lock = vault.get_ratings().await;
+        let top = ratings_lock.all_time(top.map_or(LEADERBOARD_TOP, |top| top as usize));
+        let rank = player.map(|name| {
+            ratings_lock
+                .rank(name)
+                .map(|place| (name, place, ratings_lock.get(name)))
+        });
+        (top, rank)
+    };
+    let resp = match rank {
+        Some(None) => error(LeaderboardError::NotFound {
+            description: "no such rated player".to_string(),
+        })?,
+        Some(Some((name, place, rating))) => {
+            let results = game_results(&vault.get_records().await.of_player(name), name);
+            let count = |result| results.iter().filter(|r| **r == result).count() as u32;
+            let rank = PlayerRank {
+                player: name.to_string(),
+                place,
+                rating,
+                wins: count(GameResult::Win),
+                draws: count(GameResult::Draw),
+                losses: count(GameResult::Loss),
+                form: results.iter().rev().take(FORM_GAMES).copied().collect(),
+            };
+            Pdu::Rating(Rating::Leaderboard(Leaderboard::Ok {
+                top,
+                player: Some(rank),
+            }))
+            .to_message()?
+        }
+        None => {
+            Pdu::Rating(Rating::Leaderboard(Leaderboard::Ok { top, player: None })).to_message()?
+        }
+    };
+    peer_lock.tx.send(resp)?;
+    Ok(())
+}
+
+// Results of the player in the rated games of the records, in the order of the records
+fn game_results(records: &[&GameSnapshot], name: &str) -> Vec<GameResult> {
+    records
+        .iter()
+        .filter(|record| !record.casual)
+        .filter_map(|record| {
+            let color = record.player_color(name)?;
+            let game = restored_game((*record).clone(), unbounded().0);
+            let place = game.place(color);
+            let shared = game
+                .players()
+                .iter()
+                .filter(|p| game.place(p.color) == place)
+                .count();
+            Some(match (place, shared) {
+                (0, 1) => GameResult::Win,
+                (0, _) => GameResult::Draw,
+                _ => GameResult::Loss,
+            })
+        })
+        .collect()
+}
+
+fn tournament_error(e: TournamentError) -> Result<Message> {
+    Pdu::Tournament(Tournament::Error(e)).to_message()
+}
+
+// Info of the tournament, all of them without the id
+async fn process_tournament_info(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    tournament_id: Option<u64>,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let peer_lock = peer.lock().await;
+
+    let resp = if peer_lock.state.is_unknown() {
+        tournament_error(TournamentError::Handshake {
+            description: "pass handshake first".to_string(),
+        })?
+    } else {
+        let tournaments_lock = vault.get_tournaments().await;
+        match tournament_id {
+            None => Pdu::Tournament(Tournament::Tournaments {
+                tournaments: tournaments_lock.all().iter().map(|t| t.info()).collect(),
+            })
+            .to_message()?,
+            Some(id) => match tournaments_lock.get(id) {
+                Some(tournament) => {
+                    Pdu::Tournament(Tournament::Info(tournament.info())).to_message()?
+                }
+                None => tournament_error(TournamentError::NotFound {
+                    description: "no such tournament".to_string(),
+                })?,
+            },
+        }
+    };
+    peer_lock.tx.send(resp)?;
+    Ok(())
+}
+
+fn social_error(e: SocialError) -> Result<Message> {
+    Pdu::Social(Social::Error(e)).to_message()
+}
+
+// Friends of the player with their presence, after adding or removing one of them
+async fn process_social_friend(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    change: Option<(&str, bool)>,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let peer_lock = peer.lock().await;
+
+    let name = match &peer_lock.account {
+        Some(account) => account.name.clone(),
+        None => {
+            let resp = social_error(SocialError::Unauthorized {
+                description: "log in first".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    };
+    let result = {
+        let mut accounts_lock = vault.get_accounts().await;
+        let result = match change {
+            Some((other, friend)) => accounts_lock.set_friend(&name, other, friend),
+            None => Ok(accounts_lock.friends(&name)),
+        };
+        result.map(|ids| {
+            accounts_lock
+                .names(&ids)
+                .into_iter()
+                .map(|name| Friend {
+                    online: accounts_lock
+                        .get(&name)
+                        .is_some_and(|account| vault.is_online(account.id)),
+                    name,
+                })
+                .collect::<Vec<_>>()
+        })
+    };
+    let resp = match result {
+        Ok(friends) => Pdu::Social(Social::Friends { friends }).to_message()?,
+        Err(AccountError::NotFound) => social_error(SocialError::NotFound {
+            description: "no other player with this name".to_string(),
+        })?,
+        Err(AccountError::TooMany) => social_error(SocialError::Forbidden {
+            description: "too many friends".to_string(),
+        })?,
+        Err(_) => social_error(SocialError::UnspecifiedError {
+            description: "account not found".to_string(),
+        })?,
+    };
+    peer_lock.tx.send(resp)?;
+    Ok(())
+}
+
+// Account ids and names of the invited players, online friends of the challenger
+// who did not block the challenger
+fn challenge_players(
+    vault: &Vault,
+    accounts: &Accounts,
+    challenger: &AccountInfo,
+    players: &[String],
+) -> Result<Vec<(u64, String)>, SocialError> {
+    let forbidden = |description: String| SocialError::Forbidden { description };
+    if players.len() != 3 {
+        return Err(forbidden("challenge three players".to_string()));
+    }
+    let friends = accounts.friends(&challenger.name);
+    let mut invited: Vec<(u64, String)> = Vec::new();
+    for player in players {
+        let account = match accounts.get(player) {
+            Some(account) if friends.contains(&account.id) => account,
+            _ => return Err(forbidden(format!("\"{}\" is not your friend", player))),
+        };
+        if invited.iter().any(|(id, _)| *id == account.id) {
+            return Err(forbidden(format!("\"{}\" is invited twice", player)));
+        }
+        if accounts.blocked(player).contains(&challenger.id) {
+            return Err(forbidden(format!(
+                "\"{}\" takes no challenges from you",
+                player
+            )));
+        }
+        if !vault.is_online(account.id) {
+            return Err(forbidden(format!("\"{}\" is offline", player)));
+        }
+        invited.push((account.id, account.name.clone()));
+    }
+    Ok(invited)
+}
+
+// message to every connection of the players
+fn challenge_send(vault: &Vault, challenge: &Challenge, msg: Message) {
+    for (account_id, _) in &challenge.players {
+        for conn_id in vault.connections_of(*account_id) {
+            if let Err(e) = vault.reply(&conn_id, msg.clone()) {
+                error!("send failed \"{}\"", e);
+            }
+        }
+    }
+}
+
+fn challenge_notify(vault: &Vault, challenge_id: u64, challenge: &Challenge) -> Result<()> {
+    let msg = Pdu::Social(Social::Challenged(ChallengeInfo {
+        challenge_id,
+        players: challenge.players.iter().map(|(_, n)| n.clone()).collect(),
+        accepted: challenge
+            .players
+            .iter()
+            .filter(|(id, _)| challenge.accepted.contains(id))
+            .map(|(_, n)| n.clone())
+            .collect(),
+    }))
+    .to_message()?;
+    challenge_send(vault, challenge, msg);
+    Ok(())
+}
+
+// Invite three friends to a table, the challenger has accepted already
+async fn process_social_challenge(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    players: &[String],
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let mut challenges_lock = vault.get_challenges().await;
+    let peer_lock = peer.lock().await;
+
+    let challenger = match &peer_lock.account {
+        Some(account) => account,
+        None => {
+            let resp = social_error(SocialError::Unauthorized {
+                description: "log in first".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    };
+    if !matches!(peer_lock.state, PeerState::Idle) {
+        let resp = social_error(SocialError::Forbidden {
+            description: "leave matchmaking queue, lobby or active game session first".to_string(),
+        })?;
+        peer_lock.tx.send(resp)?;
+        return Ok(());
+    }
+    let invited = challenge_players(vault, &*vault.get_accounts().await, challenger, players);
+    let invited = match invited {
+        Ok(invited) => invited,
+        Err(e) => {
+            peer_lock.tx.send(social_error(e)?)?;
+            return Ok(());
+        }
+    };
+
+    challenges_lock.retain(|_, c| c.created.elapsed() < CHALLENGE_TIMEOUT);
+    let challenge_id = vault.next_challenge_id();
+    let mut players = vec![(challenger.id, challenger.name.clone())];
+    players.extend(invited);
+    let challenge = Challenge {
+        players,
+        accepted: vec![challenger.id],
+        created: Instant::now(),
+    };
+    challenge_notify(vault, challenge_id, &challenge)?;
+    info!("challenge {} sent by {}", challenge_id, conn_id);
+    challenges_lock.insert(challenge_id, challenge);
+    Ok(())
+}
+
+// Accept or decline the challenge, the game starts once all players accepted while idle
+async fn process_social_answer(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    challenge_id: u64,
+    accept: bool,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let mut challenges_lock = vault.get_challenges().await;
+    let peer_lock = peer.lock().await;
+
+    let (account_id, name) = match &peer_lock.account {
+        Some(account) => (account.id, account.name.clone()),
+        None => {
+            let resp = social_error(SocialError::Unauthorized {
+                description: "log in first".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    };
+    let challenge = match challenges_lock.get_mut(&challenge_id) {
+        Some(challenge)
+            if challenge.created.elapsed() < CHALLENGE_TIMEOUT
+                && challenge.players.iter().any(|(id, _)| *id == account_id) =>
+        {
+            challenge
+        }
+        _ => {
+            let resp = social_error(SocialError::NotFound {
+                description: "no such challenge".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    };
+    if !accept {
+        let challenge = challenges_lock.remove(&challenge_id).unwrap();
+        let declined = Pdu::Social(Social::Declined {
+            challenge_id,
+            player: name,
+        })
+        .to_message()?;
+        challenge_send(vault, &challenge, declined);
+        return Ok(());
+    }
+    if !matches!(peer_lock.state, PeerState::Idle) {
+        let resp = social_error(SocialError::Forbidden {
+            description: "leave matchmaking queue, lobby or active game session first".to_string(),
+        })?;
+        peer_lock.tx.send(resp)?;
+        return Ok(());
+    }
+    if !challenge.accepted.contains(&account_id) {
+        challenge.accepted.push(account_id);
+    }
+    // other peers are locked one by one, never while this one is held
+    drop(peer_lock);
+
+    if challenge.accepted.len() == challenge.players.len() {
+        // players busy by now accept again once they are idle
+        let mut idle = Vec::new();
+        for (account_id, _) in &challenge.players {
+            for conn_id in vault.connections_of(*account_id) {
+                let peer = match vault.get_peer(&conn_id) {
+                    Some(peer) => peer,
+                    None => continue,
+                };
+                if matches!(peer.lock().await.state, PeerState::Idle) {
+                    idle.push(*account_id);
+                    break;
+                }
+            }
+        }
+        challenge.accepted.retain(|id| idle.contains(id));
+    }
+    if challenge.accepted.len() < challenge.players.len() {
+        return challenge_notify(vault, challenge_id, challenge);
+    }
+
+    let challenge = challenges_lock.remove(&challenge_id).unwrap();
+    let names = challenge
+        .players
+        .iter()
+        .map(|(_, name)| name.clone())
+        .collect::<Vec<_>>();
+    if let Some(game_id) =
+        start_table(vault, &names, PLAYER_TIMER, default_disconnect_policy()).await
+    {
+        info!("challenge {} started game {}", challenge_id, game_id);
+    }
+    Ok(())
+}
+
+// Registration or leave of the account until the first round starts,
+// the player plays the tournament under the name of the account
+async fn process_tournament_entry(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    tournament_id: u64,
+    register: bool,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let peer_lock = peer.lock().await;
+
+    let resp = match (&peer_lock.state, &peer_lock.account) {
+        (PeerState::Unknown(_), _) => tournament_error(TournamentError::Handshake {
+            description: "pass handshake first".to_string(),
+        })?,
+        (_, None) => tournament_error(TournamentError::Unauthorized {
+            description: "log in first".to_string(),
+        })?,
+        (_, Some(account)) => {
+            let mut tournaments_lock = vault.get_tournaments().await;
+            match tournaments_lock.get_mut(tournament_id) {
+                Some(tournament) => {
+                    let entry = match register {
+                        true => tournament.register(&account.name),
+                        false => tournament.leave(&account.name),
+                    };
+                    match entry {
+                        Ok(()) => {
+                            Pdu::Tournament(Tournament::Info(tournament.info())).to_message()?
+                        }
+                        Err(e) => tournament_error(TournamentError::Forbidden {
+                            description: e.to_string(),
+                        })?,
+                    }
+                }
+                None => tournament_error(TournamentError::NotFound {
+                    description: "no such tournament".to_string(),
+                })?,
+            }
+        }
+    };
+    peer_lock.tx.send(resp)?;
+    Ok(())
+}
+
+// Register, log in with password or with token issued before
+async fn process_auth(vault: &Vault, conn_id: &ConnectionId, request: &Auth) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let peer_lock = peer.lock().await;
+    let error = |e: AuthError| Pdu::Auth(Auth::Error(e)).to_message();
+    if peer_lock.state.is_unknown() {
+        let resp = error(AuthError::Handshake {
+            description: "pass handshake first".to_string(),
+        })?;
+        peer_lock.tx.send(resp)?;
+        return Ok(());
+    }
+    if peer_lock.account.is_some() {
+        let resp = error(AuthError::AlreadyAuthorized {
+            description: "you are already logged in".to_string(),
+        })?;
+        peer_lock.tx.send(resp)?;
+        return Ok(());
+    }
+    if let Auth::Register { name, .. } = request {
+        let locale = peer_lock
+            .client_info
+            .as_ref()
+            .and_then(|c| c.locale.clone());
+        if !vault
+            .get_chat_filter()
+            .await
+            .allows_name(name, locale.as_deref())
+        {
+            let resp = error(AuthError::BadName {
+                description: "name is not allowed".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    }
+    // the password is hashed with neither the peer nor the accounts locked
+    drop(peer_lock);
+
+    let result = match request {
+        Auth::Register { name, password } => {
+            let checked = vault.get_accounts().await.check_new(name, password);
+            match checked {
+                Ok(()) => {
+                    let hash = hash_new_password(password).await?;
+                    vault.get_accounts().await.register(name, hash)
+                }
+                Err(e) => Err(e),
+            }
+            .map(|(id, token)| (id, name.clone(), token))
+        }
+        Auth::Login { name, password } => match verified_hash(vault, name, password).await? {
+            Ok(hash) => vault.get_accounts().await.login(name, &hash),
+            Err(e) => Err(e),
+        }
+        .map(|(id, token)| (id, name.clone(), token)),
+        Auth::Token { token } => vault
+            .get_accounts()
+            .await
+            .login_token(token)
+            .map(|(id, name)| (id, name, token.clone())),
+        _ => return Ok(()),
+    };
+    let result = {
+        let accounts_lock = vault.get_accounts().await;
+        result.map(|(id, name, token)| {
+            let blocked = accounts_lock.blocked(&name);
+            let private_pools = accounts_lock.private_pools(&name);
+            (id, name, token, blocked, private_pools)
+        })
+    };
+    let mut peer_lock = peer.lock().await;
+    let resp = match result {
+        Ok((account_id, name, token, blocked, private_pools)) => {
+            let account = AccountInfo {
+                id: account_id,
+                name: name.clone(),
+                blocked,
+                private_pools,
+            };
+            info!(
+                "{} logged in as \"{}\" (account {})",
+                conn_id, account.name, account.id
+            );
+            peer_lock.account = Some(account);
+            vault.set_online(account_id, *conn_id);
+            Pdu::Auth(Auth::Ok {
+                account_id,
+                name,
+                token,
+            })
+            .to_message()?
+        }
+        Err(AccountError::BadName) => error(AuthError::BadName {
+            description: "name must be 1-32 letters, digits, '_' or '-'".to_string(),
+        })?,
+        Err(AccountError::NameTaken) => error(AuthError::NameTaken {
+            description: "name is already registered".to_string(),
+        })?,
+        Err(AccountError::WeakPassword) => error(AuthError::WeakPassword {
+            description: "password must be at least 8 characters".to_string(),
+        })?,
+        Err(AccountError::BadCredentials) => error(AuthError::BadCredentials {
+            description: "wrong name, password or token".to_string(),
+        })?,
+        Err(AccountError::NotFound | AccountError::TooMany) => {
+            error(AuthError::UnspecifiedError {
+                description: "login failed".to_string(),
+            })?
+        }
+    };
+    peer_lock.tx.send(resp)?;
+    Ok(())
+}
+
+// Hash of the new password, computed off the async workers
+async fn hash_new_password(password: &str) -> Result<String> {
+    let password = password.to_string();
+    Ok(tokio::task::spawn_blocking(move || account::hash_password(&password)).await?)
+}
+
+// Stored hash of the account once the password matched it, the accounts are not locked
+// while the password is checked off the async workers
+async fn verified_hash(
+    vault: &Vault,
+    name: &str,
+    password: &str,
+) -> Result<std::result::Result<String, AccountError>> {
+    let hash = vault.get_accounts().await.password_hash(name);
+    let hash = match hash {
+        Ok(hash) => hash,
+        Err(e) => return Ok(Err(e)),
+    };
+    let password = password.to_string();
+    Ok(tokio::task::spawn_blocking(move || {
+        account::verify_password(&password, &hash)
+            .then_some(hash)
+            .ok_or(AccountError::BadCredentials)
+    })
+    .await?)
+}
+
+// Everything stored about the logged in player
+async fn process_account_export(vault: &Vault, conn_id: &ConnectionId) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let peer_lock = peer.lock().await;
+
+    let profile = match &peer_lock.account {
+        Some(account) => vault
+            .get_accounts()
+            .await
+            .get(&account.name)
+            .map(|account| AccountProfile {
+                account_id: account.id,
+                name: account.name.clone(),
+                created: account.created,
+            }),
+        None => None,
+    };
+    let resp = match profile {
+        None => Account::Error(AccountDataError::Unauthorized {
+            description: "log in first".to_string(),
+        }),
+        Some(profile) => {
+            let name = profile.name.clone();
+            let games = vault
+                .get_records()
+                .await
+                .of_player(&name)
+                .into_iter()
+                .map(|record| game_replay(record, true))
+                .collect::<Vec<_>>();
+            let ratings_lock = vault.get_ratings().await;
+            let stats = AccountStats {
+                rating: ratings_lock.get(&name),
+                badges: ratings_lock.badges(&name),
+                season_games: ratings_lock.season_games(&name),
+                games: games.len() as u32,
+            };
+            let accounts_lock = vault.get_accounts().await;
+            let blocked = accounts_lock.names(&accounts_lock.blocked(&name));
+            let private_pools = accounts_lock.private_pools(&name);
+            Account::Data(AccountData {
+                profile,
+                stats,
+                games,
+                blocked,
+                private_pools,
+            })
+        }
+    };
+    let resp = Pdu::Account(resp).to_message()?;
+    peer_lock.tx.send(resp)?;
+    Ok(())
+}
+
+// Remove the account confirmed by password, all connections of the player must be idle
+async fn process_account_delete(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    password: &str,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let peer_lock = peer.lock().await;
+    let error = |e: AccountDataError| Pdu::Account(Account::Error(e)).to_message();
+
+    let name = match &peer_lock.account {
+        Some(account) => account.name.clone(),
+        None => {
+            let resp = error(AccountDataError::Unauthorized {
+                description: "log in first".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    };
+    let mut busy = !matches!(peer_lock.state, PeerState::Idle);
+    // other peers are locked one by one, never while this one is held
+    let tx = peer_lock.tx.clone();
+    drop(peer_lock);
+    let others = vault.get_peers();
+    let others = others.iter().filter(|(id, _)| id != conn_id);
+    for (_, other) in others.clone() {
+        let other_lock = other.lock().await;
+        busy |= other_lock.owns_name(&name) && !matches!(other_lock.state, PeerState::Idle);
+    }
+    if busy {
+        let resp = error(AccountDataError::Forbidden {
+            description: "leave matchmaking queue, lobby or active game session first".to_string(),
+        })?;
+        tx.send(resp)?;
+        return Ok(());
+    }
+
+    let deleted = match verified_hash(vault, &name, password).await? {
+        Ok(hash) => vault.get_accounts().await.delete(&name, &hash),
+        Err(e) => Err(e),
+    };
+    let resp = match deleted {
+        Ok(account) => {
+            let alias = deleted_name(account.id);
+            vault.get_ratings().await.forget(&name, &alias);
+            vault.get_records().await.anonymize(&name, &alias);
+            vault.get_fair_play().await.forget(&name);
+            peer.lock().await.account = None;
+            for conn_id in vault.connections_of(account.id) {
+                vault.set_offline(account.id, &conn_id);
+            }
+            // other connections of the account are logged out too
+            for (_, other) in others {
+                let mut other_lock = other.lock().await;
+                if other_lock.owns_name(&name) {
+                    other_lock.account = None;
+                }
+            }
+            info!("account {} deleted by {}", account.id, conn_id);
+            Pdu::Account(Account::Deleted {}).to_message()?
+        }
+        Err(_) => error(AccountDataError::BadCredentials {
+            description: "wrong password".to_string(),
+        })?,
+    };
+    tx.send(resp)?;
+    Ok(())
+}
+
+// Block, unblock or only list blocked players, every connection of the account gets the new list
+async fn process_account_block(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    change: Option<(&str, bool)>,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let mut peer_lock = peer.lock().await;
+    let error = |e: AccountDataError| Pdu::Account(Account::Error(e)).to_message();
+
+    let name = match &peer_lock.account {
+        Some(account) => account.name.clone(),
+        None => {
+            let resp = error(AccountDataError::Unauthorized {
+                description: "log in first".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    };
+    let (result, names) = {
+        let mut accounts_lock = vault.get_accounts().await;
+        let result = match change {
+            Some((other, blocked)) => accounts_lock.set_blocked(&name, other, blocked),
+            None => Ok(accounts_lock.blocked(&name)),
+        };
+        let names = result
+            .as_ref()
+            .map_or_else(|_| Vec::new(), |ids| accounts_lock.names(ids));
+        (result, names)
+    };
+    let blocked = match result {
+        Ok(blocked) => blocked,
+        Err(AccountError::NotFound) => {
+            let resp = error(AccountDataError::NotFound {
+                description: "no other player with this name".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+        Err(AccountError::TooMany) => {
+            let resp = error(AccountDataError::Forbidden {
+                description: "too many blocked players".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+        Err(_) => {
+            let resp = error(AccountDataError::UnspecifiedError {
+                description: "account not found".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    };
+
+    let resp = Pdu::Account(Account::Blocked { names }).to_message()?;
+    if let Some(account) = peer_lock.account.as_mut() {
+        account.blocked = blocked.clone();
+    }
+    peer_lock.tx.send(resp.clone())?;
+    // other peers are locked one by one, never while this one is held
+    drop(peer_lock);
+    if change.is_some() {
+        for (_, other) in vault.get_peers().iter().filter(|(id, _)| id != conn_id) {
+            let mut other_lock = other.lock().await;
+            if other_lock.owns_name(&name) {
+                if let Some(account) = other_lock.account.as_mut() {
+                    account.blocked = blocked.clone();
+                }
+                let _ = other_lock.tx.send(resp.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Keep games of the pool private, publish them again or only list private pools.
+// Games already started keep their visibility, every connection of the account gets the new list.
+async fn process_account_private(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    change: Option<(&str, bool)>,
+) -> Result<()> {
+    let peer = vault
+        .get_peer(conn_id)
+        .context(format!("get({}) from peer_map failed", conn_id))?;
+    let mut peer_lock = peer.lock().await;
+    let error = |e: AccountDataError| Pdu::Account(Account::Error(e)).to_message();
+
+    let name = match &peer_lock.account {
+        Some(account) => account.name.clone(),
+        None => {
+            let resp = error(AccountDataError::Unauthorized {
+                description: "log in first".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    };
+    let result = match change {
+        Some((pool, _)) if vault.tenant().pool(Some(pool)).is_none() => Err(AccountError::NotFound),
+        Some((pool, private)) => vault.get_accounts().await.set_private(&name, pool, private),
+        None => Ok(vault.get_accounts().await.private_pools(&name)),
+    };
+    let pools = match result {
+        Ok(pools) => pools,
+        Err(AccountError::NotFound) => {
+            let resp = error(AccountDataError::NotFound {
+                description: "no pool with this name".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+        Err(AccountError::TooMany) => {
+            let resp = error(AccountDataError::Forbidden {
+                description: "too many private pools".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+        Err(_) => {
+            let resp = error(AccountDataError::UnspecifiedError {
+                description: "account not found".to_string(),
+            })?;
+            peer_lock.tx.send(resp)?;
+            return Ok(());
+        }
+    };
+
+    let resp = Pdu::Account(Account::PrivatePools {
+        pools: pools.clone(),
+    })
+    .to_message()?;
+    if let Some(account) = peer_lock.account.as_mut() {
+        account.private_pools = pools.clone();
+    }
+    peer_lock.tx.send(resp.clone())?;
+    // other peers are locked one by one, never while this one is held
+    drop(peer_lock);
+    if change.is_some() {
+        for (_, other) in vault.get_peers().iter().filter(|(id, _)| id != conn_id) {
+            let mut other_lock = other.lock().await;
+            if other_lock.owns_name(&name) {
+                if let Some(account) = other_lock.account.as_mut() {
+                    account.private_pools = pools.clone();
+                }
+                let _ = other_lock.tx.send(resp.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[instrument(skip_all, fields(%conn_id))]
+async fn process_msg(pdu: &Pdu, vault: &Vault, conn_id: &ConnectionId) -> Result<()> {
+    match pdu {
+        Pdu::Handshake(hs) => match hs {
+            Handshake::GetInfo(gi) => match gi {
+                GetInfo::Request {} => process_hs_get_info(vault, conn_id).await,
+                _ => Ok(()),
+            },
+            Handshake::Connect(c) => match c {
+                Connect::Client {
+                    name,
+                    version,
+                    protocol,
+                    capabilities,
+                    locale,
+                } => {
+                    process_hs_connect(
+                        vault,
+                        conn_id,
+                        name,
+                        version,
+                        protocol,
+                        capabilities,
+                        locale.as_deref(),
+                    )
+                    .await
+                }
+                _ => Ok(()),
+            },
+            Handshake::Timeout { .. } => Ok(()),
+        },
+        Pdu::MatchmakingQueue(mq) => match mq {
+            MatchmakingQueue::PlayerRegister(pr) => match pr {
+                PlayerRegister::Name(name) => {
+                    process_mm_player_reg(vault, conn_id, name, DEFAULT_POOL, Variant::Ffa, None)
+                        .await
+                }
+                PlayerRegister::Join {
+                    name,
+                    pool,
+                    variant,
+                    time_control,
+                } => {
+                    process_mm_player_reg(vault, conn_id, name, pool, *variant, *time_control).await
+                }
+                _ => Ok(()),
+            },
+            MatchmakingQueue::PlayerLeave {} => process_mm_player_leave(vault, conn_id).await,
+            MatchmakingQueue::Pools(Pools::Request {}) => process_mm_pools(vault, conn_id).await,
+            MatchmakingQueue::HeartbeatCheck {} => process_mm_heartbeat_check(vault, conn_id).await,
+            _ => Ok(()),
+        },
+        Pdu::GameSession(gs) => match gs {
+            GameSession::Move(mv) => process_move_make(vault, conn_id, mv).await,
+            GameSession::Reconnect(Reconnect::Request { reconnect_id }) => {
+                process_gs_reconnect(vault, conn_id, reconnect_id).await
+            }
+            GameSession::Chat(chat) => process_gs_chat(vault, conn_id, chat).await,
+            GameSession::DrawVote(DrawVote::Vote { agree }) => {
+                process_gs_draw_vote(vault, conn_id, *agree).await
+            }
+            GameSession::Pause(Pause::Vote { agree }) => {
+                process_gs_pause(vault, conn_id, *agree).await
+            }
+            GameSession::Adjudication(Adjudication::Consent { agree }) => {
+                process_gs_adjudication(vault, conn_id, *agree).await
+            }
+            GameSession::Ready {} => process_gs_ready(vault, conn_id).await,
+            GameSession::LegalMoves(LegalMoves::Request { position }) => {
+                process_gs_legal_moves(vault, conn_id, *position).await
+            }
+            GameSession::StateSync(StateSync::Request { game_id }) => {
+                process_gs_state_sync(vault, conn_id, *game_id).await
+            }
+            _ => Ok(()),
+        },
+        Pdu::Analysis(a) => match a {
+            Analysis::Subscribe(Subscribe::Request { game_id, token }) => {
+                process_analysis_subscribe(vault, conn_id, *game_id, token).await
+            }
+            Analysis::Spectate(Spectate::Request { game_id }) => {
+                process_analysis_spectate(vault, conn_id, *game_id).await
+            }
+            Analysis::Replay(Replay::Request { game_id, token }) => {
+                process_analysis_replay(vault, conn_id, *game_id, token.as_deref()).await
+            }
+            Analysis::Pgn4(Pgn4::Request { game_id }) => {
+                process_analysis_pgn4(vault, conn_id, *game_id).await
+            }
+            Analysis::Unsubscribe { game_id } => {
+                process_analysis_unsubscribe(vault, conn_id, *game_id).await
+            }
+            Analysis::Consent { allow } => process_analysis_consent(vault, conn_id, *allow).await,
+            Analysis::Evaluation(evaluation) => {
+                process_analysis_evaluation(vault, conn_id, evaluation).await
+            }
+            _ => Ok(()),
+        },
+        Pdu::Lobby(l) => match l {
+            Lobby::Create { player_name, start } => {
+                process_lobby_create(vault, conn_id, player_name, start.as_deref()).await
+            }
+            Lobby::Join { code, player_name } => {
+                process_lobby_join(vault, conn_id, code, player_name).await
+            }
+            Lobby::Leave {} => process_lobby_leave(vault, conn_id).await,
+            _ => Ok(()),
+        },
+        Pdu::Tournament(t) => match t {
+            Tournament::List {} => process_tournament_info(vault, conn_id, None).await,
+            Tournament::Request { tournament_id } => {
+                process_tournament_info(vault, conn_id, Some(*tournament_id)).await
+            }
+            Tournament::Register { tournament_id } => {
+                process_tournament_entry(vault, conn_id, *tournament_id, true).await
+            }
+            Tournament::Leave { tournament_id } => {
+                process_tournament_entry(vault, conn_id, *tournament_id, false).await
+            }
+            _ => Ok(()),
+        },
+        Pdu::Rating(r) => match r {
+            Rating::Season(Season::Request { number }) => {
+                process_rating_season(vault, conn_id, *number).await
+            }
+            Rating::Leaderboard(Leaderboard::Request { top, player }) => {
+                process_rating_leaderboard(vault, conn_id, *top, player.as_deref()).await
+            }
+            _ => Ok(()),
+        },
+        Pdu::Auth(a) => match a {
+            Auth::Register { .. } | Auth::Login { .. } | Auth::Token { .. } => {
+                process_auth(vault, conn_id, a).await
+            }
+            _ => Ok(()),
+        },
+        Pdu::Account(a) => match a {
+            Account::Export {} => process_account_export(vault, conn_id).await,
+            Account::Delete { password } => process_account_delete(vault, conn_id, password).await,
+            Account::Block { name } => {
+                process_account_block(vault, conn_id, Some((name, true))).await
+            }
+            Account::Unblock { name } => {
+                process_account_block(vault, conn_id, Some((name, false))).await
+            }
+            Account::BlockList {} => process_account_block(vault, conn_id, None).await,
+            Account::Private { pool } => {
+                process_account_private(vault, conn_id, Some((pool, true))).await
+            }
+            Account::Public { pool } => {
+                process_account_private(vault, conn_id, Some((pool, false))).await
+            }
+            Account::PrivateList {} => process_account_private(vault, conn_id, None).await,
+            _ => Ok(()),
+        },
+        Pdu::Social(s) => match s {
+            Social::AddFriend { name } => {
+                process_social_friend(vault, conn_id, Some((name, true))).await
+            }
+            Social::RemoveFriend { name } => {
+                process_social_friend(vault, conn_id, Some((name, false))).await
+            }
+            Social::FriendList {} => process_social_friend(vault, conn_id, None).await,
+            Social::Challenge { players } => {
+                process_social_challenge(vault, conn_id, players).await
+            }
+            Social::Accept { challenge_id } => {
+                process_social_answer(vault, conn_id, *challenge_id, true).await
+            }
+            Social::Decline { challenge_id } => {
+                process_social_answer(vault, conn_id, *challenge_id, false).await
+            }
+            _ => Ok(()),
+        },
+        Pdu::Moderation(m) => match m {
+            Moderation::Redact {
+                token,
+                game_id,
+                message_id,
+            } => process_moderation_redact(vault, conn_id, token, *game_id, *message_id).await,
+            _ => Ok(()),
+        },
+        Pdu::GameHistory(h) => match h {
+            GameHistory::Request { game_id } => {
+                process_game_history(vault, conn_id, *game_id).await
+            }
+            _ => Ok(()),
+        },
+        Pdu::Admin(a) => match a {
+            Admin::Benchmark(Benchmark::Request { token }) => {
+                process_admin_benchmark(vault, conn_id, token).await
+            }
+            Admin::FilterStats(FilterStats::Request { token }) => {
+                process_admin_filter_stats(vault, conn_id, token).await
+            }
+            Admin::TimingReport(TimingReport::Request { token }) => {
+                process_admin_timing_report(vault, conn_id, token).await
+            }
+            Admin::FairPlayReview(FairPlayReview::List { token }) => {
+                process_admin_fair_play(vault, conn_id, token, None).await
+            }
+            Admin::FairPlayReview(FairPlayReview::Clear { token, player }) => {
+                process_admin_fair_play(vault, conn_id, token, Some(player)).await
+            }
+            Admin::GameDiff(GameDiff::Request {
+                token,
+                game_id,
+                from,
+                to,
+            }) => process_admin_game_diff(vault, conn_id, token, *game_id, *from, *to).await,
+            Admin::FeatureFlags(FeatureFlags::List { token }) => {
+                process_admin_feature_flags(vault, conn_id, token, None).await
+            }
+            Admin::FeatureFlags(FeatureFlags::Set { token, flag }) => {
+                process_admin_feature_flags(vault, conn_id, token, Some(flag)).await
+            }
+            Admin::PeerList(PeerList::Request { token }) => {
+                process_admin_peer_list(vault, conn_id, token).await
+            }
+            Admin::GameList(GameList::Request { token }) => {
+                process_admin_game_list(vault, conn_id, token).await
+            }
+            Admin::Terminate(Terminate::Request {
+                token,
+                game_id,
+                description,
+            }) => process_admin_terminate(vault, conn_id, token, *game_id, description).await,
+            Admin::Kick(Kick::Request {
+                token,
+                conn_id: kicked,
+            }) => process_admin_kick(vault, conn_id, token, ConnectionId(*kicked)).await,
+            Admin::Broadcast(Broadcast::Request { token, text }) => {
+                process_admin_broadcast(vault, conn_id, token, text).await
+            }
+            Admin::ChatMute(ChatMute::Request {
+                token,
+                player,
+                seconds,
+            }) => process_admin_chat_mute(vault, conn_id, token, player, *seconds).await,
+            Admin::CreateTournament(request) => {
+                process_admin_create_tournament(vault, conn_id, request).await
+            }
+            _ => Ok(()),
+        },
+        // sent by the server only
+        Pdu::Notice(_) | Pdu::RateLimited(_) | Pdu::Disconnect(_) | Pdu::Route(_) => Ok(()),
+    }
+}
+
+// Measure move generation on the position of the busiest game, serialization
+// and lock latency, so operators can compare instances
+async fn process_admin_benchmark(vault: &Vault, conn_id: &ConnectionId, token: &str) -> Result<()> {
+    if !vault.tenant().is_admin(token) {
+        let resp = Pdu::Admin(Admin::Benchmark(Benchmark::Error(AdminError::Forbidden {
+            description: "bad admin token".to_string(),
+        })))
+        .to_message()?;
+        send_msg_to!(vault, conn_id, resp);
+        return Ok(());
+    }
+
+    let busiest = {
+        let mut busiest: Option<(usize, u64, Board)> = None;
+        for game in vault.get_games() {
+            let game_lock = game.lock().await;
+            let subscribers = game_lock.subscribers.len();
+            if busiest.as_ref().is_none_or(|b| subscribers > b.0) {
+                busiest = Some((subscribers, game_lock.id, game_lock.board.clone()));
+            }
+        }
+        busiest
+    };
+    let (game_id, board) = match busiest {
+        Some((_, game_id, board)) => (Some(game_id), board),
+        None => (None, Board::new()),
+    };
+
+    // cpu bound, other tasks keep running meanwhile
+    let position_moves = board.pseudo_legal_moves() as u64;
+    let (movegen_rate, attack_rate, (serialize_rate, serialize_bytes_rate)) =
+        tokio::task::spawn_blocking(move || {
+            (
+                bench::movegen_rate(&board, BENCHMARK_DURATION),
+                bench::attack_rate(&board, BENCHMARK_DURATION),
+                bench::serialize_rate(&board, BENCHMARK_DURATION),
+            )
+        })
+        .await?;
+
+    let mut lock_total = Duration::ZERO;
+    let mut lock_max = Duration::ZERO;
+    let mut lock_runs = 0u32;
+    let start = Instant::now();
+    while start.elapsed() < BENCHMARK_DURATION {
+        let since = Instant::now();
+        if let Some(game) = game_id.and_then(|id| vault.get_game(id)) {
+            let _game_lock = game.lock().await;
+        }
+        let took = since.elapsed();
+        lock_total += took;
+        lock_max = lock_max.max(took);
+        lock_runs += 1;
+        tokio::task::yield_now().await;
+    }
+
+    let report = BenchmarkReport {
+        game_id,
+        position_moves,
+        movegen_rate,
+        attack_rate,
+        serialize_rate,
+        serialize_bytes_rate,
+        lock_avg_us: (lock_total / lock_runs.max(1)).as_micros() as u64,
+        lock_max_us: lock_max.as_micros() as u64,
+        handshake_rate: handshake_rate(BENCHMARK_HANDSHAKES).await?,
+    };
+    info!("Benchmark requested by {}: {:?}", conn_id, report);
+    let resp = Pdu::Admin(Admin::Benchmark(Benchmark::Ok(report))).to_message()?;
+    send_msg_to!(vault, conn_id, resp);
+    Ok(())
+}
+
+// GetInfo and Connect of that many connections at once routed by process_msg, per second.
+// Connections of a scratch vault, the players of the instance are not touched.
+// Boxed, the benchmark itself is answered by process_msg.
+fn handshake_rate(connections: usize) -> futures::future::BoxFuture<'static, Result<u64>> {
+    Box::pin(async move {
+        let vault: Vault = Arc::new(vault::Vault::new());
+        let mut receivers = Vec::new();
+        for _ in 0..connections {
+            let (tx, rx) = peer_channel(PEER_QUEUE_LIMIT);
+            let conn_id = vault.next_connection_id();
+            let _ =
+                vault.try_insert_peer(conn_id, new_peer(tx, SocketAddr::from(([127, 0, 0, 1], 0))));
+            receivers.push(rx);
+        }
+        let get_info = Pdu::Handshake(Handshake::GetInfo(GetInfo::Request {}));
+        let connect = Pdu::Handshake(Handshake::Connect(Connect::Client {
+            name: "benchmark".to_string(),
+            version: SERV_VER.to_string(),
+            protocol: Protocol::Version(ProtoVersion::LATEST.as_str().to_string()),
+            capabilities: Vec::new(),
+            locale: None,
+        }));
+        let pdus = Arc::new((get_info, connect));
+        let start = Instant::now();
+        let handshakes = vault
+            .get_peers()
+            .into_iter()
+            .map(|(conn_id, _)| {
+                let vault = vault.clone();
+                let pdus = pdus.clone();
+                tokio::spawn(async move {
+                    process_msg(&pdus.0, &vault, &conn_id).await?;
+                    process_msg(&pdus.1, &vault, &conn_id).await
+                })
+            })
+            .collect::<Vec<_>>();
+        for handshake in future::join_all(handshakes).await {
+            handshake??;
+        }
+        Ok((connections as f64 / start.elapsed().as_secs_f64()) as u64)
+    })
+}
+
+// Chat filter hits, for tuning of word lists
+async fn process_admin_filter_stats(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    token: &str,
+) -> Result<()> {
+    if !vault.tenant().is_admin(token) {
+        let resp = Pdu::Admin(Admin::FilterStats(FilterStats::Error(
+            AdminError::Forbidden {
+                description: "bad admin token".to_string(),
+            },
+        )))
+        .to_message()?;
+        send_msg_to!(vault, conn_id, resp);
+        return Ok(());
+    }
+
+    let report = {
+        let filter_lock = vault.get_chat_filter().await;
+        let stats = &filter_lock.stats;
+        let mut words = stats
+            .words
+            .iter()
+            .map(|((word, severity), hits)| WordHits {
+                word: word.clone(),
+                severity: *severity,
+                hits: *hits,
+            })
+            .collect::<Vec<_>>();
+        words.sort_by(|a, b| b.hits.cmp(&a.hits).then_with(|| a.word.cmp(&b.word)));
+        FilterReport {
+            checked: stats.checked,
+            censored: stats.censored,
+            dropped: stats.dropped,
+            muted: stats.muted,
+            names_rejected: stats.names_rejected,
+            words,
+        }
+    };
+    let resp = Pdu::Admin(Admin::FilterStats(FilterStats::Ok(report))).to_message()?;
+    send_msg_to!(vault, conn_id, resp);
+    Ok(())
+}
+
+// Move durations of humans in finished games with suggested timer values, for tuning of the
+// timer constants
+async fn process_admin_timing_report(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    token: &str,
+) -> Result<()> {
+    if !vault.tenant().is_admin(token) {
+        let resp = Pdu::Admin(Admin::TimingReport(TimingReport::Error(
+            AdminError::Forbidden {
+                description: "bad admin token".to_string(),
+            },
+        )))
+        .to_message()?;
+        send_msg_to!(vault, conn_id, resp);
+        return Ok(());
+    }
+
+    let mut timings = MoveTimings::default();
+    for record in vault.get_records().await.values() {
+        let players = [&record.red, &record.green, &record.blue, &record.yellow];
+        let bots = [Color::Red, Color::Green, Color::Blue, Color::Yellow]
+            .iter()
+            .zip(players)
+            .filter(|(_, p)| p.bot)
+            .map(|(color, _)| *color)
+            .collect::<Vec<_>>();
+        for mv in record.moves.iter().filter(|m| !bots.contains(&m.color)) {
+            timings.add(
+                record.pool.as_deref(),
+                record.time_control.as_secs(),
+                mv.time_used,
+            );
+        }
+    }
+    let resp = Pdu::Admin(Admin::TimingReport(TimingReport::Ok {
+        timings: timings.report(),
+    }))
+    .to_message()?;
+    send_msg_to!(vault, conn_id, resp);
+    Ok(())
+}
+
+// Players flagged by the fair play review, answered with the remaining ones after clearing
+async fn process_admin_fair_play(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    token: &str,
+    clear: Option<&str>,
+) -> Result<()> {
+    let error =
+        |e: AdminError| Pdu::Admin(Admin::FairPlayReview(FairPlayReview::Error(e))).to_message();
+    let resp = if !vault.tenant().is_admin(token) {
+        error(AdminError::Forbidden {
+            description: "bad admin token".to_string(),
+        })?
+    } else {
+        let mut fair_play_lock = vault.get_fair_play().await;
+        match clear {
+            Some(player) if !fair_play_lock.clear(player) => error(AdminError::NotFound {
+                description: "player is not flagged".to_string(),
+            })?,
+            _ => {
+                if let Some(player) = clear {
+                    info!("player \"{}\" cleared by {}", player, conn_id);
+                }
+                Pdu::Admin(Admin::FairPlayReview(FairPlayReview::Ok {
+                    suspects: fair_play_lock.suspects(),
+                }))
+                .to_message()?
+            }
+        }
+    };
+    send_msg_to!(vault, conn_id, resp);
+    Ok(())
+}
+
+// Diff of the record of the finished game or of the current state of the active one
+async fn process_admin_game_diff(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    token: &str,
+    game_id: u64,
+    from: usize,
+    to: usize,
+) -> Result<()> {
+    let error =
+        |e| -> Result<Message> { Pdu::Admin(Admin::GameDiff(GameDiff::Error(e))).to_message() };
+    if !vault.tenant().is_admin(token) {
+        let resp = error(AdminError::Forbidden {
+            description: "bad admin token".to_string(),
+        })?;
+        send_msg_to!(vault, conn_id, resp);
+        return Ok(());
+    }
+
+    let record = match vault.get_records().await.get(game_id).cloned() {
+        Some(record) => Some(record),
+        None => match vault.get_game(game_id) {
+            Some(game) => Some(game.lock().await.record()),
+            None => None,
+        },
+    };
+    let resp = match record {
+        None => error(AdminError::NotFound {
+            description: format!("no game {}", game_id),
+        })?,
+        Some(record) if from > to || to > record.moves.len() => error(AdminError::BadRequest {
+            description: format!("plies must be 0 <= from <= to <= {}", record.moves.len()),
+        })?,
+        Some(record) => match game_diff(&record, from, to) {
+            Ok(lines) => Pdu::Admin(Admin::GameDiff(GameDiff::Ok {
+                game_id,
+                from,
+                to,
+                lines,
+            }))
+            .to_message()?,
+            Err(e) => error(AdminError::UnspecifiedError {
+                description: e.to_string(),
+            })?,
+        },
+    };
+    send_msg_to!(vault, conn_id, resp);
+    Ok(())
+}
+
+// Game of the record after its first plies, the moves are applied again on the start position.
+// Records keep no turn of the elimination, players are taken out after their last move.
+fn replay_plies(record: &GameSnapshot, plies: usize) -> Result<Game> {
+    let clock = match record.time_control {
+        Duration::ZERO => PLAYER_TIMER,
+        time_control => time_control,
+    };
+    let mut start = record.clone();
+    start.board = match &record.start {
+        Some(fen) => Fen4::try_from(fen.as_str())?.board,
+        None => Board::new(),
+    };
+    start.captured.clear();
+    start.moves.clear();
+    start.eliminated.clear();
+    start.who_move = None;
+    start.end = GameEnd::Standings;
+    for player in [
+        &mut start.red,
+        &mut start.green,
+        &mut start.blue,
+        &mut start.yellow,
+    ] {
+        player.time_remaining = clock;
+        player.state = PlayerState::NoState;
+        player.points = 0;
+    }
+    let mut game = restored_game(start, unbounded().0);
+    for (i, record_move) in record.moves.iter().take(plies).enumerate() {
+        game.who_move = Some(WhoMove {
+            color: record_move.color,
+            since: tokio::time::Instant::now(),
+            complete: None,
+            had_input: false,
+        });
+        if let Err(e) = game.apply_move(&record_move.mv) {
+            anyhow::bail!("ply {} not replayed {:?}", i + 1, e);
+        }
+        game.update_players_states();
+        game.moves.push(record_move.clone());
+        let player = game.player_mut(&record_move.color);
+        player.time_remaining = clock_after(player.time_remaining, record_move.time_used);
+        for color in &record.eliminated {
+            let moves_later = record.moves[i + 1..].iter().any(|m| m.color == *color);
+            if !moves_later && !game.eliminated.contains(color) {
+                game.eliminate(*color);
+            }
+        }
+    }
+    game.who_move = None;
+    Ok(game)
+}
+
+// Moves, changed cells, clocks and players states between the plies, one line each
+fn game_diff(record: &GameSnapshot, from: usize, to: usize) -> Result<Vec<String>> {
+    let before = replay_plies(record, from)?;
+    let after = replay_plies(record, to)?;
+    let mut lines = Vec::new();
+    for (i, mv) in record.moves.iter().enumerate().take(to).skip(from) {
+        lines.push(format!(
+            "ply {} {} {}",
+            i + 1,
+            mv.color.to_string().to_lowercase(),
+            pgn4::notation(mv)
+        ));
+    }
+
+    let cell = |game: &Game, pos: Position| match game.board.piece(pos) {
+        Some(piece) => format!(
+            "{} {}{}",
+            piece.color.to_string().to_lowercase(),
+            format!("{:?}", piece.figure).to_lowercase(),
+            if piece.stone { " stone" } else { "" }
+        ),
+        None => "empty".to_string(),
+    };
+    for pos in Position::into_enum_iter() {
+        let (was, is) = (cell(&before, pos), cell(&after, pos));
+        if was != is {
+            lines.push(format!("{}: {} -> {}", pos, was, is));
+        }
+    }
+
+    for color in [Color::Red, Color::Blue, Color::Yellow, Color::Green] {
+        let (was, is) = (before.player(&color), after.player(&color));
+        let name = color.to_string().to_lowercase();
+        if was.time_remaining != is.time_remaining {
+            lines.push(format!(
+                "{} clock {:.1}s -> {:.1}s",
+                name,
+                was.time_remaining.as_secs_f64(),
+                is.time_remaining.as_secs_f64()
+            ));
+        }
+        if was.state != is.state {
+            lines.push(format!(
+                "{} {} -> {}",
+                name,
+                was.state.name(),
+                is.state.name()
+            ));
+        }
+    }
+    Ok(lines)
+}
+
+// Feature flags of new games, running games keep the ones they started with
+async fn process_admin_feature_flags(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    token: &str,
+    flag: Option<&Flag>,
+) -> Result<()> {
+    let error =
+        |e: AdminError| Pdu::Admin(Admin::FeatureFlags(FeatureFlags::Error(e))).to_message();
+    if !vault.tenant().is_admin(token) {
+        let resp = error(AdminError::Forbidden {
+            description: "bad admin token".to_string(),
+        })?;
+        send_msg_to!(vault, conn_id, resp);
+        return Ok(());
+    }
+
+    let resp = {
+        let mut flags_lock = vault.get_flags().await;
+        let set = flag.map(|f| flags_lock.set(f.clone()));
+        match set {
+            Some(Err(e)) => error(AdminError::BadRequest {
+                description: format!("{:#}", e),
+            })?,
+            _ => {
+                if let Some(flag) = flag {
+                    info!("Flag {:?} set by {}", flag, conn_id);
+                }
+                Pdu::Admin(Admin::FeatureFlags(FeatureFlags::Ok {
+                    flags: flags_lock.list(),
+                }))
+                .to_message()?
+            }
+        }
+    };
+    send_msg_to!(vault, conn_id, resp);
+    Ok(())
+}
+
+// Every connection with its state, for operators
+async fn process_admin_peer_list(vault: &Vault, conn_id: &ConnectionId, token: &str) -> Result<()> {
+    if !vault.tenant().is_admin(token) {
+        let resp = Pdu::Admin(Admin::PeerList(PeerList::Error(AdminError::Forbidden {
+            description: "bad admin token".to_string(),
+        })))
+        .to_message()?;
+        send_msg_to!(vault, conn_id, resp);
+        return Ok(());
+    }
+
+    let now = Instant::now();
+    let mut peers = Vec::new();
+    for (id, peer) in vault.get_peers() {
+        let peer_lock = peer.lock().await;
+        let game = match &peer_lock.state {
+            PeerState::Game { game, .. } => Some(game.clone()),
+            _ => None,
+        };
+        let mut info = PeerInfo {
+            conn_id: id.0,
+            addr: peer_lock.addr.to_string(),
+            state: peer_lock.state.name().to_string(),
+            player_name: peer_lock.player_name.clone(),
+            account: peer_lock.account.as_ref().map(|a| a.name.clone()),
+            client: peer_lock.client_info.as_ref().map(|c| c.name.clone()),
+            pool: peer_lock.pool.clone(),
+            game_id: None,
+            silent_ms: now.duration_since(peer_lock.last_activity).as_millis() as u64,
+            queued: peer_lock.tx.queued(),
+        };
+        // games are locked before peers
+        drop(peer_lock);
+        if let Some(game) = game {
+            info.game_id = Some(game.lock().await.id);
+        }
+        peers.push(info);
+    }
+    peers.sort_by_key(|p| p.conn_id);
+    let resp = Pdu::Admin(Admin::PeerList(PeerList::Ok { peers })).to_message()?;
+    send_msg_to!(vault, conn_id, resp);
+    Ok(())
+}
+
+// Active games with clocks, for operators
+async fn process_admin_game_list(vault: &Vault, conn_id: &ConnectionId, token: &str) -> Result<()> {
+    if !vault.tenant().is_admin(token) {
+        let resp = Pdu::Admin(Admin::GameList(GameList::Error(AdminError::Forbidden {
+            description: "bad admin token".to_string(),
+        })))
+        .to_message()?;
+        send_msg_to!(vault, conn_id, resp);
+        return Ok(());
+    }
+
+    let mut games = Vec::new();
+    {
+        for game in vault.get_games() {
+            let game_lock = game.lock().await;
+            games.push(GameInfo {
+                game_id: game_lock.id,
+                players: PlayersNames {
+                    red: game_lock.red.name.clone(),
+                    blue: game_lock.blue.name.clone(),
+                    yellow: game_lock.yellow.name.clone(),
+                    green: game_lock.green.name.clone(),
+                },
+                players_states: players_states(&game_lock),
+                players_clocks: players_clocks(&game_lock),
+                who_move: game_lock.who_move.as_ref().map(|wm| wm.color.to_string()),
+                turn_elapsed: game_lock
+                    .who_move
+                    .as_ref()
+                    .map_or(0, |wm| wm.since.elapsed().as_millis() as u64),
+                moves: game_lock.moves.len(),
+                subscribers: game_lock.subscribers.len(),
+                casual: game_lock.casual,
+                started: game_lock.started,
+            });
+        }
+    }
+    games.sort_by_key(|g| g.game_id);
+    let resp = Pdu::Admin(Admin::GameList(GameList::Ok { games })).to_message()?;
+    send_msg_to!(vault, conn_id, resp);
+    Ok(())
+}
+
+// End the game without result, players return to Idle with queue priority
+async fn process_admin_terminate(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    token: &str,
+    game_id: u64,
+    description: &str,
+) -> Result<()> {
+    let error = |e: AdminError| Pdu::Admin(Admin::Terminate(Terminate::Error(e))).to_message();
+    if !vault.tenant().is_admin(token) {
+        let resp = error(AdminError::Forbidden {
+            description: "bad admin token".to_string(),
+        })?;
+        send_msg_to!(vault, conn_id, resp);
+        return Ok(());
+    }
+
+    let resp = {
+        match vault.get_game(game_id) {
+            Some(game) => {
+                let mut game_lock = game.lock().await;
+                if !game_lock.terminated {
+                    game_lock.terminated = true;
+                    let pdu = Pdu::GameSession(GameSession::Terminated {
+                        game_id,
+                        description: description.to_string(),
+                    })
+                    .to_message()?;
+                    game_lock.broadcast(pdu.clone()).await?;
+                    game_lock.publish(pdu.clone(), Some(pdu), None).await;
+                    // wake up the session to stop it
+                    let _ = game_lock.move_happen_signal.unbounded_send(());
+                    info!("Game {} terminated by {}", game_id, conn_id);
+                }
+                Pdu::Admin(Admin::Terminate(Terminate::Ok { game_id })).to_message()?
+            }
+            None => error(AdminError::NotFound {
+                description: "no such game".to_string(),
+            })?,
+        }
+    };
+    send_msg_to!(vault, conn_id, resp);
+    Ok(())
+}
+
+// Close the connection, the player of active game may reconnect
+async fn process_admin_kick(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    token: &str,
+    kicked: ConnectionId,
+) -> Result<()> {
+    let error = |e: AdminError| Pdu::Admin(Admin::Kick(Kick::Error(e))).to_message();
+    if !vault.tenant().is_admin(token) {
+        let resp = error(AdminError::Forbidden {
+            description: "bad admin token".to_string(),
+        })?;
+        send_msg_to!(vault, conn_id, resp);
+        return Ok(());
+    }
+
+    let found = match vault.get_peer(&kicked) {
+        Some(peer) => {
+            peer.lock().await.tx.close_channel();
+            true
+        }
+        None => false,
+    };
+    let resp = if found {
+        info!("{} kicked by {}", kicked, conn_id);
+        Pdu::Admin(Admin::Kick(Kick::Ok { conn_id: kicked.0 })).to_message()?
+    } else {
+        error(AdminError::NotFound {
+            description: "no such connection".to_string(),
+        })?
+    };
+    if kicked != *conn_id {
+        send_msg_to!(vault, conn_id, resp);
+    }
+    Ok(())
+}
+
+// Notice to every connected peer, e.g. before maintenance
+async fn process_admin_broadcast(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    token: &str,
+    text: &str,
+) -> Result<()> {
+    if !vault.tenant().is_admin(token) {
+        let resp = Pdu::Admin(Admin::Broadcast(Broadcast::Error(AdminError::Forbidden {
+            description: "bad admin token".to_string(),
+        })))
+        .to_message()?;
+        send_msg_to!(vault, conn_id, resp);
+        return Ok(());
+    }
+
+    let notice = Pdu::Notice(Notice {
+        text: text.to_string(),
+    })
+    .to_message()?;
+    let mut sent = 0;
+    {
+        for (_, peer) in vault.get_peers() {
+            if peer.lock().await.tx.send(notice.clone()).is_ok() {
+                sent += 1;
+            }
+        }
+    }
+    info!("Notice sent to {} peers by {}", sent, conn_id);
+    let resp = Pdu::Admin(Admin::Broadcast(Broadcast::Ok { peers: sent })).to_message()?;
+    send_msg_to!(vault, conn_id, resp);
+    Ok(())
+}
+
+// Chat of the player is dropped in every game, the mute outlives reconnects
+async fn process_admin_chat_mute(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    token: &str,
+    player: &str,
+    seconds: u64,
+) -> Result<()> {
+    let resp = if !vault.tenant().is_admin(token) {
+        ChatMute::Error(AdminError::Forbidden {
+            description: "bad admin token".to_string(),
+        })
+    } else {
+        let until = (seconds > 0).then(|| Instant::now() + Duration::from_secs(seconds));
+        vault.mute(player, until);
+        info!(
+            "player \"{}\" muted for {} seconds by {}",
+            player, seconds, conn_id
+        );
+        ChatMute::Ok {
+            player: player.to_string(),
+            seconds,
+        }
+    };
+    let resp = Pdu::Admin(Admin::ChatMute(resp)).to_message()?;
+    send_msg_to!(vault, conn_id, resp);
+    Ok(())
+}
+
+// Tournament open for registration until its first round, knockout plays no set number of rounds
+async fn process_admin_create_tournament(
+    vault: &Vault,
+    conn_id: &ConnectionId,
+    request: &CreateTournament,
+) -> Result<()> {
+    let (token, name, format, rounds, time_control, starts_at, reconnect_grace) = match request {
+        CreateTournament::Request {
+            token,
+            name,
+            format,
+            rounds,
+            time_control,
+            starts_at,
+            reconnect_grace,
+        } => (
+            token,
+            name,
+            *format,
+            *rounds,
+            *time_control,
+            *starts_at,
+            *reconnect_grace,
+        ),
+        _ => return Ok(()),
+    };
+    let error = |e: AdminError| {
+        Pdu::Admin(Admin::CreateTournament(CreateTournament::Error(e))).to_message()
+    };
+    let resp = if !vault.tenant().is_admin(token) {
+        error(AdminError::Forbidden {
+            description: "bad admin token".to_string(),
+        })?
+    } else if name.is_empty() {
+        error(AdminError::BadRequest {
+            description: "tournament needs a name".to_string(),
+        })?
+    } else if format == TournamentFormat::Swiss && rounds == 0 {
+        error(AdminError::BadRequest {
+            description: "swiss tournament needs rounds".to_string(),
+        })?
+    } else {
+        let rounds = match format {
+            TournamentFormat::Swiss => rounds,
+            TournamentFormat::Knockout => 0,
+        };
+        let time_control = match time_control {
+            0 => DEFAULT_TIME_CONTROL,
+            seconds => seconds,
+        };
+        let reconnect_grace = match reconnect_grace {
+            0 => TOURNAMENT_RECONNECT_GRACE,
+            seconds => seconds,
+        };
+        let info = vault
+            .get_tournaments()
+            .await
+            .create(
+                name,
+                format,
+                rounds,
+                time_control,
+                starts_at,
+                reconnect_grace,
+            )
+            .info();
+        info!(
+            "Tournament {} \"{}\" created by {}",
+            info.tournament_id, name, conn_id
+        );
+        Pdu::Admin(Admin::CreateTournament(CreateTournament::Ok(info))).to_message()?
+    };
+    send_msg_to!(vault, conn_id, resp);
+    Ok(())
+}
+
+// Token bucket of the peer, flooding client is told to slow down and then disconnected
+async fn admit_message(vault: &Vault, conn_id: &ConnectionId) -> bool {
+    let peer = match vault.get_peer(conn_id) {
+        Some(peer) => peer,
+        None => return false,
+    };
+    let mut peer_lock = peer.lock().await;
+    // rest of the flood read before the connection is closed
+    if peer_lock.tx.is_closed() {
+        return false;
+    }
+    let disconnected = match peer_lock.bucket.admit(&RATE_LIMIT, Instant::now()) {
+        Admission::Accept => return true,
+        Admission::Drop { first: false } => return false,
+        Admission::Drop { first: true } => false,
+        Admission::Disconnect => true,
+    };
+    let resp = Pdu::RateLimited(RateLimited {
+        description: if disconnected {
+            "too many messages, disconnected".to_string()
+        } else {
+            "too many messages, slow down".to_string()
+        },
+        retry_after: (1000.0 / RATE_LIMIT.per_second).ceil() as u64,
+        disconnected,
+    })
+    .to_message();
+    match resp {
+        Ok(resp) => {
+            if let Err(e) = peer_lock.tx.send(resp) {
+                error!("send failed \"{}\"", e);
+            }
+        }
+        Err(e) => error!("RateLimited pdu serialization failed \"{}\"", e),
+    }
+    if disconnected {
+        info!("{} ({}) floods, disconnecting", conn_id, peer_lock.addr);
+        peer_lock.tx.close_channel();
+    }
+    false
+}
+
+// raw stream is plain TCP or TLS over it
+#[instrument(name = "connection", skip_all, fields(%addr))]
+// the handshake callback must return the error response tungstenite defines
+#[allow(clippy::result_large_err)]
+async fn handle_connection<S>(vault: Vault, mut raw_stream: S, addr: SocketAddr, deflate: bool)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    debug!("Incoming TCP connection from: {}", addr);
+
+    if !deflate {
+        match tokio_tungstenite::accept_async(raw_stream).await {
+            Ok(ws_stream) => serve_websocket(vault, ws_stream, addr).await,
+            Err(e) => handshake_failed(addr, e),
+        }
+        return;
+    }
+
+    // tungstenite 0.12 knows no permessage-deflate, the extension agreed in the handshake is
+    // served by the relay between the socket and tungstenite on the other end of a pipe
+    let mut negotiated = false;
+    let handshake = tokio_tungstenite::accept_hdr_async(
+        &mut raw_stream,
+        |request: &ws_server::Request, mut response: ws_server::Response| {
+            negotiated = deflate::negotiate(request, &mut response);
+            Ok(response)
+        },
+    )
+    .await;
+    if let Err(e) = handshake {
+        handshake_failed(addr, e);
+        return;
+    }
+    if !negotiated {
+        let ws_stream = WebSocketStream::from_raw_socket(raw_stream, Role::Server, None).await;
+        serve_websocket(vault, ws_stream, addr).await;
+        return;
+    }
+    let (pipe, relay_end) = tokio::io::duplex(DEFLATE_PIPE_CAPACITY);
+    let ws_stream = WebSocketStream::from_raw_socket(pipe, Role::Server, None).await;
+    future::join(
+        serve_websocket(vault, ws_stream, addr),
+        deflate::relay(raw_stream, relay_end),
+    )
+    .await;
+}
+
+fn handshake_failed(addr: SocketAddr, e: tungstenite::Error) {
+    error!(
+        "Error during the websocket handshake occurred from \"{}\" \"{}\"",
+        addr, e
+    );
+}
+
+async fn serve_websocket<S>(vault: Vault, ws_stream: WebSocketStream<S>, addr: SocketAddr)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (outgoing, incoming) = ws_stream.split();
+    // broken connection ends the session like the closed one
+    let incoming = incoming
+        .take_while(|msg| future::ready(msg.is_ok()))
+        .filter_map(|msg| future::ready(msg.ok()));
+    serve_peer(vault, incoming, outgoing, addr).await;
+}
+
+// Peer of the new connection before the handshake
+fn new_peer(tx: Tx, addr: SocketAddr) -> Peer {
+    Peer {
+        tx,
+        addr,
+        player_name: None,
+        state: PeerState::Unknown(Instant::now()),
+        client_info: None,
+        queue_priority_until: None,
+        last_activity: Instant::now(),
+        last_request: Instant::now(),
+        pool: None,
+        variant: Variant::Ffa,
+        time_control: DEFAULT_TIME_CONTROL,
+        account: None,
+        bucket: TokenBucket::new(&RATE_LIMIT),
+        queued_at: None,
+        merged: None,
+        bot_fill: false,
+        filter_strikes: 0,
+        muted_until: None,
+        ping_sent: None,
+        rtt: None,
+    }
+}
+
+// Session of the peer over any transport, the WebSocket or channels of the tests.
+// Incoming messages are processed one by one, messages to the peer are forwarded
+// to outgoing until either side ends, then the peer leaves its game and lobby.
+async fn serve_peer<I, O>(vault: Vault, incoming: I, outgoing: O, addr: SocketAddr)
+where
+    I: Stream<Item = Message>,
+    O: Sink<Message>,
+{
+    let conn_id = vault.next_connection_id();
+    info!("Connection {} established from: {}", conn_id, addr);
+
+    let (tx, rx) = peer_channel(PEER_QUEUE_LIMIT);
+    if vault.try_insert_peer(conn_id, new_peer(tx, addr)).is_err() {
+        error!("Duplicate connection insert \"{}\"", conn_id);
+    }
+
+    let broadcast_incoming = incoming.fold((&conn_id, &vault), |arg, msg| async move {
+        receive_msg(arg.1, arg.0, msg).await;
+        arg
+    });
+
+    let receive_from_others = rx.map(Ok).forward(outgoing);
+
+    pin_mut!(broadcast_incoming, receive_from_others);
+    future::select(broadcast_incoming, receive_from_others).await;
+
+    debug!("{} disconnected", &conn_id);
+    if let Err(e) = process_gs_disconnect(&vault, &conn_id).await {
+        error!("disconnect from game failed \"{}\"", e);
+    }
+    if let Err(e) = process_lobby_leave(&vault, &conn_id).await {
+        error!("leave lobby on disconnect failed \"{}\"", e);
+    }
+    vault.remove_peer(&conn_id).await;
+}
+
+// Parse and process the message of the peer
+async fn receive_msg(vault: &Vault, conn_id: &ConnectionId, msg: Message) {
+    vault
+        .touch_peer(conn_id, !msg.is_ping() && !msg.is_pong(), msg.is_pong())
+        .await;
+    if !admit_message(vault, conn_id).await {
+        return;
+    }
+    // answers to our pings only prove the client is alive,
+    // pings of the client are answered by tungstenite itself
+    if msg.is_ping() || msg.is_pong() {
+        return;
+    }
+    let pdu = Pdu::from_message(&msg);
+    match &msg {
+        Message::Binary(bytes) => debug!("Received binary message from {}: {:?}", conn_id, bytes),
+        _ => debug!("Received raw message from {}: \"{}\"", conn_id, msg),
+    }
+    match pdu {
+        Ok(p) => {
+            debug!("Parsed pdu: {:?}", p);
+            if let Err(e) = process_msg(&p, vault, conn_id).await {
+                error!("Error while process_msg() {}", e);
+            }
+        }
+        Err(e) => {
+            error!(
+                "Parsing received message from peer {} failed with message \"{}\"",
+                conn_id, e
+            );
+        }
+    }
+}
+
+// Export the update to analysis engines and spectators, slow spectators get clocks only
+// Update to the players, with seats next to the colors to the clients which take them
+async fn broadcast_update(game: &Game, update: &Update) -> Result<()> {
+    let plain = Pdu::GameSession(GameSession::Update(update.clone())).to_message()?;
+    let labeled = Pdu::GameSession(GameSession::Update(update.with_seats())).to_message()?;
+    for player in game.players().into_iter().filter(|p| !p.bot) {
+        let peer_lock = player.peer.lock().await;
+        let message = if peer_lock.seat_labels() {
+            &labeled
+        } else {
+            &plain
+        };
+        let _ = peer_lock.tx.send(message.clone());
+    }
+    Ok(())
+}
+
+async fn publish_update(game: &mut Game, update: Update) -> Result<()> {
+    let clocks = Pdu::Analysis(Analysis::Clocks {
+        game_id: game.id,
+        players_clocks: update.players_clocks.clone(),
+    })
+    .to_message()?;
+    let export = Pdu::Analysis(Analysis::GameUpdate {
+        game_id: game.id,
+        update,
+    })
+    .to_message()?;
+    game.set_state(game_state(game));
+    game.publish(export, Some(clocks), None).await;
+    Ok(())
+}
+
+// effects of the last recorded move, none when the turn was skipped
+fn move_effects(game: &Game, move_previous: &Move) -> MoveEffects {
+    match game.moves.last() {
+        Some(record) if record.mv == *move_previous => record.effects(),
+        _ => MoveEffects::default(),
+    }
+}
+
+// Apply the move the current player completed, returns the move for Update
+// or NoMove if the board rejected it
+fn apply_complete_move(game: &mut Game) -> Move {
+    let who_move = game.who_move.as_ref().unwrap();
+    let complete = who_move.complete.as_ref().unwrap();
+    let (mv, at) = (complete.mv.clone(), complete.at);
+    let used = at.duration_since(who_move.since);
+    let player = game.current_move_player_mut().unwrap();
+    // the clock ran out before the move was made
+    if used >= player.time_remaining + PLAYER_TIME_2 {
+        player.time_remaining = Duration::ZERO;
+        let color = player.color;
+        game.eliminate(color);
+        return Move::NoMove {};
+    }
+    let (move_previous, captured) = match game.apply_move(&mv) {
+        Ok(captured) => (mv, captured),
+        Err(e) => {
+            error!("game {} move not applied {:?}", game.id, e);
+            (Move::NoMove {}, None)
+        }
+    };
+    game.update_players_states();
+    if !matches!(move_previous, Move::NoMove {}) {
+        game.record_move(move_previous.clone(), captured, at);
+        debug!("game {} position {:016x}", game.id, game.position_key());
+    }
+    let player = game.current_move_player_mut().unwrap();
+    player.afk_count = 0;
+    player.time_remaining = clock_after(player.time_remaining, used);
+    move_previous
+}
+
+// Clock of the player after the move, the first PLAYER_TIME_2 of every move are free
+fn clock_after(time_remaining: Duration, used: Duration) -> Duration {
+    time_remaining.saturating_sub(used.saturating_sub(PLAYER_TIME_2))
+}
+
+// Until when the move of the player to move waits, his last move was accepted too recently
+fn move_hold(
+    game: &Game,
+    last_moves: &[(Color, tokio::time::Instant)],
+) -> Option<tokio::time::Instant> {
+    let color = game.who_move.as_ref()?.color;
+    let (_, last) = last_moves.iter().find(|(c, _)| *c == color)?;
+    let until = *last + *MIN_MOVE_INTERVAL;
+    (until > tokio::time::Instant::now()).then_some(until)
+}
+
+fn accept_move(game: &Game, last_moves: &mut Vec<(Color, tokio::time::Instant)>) {
+    let color = game.current_move_player().unwrap().color;
+    last_moves.retain(|(c, _)| *c != color);
+    last_moves.push((color, tokio::time::Instant::now()));
+}
+
+// Tick every second of the pause before the first move,
+// the pause ends early once all players confirmed they are ready
+async fn countdown(
+    vault: &Vault,
+    move_received: &mut UnboundedReceiver<()>,
+    game_id: u64,
+    pause: Duration,
+) -> Result<()> {
+    let deadline = tokio::time::Instant::now() + pause;
+    let mut last_tick = None;
+    loop {
+        let left = deadline.saturating_duration_since(tokio::time::Instant::now());
+        let seconds = (left.as_millis() as u64).div_ceil(1000);
+        {
+            let game = vault
+                .get_game(game_id)
+                .context("game_session game lookup failed")?;
+            let mut game_lock = game.lock().await;
+            game_lock.ready.get_or_insert_with(Vec::new);
+            if seconds == 0 || game_lock.terminated || game_lock.all_ready() {
+                return Ok(());
+            }
+            if last_tick != Some(seconds) {
+                let tick = Pdu::GameSession(GameSession::CountdownTick { game_id, seconds })
+                    .to_message()?;
+                game_lock.broadcast(tick).await?;
+                last_tick = Some(seconds);
+            }
+        }
+        let next_tick = tokio::time::sleep_until(deadline - Duration::from_secs(seconds - 1));
+        pin_mut!(next_tick);
+        // woken up by every ready player
+        future::select(next_tick, move_received.next()).await;
+    }
+}
+
+// Since when the player to move is disconnected, bots never are
+async fn absent_since(game: &Game) -> Option<Instant> {
+    let player = game.current_move_player()?;
+    if player.bot {
+        return None;
+    }
+    match player.peer.lock().await.state {
+        PeerState::Unknown(since) => Some(since),
+        _ => None,
+    }
+}
+
+// Since when the player to move is disconnected if the game holds his seat meanwhile
+async fn held_seat(game: &Game) -> Option<tokio::time::Instant> {
+    let policy = &game.disconnect_policy;
+    if !policy.hold_seat {
+        return None;
+    }
+    match absent_since(game).await {
+        Some(since) if since.elapsed() < policy.grace => {
+            Some(tokio::time::Instant::from_std(since))
+        }
+        _ => None,
+    }
+}
+
+#[instrument(skip(vault, move_received, pause))]
+async fn move_call_dispatch(
+    vault: Vault,
+    mut move_received: UnboundedReceiver<()>,
+    game_id: u64,
+    pause: Duration,
+) -> Result<()> {
+    let mut player_time_remaining;
+    // the player is not flagged while the move may still be on the way,
+    // by the round trip measured when the turn began
+    let mut player_allowance;
+    let mut turn_since;
+    let mut afk_check = true;
+    // the turn is paused since then, the seat of the disconnected player is held
+    let mut held_since: Option<tokio::time::Instant> = None;
+    let afk_policy;
+    let stalemate_rule;
+    // server time of the last accepted move of every player
+    let mut last_moves: Vec<(Color, tokio::time::Instant)> = Vec::new();
+    // the received move waits until then, premoves are not played faster than the interval
+    let mut move_held: Option<tokio::time::Instant> = None;
+
+    countdown(&vault, &mut move_received, game_id, pause).await?;
+
+    // after countdown broadcast first update
+    {
+        let game = vault
+            .get_game(game_id)
+            .context("game_session game lookup failed")?;
+        let mut game_lock = game.lock().await;
+        if game_lock.terminated {
+            return Ok(());
+        }
+        // signals of the last ready players must not be taken for a move
+        game_lock.ready = None;
+        while move_received.try_recv().is_ok() {}
+
+        // who_move is already set when the game was restored from snapshot
+        let first_moved_color = match &game_lock.who_move {
+            Some(wm) => wm.color,
+            None => game_lock.next_moved_player_mut().unwrap().color,
+        };
+        player_time_remaining = game_lock.player(&first_moved_color).time_remaining;
+        player_allowance =
+            latency_allowance(&*game_lock.player(&first_moved_color).peer.lock().await);
+        afk_policy = game_lock.afk_policy.clone();
+        stalemate_rule = game_lock.stalemate_rule;
+        turn_since = tokio::time::Instant::now();
+
+        game_lock.who_move = Some(WhoMove {
+            color: first_moved_color,
+            since: turn_since,
+            complete: None,
+            had_input: false,
+        });
+        game_lock.take_snapshot();
+        log_turn(
+            &vault,
+            game_id,
+            &WalEntry::Snapshot(Box::new(game_lock.record())),
+        )?;
+
+        let update = Update {
+            move_call: MoveCall::Call {
+                player: first_moved_color.to_string(),
+                timer: player_time_remaining.as_secs(),
+                timer_2: PLAYER_TIME_2.as_secs(),
+                seat: None,
+            },
+            move_previous: Move::NoMove {},
+            move_effects: MoveEffects::default(),
+            players_states: players_states(&game_lock),
+            players_clocks: players_clocks(&game_lock),
+            checksum: game_lock.fen4().checksum(),
+            players_points: players_points(&game_lock),
+            seq: game_lock.turn,
+        };
+
+        broadcast_update(&game_lock, &update).await?;
+        publish_update(&mut game_lock, update).await?;
+        call_bot(&vault, &game_lock);
+    }
+
+    // Process player move and timeout
+    loop {
+        let flag_deadline = turn_since + player_time_remaining + PLAYER_TIME_2 + player_allowance;
+        // player without any input during the turn is considered afk before his time ends
+        let deadline = if afk_check {
+            flag_deadline.min(turn_since + afk_policy.turn_timeout)
+        } else {
+            flag_deadline
+        };
+        let turn_deadline = deadline;
+        let deadline = deadline.min(tokio::time::Instant::now() + GS_DISCONNECT_CHECK_PERIOD);
+        let move_timeout = tokio::time::sleep_until(deadline);
+        pin_mut!(move_timeout);
+
+        // false on move timeout, true on move message
+        let moved = match move_held.take() {
+            Some(until) => {
+                tokio::time::sleep_until(until).await;
+                // the player replaced his move meanwhile, it is applied once
+                while move_received.try_recv().is_ok() {}
+                true
+            }
+            None => matches!(
+                future::select(move_timeout, move_received.next()).await,
+                Either::Right(_)
+            ),
+        };
+        {
+            let game = vault
+                .get_game(game_id)
+                .context("game_session game lookup failed")?;
+            let mut game_lock = game.lock().await;
+            // players were told by the operator request
+            if game_lock.terminated {
+                return Ok(());
+            }
+
+            let mut move_previous = Move::NoMove {};
+            match moved {
+                // woken up by the last vote
+                _ if game_lock.end == GameEnd::DrawAgreed => game_lock.agree_draw(),
+                _ if game_lock.end == GameEnd::Adjudicated => game_lock.adjudicate(),
+                // the clock stands still while the game is paused
+                _ if game_lock.pause != GamePause::Running => {
+                    let now = tokio::time::Instant::now();
+                    let from = held_since.unwrap_or(now);
+                    turn_since += now - from;
+                    if let Some(who_move) = game_lock.who_move.as_mut() {
+                        who_move.since += now - from;
+                    }
+                    held_since = Some(now);
+                    if game_lock.pause == GamePause::Resuming {
+                        drop(game_lock);
+                        countdown(&vault, &mut move_received, game_id, GS_INIT_PAUSE).await?;
+                        let mut game_lock = game.lock().await;
+                        game_lock.ready = None;
+                        while move_received.try_recv().is_ok() {}
+                        let now = tokio::time::Instant::now();
+                        let from = held_since.take().unwrap_or(now);
+                        turn_since += now - from;
+                        if let Some(who_move) = game_lock.who_move.as_mut() {
+                            who_move.since += now - from;
+                        }
+                        game_lock.pause = GamePause::Running;
+                        let resumed =
+                            Pdu::GameSession(GameSession::Pause(Pause::State { paused: false }))
+                                .to_message()?;
+                        game_lock.broadcast(resumed).await?;
+                        info!("Game {} resumed", game_id);
+                        call_bot(&vault, &game_lock);
+                    }
+                    continue;
+                }
+                // when timeout
+                false => {
+                    //let who_move = game_lock.who_move.as_ref().unwrap();
+                    //let color = game_lock.who_move.as_ref().unwrap().color.clone();
+                    /* This block prevent situation when
+                    process_move_make receive move message
+                    process_move_make lock game mutex
+                    process_move_make send message over channel
+                    move_call_dispatch select move_timeout
+                    move_call_dispatch wait lock game mutex
+                    process_move_make release lock
+                    move_call_dispatch lock game mutex
+                    move_call_dispatch loop to next iteration
+                        and get move_received from past turn */
+                    let now = tokio::time::Instant::now();
+                    let held = held_seat(&game_lock).await;
+                    if game_lock.who_move.as_ref().unwrap().complete.is_some() {
+                        // important next!
+                        move_received.next().await;
+                        move_held = move_hold(&game_lock, &last_moves);
+                        if move_held.is_some() {
+                            continue;
+                        }
+                        move_previous = apply_complete_move(&mut game_lock);
+                        accept_move(&game_lock, &mut last_moves);
+                    } else if held.is_some() || held_since.is_some() {
+                        // time of the pause is given back, also when the player is back
+                        let from = held_since
+                            .or(held.map(|since| turn_since.max(since)))
+                            .unwrap_or(now);
+                        turn_since += now - from;
+                        if let Some(who_move) = game_lock.who_move.as_mut() {
+                            who_move.since += now - from;
+                        }
+                        held_since = held.map(|_| now);
+                        continue;
+                    } else if let Some(since) = absent_since(&game_lock).await {
+                        // turns of the disconnected player do not wait for him, nor cost his time,
+                        // once the grace is over he has lost
+                        if since.elapsed() >= game_lock.disconnect_policy.grace {
+                            let color = game_lock.current_move_player().unwrap().color;
+                            info!(
+                                "{} of game {} did not reconnect",
+                                color.to_string(),
+                                game_id
+                            );
+                            game_lock.eliminate(color);
+                        }
+                    } else if now < turn_deadline {
+                        // only the disconnected players were checked
+                        continue;
+                    } else if now >= flag_deadline {
+                        let player = game_lock.current_move_player_mut().unwrap();
+                        player.time_remaining = Duration::from_secs(0);
+                        let color = player.color;
+                        game_lock.eliminate(color);
+                    } else {
+                        afk_check = false;
+                        if game_lock.who_move.as_ref().unwrap().had_input {
+                            // not afk, just thinking, wait until his time ends
+                            continue;
+                        }
+                        // afk player skips the turn, warned before auto-resign
+                        let player = game_lock.current_move_player_mut().unwrap();
+                        player.afk_count += 1;
+                        if player.afk_count >= afk_policy.resign_after {
+                            let color = player.color;
+                            game_lock.eliminate(color);
+                        } else if player.afk_count >= afk_policy.warn_after {
+                            let warning = Pdu::GameSession(GameSession::AfkWarning {
+                                description: "you missed your turns, move or resign".to_string(),
+                                turns_before_resign: (afk_policy.resign_after - player.afk_count)
+                                    as u64,
+                            })
+                            .to_message()?;
+                            if let Err(e) = player.peer.lock().await.tx.send(warning) {
+                                debug!("afk warning not delivered \"{}\"", e);
+                            }
+                        }
+                    }
+                }
+                // when move received
+                true => {
+                    move_held = move_hold(&game_lock, &last_moves);
+                    if move_held.is_some() {
+                        continue;
+                    }
+                    move_previous = apply_complete_move(&mut game_lock);
+                    accept_move(&game_lock, &mut last_moves);
+                }
+            }
+
+            // the turn of the received move is traced from its frame, other turns in the game
+            let turn_span = game_lock
+                .who_move
+                .as_ref()
+                .and_then(|w| w.complete.as_ref())
+                .map(|c| c.span.clone())
+                .filter(|span| !span.is_none())
+                .unwrap_or_else(Span::current);
+            held_since = None;
+            let mut move_call = MoveCall::NoCall {};
+            let mut skipped = Vec::new();
+
+            // find first no lost state player
+            // if he checknmate, lost him, if stalemate, apply stalemate rule
+            while let Some(player) = game_lock.next_moved_player_mut() {
+                match player.state {
+                    PlayerState::Checkmate | PlayerState::Lost => {
+                        let (color, mated) = (player.color, player.state == PlayerState::Checkmate);
+                        if mated {
+                            game_lock.award_checkmate(color);
+                        }
+                        game_lock.eliminate(color);
+                    }
+                    // never called, the game is already over
+                    PlayerState::Draw => break,
+
+                    PlayerState::Stalemate => match stalemate_rule {
+                        StalemateRule::Eliminate => {
+                            let color = player.color;
+                            game_lock.eliminate(color);
+                        }
+                        StalemateRule::Skip => {
+                            let color = player.color;
+                            // nobody can move anymore
+                            if skipped.contains(&color) {
+                                game_lock.declare_draw();
+                                break;
+                            }
+                            skipped.push(color);
+                            game_lock.who_move = Some(WhoMove {
+                                color,
+                                since: tokio::time::Instant::now(),
+                                complete: None,
+                                had_input: false,
+                            });
+                        }
+                        StalemateRule::Draw => {
+                            game_lock.declare_draw();
+                            break;
+                        }
+                    },
+
+                    PlayerState::NoState | PlayerState::Check => {
+                        player_time_remaining = player.time_remaining;
+                        player_allowance = latency_allowance(&*player.peer.lock().await);
+                        move_call = MoveCall::Call {
+                            player: player.color.clone().to_string(),
+                            timer: player.time_remaining.as_secs(),
+                            timer_2: PLAYER_TIME_2.as_secs(),
+                            seat: None,
+                        };
+                        turn_since = tokio::time::Instant::now();
+                        afk_check = true;
+                        game_lock.who_move = Some(WhoMove {
+                            color: player.color.clone(),
+                            since: turn_since,
+                            complete: None,
+                            had_input: false,
+                        });
+                        break;
+                    }
+                }
+            }
+
+            // bots do not play on without humans
+            if !move_call.is_no_call()
+                && game_lock
+                    .players()
+                    .iter()
+                    .all(|p| p.bot || !p.state.in_game())
+            {
+                game_lock.declare_draw();
+                move_call = MoveCall::NoCall {};
+            }
+            if !move_call.is_no_call() {
+                game_lock.best_move = None;
+                update_adjudication(&mut game_lock).await?;
+            } else {
+                game_lock.award_survivor();
+            }
+            let delta = game_lock.end_turn();
+            log_turn(&vault, game_id, &WalEntry::Turn(delta))?;
+
+            let update = Update {
+                move_call: move_call.clone(),
+                move_effects: move_effects(&game_lock, &move_previous),
+                move_previous,
+                players_states: players_states(&game_lock),
+                players_clocks: players_clocks(&game_lock),
+                checksum: game_lock.fen4().checksum(),
+                players_points: players_points(&game_lock),
+                seq: game_lock.turn,
+            };
+
+            let span = info_span!(parent: &turn_span, "broadcast", game_id, seq = update.seq);
+            async {
+                broadcast_update(&game_lock, &update).await?;
+                publish_update(&mut game_lock, update).await
+            }
+            .instrument(span)
+            .await?;
+            call_bot(&vault, &game_lock);
+
+            if move_call.is_no_call() {
+                let rating_deltas = rate_game(&game_lock, &mut *vault.get_ratings().await);
+                let finish = Pdu::GameSession(GameSession::Finish(Finish {
+                    game_id,
+                    summaries: players_summaries(&game_lock, &rating_deltas),
+                    end: game_lock.end,
+                }))
+                .to_message()?;
+                game_lock.broadcast(finish).await?;
+                game_lock.who_move = None;
+                vault.get_records().await.insert(game_lock.record());
+                let players = game_lock
+                    .players()
+                    .iter()
+                    .filter(|p| !p.bot)
+                    .map(|p| p.name.clone())
+                    .collect::<Vec<_>>();
+                review_fair_play(&vault, &players).await;
+                // finished game has nothing to restore
+                game_lock.snapshot = None;
+                break;
+            }
+        }
+
+        /*if game_lock.next_moved_player_mut().is_none() {
+            break;
+        }*/
+
+        // if player timeout
+        /*if let Either::Right(b) = branch {
+            let b = b.clone();
+            move_received.close();
+            let player = game_lock.current_move_player_mut();
+            println!("{:?}", b);
+            //player
+        }*/
+
+        //println!("{:?}", branch);
+    }
+
+    Ok(())
+}
+
+// Spawn the move of the bot called to move
+fn call_bot(vault: &Vault, game: &Game) {
+    let color = match &game.who_move {
+        Some(wm) if game.player(&wm.color).bot => wm.color,
+        _ => return,
+    };
+    let (vault, game_id, turn) = (vault.clone(), game.id, game.turn);
+    tokio::spawn(async move {
+        if let Err(e) = bot_move(vault, game_id, turn, color).await {
+            error!("game {} bot move failed \"{}\"", game_id, e);
+        }
+    });
+}
+
+fn bot_called(game: &Game, turn: u64, color: Color) -> bool {
+    let called = matches!(&game.who_move, Some(wm) if wm.color == color && wm.complete.is_none());
+    !game.terminated && game.turn == turn && called && game.pause == GamePause::Running
+}
+
+// Best move of the engine in its notation, the engine is started on the first use
+async fn engine_best_move(command: &str, fen: &Fen4, level: u8) -> Result<String> {
+    let mut engine = ENGINE.lock().await;
+    if engine.is_none() {
+        *engine = Some(Engine::start(command).await?);
+    }
+    let best = engine.as_mut().unwrap().best_move(fen, level).await;
+    if best.is_err() {
+        // dropped engine is killed
+        *engine = None;
+    }
+    best
+}
+
+// Bot completes the move like a player does, nothing if the turn is already over.
+// The engine thinks without the game lock, built-in bot moves if it fails.
+async fn bot_move(vault: Vault, game_id: u64, turn: u64, color: Color) -> Result<()> {
+    tokio::time::sleep(BOT_MOVE_DELAY).await;
+    let (game, level, fen) = {
+        let game = match vault.get_game(game_id) {
+            Some(game) => game,
+            None => return Ok(()),
+        };
+        let game_lock = game.lock().await;
+        if !bot_called(&game_lock, turn, color) {
+            return Ok(());
+        }
+        let (level, fen) = (game_lock.bot_level, game_lock.fen4());
+        drop(game_lock);
+        (game, level, fen)
+    };
+    let answer = match BOT_ENGINE.as_ref() {
+        Some(command) if level > 0 => match engine_best_move(command, &fen, level).await {
+            Ok(answer) => Some(answer),
+            Err(e) => {
+                error!("game {} engine failed \"{:#}\"", game_id, e);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let mut game_lock = game.lock().await;
+    if !bot_called(&game_lock, turn, color) {
+        return Ok(());
+    }
+    game_lock.mark_input(&color);
+    let engine_move = answer.and_then(|answer| {
+        let mv = bot::engine_move(&mut game_lock, color, &answer);
+        if mv.is_none() {
+            error!("game {} engine move {:?} is illegal", game_id, answer);
+        }
+        mv
+    });
+    if let Some(mv) = engine_move.or_else(|| bot::choose_move(&mut game_lock, color)) {
+        game_lock.who_move.as_mut().unwrap().complete = Some(Complete {
+            mv,
+            at: tokio::time::Instant::now(),
+            span: Span::current(),
+        });
+        game_lock.move_happen_signal.unbounded_send(())?;
+    }
+    Ok(())
+}
+
+// Turn must be durable before players learn about it,
+// failed write aborts the game instead of acknowledging the move
+fn log_turn(vault: &Vault, game_id: u64, entry: &WalEntry) -> Result<()> {
+    match vault.tenant().storage.as_ref() {
+        Some(storage) => storage
+            .append_wal(game_id, entry)
+            .context(format!("game {} turn log failed", game_id)),
+        None => Ok(()),
+    }
+}
+
+// Run game session dispatcher, when it panics restore the game from the last snapshot
+// and run it again. Give up after GS_MAX_RECOVERIES and return players to Idle.
+async fn game_supervisor(
+    vault: Vault,
+    move_received: UnboundedReceiver<()>,
+    game_id: u64,
+    pause: Duration,
+) {
+    let mut handle = tokio::spawn(move_call_dispatch(
+        vault.clone(),
+        move_received,
+        game_id,
+        pause,
+    ));
+    let mut recoveries = 0;
+
+    let aborted = loop {
+        match handle.await {
+            Ok(Ok(())) => break false,
+            Ok(Err(e)) => {
+                error!("game {} dispatcher failed \"{}\"", game_id, e);
+                break true;
+            }
+            Err(e) if e.is_panic() => error!("game {} dispatcher panicked", game_id),
+            Err(_) => return,
+        }
+
+        let game = match vault.get_game(game_id) {
+            Some(game) => game,
+            None => return,
+        };
+        let mut game_lock = game.lock().await;
+
+        let recovered = recoveries < GS_MAX_RECOVERIES && game_lock.restore_snapshot();
+        let internal_error = Pdu::GameSession(GameSession::InternalError {
+            description: "game session crashed".to_string(),
+            recovered,
+        })
+        .to_message();
+        match internal_error {
+            Ok(pdu) => {
+                if let Err(e) = game_lock.broadcast(pdu).await {
+                    error!("broadcast failed \"{}\"", e);
+                }
+            }
+            Err(e) => error!("InternalError pdu serialization failed \"{}\"", e),
+        }
+
+        if !recovered {
+            break true;
+        }
+        recoveries += 1;
+        let (sender, receiver) = unbounded();
+        game_lock.move_happen_signal = sender;
+        handle = tokio::spawn(move_call_dispatch(
+            vault.clone(),
+            receiver,
+            game_id,
+            GS_RECOVERY_PAUSE,
+        ));
+    };
+
+    release_game(&vault, game_id, aborted).await;
+}
+
+// Tell the players their game is lost to the server and release it
+async fn reap_game(vault: &Vault, game_id: u64) {
+    error!("game {} supervisor ended without release, reaping", game_id);
+    vault.count_dead_game();
+    if let Some(game) = vault.get_game(game_id) {
+        let game_lock = game.lock().await;
+        let internal_error = Pdu::GameSession(GameSession::InternalError {
+            description: "game session crashed".to_string(),
+            recovered: false,
+        })
+        .to_message();
+        match internal_error {
+            Ok(pdu) => {
+                if let Err(e) = game_lock.broadcast(pdu).await {
+                    error!("broadcast failed \"{}\"", e);
+                }
+            }
+            Err(e) => error!("InternalError pdu serialization failed \"{}\"", e),
+        }
+    }
+    release_game(vault, game_id, true).await;
+}
+
+// Return players of the finished game to Idle and forget the game.
+// Players get queue priority when the game was aborted by the server
+// or all their opponents vanished. Players of the game called off before
+// the first move are put back into the queue of their pool.
+async fn release_game(vault: &Vault, game_id: u64, aborted: bool) {
+    let game = match vault.remove_game(game_id) {
+        Some(game) => game,
+        None => return,
+    };
+    let game_lock = game.lock().await;
+    let aborted = aborted || game_lock.terminated;
+
+    let mut vanished = Vec::new();
+    for player in game_lock.players() {
+        if player.state == PlayerState::Lost
+            && !player.bot
+            && player.peer.lock().await.tx.is_closed()
+        {
+            vanished.push(player.color);
+        }
+    }
+    let priority_until = Instant::now() + QUEUE_PRIORITY_PERIOD;
+
+    for player in game_lock.players() {
+        vault.remove_reconnect(&player.reconnect_id);
+        let compensated = aborted
+            || game_lock
+                .players()
+                .iter()
+                .filter(|p| p.color != player.color)
+                .all(|p| vanished.contains(&p.color));
+        let requeue = game_lock.aborted_by.is_some_and(|c| c != player.color);
+        let mut peer_lock = player.peer.lock().await;
+        if peer_lock.state.is_game() {
+            peer_lock.state = PeerState::Idle;
+            peer_lock.player_name = None;
+            if compensated {
+                peer_lock.queue_priority_until = Some(priority_until);
+            }
+            if requeue && peer_lock.pool.is_some() {
+                peer_lock.player_name = Some(player.name.clone());
+                peer_lock.queued_at = Some(Instant::now());
+                peer_lock.merged = None;
+                peer_lock.state = PeerState::MMQueue;
+            }
+        }
+    }
+}
+
+/*async fn move_call_dispatch(
+    vault: Vault,
+    game_id: u64,
+    player_color: Color,
+    timeout: Duration,
+) -> Result<()> {
+    tokio::time::sleep(timeout).await;
+
+    let lock = vault.write().await;
+    let games_lock = lock.get_games().await;
+    let game = games_lock
+        .get(&game_id)
+        .context("game_session game lookup failed")?;
+    let mut game_lock = game.lock().await;
+
+    let players = game_lock.players_mut();
+
+    let lost_pdu = Pdu::GameSession(proto::GameSession::Lost {
+        player: player_color.to_string(),
+        description: "time over".to_string(),
+    })
+    .to_message()?;
+
+    for player in players {
+        if player_color == player.color {
+            player.state = PlayerState::Lost;
+            player.time_remaining = Duration::from_secs(0);
+        }
+        player
+            .peer
+            .lock()
+            .await
+            .tx
+            .unbounded_send(lost_pdu.clone())?;
+    }
+    Ok(())
+}*/
+
+// Sample the lobby population into the history and the storage
+async fn metrics_dispatcher(vault: Vault, storage: Option<Arc<Storage>>) {
+    let mut interval = time::interval(METRICS_PERIOD);
+    loop {
+        interval.tick().await;
+
+        let states = vault.count_states().await;
+        let queued = ["mm_queue", "heartbeat_wait", "heartbeat_ready"]
+            .iter()
+            .filter_map(|name| states.get(name))
+            .sum();
+        let mut games = 0;
+        let mut players = queued;
+        for game in vault.get_games() {
+            let game_lock = game.lock().await;
+            if game_lock.who_move.is_some() {
+                games += 1;
+                players += game_lock
+                    .players()
+                    .iter()
+                    .filter(|p| p.state.in_game() && !p.bot)
+                    .count();
+            }
+        }
+        let (canary_passed, canary_failed) = vault.take_canary_counts();
+        let sample = Sample {
+            at: unix_now(),
+            queued,
+            games,
+            players,
+            connections: vault.peer_count(),
+            dead_games: vault.take_dead_game_count(),
+            canary_passed,
+            canary_failed,
+        };
+        if let Some(storage) = &storage {
+            if let Err(e) = storage.append_metrics(&sample) {
+                error!("metrics sample not persisted \"{:#}\"", e);
+            }
+        }
+        vault
+            .get_metrics()
+            .await
+            .push(sample, METRICS_RETENTION.as_secs());
+    }
+}
+
+// Read-only HTTP API with aggregates of the lobby population for community admins
+async fn observer_server(vault: Vault, addr: String) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("observer API bind to \"{}\" failed \"{}\"", addr, e);
+            return;
+        }
+    };
+    info!("Observer API listening on: {}", addr);
+    while let Ok((stream, peer_addr)) = listener.accept().await {
+        let vault = vault.clone();
+        tokio::spawn(async move {
+            let answer = time::timeout(OBSERVER_TIMEOUT, observer_answer(vault, stream)).await;
+            if let Ok(Err(e)) = answer {
+                debug!("observer request of \"{}\" failed \"{}\"", peer_addr, e);
+            }
+        });
+    }
+}
+
+async fn observer_answer(vault: Vault, mut stream: tokio::net::TcpStream) -> Result<()> {
+    let request_line = http_request_line(&mut stream).await?;
+    let (status, body) = {
+        let history = vault.get_metrics().await;
+        metrics::route(&request_line, &history, unix_now())
+    };
+    http_respond(&mut stream, status, &body).await
+}
+
+// request line of the HTTP request, the rest of the head is not needed
+async fn http_request_line(stream: &mut tokio::net::TcpStream) -> Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || head.len() + n > OBSERVER_HEAD_LIMIT {
+            anyhow::bail!("incomplete request head");
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    Ok(head.lines().next().unwrap_or_default().to_string())
+}
+
+async fn http_respond(stream: &mut tokio::net::TcpStream, status: u16, body: &str) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// Probes of the orchestrator on "host:port" of FPC_HEALTH_ADDR, for the whole process
+async fn health_server(vaults: Vec<Vault>, addr: String) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("health probes bind to \"{}\" failed \"{}\"", addr, e);
+            return;
+        }
+    };
+    info!("Health probes listening on: {}", addr);
+    while let Ok((mut stream, peer_addr)) = listener.accept().await {
+        let vaults = vaults.clone();
+        tokio::spawn(async move {
+            let answer = time::timeout(OBSERVER_TIMEOUT, async {
+                let request_line = http_request_line(&mut stream).await?;
+                let problems = vaults
+                    .iter()
+                    .flat_map(|vault| readiness_problems(vault, unix_now()))
+                    .collect::<Vec<_>>();
+                let (status, body) = health::route(&request_line, &problems);
+                http_respond(&mut stream, status, &body).await
+            })
+            .await;
+            if let Ok(Err(e)) = answer {
+                debug!("health probe of \"{}\" failed \"{}\"", peer_addr, e);
+            }
+        });
+    }
+}
+
+// what keeps the tenant from serving players, nothing once it is ready
+fn readiness_problems(vault: &Vault, now: u64) -> Vec<String> {
+    let name = vault.tenant().name.as_deref().unwrap_or("server");
+    let mut problems = Vec::new();
+    if !vault.is_listening() {
+        problems.push(format!("{} does not accept players yet", name));
+    }
+    if now.saturating_sub(vault.matchmaking_tick()) > MATCHMAKING_STALL.as_secs() {
+        problems.push(format!("matchmaking of {} is not running", name));
+    }
+    if let Some(Err(e)) = vault.tenant().storage.as_ref().map(|s| s.probe()) {
+        problems.push(format!("storage of {} is unreachable \"{:#}\"", name, e));
+    }
+    problems
+}
+
+// Reload operator metadata from the file when it changes, keep the last good one on errors
+async fn server_meta_dispatcher(vault: Vault, path: String) {
+    let mut interval = time::interval(SERVER_META_RELOAD_PERIOD);
+    let mut loaded_modified = None;
+    loop {
+        interval.tick().await;
+
+        let modified = match std::fs::metadata(&path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                error!("server meta file \"{}\" unavailable \"{}\"", path, e);
+                continue;
+            }
+        };
+        if loaded_modified == Some(modified) {
+            continue;
+        }
+        loaded_modified = Some(modified);
+
+        let meta = std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(serde_json::from_str::<ServerMeta>(&json)?));
+        match meta {
+            Ok(mut meta) => {
+                meta.version = SERVER_META_VER;
+                *vault.set_server_meta().await = Some(meta);
+                info!("server meta loaded from \"{}\"", path);
+            }
+            Err(e) => error!("server meta file \"{}\" is bad \"{}\"", path, e),
+        }
+    }
+}
+
+// Reload chat filter word lists whenever the file changes
+async fn chat_filter_dispatcher(vault: Vault, path: String) {
+    let mut interval = time::interval(CHAT_FILTER_RELOAD_PERIOD);
+    let mut loaded_modified = None;
+    loop {
+        interval.tick().await;
+
+        let modified = match std::fs::metadata(&path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                error!("chat filter file \"{}\" unavailable \"{}\"", path, e);
+                continue;
+            }
+        };
+        if loaded_modified == Some(modified) {
+            continue;
+        }
+        loaded_modified = Some(modified);
+
+        match load_filter(&path) {
+            Ok(config) => {
+                vault.get_chat_filter().await.reload(config);
+                info!("chat filter loaded from \"{}\"", path);
+            }
+            Err(e) => error!("chat filter file \"{}\" is bad \"{:#}\"", path, e),
+        }
+    }
+}
+
+// Ping every connection, close the ones silent for too long.
+// Closed connection goes through the usual disconnect, players may reconnect.
+async fn keepalive_dispatcher(vault: Vault) {
+    let mut interval = time::interval(KEEPALIVE_PERIOD);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        for (conn_id, peer) in vault.get_peers() {
+            let mut peer_lock = peer.lock().await;
+            if now.duration_since(peer_lock.last_activity) > KEEPALIVE_TIMEOUT {
+                info!(
+                    "{} ({}) is silent for too long, closing",
+                    conn_id, peer_lock.addr
+                );
+                peer_lock.tx.close_channel();
+            } else if let Err(e) = peer_lock.ping() {
+                debug!("ping to {} failed \"{}\"", conn_id, e);
+            }
+        }
+    }
+}
+
+// Pdu telling why the open connection is closed, none if it may stay.
+// Watching peers follow a game as spectators or engines.
+fn stale_peer(peer: &Peer, now: Instant, watching: bool) -> Option<Pdu> {
+    match peer.state {
+        PeerState::Unknown(since) if now.duration_since(since) > *HANDSHAKE_TIMEOUT => {
+            Some(Pdu::Handshake(Handshake::Timeout {
+                description: format!("no handshake in {} seconds", HANDSHAKE_TIMEOUT.as_secs()),
+            }))
+        }
+        PeerState::Idle
+            if !watching
+                && IDLE_TIMEOUT
+                    .is_some_and(|timeout| now.duration_since(peer.last_request) > timeout) =>
+        {
+            Some(Pdu::Disconnect(Disconnect {
+                reason: DisconnectReason::Idle,
+                description: "idle for too long".to_string(),
+            }))
+        }
+        _ => None,
+    }
+}
+
+// Publish the games and queued players of the node to the cluster. Players queued here for a
+// pool gathered on another node are routed there, clients without Route stay queued here.
+async fn cluster_dispatcher(vault: Vault, cluster: Arc<Cluster>) {
+    let mut interval = time::interval(CLUSTER_PERIOD);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        let unix = unix_now();
+        let reconnect_ids = vault
+            .get_reconnects()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        let mut queues: HashMap<String, Vec<QueueEntry>> = vault
+            .tenant()
+            .pools
+            .iter()
+            .map(|p| (p.name.clone(), Vec::new()))
+            .collect();
+        let peers = vault.get_peers();
+        for (conn_id, peer) in &peers {
+            let peer_lock = peer.lock().await;
+            let queued = matches!(
+                peer_lock.state,
+                PeerState::MMQueue | PeerState::HeartbeatWait(_) | PeerState::HeartbeatReady(_)
+            );
+            let entries = peer_lock.pool.as_ref().and_then(|p| queues.get_mut(p));
+            if let (true, Some(entries), Some(queued_at)) = (queued, entries, peer_lock.queued_at) {
+                entries.push(QueueEntry {
+                    conn_id: conn_id.0,
+                    queued_at: unix.saturating_sub(now.duration_since(queued_at).as_secs()),
+                });
+            }
+        }
+        if let Err(e) = cluster.publish(&reconnect_ids, &queues).await {
+            error!("cluster publish failed \"{:#}\"", e);
+            continue;
+        }
+
+        for (pool, entries) in &queues {
+            if entries.is_empty() {
+                continue;
+            }
+            let route = match routed_home(&cluster, pool).await {
+                Some(node) => pool_route(node, pool),
+                None => continue,
+            };
+            let resp = match route.to_message() {
+                Ok(resp) => resp,
+                Err(e) => {
+                    error!("{:?} serialization failed \"{}\"", route, e);
+                    continue;
+                }
+            };
+            // players already grouped for heartbeats are matched here
+            for (conn_id, peer) in &peers {
+                let mut peer_lock = peer.lock().await;
+                if !matches!(peer_lock.state, PeerState::MMQueue)
+                    || peer_lock.pool.as_deref() != Some(pool.as_str())
+                    || !peer_lock.protocol().understands(&route)
+                {
+                    continue;
+                }
+                info!("{} routed to the home of pool \"{}\"", conn_id, pool);
+                let _ = peer_lock.tx.send(resp.clone());
+                peer_lock.state = PeerState::Idle;
+                peer_lock.player_name = None;
+                peer_lock.queued_at = None;
+            }
+        }
+    }
+}
+
+// Node the game of the reconnect id is pinned to if it is another one
+async fn pinned_node(cluster: &Cluster, reconnect_id: &str) -> Option<String> {
+    match cluster.reconnect_node(reconnect_id).await {
+        Ok(node) => node.filter(|node| *node != cluster.node),
+        Err(e) => {
+            error!("node of reconnect id unknown \"{:#}\"", e);
+            None
+        }
+    }
+}
+
+// Node gathering the pool if it is another one. Players queue here when Redis fails.
+async fn routed_home(cluster: &Cluster, pool: &str) -> Option<String> {
+    match cluster.pool_home(pool).await {
+        Ok(home) => home.filter(|node| *node != cluster.node),
+        Err(e) => {
+            error!("home of pool \"{}\" unknown \"{:#}\"", pool, e);
+            None
+        }
+    }
+}
+
+fn pool_route(node: String, pool: &str) -> Pdu {
+    Pdu::Route(Route {
+        node,
+        description: format!("pool \"{}\" is gathered on another node", pool),
+    })
+}
+
+// Close connections stuck without the handshake and idle ones, forget dead peers and games
+// without a session, expire reconnect ids of players disconnected for too long
+async fn gc_dispatcher(vault: Vault) {
+    let mut interval = time::interval(GC_PERIOD);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+
+        // games are locked before peers
+        let mut watching = HashSet::new();
+        for game in vault.get_games() {
+            watching.extend(game.lock().await.subscribers.keys().copied());
+        }
+        let mut dead = Vec::new();
+        for (conn_id, peer) in vault.get_peers() {
+            let peer_lock = peer.lock().await;
+            if peer_lock.tx.is_closed() {
+                if now.duration_since(peer_lock.last_activity) > DEAD_PEER_TIMEOUT {
+                    dead.push(conn_id);
+                }
+            } else if let Some(pdu) = stale_peer(&peer_lock, now, watching.contains(&conn_id)) {
+                info!(
+                    "{} ({}) is stale in state {}, closing",
+                    conn_id,
+                    peer_lock.addr,
+                    peer_lock.state.name()
+                );
+                match pdu.to_message() {
+                    Ok(resp) if peer_lock.protocol().understands(&pdu) => {
+                        let _ = peer_lock.tx.send(resp);
+                    }
+                    Ok(_) => (),
+                    Err(e) => error!("{:?} serialization failed \"{}\"", pdu, e),
+                }
+                peer_lock.tx.close_channel();
+            }
+        }
+        for conn_id in dead {
+            info!("{} was closed without cleanup, removing", conn_id);
+            if let Err(e) = process_gs_disconnect(&vault, &conn_id).await {
+                error!("disconnect from game failed \"{}\"", e);
+            }
+            if let Err(e) = process_lobby_leave(&vault, &conn_id).await {
+                error!("leave lobby of dead peer failed \"{}\"", e);
+            }
+            vault.remove_peer(&conn_id).await;
+        }
+
+        // supervisor ended early, e.g. cancelled or panicked before the release
+        for game_id in vault.dead_games() {
+            reap_game(&vault, game_id).await;
+        }
+
+        // receiver of the signal is dropped together with the session
+        for game in vault.get_games() {
+            let (game_id, orphaned) = {
+                let game_lock = game.lock().await;
+                (game_lock.id, game_lock.move_happen_signal.is_closed())
+            };
+            if orphaned {
+                error!("game {} has no session, releasing", game_id);
+                release_game(&vault, game_id, true).await;
+            }
+        }
+
+        for (reconnect_id, game) in vault.get_reconnects() {
+            let game_lock = game.lock().await;
+            let player = match game_lock
+                .players()
+                .into_iter()
+                .find(|p| p.reconnect_id == reconnect_id)
+            {
+                Some(player) => player,
+                None => {
+                    vault.remove_reconnect(&reconnect_id);
+                    continue;
+                }
+            };
+            let disconnected = match player.peer.lock().await.state {
+                PeerState::Unknown(since) => now.duration_since(since),
+                _ => Duration::ZERO,
+            };
+            if disconnected > game_lock.disconnect_policy.grace {
+                info!(
+                    "{} of game {} may not reconnect anymore",
+                    player.color.to_string(),
+                    game_lock.id
+                );
+                vault.remove_reconnect(&reconnect_id);
+            }
+        }
+    }
+}
+
+// Start the next rating season when the current one is over
+async fn season_dispatcher(vault: Vault) {
+    let mut interval = time::interval(SEASON_CHECK_PERIOD);
+    loop {
+        interval.tick().await;
+
+        let now = unix_now();
+        let mut ratings_lock = vault.get_ratings().await;
+        let season = ratings_lock.current_season();
+        if ratings_lock.roll_season(now, SEASON_LENGTH.as_secs()) {
+            info!("rating season {} finished", season);
+        }
+    }
+}
+
+// Rounds of the tournaments start when they are due, a round is over once the games of all
+// its tables are. Registered players get the standings of every round.
+async fn tournament_dispatcher(vault: Vault) {
+    let mut interval = time::interval(TOURNAMENT_CHECK_PERIOD);
+    loop {
+        interval.tick().await;
+        if let Err(e) = advance_tournaments(&vault).await {
+            error!("advance tournaments failed \"{}\"", e);
+        }
+    }
+}
+
+async fn advance_tournaments(vault: &Vault) -> Result<()> {
+    // records are looked up without the tournaments lock
+    let playing = {
+        let tournaments_lock = vault.get_tournaments().await;
+        let mut playing = Vec::new();
+        for tournament in tournaments_lock.all().iter().filter(|t| !t.finished) {
+            for (table, t) in tournament.tables.iter().enumerate() {
+                if let (Some(game_id), None) = (t.game_id, &t.places) {
+                    playing.push((tournament.id, table, game_id, t.players.clone()));
+                }
+            }
+        }
+        playing
+    };
+    let mut results = Vec::new();
+    for (tournament_id, table, game_id, players) in playing {
+        if vault.get_game(game_id).is_some() {
+            continue;
+        }
+        // players who did not come were replaced by bots
+        let places = match vault.get_records().await.get(game_id) {
+            Some(record) => {
+                let game = restored_game(record.clone(), unbounded().0);
+                players
+                    .iter()
+                    .map(|name| record.player_color(name).map(|color| game.place(color)))
+                    .collect()
+            }
+            None => vec![None; players.len()],
+        };
+        results.push((tournament_id, table, places));
+    }
+
+    let now = unix_now();
+    let mut starting = Vec::new();
+    let mut notify = Vec::new();
+    {
+        let mut tournaments_lock = vault.get_tournaments().await;
+        for (tournament_id, table, places) in results {
+            if let Some(tournament) = tournaments_lock.get_mut(tournament_id) {
+                tournament.tables[table].places = Some(places);
+            }
+        }
+        let due = tournaments_lock
+            .all()
+            .iter()
+            .filter(|t| !t.finished && (t.round_over() || (t.round == 0 && now >= t.starts_at)))
+            .map(|t| t.id)
+            .collect::<Vec<_>>();
+        for id in due {
+            let tournament = tournaments_lock.get_mut(id).unwrap();
+            if tournament.round == 0 && tournament.players.len() < 2 {
+                info!("tournament {} called off, too few players", id);
+                tournament.finished = true;
+            } else {
+                if tournament.round_over() {
+                    tournament.finish_round();
+                    info!("tournament {} round {} finished", id, tournament.round);
+                }
+                if !tournament.finished {
+                    tournament.start_round();
+                    let tables = tournament
+                        .tables
+                        .iter()
+                        .map(|t| t.players.clone())
+                        .collect::<Vec<_>>();
+                    let grace = match tournament.reconnect_grace {
+                        0 => TOURNAMENT_RECONNECT_GRACE,
+                        seconds => seconds,
+                    };
+                    starting.push((id, tournament.time_control, grace, tables));
+                }
+            }
+            notify.push(id);
+        }
+    }
+
+    for (id, time_control, grace, tables) in starting {
+        let time_control = Duration::from_secs(time_control);
+        let policy = held_seat_policy(Duration::from_secs(grace));
+        for (table, players) in tables.iter().enumerate() {
+            let game_id = start_table(vault, players, time_control, policy.clone()).await;
+            let mut tournaments_lock = vault.get_tournaments().await;
+            if let Some(tournament) = tournaments_lock.get_mut(id) {
+                let table = &mut tournament.tables[table];
+                match game_id {
+                    Some(game_id) => table.game_id = Some(game_id),
+                    // nobody of the table came, none of them scores
+                    None => table.places = Some(vec![None; table.players.len()]),
+                }
+            }
+        }
+    }
+
+    for id in notify {
+        let (players, info) = match vault.get_tournaments().await.get(id) {
+            Some(t) => (
+                t.players.clone(),
+                Pdu::Tournament(Tournament::Info(t.info())).to_message()?,
+            ),
+            None => continue,
+        };
+        for (_, peer) in vault.get_peers() {
+            let peer_lock = peer.lock().await;
+            if players.iter().any(|name| peer_lock.owns_name(name)) {
+                if let Err(e) = peer_lock.tx.send(info.clone()) {
+                    error!("send failed \"{}\"", e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Game of the table with the players who are idle, seats of the others are taken by bots.
+// None if none of them is there.
+async fn start_table(
+    vault: &Vault,
+    players: &[String],
+    time_control: Duration,
+    disconnect_policy: DisconnectPolicy,
+) -> Option<u64> {
+    let mut peers = Vec::new();
+    for name in players {
+        let mut found = None;
+        for (_, peer) in vault.get_peers() {
+            let peer_lock = peer.lock().await;
+            if matches!(peer_lock.state, PeerState::Idle) && peer_lock.owns_name(name) {
+                found = Some(peer.clone());
+                break;
+            }
+        }
+        peers.push(found);
+    }
+    let mut guards = Vec::new();
+    for peer in &peers {
+        guards.push(match peer {
+            Some(peer) => Some(peer.lock().await),
+            None => None,
+        });
+    }
+    // the state may have changed before the peers were locked
+    let mut iter = peers
+        .iter()
+        .zip(guards.iter_mut())
+        .zip(players)
+        .map(|((peer, guard), name)| match (peer, guard) {
+            (Some(peer), Some(guard)) if matches!(guard.state, PeerState::Idle) => {
+                guard.player_name = Some(name.clone());
+                // tournament games are not of any pool
+                guard.pool = None;
+                Some((peer.clone(), &mut **guard))
+            }
+            _ => None,
+        });
+    let seats = [
+        iter.next().flatten(),
+        iter.next().flatten(),
+        iter.next().flatten(),
+        iter.next().flatten(),
+    ];
+    if seats.iter().all(Option::is_none) {
+        return None;
+    }
+    Some(
+        start_game(
+            vault,
+            seats,
+            false,
+            0,
+            time_control,
+            None,
+            None,
+            disconnect_policy,
+        )
+        .await,
+    )
+}
+
+// Flag the players of the finished game whose latest games look like engine play,
+// players without an account are not reviewed
+async fn review_fair_play(vault: &Vault, players: &[String]) {
+    let now = unix_now();
+    for name in players {
+        if vault.get_accounts().await.get(name).is_none() {
+            continue;
+        }
+        let review = fairplay::review(name, &vault.get_records().await.of_player(name));
+        if vault.get_fair_play().await.update(name, review, now) {
+            info!("player \"{}\" flagged for fair play review", name);
+        }
+    }
+}
+
+// Periodically persist snapshots of active games, records of finished ones, changed ratings, accounts,
+// tournaments and fair play suspects,
+// remove snapshots of finished ones
+async fn snapshot_dispatcher(vault: Vault, storage: Arc<Storage>) {
+    let mut interval = time::interval(SNAPSHOT_PERIOD);
+    loop {
+        interval.tick().await;
+
+        let ratings = {
+            let mut ratings_lock = vault.get_ratings().await;
+            if ratings_lock.take_changed() {
+                Some(ratings_lock.clone())
+            } else {
+                None
+            }
+        };
+        if let Some(ratings) = ratings {
+            if let Err(e) = storage.save_ratings(&ratings) {
+                error!("save ratings failed \"{}\"", e);
+                vault.get_ratings().await.mark_changed();
+            }
+        }
+
+        let accounts = {
+            let mut accounts_lock = vault.get_accounts().await;
+            if accounts_lock.take_changed() {
+                Some(accounts_lock.clone())
+            } else {
+                None
+            }
+        };
+        if let Some(accounts) = accounts {
+            if let Err(e) = storage.save_accounts(&accounts) {
+                error!("save accounts failed \"{}\"", e);
+                vault.get_accounts().await.mark_changed();
+            }
+        }
+
+        let tournaments = {
+            let mut tournaments_lock = vault.get_tournaments().await;
+            if tournaments_lock.take_changed() {
+                Some(tournaments_lock.clone())
+            } else {
+                None
+            }
+        };
+        if let Some(tournaments) = tournaments {
+            if let Err(e) = storage.save_tournaments(&tournaments) {
+                error!("save tournaments failed \"{}\"", e);
+                vault.get_tournaments().await.mark_changed();
+            }
+        }
+
+        let fair_play = {
+            let mut fair_play_lock = vault.get_fair_play().await;
+            if fair_play_lock.take_changed() {
+                Some(fair_play_lock.clone())
+            } else {
+                None
+            }
+        };
+        if let Some(fair_play) = fair_play {
+            if let Err(e) = storage.save_fair_play(&fair_play) {
+                error!("save fair play failed \"{}\"", e);
+                vault.get_fair_play().await.mark_changed();
+            }
+        }
+
+        let records = vault.get_records().await.take_changed();
+        for record in &records {
+            if let Err(e) = storage.save_record(record) {
+                error!("save game {} record failed \"{}\"", record.id, e);
+                vault.get_records().await.mark_changed(record.id);
+            }
+        }
+
+        // listed first, game started after the listing keeps its turn log
+        let stored_ids = match storage.game_ids() {
+            Ok(ids) => ids,
+            Err(e) => {
+                error!("list stored games failed \"{}\"", e);
+                continue;
+            }
+        };
+
+        let mut snapshots = Vec::new();
+        {
+            for game in vault.get_games() {
+                if let Some(snapshot) = &game.lock().await.snapshot {
+                    snapshots.push(snapshot.clone());
+                }
+            }
+        }
+
+        for game_id in stored_ids {
+            if !snapshots.iter().any(|s| s.id == game_id) {
+                if let Err(e) = storage.remove_game(game_id) {
+                    error!("remove game {} snapshot failed \"{}\"", game_id, e);
+                }
+            }
+        }
+        for snapshot in &snapshots {
+            if let Err(e) = storage.save_game(snapshot) {
+                error!("save game {} snapshot failed \"{}\"", snapshot.id, e);
+            } else if let Err(e) = storage.compact_wal(snapshot.id, snapshot.turn) {
+                error!("compact game {} turn log failed \"{}\"", snapshot.id, e);
+            }
+        }
+    }
+}
+
+// Game of the snapshot with placeholder peers, the players reconnect to their seats
+fn restored_game(
+    snapshot: GameSnapshot,
+    signal: futures_channel::mpsc::UnboundedSender<()>,
+) -> Game {
+    let player = |color: Color, player_snapshot: &vault::PlayerSnapshot| Player {
+        name: player_snapshot.name.clone(),
+        color,
+        reconnect_id: player_snapshot.reconnect_id.clone(),
+        time_remaining: player_snapshot.time_remaining,
+        state: player_snapshot.state.clone(),
+        peer: placeholder_peer(&player_snapshot.name),
+        afk_count: 0,
+        bot: player_snapshot.bot,
+        points: player_snapshot.points,
+    };
+
+    let mut game = Game {
+        id: snapshot.id,
+        board: snapshot.board.clone(),
+        red: player(Color::Red, &snapshot.red),
+        green: player(Color::Green, &snapshot.green),
+        blue: player(Color::Blue, &snapshot.blue),
+        yellow: player(Color::Yellow, &snapshot.yellow),
+        who_move: None,
+        move_happen_signal: signal,
+        analysis_consent: Vec::new(),
+        subscribers: HashMap::new(),
+        fanout: Fanout::default(),
+        disconnect_policy: snapshot
+            .held_seat_grace
+            .map_or_else(default_disconnect_policy, held_seat_policy),
+        snapshot: Some(snapshot),
+        afk_policy: default_afk_policy(),
+        shed_policy: default_shed_policy(),
+        stalemate_rule: *STALEMATE_RULE,
+        remaining_pieces: *REMAINING_PIECES,
+        captured: Vec::new(),
+        moves: Vec::new(),
+        eliminated: Vec::new(),
+        best_move: None,
+        chat: Vec::new(),
+        chat_unrecorded: Vec::new(),
+        chat_mutes: Vec::new(),
+        next_chat_id: 0,
+        turn: 0,
+        started: 0,
+        casual: false,
+        bot_level: 0,
+        draw_odds: None,
+        pool: None,
+        private: false,
+        variant: Variant::Ffa,
+        features: Vec::new(),
+        time_control: Duration::ZERO,
+        terminated: false,
+        aborted_by: None,
+        end: GameEnd::Standings,
+        draw_votes: Vec::new(),
+        pause: GamePause::Running,
+        pause_votes: Vec::new(),
+        ready: None,
+        adjudication: None,
+        start: None,
+        first_move: Color::Red,
+    };
+    game.restore_snapshot();
+    game
+}
+
+// Recreate games from persisted snapshots and replay their turn logs,
+// players have to reconnect with reconnect_id. Returns next free game id
+async fn restore_games(vault: &Vault, storage: &Storage) -> Result<u64> {
+    let mut next_game_id = 0;
+
+    for (snapshot, turns) in storage.load_games()? {
+        let game_id = snapshot.id;
+        let (sender, receiver) = unbounded();
+
+        let mut game = restored_game(snapshot, sender);
+        for delta in &turns {
+            // the game continues from the last consistent turn
+            if let Err(e) = game.replay_turn(delta) {
+                error!("game {} turn log replay failed \"{}\"", game_id, e);
+                break;
+            }
+        }
+        next_game_id = next_game_id.max(game_id.wrapping_add(1));
+
+        // finished just before the crash, only the record and ratings were not saved
+        if game.who_move.is_none() {
+            let mut records_lock = vault.get_records().await;
+            if records_lock.get(game_id).is_none() {
+                rate_game(&game, &mut *vault.get_ratings().await);
+                records_lock.insert(game.record());
+            }
+            info!("Game {} finished before restart", game_id);
+            continue;
+        }
+
+        let game = Arc::new(Mutex::new(game));
+        for player in game.lock().await.players().into_iter().filter(|p| !p.bot) {
+            vault.insert_reconnect(player.reconnect_id.clone(), game.clone());
+        }
+        vault.insert_game(game_id, game);
+
+        info!(
+            "Game {} restored from snapshot and {} logged turns",
+            game_id,
+            turns.len()
+        );
+        let supervisor = tokio::spawn(game_supervisor(
+            vault.clone(),
+            receiver,
+            game_id,
+            GS_RESTORE_PAUSE,
+        ));
+        vault.insert_game_task(game_id, supervisor);
+    }
+    Ok(next_game_id)
+}
+
+// player joining the game, none for the bot
+type Seat<'a> = Option<(Arc<Mutex<Peer>>, &'a mut Peer)>;
+
+// Create the game of four players in red, blue, yellow, green order, empty seats are taken
+// by bots. Send Init to every player, spawn the game session and return the id of the game.
+// Armageddon tiebreak is the game with the draw odds player.
+// Games start from the initial position with red to move unless the start position is given.
+// Tournament games hold the seats of the disconnected players by their policy.
+#[allow(clippy::too_many_arguments)]
+async fn start_game(
+    vault: &Vault,
+    seats: [Seat<'_>; 4],
+    casual: bool,
+    bot_level: u8,
+    time_control: Duration,
+    draw_odds: Option<Color>,
+    start: Option<Fen4>,
+    disconnect_policy: DisconnectPolicy,
+) -> u64 {
+    let game_id = vault.next_game_id();
+    let clock = |color: Color| match draw_odds {
+        Some(odds) if odds == color => time_control * DRAW_ODDS_TIME_PERCENT / 100,
+        _ => time_control,
+    };
+
+    // TODO: check unique
+    let player = |color: Color, seat: &Seat| match seat {
+        Some((peer, peer_lock)) => Player {
+            name: peer_lock.player_name.clone().unwrap(),
+            color,
+            reconnect_id: random_string(),
+            time_remaining: clock(color),
+            state: PlayerState::NoState,
+            peer: peer.clone(),
+            afk_count: 0,
+            bot: false,
+            points: 0,
+        },
+        None => {
+            let name = format!("Bot {}", color.to_string());
+            Player {
+                peer: placeholder_peer(&name),
+                name,
+                color,
+                reconnect_id: String::new(),
+                time_remaining: clock(color),
+                state: PlayerState::NoState,
+                afk_count: 0,
+                bot: true,
+                points: 0,
+            }
+        }
+    };
+    // players of a group are matched within one pool and variant, lobby games are free for all
+    let pool = seats
+        .iter()
+        .flatten()
+        .find_map(|(_, peer_lock)| peer_lock.pool.clone());
+    let variant = seats
+        .iter()
+        .flatten()
+        .find(|(_, peer_lock)| peer_lock.pool.is_some())
+        .map_or(Variant::Ffa, |(_, peer_lock)| peer_lock.variant);
+    let features = vault.get_flags().await.of_game(pool.as_deref(), game_id);
+    // lobby games count as games of the default pool
+    let private_pool = pool.as_deref().unwrap_or(DEFAULT_POOL);
+    let private = seats.iter().flatten().any(|(_, peer_lock)| {
+        peer_lock
+            .account
+            .as_ref()
+            .is_some_and(|account| account.private_pools.iter().any(|p| p == private_pool))
+    });
+    let [red, blue, yellow, green] = seats;
+    let (board, first_move, start) = match start {
+        Some(fen) => {
+            let start = fen.to_string();
+            (fen.board, fen.to_move, Some(start))
+        }
+        None => (Board::new(), Color::Red, None),
+    };
+
+    let (sender, receiver) = unbounded();
+
+    let game = Game {
+        id: game_id,
+        board,
+        red: player(Color::Red, &red),
+        blue: player(Color::Blue, &blue),
+        yellow: player(Color::Yellow, &yellow),
+        green: player(Color::Green, &green),
+        who_move: None,
+        move_happen_signal: sender,
+        analysis_consent: Vec::new(),
+        subscribers: HashMap::new(),
+        fanout: Fanout::default(),
+        snapshot: None,
+        afk_policy: default_afk_policy(),
+        disconnect_policy,
+        shed_policy: default_shed_policy(),
+        stalemate_rule: *STALEMATE_RULE,
+        remaining_pieces: *REMAINING_PIECES,
+        captured: Vec::new(),
+        moves: Vec::new(),
+        eliminated: Vec::new(),
+        best_move: None,
+        chat: Vec::new(),
+        chat_unrecorded: Vec::new(),
+        chat_mutes: Vec::new(),
+        next_chat_id: 0,
+        turn: 0,
+        started: unix_now(),
+        casual,
+        bot_level,
+        draw_odds,
+        pool,
+        private,
+        variant,
+        features: features.clone(),
+        time_control,
+        terminated: false,
+        aborted_by: None,
+        end: GameEnd::Standings,
+        draw_votes: Vec::new(),
+        pause: GamePause::Running,
+        pause_votes: Vec::new(),
+        ready: None,
+        adjudication: None,
+        start: start.clone(),
+        first_move,
+    };
+    let names = [
+        game.red.name.clone(),
+        game.green.name.clone(),
+        game.blue.name.clone(),
+        game.yellow.name.clone(),
+    ];
+    let reconnect_ids = [
+        game.red.reconnect_id.clone(),
+        game.blue.reconnect_id.clone(),
+        game.yellow.reconnect_id.clone(),
+        game.green.reconnect_id.clone(),
+    ];
+    let game = Arc::new(Mutex::new(game));
+    vault.insert_game(game_id, game.clone());
+
+    let colors = [Color::Red, Color::Blue, Color::Yellow, Color::Green];
+    for ((seat, color), reconnect_id) in vec![red, blue, yellow, green]
+        .into_iter()
+        .zip(colors)
+        .zip(reconnect_ids)
+    {
+        let peer = match seat {
+            Some((_, peer)) => peer,
+            None => continue,
+        };
+        vault.insert_reconnect(reconnect_id.clone(), game.clone());
+        peer.queue_priority_until = None;
+        peer.state = PeerState::Game {
+            color,
+            game: game.clone(),
+        };
+        let [red_name, green_name, blue_name, yellow_name] = names.clone();
+        let pdu = game_init_pdu!(
+            game_id,
+            GS_INIT_PAUSE.as_secs(),
+            reconnect_id,
+            red_name,
+            green_name,
+            blue_name,
+            yellow_name,
+            draw_odds,
+            features.clone(),
+            start.clone(),
+            peer.seat_labels()
+        )
+        .unwrap();
+        match peer.tx.send(pdu) {
+            Ok(_) => (),
+            Err(e) => error!("send failed \"{}\"", e),
+        }
+    }
+
+    let supervisor = tokio::spawn(game_supervisor(
+        vault.clone(),
+        receiver,
+        game_id,
+        GS_INIT_PAUSE,
+    ));
+    vault.insert_game_task(game_id, supervisor);
+    game_id
+}
+
+// peer of the player without connection, a restored player until he reconnects or a bot
+fn placeholder_peer(name: &str) -> Arc<Mutex<Peer>> {
+    let (tx, _) = peer_channel(1);
+    Arc::new(Mutex::new(Peer {
+        tx,
+        addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+        player_name: Some(name.to_string()),
+        state: PeerState::Unknown(Instant::now()),
+        client_info: None,
+        queue_priority_until: None,
+        last_activity: Instant::now(),
+        last_request: Instant::now(),
+        pool: None,
+        variant: Variant::Ffa,
+        time_control: DEFAULT_TIME_CONTROL,
+        account: None,
+        bucket: TokenBucket::new(&RATE_LIMIT),
+        queued_at: None,
+        merged: None,
+        bot_fill: false,
+        filter_strikes: 0,
+        muted_until: None,
+        ping_sent: None,
+        rtt: None,
+    }))
+}
+
+fn pool_casual(tenant: &Tenant, pool: &Option<String>) -> bool {
+    tenant.pool(pool.as_deref()).is_some_and(|p| p.casual)
+}
+
+// players of the pool matched into one game, bots take the other seats
+fn pool_group_size(tenant: &Tenant, pool: &Option<String>) -> usize {
+    tenant.pool(pool.as_deref()).map_or(4, |p| p.group_size)
+}
+
+fn pool_bot_level(tenant: &Tenant, pool: &Option<String>) -> u8 {
+    tenant.pool(pool.as_deref()).map_or(0, |p| p.bot_level)
+}
+
+type Candidate<'a> = (&'a ConnectionId, Arc<Mutex<Peer>>, MutexGuard<'a, Peer>);
+
+// Players are grouped within the pool with queue priority first, blocked pairs are kept apart.
+// Blocks are strict in casual pools only. Players of rated pools who would be left waiting
+// because of blocks are grouped anyway, blocking must not be a way to pick rated opponents.
+fn split_groups<'a>(
+    tenant: &Tenant,
+    mut candidates: Vec<Candidate<'a>>,
+    now: Instant,
+) -> (Vec<Vec<Candidate<'a>>>, Vec<Candidate<'a>>) {
+    candidates.sort_by_key(|c| (c.2.match_pool(), !c.2.has_queue_priority(now)));
+    let mut groups = Vec::new();
+    let mut leftovers = Vec::new();
+    let mut candidates = candidates.into_iter().peekable();
+    while let Some(first) = candidates.next() {
+        let match_pool = first.2.match_pool();
+        let size = pool_group_size(tenant, &first.2.pool);
+        let mut bucket = vec![first];
+        while let Some(candidate) = candidates.next_if(|c| c.2.match_pool() == match_pool) {
+            bucket.push(candidate);
+        }
+        let (full, waiting) =
+            group_apart(bucket.len(), size, |i, j| bucket[i].2.blocks(&bucket[j].2));
+        let mut bucket = bucket.into_iter().map(Some).collect::<Vec<_>>();
+        for group in full {
+            groups.push(group.iter().filter_map(|i| bucket[*i].take()).collect());
+        }
+        let (mut rated, mut casual): (Vec<_>, Vec<_>) = waiting
+            .iter()
+            .filter_map(|i| bucket[*i].take())
+            .partition(|c| !pool_casual(tenant, &c.2.pool));
+        // incomplete group waits for more players
+        leftovers.extend(rated.drain(rated.len() - rated.len() % size..));
+        leftovers.append(&mut casual);
+        while !rated.is_empty() {
+            groups.push(rated.drain(..size).collect());
+        }
+    }
+    (groups, leftovers)
+}
+
+// Eligible players without a full group are grouped by less than the group size within
+// the pool, bots take the other seats. Blocked pairs are kept apart.
+fn bot_fill_groups<'a>(
+    tenant: &Tenant,
+    candidates: Vec<Candidate<'a>>,
+    eligible: impl Fn(&Peer) -> bool,
+) -> Vec<Vec<Candidate<'a>>> {
+    let mut groups: Vec<Vec<Candidate>> = Vec::new();
+    for candidate in candidates.into_iter().filter(|c| eligible(&c.2)) {
+        let group = groups.iter_mut().find(|g| {
+            g.len() + 1 < pool_group_size(tenant, &g[0].2.pool)
+                && g[0].2.match_pool() == candidate.2.match_pool()
+                && g.iter().all(|c| !c.2.blocks(&candidate.2))
+        });
+        match group {
+            Some(group) => group.push(candidate),
+            None => groups.push(vec![candidate]),
+        }
+    }
+    groups
+}
+
+// Looping infinitely. On loop tick, if we find at least 4 MMQueue players, send HeartbeatCheck
+// Also, kick (send kick pdu and change state to Idle) players, who did not response on HeartbeatCheck
+// Also, change state HearbeatReady => MMQueue if timeout
+// Idle players are disconnected by the gc_dispatcher
+async fn matchmaking_dispatcher(vault: Vault) {
+    let mut interval = time::interval(HB_DISP_TICK_PERIOD);
+
+    let heartbeat_pdu = Pdu::MatchmakingQueue(MatchmakingQueue::HeartbeatCheck {})
+        .to_message()
+        .unwrap();
+    let kick_pdu = Pdu::MatchmakingQueue(MatchmakingQueue::PlayerKick {
+        discritpion: "Heartbeat timeout".to_string(),
+    })
+    .to_message()
+    .unwrap();
+    let pool_closed_pdu = Pdu::MatchmakingQueue(MatchmakingQueue::PlayerKick {
+        discritpion: "Pool closed".to_string(),
+    })
+    .to_message()
+    .unwrap();
+    let queued_twice_pdu = Pdu::MatchmakingQueue(MatchmakingQueue::PlayerKick {
+        discritpion: "Account queued from another connection".to_string(),
+    })
+    .to_message()
+    .unwrap();
 
-        player_time_remaining = first_moved_player.time_remaining;
+    //Err::<(),()>(()).unwrap();
 
-        game_lock.who_move = Some(WhoMove {
-            color: first_moved_player.color.clone(),
-            since: tokio::time::Instant::now(),
-            complete: None,
-        });
+    loop {
+        interval.tick().await;
+        vault.matchmaking_ticked(unix_now());
+        let start = Instant::now();
 
-        game_lock.broadcast(call).await;
-    }
+        // MMQueue => HeartbeatWait
+        // Send heartbeat to every 4 players which in MMQueue state,
+        // players answering WebSocket pings are ready at once
+        let peers = vault.get_peers();
+        {
+            let now = unix_now();
+            let mut candidates = Vec::new();
+            let mut accounts = HashSet::new();
+            for (key, peer) in peers.iter() {
+                let mut peer_lock = peer.lock().await;
+                if peer_lock.state.is_mm_queue() {
+                    // players of the pool closed by schedule go back to Idle
+                    let pool_open = vault
+                        .tenant()
+                        .pool(peer_lock.pool.as_deref())
+                        .is_some_and(|p| p.is_open(now));
+                    if !pool_open {
+                        match peer_lock.tx.send(pool_closed_pdu.clone()) {
+                            Ok(_) => {
+                                peer_lock.state = PeerState::Idle;
+                                peer_lock.player_name = None;
+                            }
+                            Err(e) => error!("send failed \"{}\"", e),
+                        }
+                        continue;
+                    }
+                    // connections registering at once may both pass the registration check
+                    let account_id = peer_lock.account.as_ref().map(|a| a.id);
+                    if account_id.is_some_and(|id| !accounts.insert(id)) {
+                        match peer_lock.tx.send(queued_twice_pdu.clone()) {
+                            Ok(_) => {
+                                peer_lock.state = PeerState::Idle;
+                                peer_lock.player_name = None;
+                            }
+                            Err(e) => error!("send failed \"{}\"", e),
+                        }
+                        continue;
+                    }
+                    let pong_capable = peer_lock
+                        .client_info
+                        .as_ref()
+                        .is_some_and(|c| c.pong_readiness);
+                    if pong_capable {
+                        if let Err(e) = peer_lock.ping() {
+                            error!("send failed \"{}\"", e);
+                        }
+                    }
+                    candidates.push((key, peer.clone(), peer_lock));
+                }
+            }
+            let (mut groups, mut leftovers) = split_groups(vault.tenant(), candidates, start);
 
-    // Process player move and timeout
-    loop {
-        let move_timeout = tokio::time::sleep(player_time_remaining + PLAYER_TIME_2);
-        pin_mut!(move_timeout);
+            // players left without a group for too long are matched across the merge group
+            let now = Instant::now();
+            for leftover in &mut leftovers {
+                if leftover.2.merged.is_some() {
+                    continue;
+                }
+                let pool = match vault.tenant().pool(leftover.2.pool.as_deref()) {
+                    Some(pool) => pool,
+                    None => continue,
+                };
+                let group = match &pool.merge_group {
+                    Some(group) => group,
+                    None => continue,
+                };
+                let waited = leftover
+                    .2
+                    .queued_at
+                    .map_or(Duration::ZERO, |t| now.duration_since(t));
+                if waited < Duration::from_secs(pool.merge_after) {
+                    continue;
+                }
+                let pdu = Pdu::MatchmakingQueue(MatchmakingQueue::PoolMerged {
+                    group: group.clone(),
+                    pools: vault
+                        .tenant()
+                        .pools
+                        .iter()
+                        .filter(|p| p.merge_group.as_ref() == Some(group))
+                        .map(|p| p.name.clone())
+                        .collect(),
+                    description: format!(
+                        "Too few players in pool {:?}, matching across {:?}",
+                        pool.name, group
+                    ),
+                })
+                .to_message()
+                .unwrap();
+                match leftover.2.tx.send(pdu) {
+                    Ok(_) => leftover.2.merged = Some(group.clone()),
+                    Err(e) => error!("send failed \"{}\"", e),
+                }
+            }
+            let (mut merged, leftovers) = split_groups(vault.tenant(), leftovers, start);
+            groups.append(&mut merged);
+            let bot_groups = match *BOT_FILL_AFTER {
+                Some(fill_after) => bot_fill_groups(vault.tenant(), leftovers, |peer| {
+                    peer.queued_at
+                        .is_some_and(|t| now.duration_since(t) >= fill_after)
+                }),
+                None => Vec::new(),
+            };
 
-        // left move timeout, right receive move message
-        let branch = future::select(move_timeout, move_received.next()).await;
+            let groups = groups.into_iter().map(|g| (false, g));
+            for (bot_fill, mut tmp_peers) in groups.chain(bot_groups.into_iter().map(|g| (true, g)))
+            {
+                let now = Instant::now();
+                for tmp_peer in &mut tmp_peers {
+                    tmp_peer.2.bot_fill = bot_fill;
+                    if tmp_peer.2.is_pong_ready(now, HB_WAIT_TIMEOUT) {
+                        tmp_peer.2.state = PeerState::HeartbeatReady(now);
+                        continue;
+                    }
+                    match tmp_peer.2.tx.send(heartbeat_pdu.clone()) {
+                        Ok(_) => tmp_peer.2.state = PeerState::HeartbeatWait(now),
+                        Err(e) => error!("send failed \"{}\"", e),
+                    }
+                }
+            }
+        }
+
+        // HeartbeatWait => Idle
+        // Drop to Idle state all players who timeout their HeartbeatWait state
         {
-            let lock = vault.write().await;
-            let games_lock = lock.get_games().await;
-            let game = games_lock
-                .get(&game_id)
-                .context("game_session game lookup failed")?;
-            let mut game_lock = game.lock().await;
+            //let lock = peers.write().await;
+            let now = Instant::now();
+            for (_, peer) in peers.iter() {
+                let mut peer_lock = peer.lock().await;
+                match peer_lock.state.get_hb_wait_since() {
+                    Some(hb_wait_since) => {
+                        let wait_time = now.duration_since(hb_wait_since);
+                        if wait_time > HB_WAIT_TIMEOUT {
+                            match peer_lock.tx.send(kick_pdu.clone()) {
+                                Ok(_) => {
+                                    peer_lock.state = PeerState::Idle;
+                                    peer_lock.player_name = None;
+                                }
+                                Err(e) => error!("send failed \"{}\"", e),
+                            }
+                        }
+                    }
+                    None => (),
+                }
+            }
+        }
 
-            let mut move_previous = Move::NoMove {};
-            match branch {
-                // when timeout
-                Either::Left(_) => {
-                    //let who_move = game_lock.who_move.as_ref().unwrap();
-                    //let color = game_lock.who_move.as_ref().unwrap().color.clone();
-                    /* This block prevent situation when
-                    process_move_make receive move message
-                    process_move_make lock game mutex
-                    process_move_make send message over channel
-                    move_call_dispatch select move_timeout
-                    move_call_dispatch wait lock game mutex
-                    process_move_make release lock
-                    move_call_dispatch lock game mutex
-                    move_call_dispatch loop to next iteration
-                        and get move_received from past turn */
-                    if game_lock.who_move.as_ref().unwrap().complete.is_some() {
-                        // important next!
-                        move_received.next().await;
-                        let mv = game_lock
-                            .who_move
-                            .as_ref()
-                            .unwrap()
-                            .complete
-                            .as_ref()
-                            .unwrap()
-                            .mv
-                            .clone();
-                        game_lock.apply_move(&mv);
-                        move_previous = mv;
-                        //TODO: process move
-                    } else {
-                        let player = game_lock.current_move_player_mut().unwrap();
-                        player.state = PlayerState::Lost;
-                        player.time_remaining = Duration::from_secs(0);
+        // HeartbeatReady => MMQueue if timeout
+        // This require coz group of four player may not get ready
+        // for a long time due to other players leave by HeartbeatWait timeout.
+        {
+            let now = Instant::now();
+            for (_, peer) in peers.iter() {
+                let mut peer_lock = peer.lock().await;
+                match peer_lock.state.get_hb_ready_since() {
+                    Some(hb_ready_since) => {
+                        let wait_time = now.duration_since(hb_ready_since);
+                        if wait_time > HB_READY_TIMEOUT {
+                            peer_lock.state = PeerState::MMQueue;
+                        }
                     }
+                    None => (),
                 }
-                // when move received
-                Either::Right(_) => {
-                    let mv = game_lock
-                        .who_move
-                        .as_ref()
-                        .unwrap()
-                        .complete
-                        .as_ref()
-                        .unwrap()
-                        .mv
-                        .clone();
-                    game_lock.apply_move(&mv);
-                    move_previous = mv;
+            }
+        }
+
+        // Now create GameSession form the HeartbeatReady players and broadcast init
+        {
+            let mut candidates = Vec::new();
+            for (key, peer) in peers.iter() {
+                let peer_lock = peer.lock().await;
+                if peer_lock.state.is_hb_ready() {
+                    candidates.push((key, peer.clone(), peer_lock));
                 }
             }
+            let (groups, leftovers) = split_groups(vault.tenant(), candidates, start);
+            // games with bots are never rated
+            let bot_groups = bot_fill_groups(vault.tenant(), leftovers, |peer| peer.bot_fill);
+            for mut tmp_peers in groups.into_iter().chain(bot_groups) {
+                let casual = tmp_peers.len() < 4
+                    || tmp_peers
+                        .iter()
+                        .any(|p| pool_casual(vault.tenant(), &p.2.pool));
+                let bot_level = tmp_peers
+                    .iter()
+                    .map(|p| pool_bot_level(vault.tenant(), &p.2.pool))
+                    .max()
+                    .unwrap_or(0);
+                let time_control = Duration::from_secs(tmp_peers[0].2.time_control);
+                let mut iter = tmp_peers.iter_mut().map(|p| (p.1.clone(), &mut *p.2));
+                let players = [iter.next(), iter.next(), iter.next(), iter.next()];
+                start_game(
+                    &vault,
+                    players,
+                    casual,
+                    bot_level,
+                    time_control,
+                    None,
+                    None,
+                    default_disconnect_policy(),
+                )
+                .await;
+            }
+        }
+        let states = vault.count_states().await;
+        let count = |name| states.get(name).copied().unwrap_or(0);
+        debug!(
+            "peers:{},  idle:{},  mm_queue:{},  hb_wait:{},  hb_ready:{},  reconnect:{},  tick:{:?}",
+            vault.peer_count(),
+            count("idle"),
+            count("mm_queue"),
+            count("heartbeat_wait"),
+            count("heartbeat_ready"),
+            vault.reconnect_count(),
+            Instant::now().duration_since(start)
+        );
+    }
+}
 
-            let mut move_call = MoveCall::NoCall {};
+#[tokio::main]
+async fn main() -> Result<(), IoError> {
+    let mut builder = Builder::new();
+    builder.filter(Some("server_rs"), LevelFilter::Debug).init();
+    if let Err(e) = telemetry::init(SERV_NAME) {
+        error!("span export failed to start \"{:#}\"", e);
+    }
 
-            // find first no lost state player
-            // if he checknmate or stalemate, lost him
-            while let Some(player) = game_lock.next_moved_player_mut() {
-                match player.state {
-                    PlayerState::Checkmate | PlayerState::Stalemate | PlayerState::Lost => {
-                        player.state = PlayerState::Lost
+    // "conformance ws://host:port" checks the running server instead of serving
+    if env::args().nth(1).as_deref() == Some("conformance") {
+        let url = env::args()
+            .nth(2)
+            .unwrap_or_else(|| "ws://127.0.0.1:8080".to_string());
+        std::process::exit(if conformance::run(&url).await { 0 } else { 1 });
+    }
+
+    let addr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "0.0.0.0:8080".to_string());
+
+    // wss:// when both certificate and key are configured, plain ws:// otherwise
+    let tls = match (env::var("FPC_TLS_CERT"), env::var("FPC_TLS_KEY")) {
+        (Ok(cert), Ok(key)) => {
+            Some(tls::load_acceptor(&cert, &key).expect("Failed to load TLS certificate"))
+        }
+        _ => None,
+    };
+    if tls.is_some() {
+        info!("TLS enabled");
+    }
+
+    // json list of tenants each served on its own address,
+    // the only tenant is configured by the environment if unset
+    let tenants = match env::var("FPC_TENANTS") {
+        Ok(path) => tenant::load_tenants(&path)
+            .expect("bad FPC_TENANTS")
+            .into_iter()
+            .map(|config| {
+                let tenant = config.load(*WAL_FSYNC).expect("Failed to load tenant");
+                (config.addr, tenant, config.observer_addr)
+            })
+            .collect(),
+        Err(_) => vec![(addr, env_tenant(), env::var("FPC_OBSERVER_ADDR").ok())],
+    };
+    let tenants = tenants
+        .into_iter()
+        .map(|(addr, tenant, observer_addr)| {
+            (
+                addr,
+                Arc::new(vault::Vault::with_tenant(tenant)),
+                observer_addr,
+            )
+        })
+        .collect::<Vec<_>>();
+    if let Ok(addr) = env::var("FPC_HEALTH_ADDR") {
+        let vaults = tenants.iter().map(|(_, vault, _)| vault.clone()).collect();
+        tokio::spawn(health_server(vaults, addr));
+    }
+    future::join_all(
+        tenants.into_iter().map(|(addr, vault, observer_addr)| {
+            serve_tenant(addr, vault, observer_addr, tls.clone())
+        }),
+    )
+    .await;
+
+    Ok(())
+}
+
+// The only tenant of the server, served on the address argument.
+// Nothing is persisted without FPC_STORAGE_DIR, FPC_POOLS is the json list of pools with
+// schedules, only the default pool if unset. Moderators redact chat and read chat of any
+// game record with FPC_MODERATOR_TOKEN, operators run admin commands with FPC_ADMIN_TOKEN,
+// both are disabled if unset. The canary probes FPC_CANARY_URL with the FPC_CANARY_NAME
+// account of FPC_CANARY_PASSWORD in FPC_CANARY_POOL every FPC_CANARY_INTERVAL seconds and
+// posts failures to FPC_CANARY_WEBHOOK, it is disabled without url and password.
+// Nodes of one FPC_REDIS_URL share games and queues, FPC_NODE_ADDR is where clients reach
+// this node, both are needed for the distributed mode.
+fn env_tenant() -> Tenant {
+    Tenant {
+        name: None,
+        pools: match env::var("FPC_POOLS") {
+            Ok(path) => pool::load_pools(&path).expect("bad FPC_POOLS"),
+            Err(_) => vec![Pool::new(DEFAULT_POOL)],
+        },
+        storage: env::var("FPC_STORAGE_DIR")
+            .ok()
+            .map(|dir| Arc::new(Storage::new(&dir, *WAL_FSYNC).expect("Failed to open storage"))),
+        admin_token: env::var("FPC_ADMIN_TOKEN").ok(),
+        moderator_token: env::var("FPC_MODERATOR_TOKEN").ok(),
+        canary: match (env::var("FPC_CANARY_URL"), env::var("FPC_CANARY_PASSWORD")) {
+            (Ok(url), Ok(password)) => Some(CanaryConfig {
+                url,
+                name: env::var("FPC_CANARY_NAME").unwrap_or_else(|_| "canary".to_string()),
+                password,
+                pool: env::var("FPC_CANARY_POOL").unwrap_or_else(|_| "canary".to_string()),
+                interval: env::var("FPC_CANARY_INTERVAL")
+                    .map(|s| s.parse().expect("bad FPC_CANARY_INTERVAL"))
+                    .unwrap_or(300),
+                webhook: env::var("FPC_CANARY_WEBHOOK").ok(),
+            }),
+            _ => None,
+        },
+        cluster: match (env::var("FPC_REDIS_URL"), env::var("FPC_NODE_ADDR")) {
+            (Ok(redis_url), Ok(node_addr)) => Some(Arc::new(
+                Cluster::new(
+                    &ClusterConfig {
+                        redis_url,
+                        node_addr,
+                    },
+                    None,
+                )
+                .expect("bad FPC_REDIS_URL"),
+            )),
+            _ => None,
+        },
+    }
+}
+
+// Restore the state of the tenant, run its dispatchers and accept its players.
+// Observer API is served on "host:port" of observer_addr, not served if unset.
+async fn serve_tenant(
+    addr: String,
+    vault: Vault,
+    observer_addr: Option<String>,
+    tls: Option<TlsAcceptor>,
+) {
+    let name = vault
+        .tenant()
+        .name
+        .clone()
+        .unwrap_or_else(|| "server".to_string());
+
+    // Create the event loop and TCP listener we'll accept connections on.
+    let try_socket = TcpListener::bind(&addr).await;
+    let listener = try_socket.expect("Failed to bind");
+    info!("Listening on: {} ({})", addr, name);
+
+    let mut first_game_id = 0;
+    if let Some(storage) = vault.tenant().storage.clone() {
+        *vault.get_ratings().await = storage.load_ratings().expect("Failed to load ratings");
+        *vault.get_accounts().await = storage.load_accounts().expect("Failed to load accounts");
+        *vault.get_tournaments().await = storage
+            .load_tournaments()
+            .expect("Failed to load tournaments");
+        *vault.get_fair_play().await = storage
+            .load_fair_play()
+            .expect("Failed to load fair play suspects");
+        for record in storage.load_records().expect("Failed to load game records") {
+            vault.get_records().await.insert(record);
+        }
+        // loaded records are already persisted
+        vault.get_records().await.take_changed();
+        let samples = storage.load_metrics().expect("Failed to load metrics");
+        let mut history = vault.get_metrics().await;
+        for sample in samples {
+            history.push(sample, METRICS_RETENTION.as_secs());
+        }
+        drop(history);
+        first_game_id = restore_games(&vault, &storage)
+            .await
+            .expect("Failed to restore games");
+        tokio::spawn(snapshot_dispatcher(vault.clone(), storage));
+    }
+
+    tokio::spawn(season_dispatcher(vault.clone()));
+    tokio::spawn(tournament_dispatcher(vault.clone()));
+    tokio::spawn(keepalive_dispatcher(vault.clone()));
+    tokio::spawn(gc_dispatcher(vault.clone()));
+    tokio::spawn(metrics_dispatcher(
+        vault.clone(),
+        vault.tenant().storage.clone(),
+    ));
+    if let Some(addr) = observer_addr {
+        tokio::spawn(observer_server(vault.clone(), addr));
+    }
+    if let Some(config) = vault.tenant().canary.clone() {
+        tokio::spawn(canary::canary_dispatcher(vault.clone(), config));
+    }
+    if let Some(cluster) = vault.tenant().cluster.clone() {
+        tokio::spawn(cluster_dispatcher(vault.clone(), cluster));
+    }
+
+    if let Ok(path) = env::var("FPC_FLAGS") {
+        *vault.get_flags().await = flags::load_flags(&path).expect("bad FPC_FLAGS");
+    }
+    if let Ok(path) = env::var("FPC_SERVER_META") {
+        tokio::spawn(server_meta_dispatcher(vault.clone(), path));
+    }
+    if let Ok(path) = env::var("FPC_CHAT_FILTER") {
+        tokio::spawn(chat_filter_dispatcher(vault.clone(), path));
+    }
+
+    vault.set_next_game_id(first_game_id);
+    tokio::spawn(matchmaking_dispatcher(vault.clone()));
+    vault.set_listening();
+
+    // Let's spawn the handling of each connection in a separate task.
+    while let Ok((stream, addr)) = listener.accept().await {
+        match &tls {
+            Some(acceptor) => {
+                let acceptor = acceptor.clone();
+                let vault = vault.clone();
+                tokio::spawn(async move {
+                    match acceptor.accept(stream).await {
+                        Ok(stream) => handle_connection(vault, stream, addr, *WS_DEFLATE).await,
+                        Err(e) => error!("TLS handshake with \"{}\" failed \"{}\"", addr, e),
                     }
+                });
+            }
+            None => {
+                tokio::spawn(handle_connection(vault.clone(), stream, addr, *WS_DEFLATE));
+            }
+        }
+    }
+}
 
-                    PlayerState::NoState | PlayerState::Check => {
-                        player_time_remaining = player.time_remaining;
-                        move_call = MoveCall::Call {
-                            player: player.color.clone().to_string(),
-                            timer: player.time_remaining.as_secs(),
-                            timer_2: PLAYER_TIME_2.as_secs(),
-                        };
-                        game_lock.who_move = Some(WhoMove {
-                            color: player.color.clone(),
-                            since: tokio::time::Instant::now(),
-                            complete: None,
-                        });
-                        break;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_channel::mpsc::UnboundedSender;
+    use serde_json::{json, Value};
+
+    // virtual time, the tests run on the paused clock which jumps to the next timer
+    static EXPECT_TIMEOUT: Duration = Duration::from_secs(600);
+
+    // In-memory transport, the test plays the client side of the session
+    struct TestClient {
+        to_server: UnboundedSender<Message>,
+        from_server: UnboundedReceiver<Message>,
+    }
+
+    impl TestClient {
+        fn connect(vault: &Vault) -> TestClient {
+            let (to_server, incoming) = unbounded();
+            let (outgoing, from_server) = unbounded();
+            let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+            tokio::spawn(serve_peer(vault.clone(), incoming, outgoing, addr));
+            TestClient {
+                to_server,
+                from_server,
+            }
+        }
+
+        fn send(&self, pdu: Value) {
+            self.to_server
+                .unbounded_send(Message::Text(pdu.to_string()))
+                .unwrap();
+        }
+
+        // next pdu with the value at the pointer, others are skipped
+        async fn expect(&mut self, pointer: &str) -> Value {
+            let wait = async {
+                while let Some(msg) = self.from_server.next().await {
+                    if let Ok(text) = msg.to_text() {
+                        let pdu: Value = serde_json::from_str(text).unwrap();
+                        if pdu.pointer(pointer).is_some() {
+                            return pdu;
+                        }
                     }
                 }
+                panic!("session closed while waiting for {}", pointer)
+            };
+            time::timeout(EXPECT_TIMEOUT, wait)
+                .await
+                .unwrap_or_else(|_| panic!("no {} in time", pointer))
+        }
+
+        async fn login(&mut self, name: &str) {
+            self.send(json!({"handshake": {"connect": {"client": {
+                "name": "test", "version": "1", "protocol": {"version": ProtoVersion::LATEST.as_str()}}}}}));
+            self.expect("/handshake/connect/ok").await;
+            self.send(json!({"auth": {"register": {"name": name, "password": "password1"}}}));
+            self.expect("/auth/ok").await;
+        }
+    }
+
+    fn send_move(client: &TestClient, from: &str, to: &str) {
+        client.send(json!({"game_session": {"move": {"basic": {"from": from, "to": to}}}}));
+    }
+
+    // Scripted players matched into a game in red, blue, yellow, green order,
+    // the countdown is over and red is called
+    async fn game_of_four(vault: &Vault) -> Vec<TestClient> {
+        tokio::spawn(matchmaking_dispatcher(vault.clone()));
+        let names = ["alice", "bob", "carol", "dave"];
+        let mut clients = Vec::new();
+        for name in names {
+            let mut client = TestClient::connect(vault);
+            client.login(name).await;
+            client.send(json!({"matchmaking_queue": {"player_register": {"name": name}}}));
+            client.expect("/matchmaking_queue/player_register/ok").await;
+            clients.push(client);
+        }
+        for client in clients.iter_mut() {
+            client.expect("/matchmaking_queue/heartbeat_check").await;
+            client.send(json!({"matchmaking_queue": {"heartbeat_check": {}}}));
+        }
+        let mut seated = Vec::new();
+        for (client, name) in clients.into_iter().zip(names) {
+            let mut client = client;
+            let init = client.expect("/game_session/init").await;
+            let positions = &init["game_session"]["init"]["start_positions"];
+            let seat = ["red", "blue", "yellow", "green"]
+                .iter()
+                .position(|c| positions[c]["player_name"] == name)
+                .unwrap();
+            client.send(json!({"game_session": {"ready": {}}}));
+            seated.push((seat, client));
+        }
+        seated.sort_by_key(|(seat, _)| *seat);
+        let mut clients = seated.into_iter().map(|(_, c)| c).collect::<Vec<_>>();
+        for client in clients.iter_mut() {
+            let update = client.expect("/game_session/update").await;
+            assert_eq!(
+                update["game_session"]["update"]["move_call"]["call"]["player"],
+                "Red"
+            );
+        }
+        clients
+    }
+
+    // next update seen by all players
+    async fn next_update(clients: &mut [TestClient]) -> Value {
+        let mut updates = Vec::new();
+        for client in clients.iter_mut() {
+            let update = client.expect("/game_session/update").await;
+            updates.push(update["game_session"]["update"].clone());
+        }
+        assert!(updates.iter().all(|u| *u == updates[0]));
+        updates.remove(0)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn players_matched_into_game_and_move() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut clients = game_of_four(&vault).await;
+        send_move(&clients[0], "e2", "e4");
+        let update = next_update(&mut clients).await;
+        assert_eq!(update["move_previous"]["basic"]["to"], "e4");
+        assert_eq!(update["move_call"]["call"]["player"], "Blue");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn protocol_negotiated_and_older_clients_spared_newer_pdus() {
+        let vault = Arc::new(vault::Vault::new());
+        for (offered, negotiated) in [(json!(["0", "1", "2"]), "1"), (json!(["9", "0"]), "0")] {
+            let mut client = TestClient::connect(&vault);
+            client.send(json!({"handshake": {"connect": {"client": {
+                "name": "test", "version": "1", "protocol": {"supported_version": offered}}}}}));
+            let ok = client.expect("/handshake/connect/ok").await;
+            assert_eq!(
+                ok["handshake"]["connect"]["ok"]["server"]["protocol"],
+                negotiated
+            );
+        }
+        let mut client = TestClient::connect(&vault);
+        client.send(json!({"handshake": {"connect": {"client": {
+            "name": "test", "version": "1", "protocol": {"supported_version": ["9"]}}}}}));
+        client
+            .expect("/handshake/connect/error/unsupported_protocol_version")
+            .await;
+
+        let mut clients = game_of_four(&vault).await;
+        for (_, peer) in vault.get_peers() {
+            let mut peer_lock = peer.lock().await;
+            if matches!(
+                peer_lock.state,
+                PeerState::Game {
+                    color: Color::Red,
+                    ..
+                }
+            ) {
+                peer_lock.client_info.as_mut().unwrap().protocol = ProtoVersion::V0;
+            }
+        }
+        send_move(&clients[0], "e2", "e4");
+        let next = clients[0].expect("/game_session").await;
+        assert!(next.pointer("/game_session/update").is_some());
+        next_update(&mut clients[1..]).await;
+        send_move(&clients[1], "b5", "c5");
+        let next = clients[1].expect("/game_session").await;
+        assert!(next.pointer("/game_session/move/ok").is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cells_read_in_either_form_and_written_as_strings() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut old = TestClient::connect(&vault);
+        old.send(json!({"handshake": {"connect": {"client": {
+            "name": "test", "version": "1", "protocol": {"version": "0"}}}}}));
+        old.expect("/handshake/connect/ok").await;
+
+        let mut clients = game_of_four(&vault).await;
+        clients[0].send(json!({"game_session": {"move": {"basic": {"from": "E2", "to": [4, 3]}}}}));
+        let update = next_update(&mut clients).await;
+        assert_eq!(update["move_previous"]["basic"]["from"], "e2");
+        assert_eq!(update["move_previous"]["basic"]["to"], "e4");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lobby_game_starts_from_the_chosen_position() {
+        let vault = Arc::new(vault::Vault::new());
+        let board = "3,yR,yN,yB,yK,yQ,yB,yN,yR,3/3,yP,yP,yP,yP,yP,yP,yP,yP,3/14/bR,bP,10,gP,gR/bN,bP,10,gP,gN/bB,bP,10,gP,gB/bK,bP,10,gP,gQ/bQ,bP,10,gP,gK/bB,bP,10,gP,gB/bN,bP,10,gP,gN/bR,bP,10,gP,gR/14/3,rP,rP,rP,rP,rP,rP,rP,rP,3/3,rR,rN,rB,rQ,rK,rB,rN,rR,3";
+        let flags = "-1,1,1,1-1,1,1,1-0,0,0,0-0-{'enPassant':('','','','')}-";
+        let start = format!("Y-0,0,0,0{}{}", flags, board);
+
+        let mut clients = Vec::new();
+        for name in ["alice", "bob", "carol", "dave"] {
+            let mut client = TestClient::connect(&vault);
+            client.login(name).await;
+            clients.push(client);
+        }
+        let dead = format!("Y-0,1,0,0{}{}", flags, board);
+        let kingless = format!("Y-0,0,0,0{}{}", flags, board.replace("yK", "yP"));
+        for bad in [dead.as_str(), kingless.as_str(), "Y-0,0,0,0"] {
+            clients[0].send(json!({"lobby": {"create": {"player_name": "alice", "start": bad}}}));
+            clients[0].expect("/lobby/error/bad_position").await;
+        }
+        clients[0].send(json!({"lobby": {"create": {"player_name": "alice", "start": start}}}));
+        let ok = clients[0].expect("/lobby/ok").await;
+        let code = ok["lobby"]["ok"]["code"].clone();
+        for (client, name) in clients[1..].iter_mut().zip(["bob", "carol", "dave"]) {
+            client.send(json!({"lobby": {"join": {"code": code, "player_name": name}}}));
+            client.expect("/lobby/ok").await;
+        }
+        for client in clients.iter_mut() {
+            let init = client.expect("/game_session/init").await;
+            assert_eq!(init["game_session"]["init"]["start"], start);
+            client.send(json!({"game_session": {"ready": {}}}));
+        }
+        let update = next_update(&mut clients).await;
+        assert_eq!(update["move_call"]["call"]["player"], "Yellow");
+        send_move(&clients[2], "e13", "e12");
+        let update = next_update(&mut clients).await;
+        assert_eq!(update["move_call"]["call"]["player"], "Green");
+
+        let game = vault.get_games().pop().unwrap();
+        let record = game.lock().await.record();
+        assert_eq!(record.start.as_deref(), Some(start.as_str()));
+        assert_eq!(record.first_move, Color::Yellow);
+        let replayed = replay_plies(&record, 0).unwrap();
+        assert!(replayed.board.piece(Position::e13).is_some());
+        assert!(replayed.board.piece(Position::e12).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn moves_acknowledged_and_updates_numbered() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut clients = game_of_four(&vault).await;
+        for (seat, (from, to)) in [("e2", "e4"), ("b5", "c5")].iter().enumerate() {
+            send_move(&clients[seat], from, to);
+            let ack = clients[seat].expect("/game_session/move/ok").await;
+            let seq = ack["game_session"]["move"]["ok"]["seq"].clone();
+            assert_eq!(seq, seat + 1);
+            let update = next_update(&mut clients).await;
+            assert_eq!(update["seq"], seq);
+        }
+        send_move(&clients[0], "e4", "e6");
+        clients[0].expect("/game_session/move/error").await;
+
+        clients[2].send(json!({"game_session": {"state_sync": {"request": {}}}}));
+        let sync = clients[2].expect("/game_session/state_sync/state").await;
+        assert_eq!(sync["game_session"]["state_sync"]["state"]["seq"], 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn state_synced_for_players_and_spectators() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut clients = game_of_four(&vault).await;
+        send_move(&clients[0], "e2", "e4");
+        next_update(&mut clients).await;
+        time::sleep(Duration::from_secs(2)).await;
+
+        clients[1].send(json!({"game_session": {"state_sync": {"request": {}}}}));
+        let sync = clients[1].expect("/game_session/state_sync/state").await;
+        let state = &sync["game_session"]["state_sync"]["state"];
+        assert_eq!(state["move_call"]["call"]["player"], "Blue");
+        assert_eq!(state["moves"], 1);
+        assert!(state["turn_elapsed"].as_u64().unwrap() >= 2000);
+        assert!(state["position"]
+            .as_str()
+            .unwrap()
+            .starts_with("B-0,0,0,0-"));
+        let game_id = state["game_id"].clone();
+
+        let mut spectator = TestClient::connect(&vault);
+        spectator.login("eve").await;
+        spectator.send(json!({"game_session": {"state_sync": {"request": {"game_id": game_id}}}}));
+        spectator
+            .expect("/game_session/state_sync/error/forbidden")
+            .await;
+        spectator.send(json!({"analysis": {"spectate": {"request": {"game_id": game_id}}}}));
+        spectator.expect("/analysis/spectate/ok").await;
+        spectator.send(json!({"game_session": {"state_sync": {"request": {"game_id": game_id}}}}));
+        let seen = spectator.expect("/game_session/state_sync/state").await;
+        assert_eq!(
+            seen["game_session"]["state_sync"]["state"]["checksum"],
+            state["checksum"]
+        );
+
+        spectator.send(json!({"game_session": {"state_sync": {"request": {"game_id": 99}}}}));
+        spectator
+            .expect("/game_session/state_sync/error/game_not_found")
+            .await;
+    }
+
+    #[test]
+    fn stale_peers_told_why_they_are_closed() {
+        let (tx, _rx) = peer_channel(1);
+        let mut peer = new_peer(tx, SocketAddr::from(([127, 0, 0, 1], 0)));
+        let after = |timeout: Duration| peer.last_request + timeout + Duration::from_secs(1);
+        let (handshake, idle) = (after(*HANDSHAKE_TIMEOUT), after(IDLE_TIMEOUT.unwrap()));
+
+        assert!(stale_peer(&peer, peer.last_request, false).is_none());
+        assert!(matches!(
+            stale_peer(&peer, handshake, false),
+            Some(Pdu::Handshake(Handshake::Timeout { .. }))
+        ));
+        peer.state = PeerState::Idle;
+        assert!(stale_peer(&peer, handshake, false).is_none());
+        assert!(matches!(
+            stale_peer(&peer, idle, false),
+            Some(Pdu::Disconnect(Disconnect {
+                reason: DisconnectReason::Idle,
+                ..
+            }))
+        ));
+        assert!(stale_peer(&peer, idle, true).is_none());
+        peer.state = PeerState::MMQueue;
+        assert!(stale_peer(&peer, idle, false).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fast_moves_wait_for_the_move_interval() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut clients = game_of_four(&vault).await;
+        let started = time::Instant::now();
+        for (seat, (from, to)) in [("h2", "h3"), ("b5", "c5"), ("e13", "e12"), ("m5", "l5")]
+            .iter()
+            .enumerate()
+        {
+            send_move(&clients[seat], from, to);
+            next_update(&mut clients).await;
+        }
+        // the others answered at once, the second move of red is held back
+        assert!(started.elapsed() < *MIN_MOVE_INTERVAL);
+        send_move(&clients[0], "h3", "h4");
+        let update = next_update(&mut clients).await;
+        assert!(started.elapsed() >= *MIN_MOVE_INTERVAL);
+        assert_eq!(update["move_previous"]["basic"]["to"], "h4");
+        assert_eq!(update["move_call"]["call"]["player"], "Blue");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn seat_labels_sent_to_clients_which_take_them() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut client = TestClient::connect(&vault);
+        client.send(json!({"handshake": {"connect": {"client": {
+            "name": "test", "version": "1", "protocol": {"version": ProtoVersion::LATEST.as_str()},
+            "capabilities": [CAPABILITY_SEAT_LABELS]}}}}));
+        let ok = client.expect("/handshake/connect/ok").await;
+        let seats = &ok["handshake"]["connect"]["ok"]["server"]["rules"]["seats"];
+        assert_eq!(seats["blue"], json!({"number": 2, "direction": "west"}));
+
+        let mut clients = game_of_four(&vault).await;
+        for (_, peer) in vault.get_peers() {
+            let mut peer_lock = peer.lock().await;
+            if matches!(
+                peer_lock.state,
+                PeerState::Game {
+                    color: Color::Blue,
+                    ..
+                }
+            ) {
+                peer_lock.client_info.as_mut().unwrap().seat_labels = true;
             }
+        }
+        send_move(&clients[0], "e2", "e4");
+        let plain = clients[0].expect("/game_session/update").await;
+        assert!(plain
+            .pointer("/game_session/update/move_call/call/seat")
+            .is_none());
+        let labeled = clients[1].expect("/game_session/update").await;
+        let update = &labeled["game_session"]["update"];
+        assert_eq!(update["move_call"]["call"]["player"], "Blue");
+        assert_eq!(update["move_call"]["call"]["seat"]["number"], 2);
+        assert_eq!(
+            update["players_states"]["seats"]["red"]["direction"],
+            "south"
+        );
+    }
 
-            let players_states = PlayersStates {
-                red: game_lock.player(&Color::Red).state.clone().into(),
-                blue: game_lock.player(&Color::Blue).state.clone().into(),
-                yellow: game_lock.player(&Color::Yellow).state.clone().into(),
-                green: game_lock.player(&Color::Green).state.clone().into(),
-            };
+    #[tokio::test(start_paused = true)]
+    async fn msgpack_pdus_after_the_handshake() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut client = TestClient::connect(&vault);
+        client.send(json!({"handshake": {"connect": {"client": {
+            "name": "test", "version": "1", "protocol": {"version": ProtoVersion::LATEST.as_str()},
+            "capabilities": [CAPABILITY_MSGPACK]}}}}));
+        let ok = client.expect("/handshake/connect/ok").await;
+        assert_eq!(
+            ok["handshake"]["connect"]["ok"]["server"]["capabilities"],
+            json!([CAPABILITY_MSGPACK])
+        );
 
-            let update = Pdu::GameSession(GameSession::Update(Update {
-                move_call: move_call.clone(),
-                move_previous,
-                players_states,
-            }))
-            .to_message()?;
+        let register = json!({"auth": {"register": {"name": "alice", "password": "password1"}}});
+        let bytes = rmp_serde::to_vec_named(&register).unwrap();
+        client
+            .to_server
+            .unbounded_send(Message::Binary(bytes))
+            .unwrap();
+        let reply = match client.from_server.next().await.unwrap() {
+            Message::Binary(bytes) => rmp_serde::from_slice::<Value>(&bytes).unwrap(),
+            other => panic!("json reply {:?}", other),
+        };
+        assert!(reply.pointer("/auth/ok").is_some());
 
-            game_lock.broadcast(update).await?;
+        // json is still understood
+        client.send(json!({"game_session": {"state_sync": {"request": {}}}}));
+        let reply = client.from_server.next().await.unwrap();
+        assert!(matches!(
+            Pdu::from_message(&reply).unwrap(),
+            Pdu::GameSession(GameSession::StateSync(StateSync::Error(
+                StateSyncError::Forbidden { .. }
+            )))
+        ));
+        assert!(reply.is_binary());
+    }
 
-            if move_call.is_no_call() {
-                game_lock.who_move = None;
-                break;
-            }
+    #[tokio::test(start_paused = true)]
+    async fn reply_does_not_wait_for_the_peer() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut client = TestClient::connect(&vault);
+        client.send(json!({"handshake": {"get_info": {"request": {}}}}));
+        client.expect("/handshake/get_info/ok").await;
+
+        let (conn_id, peer) = vault.get_peers().pop().unwrap();
+        let _peer_lock = peer.lock().await;
+        let pdu = Pdu::Handshake(Handshake::GetInfo(GetInfo::Request {}));
+        process_msg(&pdu, &vault, &conn_id).await.unwrap();
+        client.expect("/handshake/get_info/ok").await;
+    }
+
+    #[tokio::test]
+    async fn thousands_of_handshakes_at_once() {
+        assert!(handshake_rate(BENCHMARK_HANDSHAKES).await.unwrap() > 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn game_of_ended_supervisor_reaped() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut clients = game_of_four(&vault).await;
+        let game_id = vault.get_games()[0].lock().await.id;
+        assert!(vault.dead_games().is_empty());
+
+        let ended = tokio::spawn(async {});
+        while !ended.is_finished() {
+            tokio::task::yield_now().await;
         }
+        vault.insert_game_task(game_id, ended);
+        assert_eq!(vault.dead_games(), vec![game_id]);
+        reap_game(&vault, game_id).await;
 
-        /*if game_lock.next_moved_player_mut().is_none() {
-            break;
-        }*/
+        for client in clients.iter_mut() {
+            let error = client.expect("/game_session/internal_error").await;
+            assert_eq!(error["game_session"]["internal_error"]["recovered"], false);
+        }
+        assert!(vault.get_game(game_id).is_none());
+        assert!(vault.dead_games().is_empty());
+        assert_eq!(vault.take_dead_game_count(), 1);
+    }
 
-        // if player timeout
-        /*if let Either::Right(b) = branch {
-            let b = b.clone();
-            move_received.close();
-            let player = game_lock.current_move_player_mut();
-            println!("{:?}", b);
-            //player
-        }*/
+    #[tokio::test(start_paused = true)]
+    async fn round_trip_of_the_player_not_charged() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut clients = game_of_four(&vault).await;
+        let game = vault.get_games().pop().unwrap();
+        let red = game.lock().await.player(&Color::Red).peer.clone();
+        red.lock().await.ping_sent = Instant::now().checked_sub(Duration::from_millis(300));
+        clients[0]
+            .to_server
+            .unbounded_send(Message::Pong(Vec::new()))
+            .unwrap();
+        send_move(&clients[0], "e2", "e4");
+        next_update(&mut clients).await;
+        let rtt = red.lock().await.rtt.unwrap();
+        assert!(rtt >= Duration::from_millis(300) && rtt < *LATENCY_CAP);
+        for (seat, from, to) in [(1, "b5", "c5"), (2, "e13", "e12"), (3, "m5", "l5")] {
+            send_move(&clients[seat], from, to);
+            next_update(&mut clients).await;
+        }
 
-        //println!("{:?}", branch);
+        // the move reached the server after the time of red, it was made before
+        let started = time::Instant::now();
+        clients[0].send(json!({"game_session": {"move": {"no_move": {}}}}));
+        time::sleep_until(started + PLAYER_TIMER + PLAYER_TIME_2 + Duration::from_millis(150))
+            .await;
+        send_move(&clients[0], "f2", "f4");
+        let update = next_update(&mut clients).await;
+        assert!(update["players_states"]["red"].get("lost").is_none());
+        assert_eq!(update["move_previous"]["basic"]["to"], "f4");
+        assert_eq!(update["move_call"]["call"]["player"], "Blue");
+        assert_eq!(
+            game.lock().await.moves.last().unwrap().time_used,
+            PLAYER_TIMER + PLAYER_TIME_2 + Duration::from_millis(150) - rtt
+        );
     }
 
-    Ok(())
-}
+    #[tokio::test(start_paused = true)]
+    async fn flagged_player_loses_and_turns_skip_him() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut clients = game_of_four(&vault).await;
+        let started = time::Instant::now();
+        // red is present, so he is not taken for afk and thinks until his time is over
+        clients[0].send(json!({"game_session": {"move": {"no_move": {}}}}));
+        let update = next_update(&mut clients).await;
+        assert!(started.elapsed() >= PLAYER_TIMER + PLAYER_TIME_2);
+        assert!(update["players_states"]["red"].get("lost").is_some());
+        assert_eq!(update["players_clocks"]["red"], 0);
+        assert_eq!(
+            update["players_clocks"]["blue"],
+            PLAYER_TIMER.as_millis() as u64
+        );
+        assert_eq!(update["move_call"]["call"]["player"], "Blue");
 
-/*async fn move_call_dispatch(
-    vault: Vault,
-    game_id: u64,
-    player_color: Color,
-    timeout: Duration,
-) -> Result<()> {
-    tokio::time::sleep(timeout).await;
+        send_move(&clients[1], "b5", "c5");
+        let update = next_update(&mut clients).await;
+        assert_eq!(update["move_call"]["call"]["player"], "Yellow");
+        send_move(&clients[2], "e13", "e12");
+        let update = next_update(&mut clients).await;
+        assert_eq!(update["move_call"]["call"]["player"], "Green");
+        send_move(&clients[3], "m5", "l5");
+        let update = next_update(&mut clients).await;
+        assert_eq!(update["move_call"]["call"]["player"], "Blue");
+    }
 
-    let lock = vault.write().await;
-    let games_lock = lock.get_games().await;
-    let game = games_lock
-        .get(&game_id)
-        .context("game_session game lookup failed")?;
-    let mut game_lock = game.lock().await;
+    #[tokio::test(start_paused = true)]
+    async fn think_time_over_the_grace_taken_from_the_clock() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut clients = game_of_four(&vault).await;
+        clients[0].send(json!({"game_session": {"move": {"no_move": {}}}}));
+        time::sleep(PLAYER_TIME_2 + Duration::from_secs(3)).await;
+        send_move(&clients[0], "e2", "e4");
+        let update = next_update(&mut clients).await;
+        let clock = PLAYER_TIMER - Duration::from_secs(3);
+        assert_eq!(update["players_clocks"]["red"], clock.as_millis() as u64);
 
-    let players = game_lock.players_mut();
+        // moves within the grace are free
+        time::sleep(PLAYER_TIME_2 - Duration::from_secs(1)).await;
+        send_move(&clients[1], "b5", "c5");
+        let update = next_update(&mut clients).await;
+        assert_eq!(
+            update["players_clocks"]["blue"],
+            PLAYER_TIMER.as_millis() as u64
+        );
+        assert_eq!(update["players_clocks"]["red"], clock.as_millis() as u64);
+    }
 
-    let lost_pdu = Pdu::GameSession(proto::GameSession::Lost {
-        player: player_color.to_string(),
-        description: "time over".to_string(),
-    })
-    .to_message()?;
+    #[test]
+    fn move_made_after_the_flag_not_played() {
+        let mut game = vault::fixtures::game(Board::new());
+        let who_move = game.who_move.as_mut().unwrap();
+        let time_remaining = Duration::from_secs(10);
+        who_move.complete = Some(Complete {
+            mv: Move::Basic {
+                from: Position::e2,
+                to: Position::e4,
+            },
+            at: who_move.since + time_remaining + PLAYER_TIME_2,
+            span: Span::none(),
+        });
+        let color = who_move.color;
+        game.player_mut(&color).time_remaining = time_remaining;
+        assert!(matches!(apply_complete_move(&mut game), Move::NoMove {}));
+        assert_eq!(game.player(&color).time_remaining, Duration::ZERO);
+        assert!(game.eliminated.contains(&color));
+        assert!(game.moves.is_empty());
+    }
 
-    for player in players {
-        if player_color == player.color {
-            player.state = PlayerState::Lost;
-            player.time_remaining = Duration::from_secs(0);
+    #[tokio::test]
+    async fn lagging_spectator_resynced() {
+        let mut game = vault::fixtures::game(Board::new());
+        let (tx, rx) = vault::peer_channel(8);
+        let mut rx = Box::pin(rx);
+        game.subscribe(
+            ConnectionId(1),
+            tx.clone(),
+            SubscriberKind::Spectator,
+            false,
+        );
+        game.set_state(game_state(&game));
+        // published faster than the spectator forwards
+        for _ in 0..100 {
+            game.publish(Message::Text("update".to_string()), None, None)
+                .await;
         }
-        player
-            .peer
-            .lock()
-            .await
-            .tx
-            .unbounded_send(lost_pdu.clone())?;
+        let state = rx.next().await.unwrap();
+        let state: serde_json::Value = serde_json::from_str(state.to_text().unwrap()).unwrap();
+        assert_eq!(state["game_session"]["state_sync"]["state"]["game_id"], 0);
+        game.publish(Message::Text("next".to_string()), None, None)
+            .await;
+        assert_eq!(rx.next().await, Some(Message::Text("next".to_string())));
+        assert!(!tx.is_closed());
     }
-    Ok(())
-}*/
-
-// Looping infinitely. On loop tick, if we find at least 4 MMQueue players, send HeartbeatCheck
-// Also, kick (send kick pdu and change state to Idle) players, who did not response on HeartbeatCheck
-// Also, change state HearbeatReady => MMQueue if timeout
-// TODO: Disconnect Idle players?
-async fn matchmaking_dispatcher(vault: Vault) {
-    let mut interval = time::interval(HB_DISP_TICK_PERIOD);
 
-    let heartbeat_pdu = Pdu::MatchmakingQueue(MatchmakingQueue::HeartbeatCheck {})
-        .to_message()
+    #[test]
+    fn agreed_draw_splits_remaining_points() {
+        let mut board = Board::new();
+        board.piece_move(Position::a5, Position::f3);
+        let mut game = vault::fixtures::game(board);
+        game.variant = Variant::Points;
+        // red took a knight of blue, yellow is out
+        game.apply_move(&Move::Capture {
+            from: Position::e2,
+            to: Position::f3,
+        })
         .unwrap();
-    let kick_pdu = Pdu::MatchmakingQueue(MatchmakingQueue::PlayerKick {
-        discritpion: "Heartbeat timeout".to_string(),
-    })
-    .to_message()
-    .unwrap();
+        game.eliminate(Color::Yellow);
 
-    //Err::<(),()>(()).unwrap();
-    let mut game_id = 0;
+        game.agree_draw();
+        // 63 points of the full army each but the knight, split by three
+        assert_eq!(
+            players_points(&game),
+            Some(PlayersPoints {
+                red: 65,
+                blue: 62,
+                yellow: 0,
+                green: 62,
+            })
+        );
+        assert!(game.player(&Color::Red).state == PlayerState::Draw);
+    }
 
-    loop {
-        interval.tick().await;
-        let start = Instant::now();
+    #[tokio::test(start_paused = true)]
+    async fn game_paused_and_resumed_by_all_players() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut clients = game_of_four(&vault).await;
+        for client in clients.iter() {
+            client.send(json!({"game_session": {"pause": {"vote": {"agree": true}}}}));
+        }
+        for client in clients.iter_mut() {
+            let state = client.expect("/game_session/pause/state").await;
+            assert_eq!(state["game_session"]["pause"]["state"]["paused"], true);
+        }
 
-        let lock = vault.write().await;
+        // red neither loses on time nor moves meanwhile
+        time::sleep(PLAYER_TIMER * 2).await;
+        send_move(&clients[0], "e2", "e4");
+        let error = clients[0].expect("/game_session/move/error").await;
+        assert_eq!(
+            error["game_session"]["move"]["error"]["illegal_move"]["reason"],
+            "game_paused"
+        );
+        for client in clients.iter() {
+            client.send(json!({"game_session": {"pause": {"vote": {"agree": true}}}}));
+        }
+        clients[0].expect("/game_session/countdown_tick").await;
+        for client in clients.iter_mut() {
+            let state = client.expect("/game_session/pause/state").await;
+            assert_eq!(state["game_session"]["pause"]["state"]["paused"], false);
+        }
+        send_move(&clients[0], "e2", "e4");
+        let update = next_update(&mut clients).await;
+        assert_eq!(update["move_previous"]["basic"]["to"], "e4");
+        assert_eq!(
+            update["players_clocks"]["red"],
+            PLAYER_TIMER.as_millis() as u64
+        );
+        assert_eq!(update["move_call"]["call"]["player"], "Blue");
+    }
 
-        // MMQueue => HeartbeatWait
-        // Send heartbeat to every 4 players which in MMQueue state
-        {
-            let mm_queue_lock = lock.get_mm_queue().await;
-            let mut hb_wait_lock = lock.get_hb_wait().await;
-            let mut tmp_peers = Vec::new();
-            for (key, peer) in mm_queue_lock.iter() {
-                let peer_lock = peer.lock().await;
-                if peer_lock.state.is_mm_queue() {
-                    tmp_peers.push((key, peer.clone(), peer_lock));
-                    if tmp_peers.len() == 4 {
-                        let now = Instant::now();
-                        for tmp_peer in &mut tmp_peers {
-                            match tmp_peer.2.tx.unbounded_send(heartbeat_pdu.clone()) {
-                                Ok(_) => {
-                                    tmp_peer.2.state = PeerState::HeartbeatWait(now);
-                                    hb_wait_lock.insert(*tmp_peer.0, tmp_peer.1.clone());
-                                }
-                                Err(e) => error!("unbounded_send failed \"{}\"", e),
-                            }
-                        }
-                        tmp_peers.clear();
-                    }
+    #[tokio::test(start_paused = true)]
+    async fn afk_player_skipped_then_resigned() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut clients = game_of_four(&vault).await;
+        // pawns of the others advance a square every round
+        let moves = [
+            ["b5", "c5", "d5", "e5"],
+            ["e13", "e12", "e11", "e10"],
+            ["m5", "l5", "k5", "j5"],
+        ];
+        let started = time::Instant::now();
+        let mut afk_turns = 0;
+        let mut rounds = [0; 3];
+        let update = loop {
+            let update = next_update(&mut clients).await;
+            if update["players_states"]["red"].get("lost").is_some() {
+                break update;
+            }
+            let seat = match update["move_call"]["call"]["player"].as_str() {
+                Some("Red") => {
+                    afk_turns += 1;
+                    continue;
                 }
+                Some("Blue") => 1,
+                Some("Yellow") => 2,
+                Some("Green") => 3,
+                _ => panic!("nobody called"),
+            };
+            if seat == 1 {
+                // skipped turns of red took the afk timeout only
+                assert!(started.elapsed() >= AFK_TURN_TIMEOUT * (afk_turns + 1));
             }
-        }
+            let round = rounds[seat - 1];
+            let (from, to) = (moves[seat - 1][round], moves[seat - 1][round + 1]);
+            send_move(&clients[seat], from, to);
+            rounds[seat - 1] += 1;
+        };
+        assert_eq!(afk_turns as usize, AFK_RESIGN_AFTER - 1);
+        assert_eq!(update["move_call"]["call"]["player"], "Blue");
+        // the clock of the afk player is not touched
+        assert_eq!(
+            update["players_clocks"]["red"],
+            PLAYER_TIMER.as_millis() as u64
+        );
+    }
 
-        // HeartbeatWait => Idle
-        // Drop to Idle state all players who timeout their HeartbeatWait state
+    #[tokio::test(start_paused = true)]
+    async fn tournament_seat_held_until_grace() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut clients = game_of_four(&vault).await;
+        let grace = Duration::from_secs(TOURNAMENT_RECONNECT_GRACE);
+        let game = vault.get_games().pop().unwrap();
+        game.lock().await.disconnect_policy = held_seat_policy(grace);
+        drop(clients.remove(0));
+
+        // the clock of red stands still and nobody moves in his place
+        time::sleep(PLAYER_TIMER * 2).await;
         {
-            //let lock = peers.write().await;
-            let now = Instant::now();
-            let hb_wait_lock = lock.get_hb_wait().await;
-            let mut idle = lock.get_idle().await;
-            for (key, peer) in hb_wait_lock.iter() {
-                let mut peer_lock = peer.lock().await;
-                match peer_lock.state.get_hb_wait_since() {
-                    Some(hb_wait_since) => {
-                        let wait_time = now.duration_since(hb_wait_since);
-                        if wait_time > HB_WAIT_TIMEOUT {
-                            match peer_lock.tx.unbounded_send(kick_pdu.clone()) {
-                                Ok(_) => {
-                                    peer_lock.state = PeerState::Idle;
-                                    peer_lock.player_name = None;
-                                    idle.insert(*key, peer.clone());
-                                }
-                                Err(e) => error!("unbounded_send failed \"{}\"", e),
-                            }
-                        }
-                    }
-                    None => (),
-                }
-            }
+            let game_lock = game.lock().await;
+            let red = game_lock.player(&Color::Red);
+            assert!(matches!(red.peer.lock().await.state, PeerState::Unknown(_)));
+            assert!(red.state == PlayerState::NoState);
+            assert_eq!(red.time_remaining, PLAYER_TIMER);
+            assert_eq!(game_lock.who_move.as_ref().unwrap().color, Color::Red);
         }
 
-        // HeartbeatReady => MMQueue if timeout
-        // This require coz group of four player may not get ready
-        // for a long time due to other players leave by HeartbeatWait timeout.
-        {
-            let now = Instant::now();
-            let hb_ready_lock = lock.get_hb_ready().await;
-            let mut mm_queue_lock = lock.get_mm_queue().await;
-            for (key, peer) in hb_ready_lock.iter() {
-                let mut peer_lock = peer.lock().await;
-                match peer_lock.state.get_hb_ready_since() {
-                    Some(hb_ready_since) => {
-                        let wait_time = now.duration_since(hb_ready_since);
-                        if wait_time > HB_READY_TIMEOUT {
-                            peer_lock.state = PeerState::MMQueue;
-                            mm_queue_lock.insert(*key, peer.clone());
-                        }
-                    }
-                    None => (),
-                }
+        // the grace runs out
+        let since = Instant::now().checked_sub(grace).unwrap();
+        game.lock()
+            .await
+            .player(&Color::Red)
+            .peer
+            .lock()
+            .await
+            .state = PeerState::Unknown(since);
+        let update = next_update(&mut clients).await;
+        assert!(update["players_states"]["red"].get("lost").is_some());
+        assert_eq!(update["move_call"]["call"]["player"], "Blue");
+        assert_eq!(
+            update["players_clocks"]["red"],
+            PLAYER_TIMER.as_millis() as u64
+        );
+    }
+
+    #[tokio::test]
+    async fn closed_session_removes_peer() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut client = TestClient::connect(&vault);
+        client.login("erin").await;
+        assert_eq!(vault.peer_count(), 1);
+        drop(client);
+        for _ in 0..100 {
+            if vault.peer_count() == 0 {
+                return;
             }
+            time::sleep(Duration::from_millis(10)).await;
         }
+        panic!("peer of the closed session is kept");
+    }
+    #[tokio::test]
+    async fn private_record_seen_by_its_players_only() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut player = TestClient::connect(&vault);
+        player.login("gina").await;
+        player.send(json!({"account": {"private": {"pool": DEFAULT_POOL}}}));
+        let pools = player.expect("/account/private_pools").await;
+        assert_eq!(
+            pools["account"]["private_pools"]["pools"],
+            json!([DEFAULT_POOL])
+        );
+        player.send(json!({"account": {"private": {"pool": "nowhere"}}}));
+        player.expect("/account/error/not_found").await;
 
-        // Now create GameSession form the HeartbeatReady players and broadcast init
-        {
-            let hb_ready_lock = lock.get_hb_ready().await;
-            let mut games_lock = lock.get_games().await;
-            let mut reconnect_lock = lock.get_reconnect().await;
-            let mut tmp_peers = Vec::new();
-            for (key, peer) in hb_ready_lock.iter() {
-                let peer_lock = peer.lock().await;
-                if peer_lock.state.is_hb_ready() {
-                    tmp_peers.push((key, peer.clone(), peer_lock));
-                    if tmp_peers.len() == 4 {
-                        let mut iter = tmp_peers.iter_mut();
-                        let red = iter.next().unwrap();
-                        let blue = iter.next().unwrap();
-                        let yellow = iter.next().unwrap();
-                        let green = iter.next().unwrap();
-
-                        // TODO: check unique
-                        let red_reconnect_id = random_string();
-                        let blue_reconnect_id = random_string();
-                        let yellow_reconnect_id = random_string();
-                        let green_reconnect_id = random_string();
-
-                        let (sender, receiver) = unbounded();
-
-                        let game = Arc::new(Mutex::new(Game {
-                            id: game_id,
-                            board: Board::new(),
-                            red: Player {
-                                color: Color::Red,
-                                reconnect_id: red_reconnect_id.clone(),
-                                time_remaining: PLAYER_TIMER,
-                                state: PlayerState::NoState,
-                                peer: red.1.clone(),
-                            },
-                            blue: Player {
-                                color: Color::Blue,
-                                reconnect_id: blue_reconnect_id.clone(),
-                                time_remaining: PLAYER_TIMER,
-                                state: PlayerState::NoState,
-                                peer: blue.1.clone(),
-                            },
-                            yellow: Player {
-                                color: Color::Yellow,
-                                reconnect_id: yellow_reconnect_id.clone(),
-                                time_remaining: PLAYER_TIMER,
-                                state: PlayerState::NoState,
-                                peer: yellow.1.clone(),
-                            },
-                            green: Player {
-                                color: Color::Green,
-                                reconnect_id: green_reconnect_id.clone(),
-                                time_remaining: PLAYER_TIMER,
-                                state: PlayerState::NoState,
-                                peer: green.1.clone(),
-                            },
-                            who_move: None,
-                            move_happen_signal: sender,
-                        }));
-
-                        games_lock.insert(game_id, game.clone());
-                        reconnect_lock.insert(red_reconnect_id.clone(), game.clone());
-                        reconnect_lock.insert(blue_reconnect_id.clone(), game.clone());
-                        reconnect_lock.insert(yellow_reconnect_id.clone(), game.clone());
-                        reconnect_lock.insert(green_reconnect_id.clone(), game.clone());
-
-                        red.2.state = PeerState::Game {
-                            color: Color::Red,
-                            game: game.clone(),
-                        };
-                        blue.2.state = PeerState::Game {
-                            color: Color::Blue,
-                            game: game.clone(),
-                        };
-                        yellow.2.state = PeerState::Game {
-                            color: Color::Yellow,
-                            game: game.clone(),
-                        };
-                        green.2.state = PeerState::Game {
-                            color: Color::Green,
-                            game: game.clone(),
-                        };
+        let mut record = vault::fixtures::game(Board::new()).record();
+        record.id = 5;
+        record.red.name = "gina".to_string();
+        record.private = true;
+        vault.get_records().await.insert(record);
 
-                        let red_name = red.2.player_name.clone().unwrap();
-                        let blue_name = blue.2.player_name.clone().unwrap();
-                        let yellow_name = red.2.player_name.clone().unwrap();
-                        let green_name = green.2.player_name.clone().unwrap();
-
-                        let red_pdu = game_init_pdu!(
-                            GS_INIT_PAUSE.as_secs(),
-                            red_reconnect_id,
-                            red_name.clone(),
-                            green_name.clone(),
-                            blue_name.clone(),
-                            yellow_name.clone()
-                        )
-                        .unwrap();
-                        let blue_pdu = game_init_pdu!(
-                            GS_INIT_PAUSE.as_secs(),
-                            blue_reconnect_id,
-                            red_name.clone(),
-                            green_name.clone(),
-                            blue_name.clone(),
-                            yellow_name.clone()
-                        )
-                        .unwrap();
-                        let yellow_pdu = game_init_pdu!(
-                            GS_INIT_PAUSE.as_secs(),
-                            yellow_reconnect_id,
-                            red_name.clone(),
-                            green_name.clone(),
-                            blue_name.clone(),
-                            yellow_name.clone()
-                        )
-                        .unwrap();
-                        let green_pdu = game_init_pdu!(
-                            GS_INIT_PAUSE.as_secs(),
-                            green_reconnect_id,
-                            red_name.clone(),
-                            green_name.clone(),
-                            blue_name.clone(),
-                            yellow_name.clone()
-                        )
-                        .unwrap();
-
-                        for (peer, pdu) in [
-                            (&red.2, red_pdu),
-                            (&blue.2, blue_pdu),
-                            (&yellow.2, yellow_pdu),
-                            (&green.2, green_pdu),
-                        ]
-                        .iter()
-                        {
-                            match peer.tx.unbounded_send(pdu.clone()) {
-                                Ok(_) => (),
-                                Err(e) => error!("unbounded_send failed \"{}\"", e),
-                            }
-                        }
+        let mut stranger = TestClient::connect(&vault);
+        stranger.login("hank").await;
+        stranger.send(json!({"analysis": {"replay": {"request": {"game_id": 5}}}}));
+        stranger
+            .expect("/analysis/replay/error/game_not_found")
+            .await;
+        stranger.send(json!({"analysis": {"pgn4": {"request": {"game_id": 5}}}}));
+        stranger.expect("/analysis/pgn4/error/game_not_found").await;
+        stranger.send(json!({"game_history": {"request": {"game_id": 5}}}));
+        stranger.expect("/game_history/error/game_not_found").await;
 
-                        tokio::spawn(move_call_dispatch(vault.clone(), receiver, game_id));
+        player.send(json!({"analysis": {"replay": {"request": {"game_id": 5}}}}));
+        player.expect("/analysis/replay/ok").await;
+        player.send(json!({"analysis": {"pgn4": {"request": {"game_id": 5}}}}));
+        player.expect("/analysis/pgn4/ok").await;
+    }
 
-                        game_id = game_id.wrapping_add(1);
-                        tmp_peers.clear();
-                    }
-                }
+    #[tokio::test(start_paused = true)]
+    async fn canary_plays_in_bot_pool() {
+        let mut pool = Pool::new("canary");
+        pool.casual = true;
+        pool.group_size = 1;
+        let vault = Arc::new(vault::Vault::with_tenant(Tenant {
+            pools: vec![pool],
+            ..Tenant::default()
+        }));
+        let config = CanaryConfig {
+            url: "ws://localhost".to_string(),
+            name: "canary".to_string(),
+            password: "canary-secret".to_string(),
+            pool: "canary".to_string(),
+            interval: 60,
+            webhook: None,
+        };
+        tokio::spawn(matchmaking_dispatcher(vault.clone()));
+        // the first probe registers the account, the next one logs in
+        for _ in 0..2 {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(handle_connection(
+                vault.clone(),
+                server,
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                false,
+            ));
+            let client = conformance::Client::over(client, &config.url, &config.name)
+                .await
+                .unwrap();
+            canary::play(client, &config).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn permessage_deflate_negotiated() {
+        let vault = Arc::new(vault::Vault::new());
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(handle_connection(
+            vault,
+            server,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            true,
+        ));
+        let request = tungstenite::http::Request::builder()
+            .uri("ws://localhost")
+            .header(
+                "Sec-WebSocket-Extensions",
+                "permessage-deflate; client_max_window_bits",
+            )
+            .body(())
+            .unwrap();
+        let (mut ws, response) = tokio_tungstenite::client_async(request, client)
+            .await
+            .unwrap();
+        let agreed = response.headers()["Sec-WebSocket-Extensions"]
+            .to_str()
+            .unwrap();
+        assert!(agreed.starts_with("permessage-deflate"));
+
+        // tungstenite of the client knows no compression, frames are made by hand
+        let connect = json!({"handshake": {"connect": {"client": {
+            "name": "test", "version": "1", "protocol": {"version": ProtoVersion::LATEST.as_str()}}}}});
+        let mut payload = deflate::deflate(connect.to_string().as_bytes()).unwrap();
+        let mask = [1, 2, 3, 4];
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        let frame = deflate::Frame {
+            fin: true,
+            rsv1: true,
+            opcode: 1,
+            mask: Some(mask),
+            payload,
+        };
+        deflate::write_frame(ws.get_mut(), &frame).await.unwrap();
+        let reply = deflate::read_frame(ws.get_mut()).await.unwrap();
+        assert!(reply.rsv1 && reply.fin && reply.mask.is_none());
+        let reply = deflate::inflate(&reply.payload).unwrap();
+        let reply: serde_json::Value = serde_json::from_slice(&reply).unwrap();
+        assert!(reply.pointer("/handshake/connect/ok").is_some());
+
+        // without the flag the offer is left unanswered
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(handle_connection(
+            Arc::new(vault::Vault::new()),
+            server,
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            false,
+        ));
+        let request = tungstenite::http::Request::builder()
+            .uri("ws://localhost")
+            .header("Sec-WebSocket-Extensions", "permessage-deflate")
+            .body(())
+            .unwrap();
+        let (_, response) = tokio_tungstenite::client_async(request, client)
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key("Sec-WebSocket-Extensions"));
+    }
+
+    #[tokio::test]
+    async fn tenants_keep_accounts_and_tokens_apart() {
+        let tenant = |name: &str| Tenant {
+            name: Some(name.to_string()),
+            admin_token: Some(format!("{}-admin", name)),
+            ..Tenant::default()
+        };
+        let club = Arc::new(vault::Vault::with_tenant(tenant("club")));
+        let school = Arc::new(vault::Vault::with_tenant(tenant("school")));
+
+        let mut member = TestClient::connect(&club);
+        member.login("ivan").await;
+        member.send(json!({"admin": {"game_list": {"request": {"token": "club-admin"}}}}));
+        member.expect("/admin/game_list/ok").await;
+
+        let mut stranger = TestClient::connect(&school);
+        stranger.send(json!({"handshake": {"connect": {"client": {
+            "name": "test", "version": "1", "protocol": {"version": ProtoVersion::LATEST.as_str()}}}}}));
+        let connect = stranger.expect("/handshake/connect/ok").await;
+        assert_eq!(
+            connect["handshake"]["connect"]["ok"]["server"]["tenant"],
+            "school"
+        );
+        stranger.send(json!({"auth": {"login": {"name": "ivan", "password": "password1"}}}));
+        stranger.expect("/auth/error/bad_credentials").await;
+        stranger.send(json!({"admin": {"game_list": {"request": {"token": "club-admin"}}}}));
+        stranger.expect("/admin/game_list/error/forbidden").await;
+    }
+
+    #[tokio::test]
+    async fn game_diff_between_plies() {
+        let mut record = vault::fixtures::game(Board::new()).record();
+        record.time_control = Duration::from_secs(60);
+        let ply = |color, from, to, secs| vault::MoveRecord {
+            color,
+            mv: Move::Basic { from, to },
+            time_used: Duration::from_millis(secs),
+            captured: None,
+            checks: Vec::new(),
+            best: None,
+            figure: Some(board::Figure::Pawn),
+            at: 0,
+        };
+        record.moves = vec![
+            ply(Color::Red, Position::e2, Position::e4, 2500),
+            ply(Color::Blue, Position::b5, Position::c5, 9000),
+        ];
+
+        let lines = game_diff(&record, 1, 2).unwrap();
+        assert_eq!(
+            lines,
+            [
+                "ply 2 blue b5-c5",
+                "b5: blue pawn -> empty",
+                "c5: empty -> blue pawn",
+                "blue clock 60.0s -> 56.0s",
+            ]
+        );
+        assert!(game_diff(&record, 0, 2)
+            .unwrap()
+            .contains(&"e4: empty -> red pawn".to_string()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn account_queued_once_over_connections() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut first = TestClient::connect(&vault);
+        first.login("frank").await;
+        first.send(json!({"matchmaking_queue": {"player_register": {"name": "frank"}}}));
+        first.expect("/matchmaking_queue/player_register/ok").await;
+
+        let mut second = TestClient::connect(&vault);
+        second.send(json!({"handshake": {"connect": {"client": {
+            "name": "test", "version": "1", "protocol": {"version": ProtoVersion::LATEST.as_str()}}}}}));
+        second.expect("/handshake/connect/ok").await;
+        second.send(json!({"auth": {"login": {"name": "frank", "password": "password1"}}}));
+        second.expect("/auth/ok").await;
+        second.send(json!({"matchmaking_queue": {"player_register": {"name": "frank"}}}));
+        second
+            .expect("/matchmaking_queue/player_register/error/account_limit")
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn knockout_tournament_played_to_the_final_table() {
+        let vault = Arc::new(vault::Vault::with_tenant(Tenant {
+            admin_token: Some("admin".to_string()),
+            ..Tenant::default()
+        }));
+        let mut clients = Vec::new();
+        for name in ["alice", "bob", "carol", "dave"] {
+            let mut client = TestClient::connect(&vault);
+            client.login(name).await;
+            clients.push(client);
+        }
+        clients[0].send(json!({"admin": {"create_tournament": {"request": {
+            "token": "admin", "name": "open", "format": "knockout", "starts_at": 0}}}}));
+        let created = clients[0].expect("/admin/create_tournament/ok").await;
+        let id = created["admin"]["create_tournament"]["ok"]["tournament_id"].clone();
+        for client in clients.iter_mut() {
+            client.send(json!({"tournament": {"register": {"tournament_id": id}}}));
+            client.expect("/tournament/info").await;
+        }
+
+        tokio::spawn(tournament_dispatcher(vault.clone()));
+        for client in clients.iter_mut() {
+            client.expect("/game_session/init").await;
+            client.send(json!({"game_session": {"ready": {}}}));
+        }
+        next_update(&mut clients).await;
+        let mut late = TestClient::connect(&vault);
+        late.login("erin").await;
+        late.send(json!({"tournament": {"register": {"tournament_id": id}}}));
+        late.expect("/tournament/error/forbidden").await;
+
+        for client in clients.iter() {
+            client.send(json!({"game_session": {"draw_vote": {"vote": {"agree": true}}}}));
+        }
+        let info = loop {
+            let info = clients[0].expect("/tournament/info").await;
+            if info["tournament"]["info"]["finished"] == true {
+                break info;
             }
+        };
+        let standings = info["tournament"]["info"]["standings"].as_array().unwrap();
+        assert_eq!(standings.len(), 4);
+        assert!(standings
+            .iter()
+            .all(|s| s["place"] == 1 && s["points"] == 3 && s["out"] == false));
+    }
+
+    #[tokio::test]
+    async fn leaderboard_with_rank_and_form_of_the_player() {
+        let vault = Arc::new(vault::Vault::new());
+        {
+            let mut ratings = vault.get_ratings().await;
+            ratings.rate(&[("gina", 0), ("hank", 1), ("ivan", 2), ("jane", 3)]);
+            ratings.rate(&[("gina", 3), ("hank", 0), ("ivan", 1), ("jane", 2)]);
         }
-        debug!(
-            "peers:{},  idle:{},  mm_queue:{},  hb_wait:{},  hb_ready:{},  reconnect:{},  tick:{:?}",
-            lock.get_peers().await.len(),
-            lock.get_idle().await.len(),
-            lock.get_mm_queue().await.len(),
-            lock.get_hb_wait().await.len(),
-            lock.get_hb_ready().await.len(),
-            lock.get_reconnect().await.len(),
-            Instant::now().duration_since(start)
+        let record = |id, end, eliminated: &[Color], casual| {
+            let mut record = vault::fixtures::game(Board::new()).record();
+            record.id = id;
+            record.red.name = "gina".to_string();
+            record.end = end;
+            record.eliminated = eliminated.to_vec();
+            record.casual = casual;
+            record
+        };
+        let others = [Color::Green, Color::Blue, Color::Yellow];
+        for record in [
+            record(1, GameEnd::Standings, &others, false),
+            record(2, GameEnd::Standings, &[Color::Red], false),
+            record(3, GameEnd::DrawAgreed, &[], false),
+            record(4, GameEnd::Standings, &others, true),
+        ] {
+            vault.get_records().await.insert(record);
+        }
+
+        let mut client = TestClient::connect(&vault);
+        client.login("kate").await;
+        client.send(json!({"rating": {"leaderboard": {"request": {"top": 2, "player": "gina"}}}}));
+        let ok = client.expect("/rating/leaderboard/ok").await;
+        let ok = &ok["rating"]["leaderboard"]["ok"];
+        assert_eq!(ok["top"].as_array().unwrap().len(), 2);
+        assert_eq!(ok["top"][0]["player"], "hank");
+        assert_eq!(ok["top"][1]["player"], "ivan");
+        let gina = &ok["player"];
+        assert_eq!(gina["place"], 3);
+        assert_eq!(
+            (&gina["wins"], &gina["draws"], &gina["losses"]),
+            (&json!(1), &json!(1), &json!(1))
         );
+        assert_eq!(gina["form"], json!(["draw", "loss", "win"]));
+
+        client.send(json!({"rating": {"leaderboard": {"request": {"player": "kate"}}}}));
+        client.expect("/rating/leaderboard/error/not_found").await;
     }
-}
 
-#[tokio::main]
-async fn main() -> Result<(), IoError> {
-    let mut builder = Builder::new();
-    builder.filter(Some("server_rs"), LevelFilter::Debug).init();
+    #[tokio::test(start_paused = true)]
+    async fn challenge_of_friends_starts_the_game() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut clients = Vec::new();
+        for name in ["alice", "bob", "carol", "dave"] {
+            let mut client = TestClient::connect(&vault);
+            client.login(name).await;
+            clients.push(client);
+        }
+        let hash = account::hash_password("password1");
+        let _ = vault.get_accounts().await.register("erin", hash);
+        for name in ["bob", "carol", "dave", "erin"] {
+            clients[0].send(json!({"social": {"add_friend": {"name": name}}}));
+            clients[0].expect("/social/friends").await;
+        }
+        clients[0].send(json!({"social": {"friend_list": {}}}));
+        let friends = clients[0].expect("/social/friends").await;
+        let friends = &friends["social"]["friends"]["friends"];
+        assert_eq!(friends[0], json!({"name": "bob", "online": true}));
+        assert_eq!(friends[3], json!({"name": "erin", "online": false}));
 
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "0.0.0.0:8080".to_string());
+        clients[0].send(json!({"social": {"challenge": {"players": ["bob", "carol", "erin"]}}}));
+        clients[0].expect("/social/error/forbidden").await;
+        clients[1].send(json!({"social": {"challenge": {"players": ["alice", "carol", "dave"]}}}));
+        clients[1].expect("/social/error/forbidden").await;
 
-    let vault = Arc::new(RwLock::new(vault::Vault::new()));
+        clients[0].send(json!({"social": {"challenge": {"players": ["bob", "carol", "dave"]}}}));
+        let mut challenge_id = Value::Null;
+        for client in clients.iter_mut() {
+            let challenged = client.expect("/social/challenged").await;
+            challenge_id = challenged["social"]["challenged"]["challenge_id"].clone();
+        }
+        for client in clients[1..].iter() {
+            client.send(json!({"social": {"accept": {"challenge_id": challenge_id}}}));
+        }
+        for client in clients.iter_mut() {
+            let init = client.expect("/game_session/init").await;
+            let names = &init["game_session"]["init"]["start_positions"];
+            assert_eq!(names["red"]["player_name"], "alice");
+        }
+    }
 
-    // Create the event loop and TCP listener we'll accept connections on.
-    let try_socket = TcpListener::bind(&addr).await;
-    let listener = try_socket.expect("Failed to bind");
-    info!("Listening on: {}", addr);
+    #[tokio::test]
+    async fn engine_like_player_flagged_for_admin_review() {
+        let vault = Arc::new(vault::Vault::with_tenant(Tenant {
+            admin_token: Some("admin".to_string()),
+            ..Tenant::default()
+        }));
+        let mut admin = TestClient::connect(&vault);
+        admin.login("mallory").await;
+        let mut record = vault::fixtures::game(Board::new()).record();
+        record.red.name = "mallory".to_string();
+        record.moves = (0..40)
+            .map(|_| vault::MoveRecord {
+                color: Color::Red,
+                mv: Move::Basic {
+                    from: Position::e2,
+                    to: Position::e4,
+                },
+                time_used: Duration::from_millis(1500),
+                captured: None,
+                checks: Vec::new(),
+                best: Some(true),
+                figure: None,
+                at: 0,
+            })
+            .collect();
+        vault.get_records().await.insert(record);
+        // Green of the fixture has no account
+        review_fair_play(&vault, &["mallory".to_string(), "Green".to_string()]).await;
 
-    tokio::spawn(matchmaking_dispatcher(vault.clone()));
+        admin.send(json!({"admin": {"fair_play_review": {"list": {"token": "admin"}}}}));
+        let ok = admin.expect("/admin/fair_play_review/ok").await;
+        let suspects = &ok["admin"]["fair_play_review"]["ok"]["suspects"];
+        assert_eq!(suspects.as_array().unwrap().len(), 1);
+        assert_eq!(suspects[0]["player"], "mallory");
+        assert_eq!(suspects[0]["engine_match"], 100);
 
-    // Let's spawn the handling of each connection in a separate task.
-    while let Ok((stream, addr)) = listener.accept().await {
-        tokio::spawn(handle_connection(vault.clone(), stream, addr));
+        admin.send(json!({"admin": {"fair_play_review": {"clear": {"token": "admin", "player": "mallory"}}}}));
+        let ok = admin.expect("/admin/fair_play_review/ok").await;
+        assert_eq!(ok["admin"]["fair_play_review"]["ok"]["suspects"], json!([]));
+        admin.send(json!({"admin": {"fair_play_review": {"clear": {"token": "admin", "player": "mallory"}}}}));
+        admin
+            .expect("/admin/fair_play_review/error/not_found")
+            .await;
     }
 
-    Ok(())
+    #[tokio::test(start_paused = true)]
+    async fn muted_players_not_heard() {
+        let vault = Arc::new(vault::Vault::with_tenant(Tenant {
+            admin_token: Some("admin".to_string()),
+            ..Tenant::default()
+        }));
+        let mut clients = game_of_four(&vault).await;
+        let chat = |client: &TestClient, text: &str| {
+            client.send(json!({"game_session": {"chat": {"send": {"text": text}}}}));
+        };
+        clients[0].send(json!({"game_session": {"chat": {"mute": {"player": "Red"}}}}));
+        clients[0]
+            .expect("/game_session/chat/error/bad_message")
+            .await;
+        clients[0].send(json!({"game_session": {"chat": {"mute": {"player": "Blue"}}}}));
+        let muted = clients[0].expect("/game_session/chat/muted").await;
+        assert_eq!(
+            muted["game_session"]["chat"]["muted"]["players"],
+            json!(["Blue"])
+        );
+        chat(&clients[1], "muted");
+        chat(&clients[2], "heard");
+        let message = clients[0].expect("/game_session/chat/message").await;
+        assert_eq!(message["game_session"]["chat"]["message"]["text"], "heard");
+
+        // the admin mute is not told to the player
+        let mut yellow = None;
+        for (_, peer) in vault.get_peers() {
+            let peer_lock = peer.lock().await;
+            if matches!(
+                peer_lock.state,
+                PeerState::Game {
+                    color: Color::Yellow,
+                    ..
+                }
+            ) {
+                yellow = peer_lock.player_name.clone();
+            }
+        }
+        clients[3].send(json!({"admin": {"chat_mute": {"request": {
+            "token": "admin", "player": yellow, "seconds": 60}}}}));
+        clients[3].expect("/admin/chat_mute/ok").await;
+        chat(&clients[2], "silenced");
+        chat(&clients[3], "last");
+        // every message up to the last one, the silenced one is not among them
+        for client in clients.iter_mut().skip(1) {
+            loop {
+                let message = client.expect("/game_session/chat/message").await;
+                let text = &message["game_session"]["chat"]["message"]["text"];
+                assert_ne!(text, "silenced");
+                if text == "last" {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn error_descriptions_in_the_locale_of_the_client() {
+        let vault = Arc::new(vault::Vault::new());
+        let mut client = TestClient::connect(&vault);
+        client.send(json!({"matchmaking_queue": {"player_register": {"name": "alice"}}}));
+        let error = client
+            .expect("/matchmaking_queue/player_register/error")
+            .await;
+        assert_eq!(
+            error["matchmaking_queue"]["player_register"]["error"]["handshake"]["code"],
+            "pass_handshake_first"
+        );
+
+        client.send(json!({"handshake": {"connect": {"client": {
+            "name": "test", "version": "1", "protocol": {"version": "1"}, "locale": "de-DE"}}}}));
+        let ok = client.expect("/handshake/connect/ok").await;
+        assert_eq!(ok["handshake"]["connect"]["ok"]["server"]["locale"], "de");
+        client.send(json!({"matchmaking_queue": {"player_register": {"name": "alice"}}}));
+        let error = client
+            .expect("/matchmaking_queue/player_register/error/unauthorized")
+            .await;
+        assert_eq!(
+            error["matchmaking_queue"]["player_register"]["error"]["unauthorized"],
+            json!({"code": "log_in_first", "description": "zuerst anmelden"})
+        );
+    }
+
+    #[test]
+    fn ready_once_accepting_players_and_matchmaking() {
+        let vault = Arc::new(vault::Vault::new());
+        let now = unix_now();
+        assert_eq!(readiness_problems(&vault, now).len(), 2);
+        vault.set_listening();
+        vault.matchmaking_ticked(now);
+        assert!(readiness_problems(&vault, now).is_empty());
+        let stalled = now + MATCHMAKING_STALL.as_secs() + 1;
+        assert_eq!(
+            readiness_problems(&vault, stalled),
+            ["matchmaking of server is not running"]
+        );
+    }
 }
@@ -0,0 +1,290 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+// aggregates since this long ago unless the request says otherwise
+const HOURLY_DEFAULT_SPAN: u64 = 7 * DAY;
+const DAILY_DEFAULT_SPAN: u64 = 90 * DAY;
+// upper bounds of move duration buckets in milliseconds, longer moves are counted in the last one
+const TIMING_BUCKETS: [u64; 8] = [1000, 2000, 5000, 10_000, 20_000, 30_000, 60_000, 120_000];
+
+// lobby population at the moment, unix seconds
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sample {
+    pub at: u64,
+    // players in the matchmaking queue and heartbeat checks
+    pub queued: usize,
+    pub games: usize,
+    // queued players and humans still in active games
+    pub players: usize,
+    pub connections: usize,
+    // games reaped since the previous sample, their session ended without releasing them
+    #[serde(default)]
+    pub dead_games: u64,
+    // probes of the canary since the previous sample
+    #[serde(default)]
+    pub canary_passed: u64,
+    #[serde(default)]
+    pub canary_failed: u64,
+}
+
+// averages and peaks of the samples of the hour or day starting at from
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Aggregate {
+    pub from: u64,
+    pub samples: usize,
+    pub queued_avg: f64,
+    pub queued_max: usize,
+    pub games_avg: f64,
+    pub games_max: usize,
+    pub players_avg: f64,
+    pub players_max: usize,
+    pub dead_games: u64,
+    pub canary_passed: u64,
+    pub canary_failed: u64,
+}
+
+// Samples in time order, persisted by storage
+#[derive(Default)]
+pub struct MetricsHistory {
+    samples: Vec<Sample>,
+}
+
+impl MetricsHistory {
+    // samples older than retention seconds before the new one are forgotten
+    pub fn push(&mut self, sample: Sample, retention: u64) {
+        let oldest = sample.at.saturating_sub(retention);
+        let expired = self.samples.iter().take_while(|s| s.at < oldest).count();
+        self.samples.drain(..expired);
+        self.samples.push(sample);
+    }
+
+    // aggregates of periods with samples since the given time, oldest first
+    pub fn aggregate(&self, period: u64, since: u64) -> Vec<Aggregate> {
+        let mut aggregates = Vec::new();
+        let mut samples = self.samples.iter().filter(|s| s.at >= since).peekable();
+        while let Some(first) = samples.next() {
+            let from = first.at - first.at % period;
+            let mut bucket = vec![first];
+            while let Some(sample) = samples.next_if(|s| s.at < from + period) {
+                bucket.push(sample);
+            }
+            let avg = |f: fn(&Sample) -> usize| {
+                bucket.iter().map(|s| f(s)).sum::<usize>() as f64 / bucket.len() as f64
+            };
+            let max = |f: fn(&Sample) -> usize| bucket.iter().map(|s| f(s)).max().unwrap_or(0);
+            aggregates.push(Aggregate {
+                from,
+                samples: bucket.len(),
+                queued_avg: avg(|s| s.queued),
+                queued_max: max(|s| s.queued),
+                games_avg: avg(|s| s.games),
+                games_max: max(|s| s.games),
+                players_avg: avg(|s| s.players),
+                players_max: max(|s| s.players),
+                dead_games: bucket.iter().map(|s| s.dead_games).sum(),
+                canary_passed: bucket.iter().map(|s| s.canary_passed).sum(),
+                canary_failed: bucket.iter().map(|s| s.canary_failed).sum(),
+            });
+        }
+        aggregates
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimingBucket {
+    // milliseconds, none for the last bucket
+    pub up_to: Option<u64>,
+    pub moves: usize,
+}
+
+// Move durations in the games of the pool and time control with timer values suiting them
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct MoveTiming {
+    // none for lobby games
+    pub pool: Option<String>,
+    // seconds
+    pub time_control: u64,
+    pub moves: usize,
+    pub histogram: Vec<TimingBucket>,
+    // milliseconds
+    pub median: u64,
+    pub p90: u64,
+    pub p99: u64,
+    // seconds, nine of ten moves are made before the clock would start to run
+    pub suggested_grace: u64,
+    // seconds, the usual move does not cost any time of the clock
+    pub suggested_increment: u64,
+}
+
+// Durations of human moves by pool and time control, the players are not told apart
+#[derive(Default)]
+pub struct MoveTimings {
+    durations: BTreeMap<(Option<String>, u64), Vec<u64>>,
+}
+
+impl MoveTimings {
+    pub fn add(&mut self, pool: Option<&str>, time_control: u64, duration: Duration) {
+        self.durations
+            .entry((pool.map(str::to_string), time_control))
+            .or_default()
+            .push(duration.as_millis() as u64);
+    }
+
+    pub fn report(self) -> Vec<MoveTiming> {
+        self.durations
+            .into_iter()
+            .map(|((pool, time_control), mut durations)| {
+                durations.sort_unstable();
+                let percentile = |p: usize| durations[(durations.len() - 1) * p / 100];
+                let mut histogram = TIMING_BUCKETS
+                    .iter()
+                    .map(|b| Some(*b))
+                    .chain([None])
+                    .map(|up_to| TimingBucket { up_to, moves: 0 })
+                    .collect::<Vec<_>>();
+                for duration in &durations {
+                    let bucket = TIMING_BUCKETS
+                        .iter()
+                        .position(|b| duration <= b)
+                        .unwrap_or(TIMING_BUCKETS.len());
+                    histogram[bucket].moves += 1;
+                }
+                MoveTiming {
+                    pool,
+                    time_control,
+                    moves: durations.len(),
+                    histogram,
+                    median: percentile(50),
+                    p90: percentile(90),
+                    p99: percentile(99),
+                    suggested_grace: percentile(90).div_ceil(1000),
+                    suggested_increment: percentile(50).div_ceil(1000),
+                }
+            })
+            .collect()
+    }
+}
+
+// Status and json body of the read-only HTTP request with the request line like
+// "GET /metrics/hourly?since=1700000000 HTTP/1.1", "/metrics/daily" is the other resource
+pub fn route(request_line: &str, history: &MetricsHistory, now: u64) -> (u16, String) {
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return (400, error("bad request")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let (period, span) = match path {
+        "/metrics/hourly" => (HOUR, HOURLY_DEFAULT_SPAN),
+        "/metrics/daily" => (DAY, DAILY_DEFAULT_SPAN),
+        _ => return (404, error("unknown resource")),
+    };
+    if method != "GET" {
+        return (405, error("only GET is allowed"));
+    }
+    let since = match query
+        .split('&')
+        .find_map(|param| param.strip_prefix("since="))
+    {
+        Some(since) => match since.parse() {
+            Ok(since) => since,
+            Err(_) => return (400, error("since must be unix seconds")),
+        },
+        None => now.saturating_sub(span),
+    };
+    match serde_json::to_string(&history.aggregate(period, since)) {
+        Ok(body) => (200, body),
+        Err(_) => (500, error("serialization failed")),
+    }
+}
+
+fn error(description: &str) -> String {
+    serde_json::json!({ "error": description }).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(at: u64, queued: usize) -> Sample {
+        Sample {
+            at,
+            queued,
+            games: 1,
+            players: queued + 4,
+            connections: queued + 5,
+            dead_games: at % 2,
+            canary_passed: 1,
+            canary_failed: at % 2,
+        }
+    }
+
+    #[test]
+    fn samples_aggregated_by_hour() {
+        let mut history = MetricsHistory::default();
+        for (at, queued) in [(10, 2), (HOUR - 1, 4), (HOUR + 5, 7), (3 * DAY, 1)] {
+            history.push(sample(at, queued), 2 * DAY);
+        }
+        // the first hours are older than the retention
+        assert_eq!(history.aggregate(HOUR, 0).len(), 1);
+
+        let mut history = MetricsHistory::default();
+        for (at, queued) in [(10, 2), (HOUR - 1, 4), (HOUR + 5, 7)] {
+            history.push(sample(at, queued), DAY);
+        }
+        let hourly = history.aggregate(HOUR, 0);
+        assert_eq!(hourly.len(), 2);
+        assert_eq!((hourly[0].from, hourly[0].samples), (0, 2));
+        assert_eq!(hourly[0].queued_avg, 3.0);
+        assert_eq!(hourly[0].players_max, 8);
+        assert_eq!((hourly[0].dead_games, hourly[1].dead_games), (1, 1));
+        assert_eq!((hourly[0].canary_passed, hourly[0].canary_failed), (2, 1));
+        assert_eq!((hourly[1].from, hourly[1].queued_max), (HOUR, 7));
+        assert_eq!(history.aggregate(DAY, 0)[0].samples, 3);
+        assert_eq!(history.aggregate(HOUR, HOUR).len(), 1);
+    }
+
+    #[test]
+    fn move_timings_by_pool() {
+        let mut timings = MoveTimings::default();
+        for ms in (1..=10).map(|i| i * 1000) {
+            timings.add(Some("blitz"), 60, Duration::from_millis(ms));
+        }
+        timings.add(Some("blitz"), 180, Duration::from_secs(200));
+        timings.add(None, 60, Duration::from_millis(1500));
+
+        let report = timings.report();
+        assert_eq!(report.len(), 3);
+        assert_eq!((report[0].pool.as_deref(), report[0].moves), (None, 1));
+        let blitz = &report[1];
+        assert_eq!((blitz.time_control, blitz.moves), (60, 10));
+        assert_eq!((blitz.median, blitz.p90, blitz.p99), (5000, 9000, 9000));
+        assert_eq!((blitz.suggested_grace, blitz.suggested_increment), (9, 5));
+        assert_eq!(blitz.histogram[0].moves, 1);
+        assert_eq!(blitz.histogram[3].moves, 5);
+        let long = &report[2].histogram;
+        assert_eq!((long[8].up_to, long[8].moves), (None, 1));
+    }
+
+    #[test]
+    fn requests_routed() {
+        let mut history = MetricsHistory::default();
+        history.push(sample(DAY, 3), DAY);
+        assert_eq!(
+            route("GET /metrics/daily?since=0 HTTP/1.1", &history, DAY).0,
+            200
+        );
+        let (status, body) = route("GET /metrics/hourly HTTP/1.1", &history, 2 * DAY);
+        assert_eq!(status, 200);
+        assert!(body.contains("\"queued_max\":3"));
+        assert_eq!(
+            route("GET /metrics/hourly?since=x HTTP/1.1", &history, 0).0,
+            400
+        );
+        assert_eq!(route("POST /metrics/daily HTTP/1.1", &history, 0).0, 405);
+        assert_eq!(route("GET /games HTTP/1.1", &history, 0).0, 404);
+    }
+}
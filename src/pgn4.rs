@@ -0,0 +1,274 @@
+use crate::board::{Figure, CASTLING_PATTERNS};
+use crate::proto::{Move, Variant};
+use crate::vault::{Color, GameEnd, GameSnapshot, MoveRecord, PlayerSnapshot, PlayerState};
+use std::fmt::Write;
+use std::time::Duration;
+
+// PGN4 lists players and moves in this order
+const COLORS: [Color; 4] = [Color::Red, Color::Blue, Color::Yellow, Color::Green];
+
+// Game record in PGN4 notation used by four-player chess clients.
+// Moves of a round are separated by "..", elapsed time of every move is in the emt comment.
+pub fn export(record: &GameSnapshot) -> String {
+    let mut pgn = String::new();
+    let result = result(record);
+    let _ = writeln!(pgn, "[GameNr \"{}\"]", record.id);
+    let _ = writeln!(pgn, "[Variant \"FFA\"]");
+    if let Some(start) = &record.start {
+        let _ = writeln!(pgn, "[StartFen4 \"{}\"]", start);
+    }
+    let _ = writeln!(pgn, "[Date \"{}\"]", date(record.started));
+    for color in COLORS.iter() {
+        let _ = writeln!(
+            pgn,
+            "[{} \"{}\"]",
+            color.to_string(),
+            player(record, *color).name
+        );
+    }
+    let _ = writeln!(pgn, "[Result \"{}\"]", result);
+    match record.end {
+        GameEnd::DrawAgreed => {
+            let _ = writeln!(pgn, "[Termination \"draw agreed\"]");
+        }
+        GameEnd::Adjudicated => {
+            let _ = writeln!(pgn, "[Termination \"adjudication\"]");
+        }
+        GameEnd::Standings => (),
+    }
+    pgn.push('\n');
+
+    let mut last = None;
+    let mut round = 0;
+    for mv in &record.moves {
+        let index = COLORS.iter().position(|c| *c == mv.color).unwrap_or(0);
+        // eliminated players leave their places in the round out
+        if last.is_none_or(|last| index <= last) {
+            round += 1;
+            if round > 1 {
+                pgn.push('\n');
+            }
+            let _ = write!(pgn, "{}. ", round);
+        } else {
+            pgn.push_str(" .. ");
+        }
+        let _ = write!(pgn, "{} {{[%emt {}]}}", notation(mv), emt(mv.time_used));
+        last = Some(index);
+    }
+    if last.is_some() {
+        pgn.push('\n');
+    }
+    pgn.push_str(&result);
+    pgn.push('\n');
+    pgn
+}
+
+fn player(record: &GameSnapshot, color: Color) -> &PlayerSnapshot {
+    match color {
+        Color::Red => &record.red,
+        Color::Green => &record.green,
+        Color::Blue => &record.blue,
+        Color::Yellow => &record.yellow,
+    }
+}
+
+// points of red, blue, yellow and green, shared by the players left in game, "*" if not finished.
+// Games of the points variant list the scored points.
+fn result(record: &GameSnapshot) -> String {
+    if record.who_move.is_some() {
+        return "*".to_string();
+    }
+    if record.variant == Variant::Points {
+        return COLORS
+            .iter()
+            .map(|c| player(record, *c).points.to_string())
+            .collect::<Vec<_>>()
+            .join("-");
+    }
+    let winners = COLORS
+        .iter()
+        .filter(|c| player(record, **c).state != PlayerState::Lost)
+        .count();
+    COLORS
+        .iter()
+        .map(|c| match player(record, *c).state {
+            PlayerState::Lost => "0".to_string(),
+            _ if winners == 1 => "1".to_string(),
+            _ => format!("1/{}", winners),
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn letter(figure: Option<Figure>) -> &'static str {
+    match figure {
+        Some(Figure::King) => "K",
+        Some(Figure::Queen) => "Q",
+        Some(Figure::Rook) => "R",
+        Some(Figure::Bishop) => "B",
+        Some(Figure::Knight) => "N",
+        Some(Figure::Pawn) | None => "",
+    }
+}
+
+pub fn notation(mv: &MoveRecord) -> String {
+    let mut notation = match &mv.mv {
+        Move::Basic { from, to } => format!("{}{}-{}", letter(mv.figure), from, to),
+        Move::Capture { from, to } => format!("{}{}x{}", letter(mv.figure), from, to),
+        Move::Promotion { from, to, into } => {
+            let step = if mv.captured.is_some() { "x" } else { "-" };
+            format!("{}{}{}={}", from, step, to, letter(Some(*into)))
+        }
+        Move::Castling { rook } => {
+            // three squares between king and rook on the queen side
+            let queen_side = CASTLING_PATTERNS
+                .iter()
+                .find(|((r, _), _)| r == rook)
+                .is_some_and(|(_, pattern)| pattern.space_between.len() == 3);
+            if queen_side { "O-O-O" } else { "O-O" }.to_string()
+        }
+        Move::NoMove {} | Move::Ok { .. } | Move::Error(_) => String::new(),
+    };
+    if !mv.checks.is_empty() {
+        notation.push('+');
+    }
+    notation
+}
+
+fn emt(time: Duration) -> String {
+    let secs = time.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+// "YYYY.MM.DD" of unix seconds, unknown parts are question marks
+fn date(unix: u64) -> String {
+    if unix == 0 {
+        return "????.??.??".to_string();
+    }
+    // days to civil date in the proleptic gregorian calendar
+    let days = (unix / 86400) as i64 + 719_468;
+    let era = days / 146_097;
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}.{:02}.{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::{Board, Position};
+
+    fn player(name: &str, state: PlayerState) -> PlayerSnapshot {
+        PlayerSnapshot {
+            name: name.to_string(),
+            reconnect_id: String::new(),
+            time_remaining: Duration::from_secs(60),
+            state,
+            bot: false,
+            points: 0,
+        }
+    }
+
+    fn record(color: Color, figure: Figure, mv: Move, checks: Vec<Color>) -> MoveRecord {
+        MoveRecord {
+            color,
+            mv,
+            time_used: Duration::from_secs(3),
+            captured: None,
+            checks,
+            best: None,
+            figure: Some(figure),
+            at: 0,
+        }
+    }
+
+    #[test]
+    fn finished_game_exported() {
+        let snapshot = GameSnapshot {
+            id: 7,
+            board: Board::new(),
+            red: player("alice", PlayerState::NoState),
+            green: player("bob", PlayerState::Lost),
+            blue: player("carol", PlayerState::Lost),
+            yellow: player("dave", PlayerState::Lost),
+            who_move: None,
+            captured: Vec::new(),
+            moves: vec![
+                record(
+                    Color::Red,
+                    Figure::Pawn,
+                    Move::Basic {
+                        from: Position::e2,
+                        to: Position::e4,
+                    },
+                    Vec::new(),
+                ),
+                record(
+                    Color::Blue,
+                    Figure::Knight,
+                    Move::Basic {
+                        from: Position::a5,
+                        to: Position::c6,
+                    },
+                    Vec::new(),
+                ),
+                record(
+                    Color::Red,
+                    Figure::Queen,
+                    Move::Capture {
+                        from: Position::g1,
+                        to: Position::c5,
+                    },
+                    vec![Color::Blue],
+                ),
+            ],
+            eliminated: vec![Color::Green, Color::Yellow, Color::Blue],
+            chat: Vec::new(),
+            chat_unrecorded: Vec::new(),
+            turn: 3,
+            started: 1_700_000_000,
+            casual: false,
+            bot_level: 0,
+            draw_odds: None,
+            pool: None,
+            variant: Variant::Ffa,
+            features: Vec::new(),
+            time_control: Duration::from_secs(60),
+            end: GameEnd::Standings,
+            private: false,
+            start: None,
+            first_move: Color::Red,
+            held_seat_grace: None,
+        };
+        let pgn = export(&snapshot);
+        assert!(!pgn.contains("[StartFen4"));
+        assert!(pgn.contains("[Date \"2023.11.14\"]\n"));
+        assert!(pgn.contains("[Blue \"carol\"]\n"));
+        assert!(pgn.contains("[Result \"1-0-0-0\"]\n"));
+        assert!(pgn.ends_with(
+            "1. e2-e4 {[%emt 0:00:03]} .. Na5-c6 {[%emt 0:00:03]}\n\
+             2. Qg1xc5+ {[%emt 0:00:03]}\n1-0-0-0\n"
+        ));
+
+        let mut points = snapshot;
+        points.variant = Variant::Points;
+        points.red.points = 29;
+        points.blue.points = 3;
+        assert!(export(&points).contains("[Result \"29-3-0-0\"]\n"));
+
+        let mut custom = points;
+        custom.start = Some("B-0,0,0,0-...".to_string());
+        assert!(export(&custom).contains("[StartFen4 \"B-0,0,0,0-...\"]\n"));
+    }
+
+    #[test]
+    fn unknown_date() {
+        assert_eq!(date(0), "????.??.??");
+        assert_eq!(date(951_782_400), "2000.02.29");
+    }
+}
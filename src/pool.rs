@@ -0,0 +1,349 @@
+use crate::proto::{PoolInfo, PoolWindow, Variant, Weekday};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_POOL: &str = "default";
+// seconds on the clock of every player
+pub const DEFAULT_TIME_CONTROL: u64 = 60;
+
+const DAY: u64 = 24 * 60 * 60;
+
+fn default_merge_after() -> u64 {
+    60
+}
+
+fn default_group_size() -> usize {
+    4
+}
+
+// Public matchmaking queue, players are grouped only with players of the same pool.
+// Pool with empty schedule is always open, times are UTC.
+// Pools of the same merge group are compatible, players who waited merge_after seconds
+// without a game in their own pool are matched across the group.
+// Games of casual pools are not rated.
+// Bots filling games of the pool are played by the external engine on bot_level above 0.
+// Players are queued for a variant and time control the pool offers and matched only with
+// players of the same ones. Games of less than four players are filled with bots.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Pool {
+    pub name: String,
+    #[serde(default)]
+    pub schedule: Vec<PoolWindow>,
+    #[serde(default)]
+    pub merge_group: Option<String>,
+    #[serde(default = "default_merge_after")]
+    pub merge_after: u64,
+    #[serde(default)]
+    pub casual: bool,
+    #[serde(default)]
+    pub bot_level: u8,
+    // free for all only if empty
+    #[serde(default)]
+    pub variants: Vec<Variant>,
+    // seconds, the first one is the default, DEFAULT_TIME_CONTROL only if empty
+    #[serde(default)]
+    pub time_controls: Vec<u64>,
+    // players matched into one game
+    #[serde(default = "default_group_size")]
+    pub group_size: usize,
+}
+
+impl Pool {
+    pub fn new(name: &str) -> Pool {
+        Pool {
+            name: name.to_string(),
+            schedule: Vec::new(),
+            merge_group: None,
+            merge_after: default_merge_after(),
+            casual: false,
+            bot_level: 0,
+            variants: Vec::new(),
+            time_controls: Vec::new(),
+            group_size: default_group_size(),
+        }
+    }
+
+    pub fn offers(&self, variant: Variant) -> bool {
+        if self.variants.is_empty() {
+            variant == Variant::Ffa
+        } else {
+            self.variants.contains(&variant)
+        }
+    }
+
+    // the requested time control if the pool offers it, the default one if none requested
+    pub fn time_control(&self, requested: Option<u64>) -> Option<u64> {
+        let offered = match self.time_controls.is_empty() {
+            true => &[DEFAULT_TIME_CONTROL][..],
+            false => &self.time_controls[..],
+        };
+        match requested {
+            Some(secs) => offered.iter().find(|s| **s == secs).copied(),
+            None => offered.first().copied(),
+        }
+    }
+
+    pub fn is_open(&self, now: u64) -> bool {
+        if self.schedule.is_empty() {
+            return true;
+        }
+        let day_start = now - now % DAY;
+        let time = now % DAY;
+        self.schedule.iter().any(|window| {
+            let (from, to) = window_bounds(window);
+            if from < to {
+                on_day(window, day_start) && time >= from && time < to
+            } else {
+                // window passes midnight
+                (on_day(window, day_start) && time >= from)
+                    || (on_day(window, day_start.saturating_sub(DAY)) && time < to)
+            }
+        })
+    }
+
+    // next time the closed pool opens, none for the always open pool
+    pub fn opens_at(&self, now: u64) -> Option<u64> {
+        let day_start = now - now % DAY;
+        (0..=7)
+            .map(|i| day_start + i * DAY)
+            .flat_map(|day| {
+                self.schedule
+                    .iter()
+                    .filter(move |window| on_day(window, day))
+                    .map(move |window| day + window_bounds(window).0)
+            })
+            .filter(|start| *start > now)
+            .min()
+    }
+
+    pub fn info(&self, now: u64) -> PoolInfo {
+        let open = self.is_open(now);
+        PoolInfo {
+            name: self.name.clone(),
+            open,
+            opens_at: if open { None } else { self.opens_at(now) },
+            schedule: self.schedule.clone(),
+            merge_group: self.merge_group.clone(),
+            casual: self.casual,
+            variants: match self.variants.is_empty() {
+                true => vec![Variant::Ffa],
+                false => self.variants.clone(),
+            },
+            time_controls: match self.time_controls.is_empty() {
+                true => vec![DEFAULT_TIME_CONTROL],
+                false => self.time_controls.clone(),
+            },
+            group_size: self.group_size,
+        }
+    }
+}
+
+// Split players into groups of size, players apart(i, j) is true for are never in one group.
+// Players are taken in order, everyone joins the first group with room and without conflict.
+// Returns full groups and players left waiting, all as indexes.
+pub fn group_apart(
+    len: usize,
+    size: usize,
+    apart: impl Fn(usize, usize) -> bool,
+) -> (Vec<Vec<usize>>, Vec<usize>) {
+    let mut groups = Vec::new();
+    let mut open: Vec<Vec<usize>> = Vec::new();
+    for i in 0..len {
+        let k = match open.iter().position(|g| !g.iter().any(|j| apart(*j, i))) {
+            Some(k) => {
+                open[k].push(i);
+                k
+            }
+            None => {
+                open.push(vec![i]);
+                open.len() - 1
+            }
+        };
+        if open[k].len() == size {
+            groups.push(open.remove(k));
+        }
+    }
+    let mut waiting = open.concat();
+    waiting.sort_unstable();
+    (groups, waiting)
+}
+
+// Load pools from json file, the default pool is always present
+pub fn load_pools(path: &str) -> Result<Vec<Pool>> {
+    let json = std::fs::read_to_string(path).context(format!("read {:?} failed", path))?;
+    let mut pools: Vec<Pool> =
+        serde_json::from_str(&json).context(format!("parse {:?} failed", path))?;
+    for window in pools.iter().flat_map(|p| p.schedule.iter()) {
+        day_time(&window.from)?;
+        day_time(&window.to)?;
+    }
+    for pool in &pools {
+        if !(1..=4).contains(&pool.group_size) {
+            bail!("group size of pool \"{}\" must be 1 to 4", pool.name);
+        }
+        if pool.time_controls.contains(&0) {
+            bail!("pool \"{}\" has zero time control", pool.name);
+        }
+        // merged players are grouped together
+        let mismatch = pools.iter().any(|other| {
+            other.merge_group.is_some()
+                && other.merge_group == pool.merge_group
+                && other.group_size != pool.group_size
+        });
+        if mismatch {
+            bail!(
+                "pools of merge group of \"{}\" differ in group size",
+                pool.name
+            );
+        }
+    }
+    if !pools.iter().any(|p| p.name == DEFAULT_POOL) {
+        pools.insert(0, Pool::new(DEFAULT_POOL));
+    }
+    Ok(pools)
+}
+
+// seconds since midnight of "HH:MM"
+fn day_time(s: &str) -> Result<u64> {
+    let (hours, minutes) = s.split_once(':').context(format!("bad time \"{}\"", s))?;
+    let hours: u64 = hours.parse().context(format!("bad time \"{}\"", s))?;
+    let minutes: u64 = minutes.parse().context(format!("bad time \"{}\"", s))?;
+    if hours > 24 || minutes > 59 || hours * 60 + minutes > 24 * 60 {
+        bail!("bad time \"{}\"", s);
+    }
+    Ok((hours * 60 + minutes) * 60)
+}
+
+// times are validated when pools are loaded
+fn window_bounds(window: &PoolWindow) -> (u64, u64) {
+    (
+        day_time(&window.from).unwrap_or(0),
+        day_time(&window.to).unwrap_or(0),
+    )
+}
+
+// window without days is open every day
+fn on_day(window: &PoolWindow, day_start: u64) -> bool {
+    window.days.is_empty() || window.days.contains(&weekday(day_start))
+}
+
+fn weekday(time: u64) -> Weekday {
+    // 1970-01-01 was thursday
+    match (time / DAY + 3) % 7 {
+        0 => Weekday::Mon,
+        1 => Weekday::Tue,
+        2 => Weekday::Wed,
+        3 => Weekday::Thu,
+        4 => Weekday::Fri,
+        5 => Weekday::Sat,
+        _ => Weekday::Sun,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2021-03-01 00:00 UTC, monday
+    const MONDAY: u64 = 1614556800;
+    const HOUR: u64 = 60 * 60;
+
+    fn pool(days: Vec<Weekday>, from: &str, to: &str) -> Pool {
+        Pool {
+            merge_group: None,
+            merge_after: default_merge_after(),
+            casual: false,
+            bot_level: 0,
+            variants: Vec::new(),
+            time_controls: Vec::new(),
+            group_size: 4,
+            name: "arena".to_string(),
+            schedule: vec![PoolWindow {
+                days,
+                from: from.to_string(),
+                to: to.to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn pool_without_schedule_always_open() {
+        let pool = Pool::new(DEFAULT_POOL);
+        assert!(pool.is_open(MONDAY));
+        assert_eq!(pool.opens_at(MONDAY), None);
+    }
+
+    #[test]
+    fn pool_open_only_in_window() {
+        let pool = pool(vec![Weekday::Mon], "18:00", "22:00");
+        assert!(!pool.is_open(MONDAY + 17 * HOUR));
+        assert!(pool.is_open(MONDAY + 18 * HOUR));
+        assert!(!pool.is_open(MONDAY + 22 * HOUR));
+        assert!(!pool.is_open(MONDAY + DAY + 19 * HOUR));
+        assert_eq!(pool.opens_at(MONDAY + 17 * HOUR), Some(MONDAY + 18 * HOUR));
+        assert_eq!(
+            pool.opens_at(MONDAY + 23 * HOUR),
+            Some(MONDAY + 7 * DAY + 18 * HOUR)
+        );
+    }
+
+    #[test]
+    fn window_passes_midnight() {
+        let pool = pool(vec![Weekday::Sun], "22:00", "02:00");
+        let sunday = MONDAY + 6 * DAY;
+        assert!(pool.is_open(sunday + 23 * HOUR));
+        assert!(pool.is_open(sunday + DAY + HOUR));
+        assert!(!pool.is_open(sunday + DAY + 3 * HOUR));
+        assert!(!pool.is_open(MONDAY + DAY + HOUR));
+    }
+
+    #[test]
+    fn bad_time_rejected() {
+        assert!(day_time("25:00").is_err());
+        assert!(day_time("12:60").is_err());
+        assert!(day_time("noon").is_err());
+        assert_eq!(day_time("24:00").unwrap(), DAY);
+    }
+
+    #[test]
+    fn merge_after_defaults() {
+        let pool: Pool =
+            serde_json::from_str(r#"{"name": "rapid", "merge_group": "casual"}"#).unwrap();
+        assert_eq!(pool.merge_group.as_deref(), Some("casual"));
+        assert_eq!(pool.merge_after, 60);
+        assert!(Pool::new("default").merge_group.is_none());
+    }
+
+    #[test]
+    fn blocked_players_grouped_apart() {
+        // 0 and 1 block each other
+        let apart = |i: usize, j: usize| (i, j) == (0, 1) || (i, j) == (1, 0);
+        let (groups, waiting) = group_apart(5, 4, apart);
+        assert_eq!(groups, vec![vec![0, 2, 3, 4]]);
+        assert_eq!(waiting, vec![1]);
+        let (groups, waiting) = group_apart(4, 4, apart);
+        assert!(groups.is_empty());
+        assert_eq!(waiting, vec![0, 1, 2, 3]);
+        let (groups, waiting) = group_apart(5, 2, apart);
+        assert_eq!(groups, vec![vec![0, 2], vec![1, 3]]);
+        assert_eq!(waiting, vec![4]);
+        // every player is a game of its own in pools filled with bots
+        let (groups, waiting) = group_apart(2, 1, apart);
+        assert_eq!(groups, vec![vec![0], vec![1]]);
+        assert!(waiting.is_empty());
+    }
+
+    #[test]
+    fn offered_time_controls() {
+        let pool: Pool =
+            serde_json::from_str(r#"{"name": "blitz", "time_controls": [30, 180]}"#).unwrap();
+        assert_eq!(pool.time_control(None), Some(30));
+        assert_eq!(pool.time_control(Some(180)), Some(180));
+        assert_eq!(pool.time_control(Some(60)), None);
+        assert_eq!(
+            Pool::new(DEFAULT_POOL).time_control(None),
+            Some(DEFAULT_TIME_CONTROL)
+        );
+        assert!(pool.offers(Variant::Ffa));
+    }
+}
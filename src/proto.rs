@@ -1,8 +1,13 @@
 use crate::board::{Figure, Position};
+use crate::filter::Severity;
+use crate::flags::Flag;
+use crate::locale;
+use crate::metrics::MoveTiming;
 use crate::proto::MatchmakingQueue::PlayerKick;
 use crate::vault;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 use tungstenite::protocol::Message;
 use tungstenite::stream::Mode::Plain;
 
@@ -15,6 +20,57 @@ pub enum Protocol {
     Version(String),
 }
 
+// Versions of the protocol the server speaks, oldest first.
+// Version 1 takes cells as [column, row] pairs too, acknowledges moves with Move::Ok
+// and tells why it closes the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtoVersion {
+    V0,
+    V1,
+}
+
+impl ProtoVersion {
+    pub const SUPPORTED: [ProtoVersion; 2] = [ProtoVersion::V0, ProtoVersion::V1];
+    pub const LATEST: ProtoVersion = ProtoVersion::V1;
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            ProtoVersion::V0 => "0",
+            ProtoVersion::V1 => "1",
+        }
+    }
+
+    pub fn parse(version: &str) -> Option<ProtoVersion> {
+        ProtoVersion::SUPPORTED
+            .iter()
+            .copied()
+            .find(|v| v.as_str() == version)
+    }
+
+    // the latest version of the server the client offered
+    pub fn negotiate(protocol: &Protocol) -> Option<ProtoVersion> {
+        match protocol {
+            Protocol::Version(version) => ProtoVersion::parse(version),
+            Protocol::SupportedVersion(versions) => {
+                versions.iter().filter_map(|v| ProtoVersion::parse(v)).max()
+            }
+        }
+    }
+
+    // Pdus added by later versions are not sent to older clients, which fail to parse them.
+    // Messages present in both are the same, older clients skip the added fields.
+    pub fn understands(self, pdu: &Pdu) -> bool {
+        match pdu {
+            Pdu::GameSession(GameSession::Move(Move::Ok { .. }))
+            | Pdu::GameSession(GameSession::StateSync(_))
+            | Pdu::Handshake(Handshake::Timeout { .. })
+            | Pdu::Disconnect(_)
+            | Pdu::Route(_) => self >= ProtoVersion::V1,
+            _ => true,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum GetInfoError {
@@ -29,10 +85,54 @@ pub enum GetInfo {
     Error(GetInfoError),
 }
 
+// operator defined metadata, missing fields are not shown to users
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerMeta {
+    // version of this extension, bumped when fields are added
+    pub version: u32,
+    pub motd: Option<String>,
+    pub rules_url: Option<String>,
+    pub support_contact: Option<String>,
+    pub region: Option<String>,
+}
+
+// client answers WebSocket pings and is matched without HeartbeatCheck
+pub const CAPABILITY_PONG_READINESS: &str = "pong_readiness";
+// client takes the compact board snapshot in a binary frame after the resync update
+pub const CAPABILITY_BINARY_SNAPSHOT: &str = "binary_snapshot";
+// client takes seat numbers and directions next to the colors, for players who tell colors apart badly
+pub const CAPABILITY_SEAT_LABELS: &str = "seat_labels";
+// client sends and takes the pdus as MessagePack maps in binary frames after the Connect::Ok,
+// compact snapshots start with their version byte and are told apart from the maps
+pub const CAPABILITY_MSGPACK: &str = "msgpack";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Server {
     pub name: String,
     pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ServerMeta>,
+    // capabilities of the client supported by the server
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rules: Option<RulesDescriptor>,
+    // community served on the address, none on servers of one tenant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    // version negotiated with the client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    // locale of the error descriptions if the client asked for one, english without catalog
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+}
+
+// fixed rules the client may present, sent to clients with seat_labels
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RulesDescriptor {
+    pub seats: Seats,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -49,9 +149,15 @@ pub enum Connect {
         name: String,
         version: String,
         protocol: Protocol,
+        #[serde(default)]
+        capabilities: Vec<String>,
+        // e.g. "en", selects chat filter word lists and the language of error descriptions
+        #[serde(default)]
+        locale: Option<String>,
     },
     Ok {
-        server: Server,
+        // boxed, the answer is far larger than the requests of the handshake
+        server: Box<Server>,
     },
     Error(ConnectError),
 }
@@ -61,33 +167,153 @@ pub enum Connect {
 pub enum Handshake {
     GetInfo(GetInfo),
     Connect(Connect),
+    // connection without the handshake in time is closed right after it
+    Timeout { description: String },
 }
 
 // MatchmakingQueue ///////////////////////////
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum PlayerRegisterError {
-    BadName { description: String },
-    AlreadyRegistered { description: String },
-    Handshake { description: String },
-    UnspecifiedError { description: String },
+    BadName {
+        description: String,
+    },
+    AlreadyRegistered {
+        description: String,
+    },
+    // log in with Auth first
+    Unauthorized {
+        description: String,
+    },
+    UnknownPool {
+        description: String,
+    },
+    // unix seconds, none if the pool has no future window
+    PoolClosed {
+        description: String,
+        opens_at: Option<u64>,
+    },
+    // variant or time control the pool does not offer
+    UnsupportedQueue {
+        description: String,
+    },
+    // the account is queued from another connection or plays too many games
+    AccountLimit {
+        description: String,
+    },
+    Handshake {
+        description: String,
+    },
+    UnspecifiedError {
+        description: String,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum PlayerRegister {
+    // register into the default pool, name must be the name of the logged in account
     Name(String),
-    Ok {},
+    Join {
+        name: String,
+        pool: String,
+        #[serde(default)]
+        variant: Variant,
+        // seconds on the clock, the default one of the pool if none
+        #[serde(default)]
+        time_control: Option<u64>,
+    },
+    // current rating of the player and badges of past seasons
+    Ok {
+        rating: i32,
+        #[serde(default)]
+        badges: Vec<Badge>,
+    },
     Error(PlayerRegisterError),
 }
 
+// rules of the game, only free for all is played so far
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Variant {
+    #[default]
+    Ffa,
+    // free for all won by points of captures, checkmates and surviving
+    Points,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+// UTC "HH:MM", window passes midnight when to is before from
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct PoolWindow {
+    // every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PoolInfo {
+    pub name: String,
+    pub open: bool,
+    pub opens_at: Option<u64>,
+    // always open if empty
+    pub schedule: Vec<PoolWindow>,
+    // players waiting too long are matched with other pools of the group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merge_group: Option<String>,
+    // games are not rated, blocked players are never matched together
+    #[serde(default)]
+    pub casual: bool,
+    #[serde(default)]
+    pub variants: Vec<Variant>,
+    // seconds, the first one is the default
+    #[serde(default)]
+    pub time_controls: Vec<u64>,
+    // players matched into one game, bots take the other seats
+    #[serde(default)]
+    pub group_size: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pools {
+    Request {},
+    Ok { pools: Vec<PoolInfo> },
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum MatchmakingQueue {
     PlayerRegister(PlayerRegister),
+    Pools(Pools),
     PlayerLeave {},
     HeartbeatCheck {},
-    PlayerKick { discritpion: String },
+    PlayerKick {
+        discritpion: String,
+    },
+    // pool of the player was too empty, the player is matched with players of these pools
+    PoolMerged {
+        group: String,
+        pools: Vec<String>,
+        description: String,
+    },
 }
 
 // GameSession ///////////////////////////
@@ -103,6 +329,68 @@ pub struct Position {
 pub struct StartPosition {
     pub player_name: String,
     pub left_rook: Position,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seat: Option<Seat>,
+}
+
+// side of the board the seat plays from, red at the bottom
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    South,
+    West,
+    North,
+    East,
+}
+
+// color independent name of the seat, numbered in the order of the moves
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Seat {
+    pub number: u8,
+    pub direction: Direction,
+}
+
+impl Seat {
+    pub fn of(color: vault::Color) -> Self {
+        let (number, direction) = match color {
+            vault::Color::Red => (1, Direction::South),
+            vault::Color::Blue => (2, Direction::West),
+            vault::Color::Yellow => (3, Direction::North),
+            vault::Color::Green => (4, Direction::East),
+        };
+        Seat { number, direction }
+    }
+
+    fn of_player(player: &str) -> Option<Self> {
+        [
+            vault::Color::Red,
+            vault::Color::Blue,
+            vault::Color::Yellow,
+            vault::Color::Green,
+        ]
+        .iter()
+        .find(|color| color.to_string() == player)
+        .map(|color| Seat::of(*color))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Seats {
+    pub red: Seat,
+    pub blue: Seat,
+    pub yellow: Seat,
+    pub green: Seat,
+}
+
+impl Seats {
+    pub fn table() -> Self {
+        Seats {
+            red: Seat::of(vault::Color::Red),
+            blue: Seat::of(vault::Color::Blue),
+            yellow: Seat::of(vault::Color::Yellow),
+            green: Seat::of(vault::Color::Green),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -117,9 +405,19 @@ pub struct StartPositions {
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct Init {
+    pub game_id: u64,
     pub countdown: u64,
     pub reconnect_id: String,
     pub start_positions: StartPositions,
+    // armageddon tiebreak, the player of this color has less time and wins if the game is drawn
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub draw_odds: Option<String>,
+    // experimental features enabled in the game
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    // FEN4 of the custom start position, none for the initial position
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -129,14 +427,51 @@ pub enum Action {
     Capture(Position),
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// why the rule engine rejected the move, description explains it to the player
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IllegalMoveReason {
+    NotYourTurn,
+    EmptyCell,
+    PieceOfAnotherPlayer,
+    NotPromotable,
+    PromotionLineNotReached,
+    PromotionRequired,
+    BadPromotionFigure,
+    DestinationOccupied,
+    DestinationOccupiedByOwnPiece,
+    NothingToCapture,
+    StoneCapture,
+    Unreachable,
+    PinnedPiece,
+    KingIntoCheck,
+    KingRemainsInCheck,
+    GamePaused,
+    NoRook,
+    RookAlreadyMoved,
+    NoKing,
+    KingAlreadyMoved,
+    CastlingBlocked,
+    CastlingUnderCheck,
+    CastlingPathAttacked,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum MoveError {
-    ForbiddenMove { description: String },
-    UnspecifiedError { description: String },
+    ForbiddenMove {
+        description: String,
+    },
+    IllegalMove {
+        reason: IllegalMoveReason,
+        description: String,
+    },
+    UnspecifiedError {
+        description: String,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum Move {
     Basic {
@@ -152,19 +487,266 @@ pub enum Move {
         to: Position,
         into: Figure,
     },
+    // king side or queen side is chosen by the rook
     Castling {
         rook: Position,
     },
     NoMove {},
+    // the move is accepted, the update of the sequence number carries it
+    Ok {
+        seq: u64,
+    },
     Error(MoveError),
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReconnectError {
+    Handshake { description: String },
+    AlreadyInGame { description: String },
+    BadReconnectId { description: String },
+    UnspecifiedError { description: String },
+}
+
+// on success server sends Init and Update of the game session
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reconnect {
+    Request { reconnect_id: String },
+    Error(ReconnectError),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatKind {
+    Text,
+    Reaction,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct ChatMessage {
+    pub id: u64,
+    pub player: String,
+    // moves made in the game before the message
+    pub move_number: usize,
+    pub kind: ChatKind,
+    // none once redacted by a moderator
+    pub text: Option<String>,
+    #[serde(default)]
+    pub redacted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatError {
+    Forbidden { description: String },
+    BadMessage { description: String },
+    UnspecifiedError { description: String },
+}
+
+// Messages are recorded with the game unless the sender turned recording off with Record.
+// Mute hides messages of the other player of the game, named by color like in the messages, from
+// the player until Unmute, both are answered with Muted. Messages of players muted by an admin are dropped silently.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Chat {
+    Send { text: String },
+    React { reaction: String },
+    Record { allow: bool },
+    Mute { player: String },
+    Unmute { player: String },
+    Message(ChatMessage),
+    Muted { players: Vec<String> },
+    Error(ChatError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DrawVoteError {
+    Forbidden { description: String },
+    UnspecifiedError { description: String },
+}
+
+// Survivors end the game in a draw when all of them vote for it, votes lapse with every turn.
+// Players get Votes after every vote, the agreed draw ends with Finish. In the points variant
+// the survivors split the points of their pieces still on the board evenly.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DrawVote {
+    Vote { agree: bool },
+    Votes { agreed: Vec<String> },
+    Error(DrawVoteError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PauseError {
+    Forbidden { description: String },
+    UnspecifiedError { description: String },
+}
+
+// Survivors pause the running game when all of them vote for it and resume it the same way,
+// votes lapse with every turn and once they are counted. The clock of the player to move stands
+// still and moves are rejected, the resumed game goes on after the countdown like at the start.
+// Players get Votes after every vote and State when the game is paused or resumed.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pause {
+    Vote { agree: bool },
+    Votes { agreed: Vec<String> },
+    State { paused: bool },
+    Error(PauseError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdjudicationError {
+    Forbidden { description: String },
+    UnspecifiedError { description: String },
+}
+
+// Offered to the players still moving while several others time out turn after turn.
+// Once all of them consent the game is decided by material, the weakest players are
+// eliminated first. Players get Consents after every answer, the game ends with Finish.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Adjudication {
+    Offer {
+        // players who decide
+        players: Vec<String>,
+        scores: PlayersScores,
+    },
+    Consent {
+        agree: bool,
+    },
+    Consents {
+        agreed: Vec<String>,
+    },
+    // players timing out are back
+    Withdrawn {},
+    Error(AdjudicationError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LegalMovesError {
+    Forbidden { description: String },
+    UnspecifiedError { description: String },
+}
+
+// Moves the player's piece on the position can make, for clients highlighting destinations.
+// Empty list for the empty cell or the piece of another player.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LegalMoves {
+    Request {
+        position: Position,
+    },
+    Moves {
+        position: Position,
+        moves: Vec<Move>,
+    },
+    Error(LegalMovesError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StateSyncError {
+    Forbidden { description: String },
+    GameNotFound { description: String },
+}
+
+// Whole current state of the game for clients which missed updates.
+// Players ask for their own game, spectators and engines for the game they follow.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StateSync {
+    Request {
+        #[serde(default)]
+        game_id: Option<u64>,
+    },
+    State(GameState),
+    Error(StateSyncError),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct GameState {
+    pub game_id: u64,
+    // FEN4 of the current position with the player to move and the lost players
+    pub position: String,
+    pub move_call: MoveCall,
+    pub players_states: PlayersStates,
+    // at the start of the turn as in updates
+    pub players_clocks: PlayersClocks,
+    // milliseconds of the turn already used by the player to move
+    pub turn_elapsed: u64,
+    pub checksum: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub players_points: Option<PlayersPoints>,
+    // plies played
+    pub moves: usize,
+    // of the last update sent
+    pub seq: u64,
+}
+
+impl GameState {
+    // the same state with seats next to the colors, for clients with seat_labels
+    pub fn with_seats(mut self) -> GameState {
+        if let MoveCall::Call { player, seat, .. } = &mut self.move_call {
+            *seat = Seat::of_player(player);
+        }
+        self.players_states.seats = Some(Seats::table());
+        self
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum GameSession {
     Init(Init),
     Move(Move),
     Update(Update),
+    InternalError {
+        description: String,
+        recovered: bool,
+    },
+    Reconnect(Reconnect),
+    AfkWarning {
+        description: String,
+        turns_before_resign: u64,
+    },
+    Finish(Finish),
+    // seconds before the first move, sent every second of the countdown
+    CountdownTick {
+        game_id: u64,
+        seconds: u64,
+    },
+    // player is ready to start, the countdown ends early once all players are
+    Ready {},
+    // game was ended by the operators
+    Terminated {
+        game_id: u64,
+        description: String,
+    },
+    // player left during the countdown, the others are back in the matchmaking queue
+    Aborted {
+        game_id: u64,
+        player: String,
+        description: String,
+    },
+    Chat(Chat),
+    DrawVote(DrawVote),
+    Pause(Pause),
+    Adjudication(Adjudication),
+    LegalMoves(LegalMoves),
+    StateSync(StateSync),
+    // player lost the connection and may come back with reconnect id
+    PlayerConnection {
+        player: String,
+        connected: bool,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -175,6 +757,8 @@ pub enum MoveCall {
         player: String,
         timer: u64,
         timer_2: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seat: Option<Seat>,
     },
 }
 
@@ -184,14 +768,27 @@ impl MoveCall {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// what happens to pieces of the lost player
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum RemainingPieces {
     Clear,
     TurnToStone,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl FromStr for RemainingPieces {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "clear" => Ok(RemainingPieces::Clear),
+            "turn_to_stone" => Ok(RemainingPieces::TurnToStone),
+            _ => bail!("unknown remaining pieces mode \"{}\"", s),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum PlayerState {
     NoState {},
@@ -199,50 +796,1088 @@ pub enum PlayerState {
     Checkmate {},
     Stalemate {},
     Lost { remaining_pieces: RemainingPieces },
+    Draw {},
 }
 
-impl From<vault::PlayerState> for PlayerState {
-    fn from(vault_state: vault::PlayerState) -> Self {
+impl PlayerState {
+    pub fn new(vault_state: vault::PlayerState, remaining_pieces: RemainingPieces) -> Self {
         match vault_state {
             vault::PlayerState::NoState => PlayerState::NoState {},
             vault::PlayerState::Check => PlayerState::Check {},
             vault::PlayerState::Checkmate => PlayerState::Checkmate {},
             vault::PlayerState::Stalemate => PlayerState::Stalemate {},
-            vault::PlayerState::Lost => PlayerState::Lost {
-                remaining_pieces: RemainingPieces::Clear,
-            },
+            vault::PlayerState::Lost => PlayerState::Lost { remaining_pieces },
+            vault::PlayerState::Draw => PlayerState::Draw {},
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
 pub struct PlayersStates {
     pub red: PlayerState,
     pub blue: PlayerState,
     pub yellow: PlayerState,
     pub green: PlayerState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seats: Option<Seats>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// remaining time of every player in milliseconds
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct PlayersClocks {
+    pub red: u64,
+    pub blue: u64,
+    pub yellow: u64,
+    pub green: u64,
+}
+
+// one move may capture, promote and check several players at once
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "snake_case")]
+pub struct MoveEffects {
+    pub captured: Option<Figure>,
+    pub promoted: Option<Figure>,
+    // players put in check, more than one on double check
+    pub checks: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
 pub struct Update {
     pub move_call: MoveCall,
     pub move_previous: Move,
+    #[serde(default)]
+    pub move_effects: MoveEffects,
     pub players_states: PlayersStates,
+    pub players_clocks: PlayersClocks,
+    // FNV-1a of the position in FEN4 including castling rights, hex encoded
+    pub checksum: String,
+    // games of the points variant only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub players_points: Option<PlayersPoints>,
+    // Turns of the game closed so far, 0 for the first call and one more for every later update.
+    // Updates resent on reconnect repeat it, a gap means missed updates.
+    #[serde(default)]
+    pub seq: u64,
+}
+
+impl Update {
+    // the same update with seats next to the colors, for clients with seat_labels
+    pub fn with_seats(&self) -> Update {
+        let mut update = self.clone();
+        if let MoveCall::Call { player, seat, .. } = &mut update.move_call {
+            *seat = Seat::of_player(player);
+        }
+        update.players_states.seats = Some(Seats::table());
+        update
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct PlayersPoints {
+    pub red: u32,
+    pub blue: u32,
+    pub yellow: u32,
+    pub green: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
-pub enum Pdu {
-    Handshake(Handshake),
-    MatchmakingQueue(MatchmakingQueue),
-    GameSession(GameSession),
+pub struct PlayerSummary {
+    pub player: String,
+    pub state: PlayerState,
+    // think time of all moves in milliseconds
+    pub time_used: u64,
+    pub moves: u64,
+    pub captures: u64,
+    pub checks_given: u64,
+    // percent of moves matching the analysis engine best move, none if analysis did not run
+    pub accuracy: Option<u8>,
+    // none for unrated games
+    pub rating_delta: Option<i32>,
+    // games of the points variant only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub points: Option<u32>,
 }
 
-impl Pdu {
-    pub fn to_message(&self) -> Result<Message> {
-        let json = serde_json::to_string(self)?;
-        Ok(Message::Text(json))
-    }
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PlayersSummaries {
+    pub red: PlayerSummary,
+    pub blue: PlayerSummary,
+    pub yellow: PlayerSummary,
+    pub green: PlayerSummary,
+}
+
+// sent to every participant after the last Update of the game
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Finish {
+    pub game_id: u64,
+    pub summaries: PlayersSummaries,
+    #[serde(default)]
+    pub end: vault::GameEnd,
+}
+
+// Analysis ///////////////////////////
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscribeError {
+    Handshake { description: String },
+    Unauthorized { description: String },
+    GameNotFound { description: String },
+    ConsentRequired { description: String },
+    Forbidden { description: String },
+    UnspecifiedError { description: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Subscribe {
+    Request { game_id: u64, token: String },
+    Ok { game_id: u64 },
+    Error(SubscribeError),
+}
+
+// centipawns from the point of view of every player
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct PlayersScores {
+    pub red: i32,
+    pub blue: i32,
+    pub yellow: i32,
+    pub green: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct Evaluation {
+    pub game_id: u64,
+    pub depth: u32,
+    pub scores: PlayersScores,
+    pub best_move: Move,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpectateError {
+    Handshake { description: String },
+    GameNotFound { description: String },
+    Forbidden { description: String },
+    UnspecifiedError { description: String },
+}
+
+// spectators receive the same game updates and evaluations as engines, leave with Unsubscribe
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Spectate {
+    Request { game_id: u64 },
+    Ok { game_id: u64 },
+    Error(SpectateError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayError {
+    GameNotFound { description: String },
+    UnspecifiedError { description: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PlayersNames {
+    pub red: String,
+    pub blue: String,
+    pub yellow: String,
+    pub green: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ReplayMove {
+    pub player: String,
+    pub mv: Move,
+    pub move_effects: MoveEffects,
+}
+
+// chat is none unless the requester played the game or is a moderator
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct GameReplay {
+    pub game_id: u64,
+    pub players: PlayersNames,
+    pub moves: Vec<ReplayMove>,
+    pub chat: Option<Vec<ChatMessage>>,
+}
+
+// record of the finished game, token is the moderator token
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Replay {
+    Request {
+        game_id: u64,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    Ok(GameReplay),
+    Error(ReplayError),
+}
+
+// record of the finished game in PGN4 notation, without chat
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pgn4 {
+    Request { game_id: u64 },
+    Ok { game_id: u64, pgn4: String },
+    Error(ReplayError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Analysis {
+    Subscribe(Subscribe),
+    Spectate(Spectate),
+    Replay(Replay),
+    Pgn4(Pgn4),
+    Unsubscribe {
+        game_id: u64,
+    },
+    ConsentRequest {
+        engine: String,
+    },
+    Consent {
+        allow: bool,
+    },
+    GameUpdate {
+        game_id: u64,
+        update: Update,
+    },
+    Evaluation(Evaluation),
+    // sent instead of GameUpdate to spectators switched to clocks only
+    Clocks {
+        game_id: u64,
+        players_clocks: PlayersClocks,
+    },
+    // slow spectator is switched to clocks only or disconnected
+    Shed {
+        game_id: u64,
+        clocks_only: bool,
+        description: String,
+    },
+}
+
+// Lobby ///////////////////////////
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LobbyError {
+    Handshake { description: String },
+    Unauthorized { description: String },
+    Forbidden { description: String },
+    NotFound { description: String },
+    BadPosition { description: String },
+    UnspecifiedError { description: String },
+}
+
+// private game of friends gathered by the join code, starts when the fourth joins
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Lobby {
+    // FEN4 of the position the game starts from, its side to move moves first.
+    // The initial position if unset.
+    Create {
+        player_name: String,
+        #[serde(default)]
+        start: Option<String>,
+    },
+    Join {
+        code: String,
+        player_name: String,
+    },
+    Leave {},
+    // sent to every member when somebody joins or leaves
+    Ok {
+        code: String,
+        players: Vec<String>,
+    },
+    Error(LobbyError),
+}
+
+// Tournament ///////////////////////////
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TournamentFormat {
+    // players of about the same score meet every round, the rounds are set in advance
+    Swiss,
+    // the two best of every table go on until the final table
+    Knockout,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TournamentError {
+    Handshake { description: String },
+    Unauthorized { description: String },
+    Forbidden { description: String },
+    NotFound { description: String },
+    UnspecifiedError { description: String },
+}
+
+// seats in turn order red, blue, yellow, green, the empty ones are taken by bots
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct TournamentTable {
+    pub players: Vec<String>,
+    // none if nobody of the table was there when the round started
+    pub game_id: Option<u64>,
+    pub finished: bool,
+}
+
+// players knocked out are below the ones still in, shared places have the same number
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct TournamentStanding {
+    pub place: u32,
+    pub player: String,
+    pub points: u32,
+    pub out: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct TournamentInfo {
+    pub tournament_id: u64,
+    pub name: String,
+    pub format: TournamentFormat,
+    // rounds of the swiss tournament, 0 for knockout
+    pub rounds: u32,
+    // seconds on the clock
+    pub time_control: u64,
+    // unix seconds of the first round
+    pub starts_at: u64,
+    // seconds the seat of the disconnected player is held
+    pub reconnect_grace: u64,
+    pub players: Vec<String>,
+    // current round, 0 before the first one
+    pub round: u32,
+    pub tables: Vec<TournamentTable>,
+    pub standings: Vec<TournamentStanding>,
+    pub finished: bool,
+}
+
+// Players logged in to their account register until the first round starts. Every round the
+// server seats them at tables of four and starts the games, players who are not idle then lose
+// the round. Registered players get Info when a round starts and when its standings are final.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tournament {
+    List {},
+    Tournaments { tournaments: Vec<TournamentInfo> },
+    Request { tournament_id: u64 },
+    Register { tournament_id: u64 },
+    Leave { tournament_id: u64 },
+    Info(TournamentInfo),
+    Error(TournamentError),
+}
+
+// Social ///////////////////////////
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SocialError {
+    Unauthorized { description: String },
+    Forbidden { description: String },
+    NotFound { description: String },
+    UnspecifiedError { description: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Friend {
+    pub name: String,
+    // logged in on any connection
+    pub online: bool,
+}
+
+// players in seat order red, blue, yellow, green with the challenger first
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ChallengeInfo {
+    pub challenge_id: u64,
+    pub players: Vec<String>,
+    pub accepted: Vec<String>,
+}
+
+// Friends are accounts the player added by name, AddFriend, RemoveFriend and FriendList are
+// answered with Friends. Challenge invites three online friends to a table, every connection
+// of the players gets Challenged on every acceptance. The game starts once all four accepted
+// while idle, players who are busy by then have to accept again. Declined ends the challenge.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Social {
+    AddFriend { name: String },
+    RemoveFriend { name: String },
+    FriendList {},
+    Friends { friends: Vec<Friend> },
+    Challenge { players: Vec<String> },
+    Accept { challenge_id: u64 },
+    Decline { challenge_id: u64 },
+    Challenged(ChallengeInfo),
+    Declined { challenge_id: u64, player: String },
+    Error(SocialError),
+}
+
+// Auth ///////////////////////////
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthError {
+    Handshake { description: String },
+    BadName { description: String },
+    NameTaken { description: String },
+    WeakPassword { description: String },
+    BadCredentials { description: String },
+    AlreadyAuthorized { description: String },
+    UnspecifiedError { description: String },
+}
+
+// token of Ok logs in without password on the next connections
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Auth {
+    Register {
+        name: String,
+        password: String,
+    },
+    Login {
+        name: String,
+        password: String,
+    },
+    Token {
+        token: String,
+    },
+    Ok {
+        account_id: u64,
+        name: String,
+        token: String,
+    },
+    Error(AuthError),
+}
+
+// Moderation ///////////////////////////
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationError {
+    Forbidden { description: String },
+    NotFound { description: String },
+    UnspecifiedError { description: String },
+}
+
+// redacted message text is removed from the active game and stored records
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Moderation {
+    Redact {
+        token: String,
+        game_id: u64,
+        message_id: u64,
+    },
+    Ok {},
+    Error(ModerationError),
+}
+
+// Account ///////////////////////////
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct AccountProfile {
+    pub account_id: u64,
+    pub name: String,
+    // unix seconds
+    pub created: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct AccountStats {
+    pub rating: i32,
+    pub badges: Vec<Badge>,
+    pub season_games: u32,
+    // games kept in records
+    pub games: u32,
+}
+
+// everything the server stores about the player, games include their chat
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct AccountData {
+    pub profile: AccountProfile,
+    pub stats: AccountStats,
+    pub games: Vec<GameReplay>,
+    // names of blocked players
+    pub blocked: Vec<String>,
+    // pools whose games are kept out of public history
+    pub private_pools: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountDataError {
+    Unauthorized { description: String },
+    Forbidden { description: String },
+    BadCredentials { description: String },
+    NotFound { description: String },
+    UnspecifiedError { description: String },
+}
+
+// Delete removes the account, the player is anonymized in game records and their chat redacted.
+// Blocked players do not get into casual games with the player, their chat is not delivered.
+// Block, Unblock and BlockList are answered with Blocked.
+// Games of private pools, lobby games count as the default pool, are kept out of public
+// replays, pgn4 exports and history for all but their players and moderators.
+// Private, Public and PrivateList are answered with PrivatePools.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Account {
+    Export {},
+    Delete { password: String },
+    Block { name: String },
+    Unblock { name: String },
+    BlockList {},
+    Private { pool: String },
+    Public { pool: String },
+    PrivateList {},
+    Data(AccountData),
+    Deleted {},
+    Blocked { names: Vec<String> },
+    PrivatePools { pools: Vec<String> },
+    Error(AccountDataError),
+}
+
+// Rating ///////////////////////////
+// place of the player at the end of the season
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct Badge {
+    pub season: u32,
+    pub place: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct LeaderboardEntry {
+    pub place: u32,
+    pub player: String,
+    pub rating: i32,
+}
+
+// times in unix seconds, ends is planned for the current season
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct SeasonInfo {
+    pub number: u32,
+    pub started: u64,
+    pub ends: u64,
+    pub leaderboard: Vec<LeaderboardEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SeasonError {
+    Handshake { description: String },
+    NotFound { description: String },
+    UnspecifiedError { description: String },
+}
+
+// current season when number is none
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Season {
+    Request { number: Option<u32> },
+    Ok(SeasonInfo),
+    Error(SeasonError),
+}
+
+// result of the rated game for the player, the shared first place is a draw
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GameResult {
+    Win,
+    Draw,
+    Loss,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PlayerRank {
+    pub player: String,
+    // among all rated players, not only those of the current season
+    pub place: u32,
+    pub rating: i32,
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+    // results of the last rated games, the latest first
+    pub form: Vec<GameResult>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaderboardError {
+    Handshake { description: String },
+    NotFound { description: String },
+    UnspecifiedError { description: String },
+}
+
+// Best rated players of all time, the default number of them if top is none.
+// Rank of the player is given with the name, NotFound if the player was never rated.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Leaderboard {
+    Request {
+        #[serde(default)]
+        top: Option<u32>,
+        #[serde(default)]
+        player: Option<String>,
+    },
+    Ok {
+        top: Vec<LeaderboardEntry>,
+        player: Option<PlayerRank>,
+    },
+    Error(LeaderboardError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rating {
+    Season(Season),
+    Leaderboard(Leaderboard),
+}
+
+// GameHistory ///////////////////////////
+// move of the finished game as the players got it in Update
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct HistoryMove {
+    // from 1
+    pub number: usize,
+    pub player: String,
+    pub move_previous: Move,
+    pub move_effects: MoveEffects,
+    // milliseconds the player thought
+    pub time_used: u64,
+    // unix milliseconds, 0 for games recorded before it was stored
+    pub at: u64,
+}
+
+// Finished game streamed move by move: Start, every Move in order and End
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GameHistory {
+    Request {
+        game_id: u64,
+    },
+    Start {
+        game_id: u64,
+        players: PlayersNames,
+        // unix seconds, 0 if unknown
+        started: u64,
+        moves: usize,
+    },
+    Move(HistoryMove),
+    End {
+        game_id: u64,
+        players_states: PlayersStates,
+    },
+    Error(ReplayError),
+}
+
+// Admin ///////////////////////////
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminError {
+    Forbidden { description: String },
+    NotFound { description: String },
+    BadRequest { description: String },
+    UnspecifiedError { description: String },
+}
+
+// built-in measurements of the instance, rates are per second
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct BenchmarkReport {
+    // game with most spectators whose position was used, none for the initial position
+    pub game_id: Option<u64>,
+    // moves of all pieces in the position, king safety is not checked
+    pub position_moves: u64,
+    pub movegen_rate: u64,
+    // check scans of all four kings in the position
+    pub attack_rate: u64,
+    // json serialization of the board
+    pub serialize_rate: u64,
+    pub serialize_bytes_rate: u64,
+    // busiest game lock acquisition in microseconds
+    pub lock_avg_us: u64,
+    pub lock_max_us: u64,
+    // connections through GetInfo and Connect handshakes, thousands of them at once
+    pub handshake_rate: u64,
+}
+
+// token is the admin token
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Benchmark {
+    Request { token: String },
+    Ok(BenchmarkReport),
+    Error(AdminError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PeerInfo {
+    pub conn_id: u64,
+    pub addr: String,
+    // unknown, idle, mm_queue, heartbeat_wait, heartbeat_ready, game or lobby
+    pub state: String,
+    pub player_name: Option<String>,
+    pub account: Option<String>,
+    pub client: Option<String>,
+    pub pool: Option<String>,
+    pub game_id: Option<u64>,
+    // milliseconds since anything was received
+    pub silent_ms: u64,
+    // messages waiting to be sent
+    pub queued: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerList {
+    Request { token: String },
+    Ok { peers: Vec<PeerInfo> },
+    Error(AdminError),
+}
+
+// clocks are as of the last turn, turn_elapsed is not yet taken from the player to move
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct GameInfo {
+    pub game_id: u64,
+    pub players: PlayersNames,
+    pub players_states: PlayersStates,
+    pub players_clocks: PlayersClocks,
+    pub who_move: Option<String>,
+    // milliseconds
+    pub turn_elapsed: u64,
+    pub moves: usize,
+    pub subscribers: usize,
+    pub casual: bool,
+    // unix seconds
+    pub started: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GameList {
+    Request { token: String },
+    Ok { games: Vec<GameInfo> },
+    Error(AdminError),
+}
+
+// game ends without result and rating changes, players get Terminated
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Terminate {
+    Request {
+        token: String,
+        game_id: u64,
+        description: String,
+    },
+    Ok {
+        game_id: u64,
+    },
+    Error(AdminError),
+}
+
+// connection is closed, the player may reconnect
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kick {
+    Request { token: String, conn_id: u64 },
+    Ok { conn_id: u64 },
+    Error(AdminError),
+}
+
+// Tournament of the format with the first round at starts_at unix seconds, rounds are for swiss only.
+// Time control in seconds, the default one if 0. Seats of the disconnected players are held and
+// their clocks stopped for reconnect_grace seconds, the default window if 0.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CreateTournament {
+    Request {
+        token: String,
+        name: String,
+        format: TournamentFormat,
+        #[serde(default)]
+        rounds: u32,
+        #[serde(default)]
+        time_control: u64,
+        starts_at: u64,
+        #[serde(default)]
+        reconnect_grace: u64,
+    },
+    Ok(TournamentInfo),
+    Error(AdminError),
+}
+
+// chat of the player is dropped in every game for the seconds, 0 lifts the mute
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatMute {
+    Request {
+        token: String,
+        player: String,
+        seconds: u64,
+    },
+    Ok {
+        player: String,
+        seconds: u64,
+    },
+    Error(AdminError),
+}
+
+// every connected peer gets Notice, Ok tells to how many it was sent
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Broadcast {
+    Request { token: String, text: String },
+    Ok { peers: usize },
+    Error(AdminError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct WordHits {
+    pub word: String,
+    pub severity: Severity,
+    pub hits: u64,
+}
+
+// chat filter hits since the start of the server, words with most hits first
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct FilterReport {
+    // chat messages and names
+    pub checked: u64,
+    pub censored: u64,
+    pub dropped: u64,
+    // by mute words or after too many dropped messages
+    pub muted: u64,
+    pub names_rejected: u64,
+    pub words: Vec<WordHits>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterStats {
+    Request { token: String },
+    Ok(FilterReport),
+    Error(AdminError),
+}
+
+// feature flags of new games, Set replaces the flag of the same name until restart,
+// Ok lists all of them
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeatureFlags {
+    List { token: String },
+    Set { token: String, flag: Flag },
+    Ok { flags: Vec<Flag> },
+    Error(AdminError),
+}
+
+// Changes of the board, clocks and players states between two plies of the game, replayed
+// from its moves. Ply 0 is the start position, lines are meant to be read by the support.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GameDiff {
+    Request {
+        token: String,
+        game_id: u64,
+        from: usize,
+        to: usize,
+    },
+    Ok {
+        game_id: u64,
+        from: usize,
+        to: usize,
+        lines: Vec<String>,
+    },
+    Error(AdminError),
+}
+
+// player flagged by the fair play review of the latest games, percents are none
+// until there are enough moves to tell
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct Suspect {
+    pub player: String,
+    // games of the player kept in records
+    pub games: u32,
+    // moves of the reviewed games
+    pub moves: u32,
+    // engine evaluated moves that were the engine best move, in percent
+    pub engine_match: Option<u8>,
+    // standard deviation of the think times in percent of their mean
+    pub time_spread: Option<u8>,
+    pub reasons: Vec<String>,
+    // unix seconds
+    pub flagged_at: u64,
+}
+
+// Suspects stay listed until cleared, a cleared player is flagged again by later games only
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FairPlayReview {
+    List { token: String },
+    Clear { token: String, player: String },
+    Ok { suspects: Vec<Suspect> },
+    Error(AdminError),
+}
+
+// move durations of finished games by pool and time control, for tuning of the timers
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimingReport {
+    Request { token: String },
+    Ok { timings: Vec<MoveTiming> },
+    Error(AdminError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Admin {
+    Benchmark(Benchmark),
+    FilterStats(FilterStats),
+    TimingReport(TimingReport),
+    FairPlayReview(FairPlayReview),
+    ChatMute(ChatMute),
+    GameDiff(GameDiff),
+    FeatureFlags(FeatureFlags),
+    PeerList(PeerList),
+    GameList(GameList),
+    Terminate(Terminate),
+    Kick(Kick),
+    Broadcast(Broadcast),
+    CreateTournament(CreateTournament),
+}
+
+// Notice ///////////////////////////
+// message of the operators, e.g. planned maintenance
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Notice {
+    pub text: String,
+}
+
+// Disconnect ///////////////////////////
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectReason {
+    Idle,
+}
+
+// server closes the connection right after it, the client may connect again
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Disconnect {
+    pub reason: DisconnectReason,
+    pub description: String,
+}
+
+// Route ///////////////////////////
+// Distributed mode, the request is served by another node which the client connects to and asks
+// again. Reconnect is answered with the node the game is pinned to, PlayerRegister with the node
+// gathering the pool.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Route {
+    // "ws://host:port" of the node
+    pub node: String,
+    pub description: String,
+}
+
+// RateLimited ///////////////////////////
+// client sends too fast, messages are dropped until retry_after and the flooding client is disconnected
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct RateLimited {
+    pub description: String,
+    // milliseconds
+    pub retry_after: u64,
+    pub disconnected: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pdu {
+    Handshake(Handshake),
+    MatchmakingQueue(MatchmakingQueue),
+    GameSession(GameSession),
+    Analysis(Analysis),
+    Lobby(Lobby),
+    Social(Social),
+    Tournament(Tournament),
+    Rating(Rating),
+    Auth(Auth),
+    Moderation(Moderation),
+    Account(Account),
+    GameHistory(GameHistory),
+    Admin(Admin),
+    Notice(Notice),
+    RateLimited(RateLimited),
+    Disconnect(Disconnect),
+    Route(Route),
+}
+
+impl Pdu {
+    pub fn to_message(&self) -> Result<Message> {
+        let json = serde_json::to_string(self)?;
+        Ok(Message::Text(json))
+    }
+
+    // json text or MessagePack binary frame of the client
+    pub fn from_message(message: &Message) -> Result<Pdu> {
+        match message {
+            Message::Binary(bytes) => Ok(rmp_serde::from_slice(bytes)?),
+            _ => Ok(serde_json::from_str(message.to_text()?)?),
+        }
+    }
+}
+
+// Json pdu with the codes of its errors and their descriptions in the catalog locale,
+// pdus without errors are kept as they are
+pub fn localize(message: Message, locale: usize) -> Message {
+    let localized = match &message {
+        Message::Text(json) if json.contains("\"error\"") => {
+            serde_json::from_str::<serde_json::Value>(json)
+                .ok()
+                .map(|mut value| {
+                    locale::localize_errors(&mut value, locale);
+                    Message::Text(value.to_string())
+                })
+        }
+        _ => None,
+    };
+    localized.unwrap_or(message)
+}
+
+// Json pdu as the MessagePack map of the same fields, other messages are kept as they are
+pub fn to_msgpack(message: Message) -> Message {
+    let bytes = match &message {
+        Message::Text(json) => serde_json::from_str::<serde_json::Value>(json)
+            .ok()
+            .and_then(|value| rmp_serde::to_vec_named(&value).ok()),
+        _ => None,
+    };
+    bytes.map_or(message, Message::Binary)
 }
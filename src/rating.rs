@@ -0,0 +1,220 @@
+use crate::proto::{Badge, LeaderboardEntry, SeasonInfo};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub const DEFAULT_RATING: i32 = 1500;
+// maximum change of rating against all opponents of the game
+const K_FACTOR: f64 = 32.0;
+pub const LEADERBOARD_SIZE: usize = 100;
+// top finishers of the season get a badge
+const BADGE_PLACES: u32 = 3;
+
+// Elo ratings of players by name, persisted by storage.
+// Ratings are softly reset at the end of every season.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Ratings {
+    ratings: HashMap<String, i32>,
+    // current season number, 0 before the first season started
+    #[serde(default)]
+    season: u32,
+    // unix seconds
+    #[serde(default)]
+    season_started: u64,
+    // games played in the current season, only those players are on the leaderboard
+    #[serde(default)]
+    season_games: HashMap<String, u32>,
+    #[serde(default)]
+    past_seasons: Vec<SeasonInfo>,
+    #[serde(default)]
+    badges: HashMap<String, Vec<Badge>>,
+    // not yet persisted changes
+    #[serde(skip)]
+    changed: bool,
+}
+
+impl Ratings {
+    pub fn get(&self, name: &str) -> i32 {
+        *self.ratings.get(name).unwrap_or(&DEFAULT_RATING)
+    }
+
+    pub fn badges(&self, name: &str) -> Vec<Badge> {
+        self.badges.get(name).cloned().unwrap_or_default()
+    }
+
+    pub fn season_games(&self, name: &str) -> u32 {
+        *self.season_games.get(name).unwrap_or(&0)
+    }
+
+    // Forget rating and badges of the player, past leaderboards keep the place under alias
+    pub fn forget(&mut self, name: &str, alias: &str) {
+        self.ratings.remove(name);
+        self.season_games.remove(name);
+        self.badges.remove(name);
+        for entry in self
+            .past_seasons
+            .iter_mut()
+            .flat_map(|season| season.leaderboard.iter_mut())
+            .filter(|entry| entry.player == name)
+        {
+            entry.player = alias.to_string();
+        }
+        self.changed = true;
+    }
+
+    // players by rating, ties by name
+    fn ranked<'a>(&self, players: impl Iterator<Item = &'a String>) -> Vec<(String, i32)> {
+        let mut players = players
+            .map(|name| (name.clone(), self.get(name)))
+            .collect::<Vec<_>>();
+        players.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        players
+    }
+
+    // best players of the current season
+    pub fn leaderboard(&self) -> Vec<LeaderboardEntry> {
+        entries(self.ranked(self.season_games.keys()), LEADERBOARD_SIZE)
+    }
+
+    // best players of all time, at most LEADERBOARD_SIZE of them
+    pub fn all_time(&self, size: usize) -> Vec<LeaderboardEntry> {
+        entries(self.ranked(self.ratings.keys()), size.min(LEADERBOARD_SIZE))
+    }
+
+    // place of the player among all rated players, none if never rated
+    pub fn rank(&self, name: &str) -> Option<u32> {
+        if !self.ratings.contains_key(name) {
+            return None;
+        }
+        let rating = self.get(name);
+        let above = self
+            .ratings
+            .iter()
+            .filter(|(other, r)| **r > rating || (**r == rating && other.as_str() < name))
+            .count();
+        Some(above as u32 + 1)
+    }
+
+    // current season when number is none, ends is planned for the current season
+    pub fn season(&self, number: Option<u32>, length: u64) -> Option<SeasonInfo> {
+        match number {
+            Some(number) if number != self.season => self
+                .past_seasons
+                .iter()
+                .find(|season| season.number == number)
+                .cloned(),
+            _ if self.season == 0 => None,
+            _ => Some(SeasonInfo {
+                number: self.season,
+                started: self.season_started,
+                ends: self.season_started + length,
+                leaderboard: self.leaderboard(),
+            }),
+        }
+    }
+
+    pub fn current_season(&self) -> u32 {
+        self.season
+    }
+
+    // Start the next season when the current one is over: top finishers get badges,
+    // ratings are pulled halfway back to the default. Returns true on rollover.
+    pub fn roll_season(&mut self, now: u64, length: u64) -> bool {
+        if self.season == 0 {
+            self.season = 1;
+            self.season_started = now;
+            self.changed = true;
+            return false;
+        }
+        if now < self.season_started + length {
+            return false;
+        }
+
+        let leaderboard = self.leaderboard();
+        for entry in leaderboard.iter().filter(|e| e.place <= BADGE_PLACES) {
+            self.badges
+                .entry(entry.player.clone())
+                .or_default()
+                .push(Badge {
+                    season: self.season,
+                    place: entry.place,
+                });
+        }
+        self.past_seasons.push(SeasonInfo {
+            number: self.season,
+            started: self.season_started,
+            ends: now,
+            leaderboard,
+        });
+        for rating in self.ratings.values_mut() {
+            *rating = DEFAULT_RATING + (*rating - DEFAULT_RATING) / 2;
+        }
+        self.season_games.clear();
+        self.season += 1;
+        self.season_started = now;
+        self.changed = true;
+        true
+    }
+
+    // Every pair of players is rated as a separate match won by the better placed one,
+    // players with the same place draw. Lower place is better.
+    // Returns rating changes in order of players.
+    pub fn rate(&mut self, players: &[(&str, usize)]) -> Vec<i32> {
+        let before = players
+            .iter()
+            .map(|(name, _)| self.get(name) as f64)
+            .collect::<Vec<_>>();
+        let opponents = players.len().saturating_sub(1).max(1) as f64;
+        let mut deltas = Vec::new();
+        for (i, (_, place)) in players.iter().enumerate() {
+            let mut score = 0.0;
+            for (j, (_, other_place)) in players.iter().enumerate() {
+                if i == j {
+                    continue;
+                }
+                let actual = if place < other_place {
+                    1.0
+                } else if place == other_place {
+                    0.5
+                } else {
+                    0.0
+                };
+                score += actual - expected(before[i], before[j]);
+            }
+            deltas.push((K_FACTOR * score / opponents).round() as i32);
+        }
+        for ((name, _), delta) in players.iter().zip(deltas.iter()) {
+            self.ratings
+                .insert(name.to_string(), self.get(name) + delta);
+            *self.season_games.entry(name.to_string()).or_default() += 1;
+        }
+        self.changed = true;
+        deltas
+    }
+
+    // true once after every change
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+    pub fn mark_changed(&mut self) {
+        self.changed = true;
+    }
+}
+
+// places of the first players of the ranking
+fn entries(players: Vec<(String, i32)>, size: usize) -> Vec<LeaderboardEntry> {
+    players
+        .into_iter()
+        .take(size)
+        .enumerate()
+        .map(|(i, (player, rating))| LeaderboardEntry {
+            place: i as u32 + 1,
+            player,
+            rating,
+        })
+        .collect()
+}
+
+// probability of player with rating a to win against rating b
+fn expected(a: f64, b: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((b - a) / 400.0))
+}
@@ -0,0 +1,350 @@
+use crate::account::Accounts;
+use crate::fairplay::FairPlay;
+use crate::metrics::Sample;
+use crate::rating::Ratings;
+use crate::tournament::Tournaments;
+use crate::vault::{GameSnapshot, TurnDelta};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+// when appended write-ahead log entries are flushed to the disk
+#[derive(Clone, Copy)]
+pub enum Fsync {
+    // every turn, survives power loss
+    Always,
+    // left to the system, survives crash of the server only
+    Never,
+}
+
+impl FromStr for Fsync {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always" => Ok(Fsync::Always),
+            "never" => Ok(Fsync::Never),
+            _ => bail!("unknown fsync policy \"{}\"", s),
+        }
+    }
+}
+
+// line of the game write-ahead log
+#[derive(Serialize, Deserialize)]
+pub enum WalEntry {
+    // full state when the game session starts, turns follow
+    Snapshot(Box<GameSnapshot>),
+    Turn(TurnDelta),
+}
+
+impl WalEntry {
+    fn turn(&self) -> u64 {
+        match self {
+            WalEntry::Snapshot(snapshot) => snapshot.turn,
+            WalEntry::Turn(delta) => delta.turn,
+        }
+    }
+}
+
+// Persist snapshots of active games and records of finished ones as json files, one file per game,
+// ratings and accounts of all players, the tournaments and the fair play suspects.
+// Turns played since the last snapshot are in the write-ahead log.
+// Lobby population samples are appended to the metrics log.
+pub struct Storage {
+    games_dir: PathBuf,
+    records_dir: PathBuf,
+    wal_dir: PathBuf,
+    ratings_path: PathBuf,
+    accounts_path: PathBuf,
+    tournaments_path: PathBuf,
+    fair_play_path: PathBuf,
+    metrics_path: PathBuf,
+    // written by the readiness probe
+    probe_path: PathBuf,
+    fsync: Fsync,
+    // appends of game sessions and compaction of snapshot dispatcher
+    wal_lock: Mutex<()>,
+}
+
+impl Storage {
+    pub fn new(dir: &str, fsync: Fsync) -> Result<Storage> {
+        let games_dir = PathBuf::from(dir).join("games");
+        fs::create_dir_all(&games_dir)
+            .context(format!("create storage dir {:?} failed", games_dir))?;
+        let records_dir = PathBuf::from(dir).join("records");
+        fs::create_dir_all(&records_dir)
+            .context(format!("create storage dir {:?} failed", records_dir))?;
+        let wal_dir = PathBuf::from(dir).join("wal");
+        fs::create_dir_all(&wal_dir).context(format!("create storage dir {:?} failed", wal_dir))?;
+        let ratings_path = PathBuf::from(dir).join("ratings.json");
+        let accounts_path = PathBuf::from(dir).join("accounts.json");
+        let tournaments_path = PathBuf::from(dir).join("tournaments.json");
+        let fair_play_path = PathBuf::from(dir).join("fair_play.json");
+        let metrics_path = PathBuf::from(dir).join("metrics.log");
+        let probe_path = PathBuf::from(dir).join("probe");
+        Ok(Storage {
+            games_dir,
+            records_dir,
+            wal_dir,
+            ratings_path,
+            accounts_path,
+            tournaments_path,
+            fair_play_path,
+            metrics_path,
+            probe_path,
+            fsync,
+            wal_lock: Mutex::new(()),
+        })
+    }
+
+    // the storage takes writes
+    pub fn probe(&self) -> Result<()> {
+        write_atomic(&self.probe_path, b"ok")
+    }
+
+    fn game_path(&self, game_id: u64) -> PathBuf {
+        self.games_dir.join(format!("{}.json", game_id))
+    }
+
+    fn wal_path(&self, game_id: u64) -> PathBuf {
+        self.wal_dir.join(format!("{}.log", game_id))
+    }
+
+    // Returns once the entry is as durable as the fsync policy promises
+    pub fn append_wal(&self, game_id: u64, entry: &WalEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let path = self.wal_path(game_id);
+        let _lock = self.wal_lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .context(format!("open {:?} failed", path))?;
+        file.write_all(&line)
+            .context(format!("write {:?} failed", path))?;
+        if let Fsync::Always = self.fsync {
+            file.sync_data()
+                .context(format!("sync {:?} failed", path))?;
+        }
+        Ok(())
+    }
+
+    // entries up to the turn are already in the saved snapshot
+    pub fn compact_wal(&self, game_id: u64, turn: u64) -> Result<()> {
+        let _lock = self.wal_lock.lock().unwrap();
+        let entries = self.read_wal(game_id)?;
+        if entries.iter().all(|e| e.turn() > turn) {
+            return Ok(());
+        }
+        let mut data = Vec::new();
+        for entry in entries.iter().filter(|e| e.turn() > turn) {
+            data.extend(serde_json::to_vec(entry)?);
+            data.push(b'\n');
+        }
+        write_atomic(&self.wal_path(game_id), &data)
+    }
+
+    // line cut by a crash during the append was never acknowledged
+    fn read_wal(&self, game_id: u64) -> Result<Vec<WalEntry>> {
+        let path = self.wal_path(game_id);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let data = fs::read_to_string(&path).context(format!("read {:?} failed", path))?;
+        let mut entries = Vec::new();
+        for line in data.lines() {
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                Err(_) if !data.ends_with('\n') && data.ends_with(line) => break,
+                Err(e) => return Err(e).context(format!("parse {:?} failed", path)),
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn save_game(&self, snapshot: &GameSnapshot) -> Result<()> {
+        write_atomic(&self.game_path(snapshot.id), &serde_json::to_vec(snapshot)?)
+    }
+
+    pub fn save_record(&self, record: &GameSnapshot) -> Result<()> {
+        let path = self.records_dir.join(format!("{}.json", record.id));
+        write_atomic(&path, &serde_json::to_vec(record)?)
+    }
+
+    pub fn load_records(&self) -> Result<Vec<GameSnapshot>> {
+        let mut records = Vec::new();
+        for entry in fs::read_dir(&self.records_dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let json = fs::read(&path).context(format!("read {:?} failed", path))?;
+                records.push(
+                    serde_json::from_slice(&json).context(format!("parse {:?} failed", path))?,
+                );
+            }
+        }
+        Ok(records)
+    }
+
+    pub fn save_ratings(&self, ratings: &Ratings) -> Result<()> {
+        write_atomic(&self.ratings_path, &serde_json::to_vec(ratings)?)
+    }
+
+    // nobody is rated yet when the file is missing
+    pub fn load_ratings(&self) -> Result<Ratings> {
+        if !self.ratings_path.exists() {
+            return Ok(Ratings::default());
+        }
+        let json =
+            fs::read(&self.ratings_path).context(format!("read {:?} failed", self.ratings_path))?;
+        serde_json::from_slice(&json).context(format!("parse {:?} failed", self.ratings_path))
+    }
+
+    pub fn save_accounts(&self, accounts: &Accounts) -> Result<()> {
+        write_atomic(&self.accounts_path, &serde_json::to_vec(accounts)?)
+    }
+
+    // nobody is registered yet when the file is missing
+    pub fn load_accounts(&self) -> Result<Accounts> {
+        if !self.accounts_path.exists() {
+            return Ok(Accounts::default());
+        }
+        let json = fs::read(&self.accounts_path)
+            .context(format!("read {:?} failed", self.accounts_path))?;
+        let mut accounts: Accounts = serde_json::from_slice(&json)
+            .context(format!("parse {:?} failed", self.accounts_path))?;
+        accounts.index_tokens();
+        Ok(accounts)
+    }
+
+    pub fn save_tournaments(&self, tournaments: &Tournaments) -> Result<()> {
+        write_atomic(&self.tournaments_path, &serde_json::to_vec(tournaments)?)
+    }
+
+    // no tournament was created yet when the file is missing
+    pub fn load_tournaments(&self) -> Result<Tournaments> {
+        if !self.tournaments_path.exists() {
+            return Ok(Tournaments::default());
+        }
+        let json = fs::read(&self.tournaments_path)
+            .context(format!("read {:?} failed", self.tournaments_path))?;
+        serde_json::from_slice(&json).context(format!("parse {:?} failed", self.tournaments_path))
+    }
+
+    pub fn save_fair_play(&self, fair_play: &FairPlay) -> Result<()> {
+        write_atomic(&self.fair_play_path, &serde_json::to_vec(fair_play)?)
+    }
+
+    // nobody was flagged yet when the file is missing
+    pub fn load_fair_play(&self) -> Result<FairPlay> {
+        if !self.fair_play_path.exists() {
+            return Ok(FairPlay::default());
+        }
+        let json = fs::read(&self.fair_play_path)
+            .context(format!("read {:?} failed", self.fair_play_path))?;
+        serde_json::from_slice(&json).context(format!("parse {:?} failed", self.fair_play_path))
+    }
+
+    // samples are not worth a sync, the last ones may be lost in a crash
+    pub fn append_metrics(&self, sample: &Sample) -> Result<()> {
+        let mut line = serde_json::to_vec(sample)?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.metrics_path)
+            .and_then(|mut file| file.write_all(&line))
+            .context(format!("write {:?} failed", self.metrics_path))
+    }
+
+    // samples in time order, the line cut by a crash is skipped
+    pub fn load_metrics(&self) -> Result<Vec<Sample>> {
+        if !self.metrics_path.exists() {
+            return Ok(Vec::new());
+        }
+        let data = fs::read_to_string(&self.metrics_path)
+            .context(format!("read {:?} failed", self.metrics_path))?;
+        Ok(data
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    pub fn remove_game(&self, game_id: u64) -> Result<()> {
+        let _lock = self.wal_lock.lock().unwrap();
+        for path in [self.game_path(game_id), self.wal_path(game_id)] {
+            if path.exists() {
+                fs::remove_file(&path).context(format!("remove {:?} failed", path))?;
+            }
+        }
+        Ok(())
+    }
+
+    // games with a snapshot or a write-ahead log
+    pub fn game_ids(&self) -> Result<Vec<u64>> {
+        let mut ids = BTreeSet::new();
+        for (dir, extension) in [(&self.games_dir, "json"), (&self.wal_dir, "log")] {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == extension) {
+                    if let Some(id) = path
+                        .file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .and_then(|stem| stem.parse().ok())
+                    {
+                        ids.insert(id);
+                    }
+                }
+            }
+        }
+        Ok(ids.into_iter().collect())
+    }
+
+    // Latest snapshot of every game with the logged turns played after it
+    pub fn load_games(&self) -> Result<Vec<(GameSnapshot, Vec<TurnDelta>)>> {
+        let mut games = Vec::new();
+        for game_id in self.game_ids()? {
+            let path = self.game_path(game_id);
+            let mut snapshot: Option<GameSnapshot> = if path.exists() {
+                let json = fs::read(&path).context(format!("read {:?} failed", path))?;
+                Some(serde_json::from_slice(&json).context(format!("parse {:?} failed", path))?)
+            } else {
+                None
+            };
+            let mut turns = Vec::new();
+            for entry in self.read_wal(game_id)? {
+                let last_turn = turns
+                    .last()
+                    .map(|t: &TurnDelta| t.turn)
+                    .or(snapshot.as_ref().map(|s| s.turn));
+                match entry {
+                    WalEntry::Snapshot(logged) if last_turn.is_none_or(|t| logged.turn >= t) => {
+                        snapshot = Some(*logged);
+                        turns.clear();
+                    }
+                    WalEntry::Turn(delta) if last_turn.is_some_and(|t| delta.turn == t + 1) => {
+                        turns.push(delta);
+                    }
+                    _ => (),
+                }
+            }
+            if let Some(snapshot) = snapshot {
+                games.push((snapshot, turns));
+            }
+        }
+        Ok(games)
+    }
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, data).context(format!("write {:?} failed", tmp_path))?;
+    // rename is atomic, a crash never leaves half written file
+    fs::rename(&tmp_path, path).context(format!("rename {:?} failed", tmp_path))?;
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use anyhow::Result;
+use std::env;
+
+static ENDPOINT_VAR: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+// Spans of the message handling, from the frame of the client to the broadcast of the turn,
+// exported to the OpenTelemetry collector of OTEL_EXPORTER_OTLP_ENDPOINT over gRPC.
+// Export is built with the otlp feature, without it or the endpoint spans go nowhere.
+#[cfg(feature = "otlp")]
+pub fn init(service: &str) -> Result<()> {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    if env::var(ENDPOINT_VAR).is_err() {
+        return Ok(());
+    }
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .build()?;
+    let service = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| service.to_string());
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service).build())
+        .build();
+    let tracer = provider.tracer("server-rs");
+    opentelemetry::global::set_tracer_provider(provider);
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "otlp"))]
+pub fn init(_service: &str) -> Result<()> {
+    if env::var(ENDPOINT_VAR).is_ok() {
+        log::warn!(
+            "{} is set, spans are not exported without the otlp feature",
+            ENDPOINT_VAR
+        );
+    }
+    Ok(())
+}
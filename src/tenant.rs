@@ -0,0 +1,185 @@
+use crate::canary::CanaryConfig;
+use crate::cluster::{Cluster, ClusterConfig};
+use crate::pool::{self, Pool, DEFAULT_POOL};
+use crate::storage::{Fsync, Storage};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::sync::Arc;
+
+// Community served on its own address, players of different tenants never meet.
+// Pools, accounts, ratings, records and tokens are of the tenant, the process and the rules
+// are shared. Fields are the per tenant FPC_POOLS, FPC_STORAGE_DIR, FPC_ADMIN_TOKEN,
+// FPC_MODERATOR_TOKEN, FPC_OBSERVER_ADDR, the FPC_CANARY_* settings and FPC_REDIS_URL with
+// FPC_NODE_ADDR.
+#[derive(Clone, Deserialize)]
+pub struct TenantConfig {
+    pub name: String,
+    // "host:port" the players of the tenant connect to
+    pub addr: String,
+    // json list of pools, only the default pool if unset
+    #[serde(default)]
+    pub pools: Option<String>,
+    // nothing is persisted if unset
+    #[serde(default)]
+    pub storage_dir: Option<String>,
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    #[serde(default)]
+    pub moderator_token: Option<String>,
+    #[serde(default)]
+    pub observer_addr: Option<String>,
+    #[serde(default)]
+    pub canary: Option<CanaryConfig>,
+    #[serde(default)]
+    pub cluster: Option<ClusterConfig>,
+}
+
+impl TenantConfig {
+    pub fn load(&self, fsync: Fsync) -> Result<Tenant> {
+        let pools = match &self.pools {
+            Some(path) => pool::load_pools(path)?,
+            None => vec![Pool::new(DEFAULT_POOL)],
+        };
+        let storage = match &self.storage_dir {
+            Some(dir) => Some(Arc::new(Storage::new(dir, fsync)?)),
+            None => None,
+        };
+        Ok(Tenant {
+            name: Some(self.name.clone()),
+            pools,
+            storage,
+            admin_token: self.admin_token.clone(),
+            moderator_token: self.moderator_token.clone(),
+            canary: self.canary.clone(),
+            cluster: match &self.cluster {
+                Some(config) => Some(Arc::new(Cluster::new(config, Some(&self.name))?)),
+                None => None,
+            },
+        })
+    }
+}
+
+// Settings of the tenant the vault serves
+pub struct Tenant {
+    // none for the only tenant of the server configured by the environment
+    pub name: Option<String>,
+    pub pools: Vec<Pool>,
+    pub storage: Option<Arc<Storage>>,
+    // admin and moderator commands are disabled if unset
+    pub admin_token: Option<String>,
+    pub moderator_token: Option<String>,
+    // the public endpoint is probed if set
+    pub canary: Option<CanaryConfig>,
+    // the tenant is served by several nodes if set
+    pub cluster: Option<Arc<Cluster>>,
+}
+
+impl Default for Tenant {
+    fn default() -> Tenant {
+        Tenant {
+            name: None,
+            pools: vec![Pool::new(DEFAULT_POOL)],
+            storage: None,
+            admin_token: None,
+            moderator_token: None,
+            canary: None,
+            cluster: None,
+        }
+    }
+}
+
+impl Tenant {
+    pub fn pool(&self, name: Option<&str>) -> Option<&Pool> {
+        self.pools.iter().find(|p| Some(p.name.as_str()) == name)
+    }
+
+    pub fn is_admin(&self, token: &str) -> bool {
+        self.admin_token.as_deref() == Some(token)
+    }
+
+    pub fn is_moderator(&self, token: &str) -> bool {
+        self.moderator_token.as_deref() == Some(token)
+    }
+}
+
+// Load tenants from json file, tenants must not share names, addresses or storage
+pub fn load_tenants(path: &str) -> Result<Vec<TenantConfig>> {
+    let json = std::fs::read_to_string(path).context(format!("read {:?} failed", path))?;
+    let tenants: Vec<TenantConfig> =
+        serde_json::from_str(&json).context(format!("parse {:?} failed", path))?;
+    check(&tenants)?;
+    Ok(tenants)
+}
+
+fn check(tenants: &[TenantConfig]) -> Result<()> {
+    if tenants.is_empty() {
+        bail!("no tenants");
+    }
+    for (i, tenant) in tenants.iter().enumerate() {
+        if tenant.name.is_empty() {
+            bail!("tenant without name");
+        }
+        for other in &tenants[..i] {
+            if other.name == tenant.name {
+                bail!("tenant \"{}\" is configured twice", tenant.name);
+            }
+            if other.addr == tenant.addr {
+                bail!(
+                    "tenants \"{}\" and \"{}\" share address {}",
+                    other.name,
+                    tenant.name,
+                    tenant.addr
+                );
+            }
+            if tenant.storage_dir.is_some() && other.storage_dir == tenant.storage_dir {
+                bail!(
+                    "tenants \"{}\" and \"{}\" share storage",
+                    other.name,
+                    tenant.name
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenants(json: &str) -> Vec<TenantConfig> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn tenants_kept_apart() {
+        let ok = tenants(
+            r#"[{"name": "club", "addr": "0.0.0.0:8080", "storage_dir": "club"},
+                {"name": "school", "addr": "0.0.0.0:8081", "admin_token": "secret"}]"#,
+        );
+        assert!(check(&ok).is_ok());
+        let school = ok[1].load(Fsync::Never).unwrap();
+        assert_eq!(school.name.as_deref(), Some("school"));
+        assert!(school.pool(Some(DEFAULT_POOL)).is_some());
+        assert!(school.storage.is_none());
+        assert!(school.is_admin("secret"));
+        assert!(!school.is_moderator("secret"));
+
+        assert!(check(&[]).is_err());
+        let same_addr = tenants(
+            r#"[{"name": "club", "addr": "0.0.0.0:8080"},
+                {"name": "school", "addr": "0.0.0.0:8080"}]"#,
+        );
+        assert!(check(&same_addr).is_err());
+        let same_storage = tenants(
+            r#"[{"name": "club", "addr": "0.0.0.0:8080", "storage_dir": "data"},
+                {"name": "school", "addr": "0.0.0.0:8081", "storage_dir": "data"}]"#,
+        );
+        assert!(check(&same_storage).is_err());
+        let same_name = tenants(
+            r#"[{"name": "club", "addr": "0.0.0.0:8080"},
+                {"name": "club", "addr": "0.0.0.0:8081"}]"#,
+        );
+        assert!(check(&same_name).is_err());
+    }
+}
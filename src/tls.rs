@@ -0,0 +1,38 @@
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+// Acceptor for wss:// from PEM encoded certificate chain and private key
+pub fn load_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor> {
+    let file = File::open(cert_path).context(format!("open {:?} failed", cert_path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .context(format!("parse {:?} failed", cert_path))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        bail!("no certificates in {:?}", cert_path);
+    }
+
+    let file = File::open(key_path).context(format!("open {:?} failed", key_path))?;
+    let key = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .context(format!("parse {:?} failed", key_path))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .context(format!("no private key in {:?}", key_path))?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("bad certificate or private key")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -0,0 +1,294 @@
+use crate::proto::{TournamentFormat, TournamentInfo, TournamentStanding, TournamentTable};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub const TABLE_SIZE: usize = 4;
+// points of the places at the table, players of the same place score the same
+const PLACE_POINTS: [u32; TABLE_SIZE] = [3, 2, 1, 0];
+// best places of the knockout table which go on to the next round, the final table has one winner
+const KNOCKOUT_ADVANCE: usize = 2;
+
+// Table of the round, players in turn order red, blue, yellow, green.
+// The round may be short of players, the empty seats are taken by bots.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Table {
+    pub players: Vec<String>,
+    pub game_id: Option<u64>,
+    // places of the players once the game is over, lower is better,
+    // none for the players who did not play
+    pub places: Option<Vec<Option<usize>>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Tournament {
+    pub id: u64,
+    pub name: String,
+    pub format: TournamentFormat,
+    // rounds of the swiss tournament, knockout plays on until the final table
+    pub rounds: u32,
+    // seconds on the clock
+    pub time_control: u64,
+    // unix seconds of the first round
+    pub starts_at: u64,
+    // seconds the seat of the disconnected player is held, 0 for tournaments stored before it
+    #[serde(default)]
+    pub reconnect_grace: u64,
+    // in the order of registration
+    pub players: Vec<String>,
+    // knocked out players
+    pub out: Vec<String>,
+    pub points: HashMap<String, u32>,
+    // current round, 0 before the first one
+    pub round: u32,
+    pub tables: Vec<Table>,
+    pub finished: bool,
+}
+
+impl Tournament {
+    pub fn register(&mut self, name: &str) -> Result<()> {
+        if self.round > 0 || self.finished {
+            bail!("the tournament has already started");
+        }
+        if !self.players.iter().any(|p| p == name) {
+            self.players.push(name.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn leave(&mut self, name: &str) -> Result<()> {
+        if self.round > 0 || self.finished {
+            bail!("the tournament has already started");
+        }
+        self.players.retain(|p| p != name);
+        Ok(())
+    }
+
+    fn points(&self, name: &str) -> u32 {
+        *self.points.get(name).unwrap_or(&0)
+    }
+
+    // Players still in seated by points, the leaders meet each other.
+    // Ties keep the order of registration.
+    pub fn start_round(&mut self) {
+        let mut players = self
+            .players
+            .iter()
+            .filter(|p| !self.out.contains(p))
+            .cloned()
+            .collect::<Vec<_>>();
+        players.sort_by_key(|p| std::cmp::Reverse(self.points(p)));
+        self.round += 1;
+        self.tables = players
+            .chunks(TABLE_SIZE)
+            .map(|players| Table {
+                players: players.to_vec(),
+                game_id: None,
+                places: None,
+            })
+            .collect();
+    }
+
+    pub fn round_over(&self) -> bool {
+        self.round > 0 && self.tables.iter().all(|t| t.places.is_some())
+    }
+
+    // Points of the finished round, knocked out players leave. Tournament is over after
+    // the last swiss round or the final table of the knockout.
+    pub fn finish_round(&mut self) {
+        let advance = match self.tables.len() {
+            0 | 1 => 1,
+            _ => KNOCKOUT_ADVANCE,
+        };
+        for table in &self.tables {
+            let places = table.places.clone().unwrap_or_default();
+            for (player, place) in table.players.iter().zip(places) {
+                let points = place.map_or(0, |place| PLACE_POINTS[place.min(TABLE_SIZE - 1)]);
+                *self.points.entry(player.clone()).or_insert(0) += points;
+                let advances = place.is_some_and(|place| place < advance);
+                if self.format == TournamentFormat::Knockout && !advances {
+                    self.out.push(player.clone());
+                }
+            }
+        }
+        self.finished = match self.format {
+            TournamentFormat::Swiss => self.round >= self.rounds,
+            TournamentFormat::Knockout => self.tables.len() <= 1,
+        };
+    }
+
+    pub fn standings(&self) -> Vec<TournamentStanding> {
+        let mut players = self
+            .players
+            .iter()
+            .map(|p| (self.out.contains(p), self.points(p), p))
+            .collect::<Vec<_>>();
+        players.sort_by_key(|(out, points, _)| (*out, std::cmp::Reverse(*points)));
+        players
+            .iter()
+            .map(|(out, points, player)| TournamentStanding {
+                place: players
+                    .iter()
+                    .filter(|(o, p, _)| {
+                        (*o, std::cmp::Reverse(*p)) < (*out, std::cmp::Reverse(*points))
+                    })
+                    .count() as u32
+                    + 1,
+                player: player.to_string(),
+                points: *points,
+                out: *out,
+            })
+            .collect()
+    }
+
+    pub fn info(&self) -> TournamentInfo {
+        TournamentInfo {
+            tournament_id: self.id,
+            name: self.name.clone(),
+            format: self.format,
+            rounds: self.rounds,
+            time_control: self.time_control,
+            starts_at: self.starts_at,
+            reconnect_grace: self.reconnect_grace,
+            players: self.players.clone(),
+            round: self.round,
+            tables: self
+                .tables
+                .iter()
+                .map(|t| TournamentTable {
+                    players: t.players.clone(),
+                    game_id: t.game_id,
+                    finished: t.places.is_some(),
+                })
+                .collect(),
+            standings: self.standings(),
+            finished: self.finished,
+        }
+    }
+}
+
+// Tournaments of the tenant, persisted by storage
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Tournaments {
+    tournaments: Vec<Tournament>,
+    next_id: u64,
+    // not yet persisted changes
+    #[serde(skip)]
+    changed: bool,
+}
+
+impl Tournaments {
+    pub fn create(
+        &mut self,
+        name: &str,
+        format: TournamentFormat,
+        rounds: u32,
+        time_control: u64,
+        starts_at: u64,
+        reconnect_grace: u64,
+    ) -> &Tournament {
+        self.next_id += 1;
+        self.tournaments.push(Tournament {
+            id: self.next_id,
+            name: name.to_string(),
+            format,
+            rounds,
+            time_control,
+            starts_at,
+            reconnect_grace,
+            players: Vec::new(),
+            out: Vec::new(),
+            points: HashMap::new(),
+            round: 0,
+            tables: Vec::new(),
+            finished: false,
+        });
+        self.changed = true;
+        self.tournaments.last().unwrap()
+    }
+    pub fn all(&self) -> &[Tournament] {
+        &self.tournaments
+    }
+    pub fn get(&self, id: u64) -> Option<&Tournament> {
+        self.tournaments.iter().find(|t| t.id == id)
+    }
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Tournament> {
+        self.changed = true;
+        self.tournaments.iter_mut().find(|t| t.id == id)
+    }
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
+    }
+    // saving failed, try again next time
+    pub fn mark_changed(&mut self) {
+        self.changed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tournament(format: TournamentFormat, players: usize) -> Tournament {
+        let mut tournaments = Tournaments::default();
+        let id = tournaments.create("open", format, 2, 60, 0, 600).id;
+        let tournament = tournaments.get_mut(id).unwrap();
+        for i in 0..players {
+            tournament.register(&format!("p{}", i)).unwrap();
+        }
+        tournament.clone()
+    }
+
+    #[test]
+    fn swiss_leaders_meet_in_the_next_round() {
+        let mut swiss = tournament(TournamentFormat::Swiss, 6);
+        swiss.start_round();
+        assert!(swiss.register("late").is_err());
+        assert_eq!(swiss.tables.len(), 2);
+        assert_eq!(swiss.tables[1].players, ["p4", "p5"]);
+        swiss.tables[0].places = Some(vec![Some(3), Some(2), Some(1), Some(0)]);
+        // p5 was not there
+        swiss.tables[1].places = Some(vec![Some(0), None]);
+        assert!(swiss.round_over());
+        swiss.finish_round();
+        assert!(!swiss.finished);
+
+        swiss.start_round();
+        assert_eq!(swiss.tables[0].players, ["p3", "p4", "p2", "p1"]);
+        assert_eq!(swiss.tables[1].players, ["p0", "p5"]);
+        let standings = swiss.standings();
+        assert_eq!(standings[0].points, 3);
+        assert_eq!(
+            (standings[1].place, standings[1].player.as_str()),
+            (1, "p4")
+        );
+        assert_eq!((standings[5].place, standings[5].points), (5, 0));
+
+        for table in swiss.tables.iter_mut() {
+            table.places = Some(vec![Some(0); table.players.len()]);
+        }
+        swiss.finish_round();
+        assert!(swiss.finished);
+    }
+
+    #[test]
+    fn knockout_ends_at_the_final_table() {
+        let mut knockout = tournament(TournamentFormat::Knockout, 8);
+        knockout.start_round();
+        knockout.tables[0].places = Some(vec![Some(0), Some(1), Some(2), Some(3)]);
+        knockout.tables[1].places = Some(vec![Some(3), None, Some(0), Some(0)]);
+        knockout.finish_round();
+        assert!(!knockout.finished);
+        assert_eq!(knockout.out, ["p2", "p3", "p4", "p5"]);
+
+        knockout.start_round();
+        assert_eq!(knockout.tables.len(), 1);
+        assert_eq!(knockout.tables[0].players, ["p0", "p6", "p7", "p1"]);
+        knockout.tables[0].places = Some(vec![Some(1), Some(0), Some(2), Some(3)]);
+        knockout.finish_round();
+        assert!(knockout.finished);
+        let standings = knockout.standings();
+        assert_eq!(standings[0].player, "p6");
+        assert!(standings.iter().skip(1).all(|s| s.out));
+    }
+}
@@ -1,20 +1,121 @@
-use crate::board::{Board, Column, Figure, Line, Position, Row, CASTLING_PATTERNS};
-use crate::proto::{Move, MoveError};
-use anyhow::{Context, Result};
-use futures::channel::mpsc::UnboundedSender;
+use crate::account::Accounts;
+use crate::board::{
+    castling_squares, zobrist, Board, CastlingPattern, CheckMate, Column, Fen4, Figure, Line,
+    Position, RawMove, Row, CASTLING_PATTERNS,
+};
+use crate::fairplay::FairPlay;
+use crate::filter::ChatFilter;
+use crate::flags::Flags;
+use crate::metrics::MetricsHistory;
+use crate::proto::{
+    self, Analysis, ChatKind, ChatMessage, GameSession, GameState, IllegalMoveReason, Move,
+    MoveEffects, MoveError, Pdu, ProtoVersion, RemainingPieces, ServerMeta, StateSync, Variant,
+};
+use crate::rating::Ratings;
+use crate::tenant::Tenant;
+use crate::tournament::Tournaments;
+use anyhow::{bail, Context, Result};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use enum_iterator::IntoEnumIterator;
+use futures::channel::mpsc::{channel, Sender, TrySendError, UnboundedSender};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fmt;
 use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{oneshot, watch};
+use tokio::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use tokio::task::JoinHandle;
 use tungstenite::protocol::Message;
 
-type Tx = UnboundedSender<Message>;
-type PeerMap = HashMap<SocketAddr, Arc<Mutex<Peer>>>;
-type GameMap = HashMap<u64, Arc<Mutex<Game>>>;
-type ReconnectMap = HashMap<String, Arc<Mutex<Game>>>;
+type ConnectionMap = DashMap<ConnectionId, Arc<Mutex<Peer>>>;
+// senders of the connected peers, replies do not lock the peer
+type SenderMap = DashMap<ConnectionId, Tx>;
+type SubscriberMap = HashMap<ConnectionId, Subscriber>;
+type GameMap = DashMap<u64, Arc<Mutex<Game>>>;
+// supervisor task of every game, the game is released before it ends
+type GameTaskMap = DashMap<u64, JoinHandle<()>>;
+type ReconnectMap = DashMap<String, Arc<Mutex<Game>>>;
+type LobbyMap = HashMap<String, PrivateLobby>;
+// connections logged in to the account by account id
+type PresenceMap = DashMap<u64, Vec<ConnectionId>>;
+type ChallengeMap = HashMap<u64, Challenge>;
+type RecordMap = HashMap<u64, GameSnapshot>;
+
+// sender half of the bounded peer channel, counts messages not yet taken to the socket
+#[derive(Clone)]
+pub struct Tx {
+    tx: Arc<std::sync::Mutex<Sender<Message>>>,
+    queued: Arc<AtomicUsize>,
+    // pdus are sent as MessagePack, set after the handshake answer is queued as json
+    msgpack: Arc<AtomicBool>,
+    // catalog locale of the error descriptions, english until the handshake
+    locale: Arc<AtomicUsize>,
+}
+
+impl Tx {
+    // Peer which does not read fast enough to keep the queue below the limit is closed,
+    // it gets the whole state again on reconnect. Pings are only dropped, the next one follows.
+    pub fn send(&self, message: Message) -> Result<(), TrySendError<Message>> {
+        let mut tx = self.tx.lock().unwrap();
+        let droppable = message.is_ping();
+        let message = proto::localize(message, self.locale.load(Ordering::Relaxed));
+        let message = match self.msgpack.load(Ordering::Relaxed) {
+            true => proto::to_msgpack(message),
+            false => message,
+        };
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        tx.try_send(message).inspect_err(|e| {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            if e.is_full() && !droppable {
+                tx.close_channel();
+            }
+        })
+    }
+    pub fn is_closed(&self) -> bool {
+        self.tx.lock().unwrap().is_closed()
+    }
+    // messages already queued are still delivered
+    pub fn close_channel(&self) {
+        self.tx.lock().unwrap().close_channel()
+    }
+    pub fn set_msgpack(&self, msgpack: bool) {
+        self.msgpack.store(msgpack, Ordering::Relaxed)
+    }
+    pub fn set_locale(&self, locale: usize) {
+        self.locale.store(locale, Ordering::Relaxed)
+    }
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+// at most limit messages wait for the socket
+pub fn peer_channel(limit: usize) -> (Tx, impl Stream<Item = Message>) {
+    // every sender has a guaranteed slot on top of the buffer
+    let (tx, rx) = channel(limit.saturating_sub(1));
+    let queued = Arc::new(AtomicUsize::new(0));
+    let taken = queued.clone();
+    let rx = rx.inspect(move |_| {
+        taken.fetch_sub(1, Ordering::Relaxed);
+    });
+    (
+        Tx {
+            tx: Arc::new(std::sync::Mutex::new(tx)),
+            queued,
+            msgpack: Arc::new(AtomicBool::new(false)),
+            locale: Arc::new(AtomicUsize::new(0)),
+        },
+        rx,
+    )
+}
 
 pub enum PeerState {
     Unknown(Instant),
@@ -26,9 +127,22 @@ pub enum PeerState {
         color: Color,
         game: Arc<Mutex<Game>>,
     },
+    // waiting in the private lobby with the code
+    Lobby(String),
 }
 
 impl PeerState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PeerState::Unknown(_) => "unknown",
+            PeerState::Idle => "idle",
+            PeerState::MMQueue => "mm_queue",
+            PeerState::HeartbeatWait(_) => "heartbeat_wait",
+            PeerState::HeartbeatReady(_) => "heartbeat_ready",
+            PeerState::Game { .. } => "game",
+            PeerState::Lobby(_) => "lobby",
+        }
+    }
     pub fn is_unknown(&self) -> bool {
         matches!(self, PeerState::Unknown(_))
     }
@@ -56,32 +170,199 @@ impl PeerState {
     pub fn is_game(&self) -> bool {
         matches!(self, PeerState::Game { .. })
     }
+    pub fn get_lobby_code(&self) -> Option<&str> {
+        match self {
+            PeerState::Lobby(code) => Some(code),
+            _ => None,
+        }
+    }
 }
 
 pub struct ClientInfo {
     pub name: String,
     pub version: String,
-    pub protocol: String,
+    // negotiated in the handshake
+    pub protocol: ProtoVersion,
+    pub pong_readiness: bool,
+    pub binary_snapshot: bool,
+    pub seat_labels: bool,
+    // language of the player, selects chat filter word lists
+    pub locale: Option<String>,
+}
+
+// account the peer logged in with
+pub struct AccountInfo {
+    pub id: u64,
+    pub name: String,
+    // accounts blocked by the player, kept in sync with the stored account
+    pub blocked: Vec<u64>,
+    // pools whose games the player keeps out of public history, kept in sync as well
+    pub private_pools: Vec<String>,
+}
+
+// Server assigned key of the connection, never reused.
+// Socket address is only metadata, proxies reuse ports.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ConnectionId(pub u64);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
 }
 
 pub struct Peer {
     pub tx: Tx,
+    // remote address of the connection
+    pub addr: SocketAddr,
     pub player_name: Option<String>,
     pub state: PeerState,
     pub client_info: Option<ClientInfo>,
+    // set when the game was aborted through no fault of the player
+    pub queue_priority_until: Option<Instant>,
+    // any message from the client including WebSocket pong
+    pub last_activity: Instant,
+    // last pdu of the client, idle peers without requests are closed
+    pub last_request: Instant,
+    // matchmaking pool the player registered into
+    pub pool: Option<String>,
+    // variant and seconds on the clock the player queued for
+    pub variant: Variant,
+    pub time_control: u64,
+    pub account: Option<AccountInfo>,
+    pub bucket: TokenBucket,
+    // when the player registered into the matchmaking queue
+    pub queued_at: Option<Instant>,
+    // merge group the player is matched in instead of the own pool
+    pub merged: Option<String>,
+    // waited too long, heartbeat checked for the game with bots
+    pub bot_fill: bool,
+    // chat messages dropped by the filter since the last mute
+    pub filter_strikes: u32,
+    pub muted_until: Option<Instant>,
+    // our last ping not answered yet
+    pub ping_sent: Option<Instant>,
+    // round trip of the last answered ping
+    pub rtt: Option<Duration>,
+}
+
+impl Peer {
+    // client answering pings recently is ready without HeartbeatCheck
+    pub fn is_pong_ready(&self, now: Instant, window: Duration) -> bool {
+        self.client_info.as_ref().is_some_and(|c| c.pong_readiness)
+            && now.duration_since(self.last_activity) <= window
+    }
+    // WebSocket ping which measures the round trip once answered
+    pub fn ping(&mut self) -> Result<(), TrySendError<Message>> {
+        self.tx.send(Message::Ping(Vec::new()))?;
+        self.ping_sent.get_or_insert_with(Instant::now);
+        Ok(())
+    }
+    pub fn pong(&mut self, now: Instant) {
+        if let Some(sent) = self.ping_sent.take() {
+            self.rtt = Some(now.duration_since(sent));
+        }
+    }
+    pub fn seat_labels(&self) -> bool {
+        self.client_info.as_ref().is_some_and(|c| c.seat_labels)
+    }
+    // the latest version until the handshake
+    pub fn protocol(&self) -> ProtoVersion {
+        self.client_info
+            .as_ref()
+            .map_or(ProtoVersion::LATEST, |c| c.protocol)
+    }
+    // players with the same key are matched together
+    pub fn match_pool(&self) -> (Option<String>, Option<String>, Variant, u64) {
+        match &self.merged {
+            Some(group) => (Some(group.clone()), None, self.variant, self.time_control),
+            None => (None, self.pool.clone(), self.variant, self.time_control),
+        }
+    }
+    // players may only play under the name of their account
+    pub fn owns_name(&self, name: &str) -> bool {
+        self.account.as_ref().is_some_and(|a| a.name == name)
+    }
+    pub fn has_blocked(&self, account_id: Option<u64>) -> bool {
+        match (&self.account, account_id) {
+            (Some(account), Some(id)) => account.blocked.contains(&id),
+            _ => false,
+        }
+    }
+    // either player blocked the other
+    pub fn blocks(&self, other: &Peer) -> bool {
+        self.has_blocked(other.account.as_ref().map(|a| a.id))
+            || other.has_blocked(self.account.as_ref().map(|a| a.id))
+    }
+    pub fn is_muted(&self, now: Instant) -> bool {
+        matches!(self.muted_until, Some(until) if until > now)
+    }
+    pub fn has_queue_priority(&self, now: Instant) -> bool {
+        matches!(self.queue_priority_until, Some(until) if until > now)
+    }
+}
+
+pub struct LobbyMember {
+    pub conn_id: ConnectionId,
+    pub peer: Arc<Mutex<Peer>>,
+    pub name: String,
+}
+
+// Table of four the challenger invited, players are account ids and names in seat order
+// with the challenger first
+pub struct Challenge {
+    pub players: Vec<(u64, String)>,
+    pub accepted: Vec<u64>,
+    pub created: Instant,
 }
 
+// members in order of joining, the creator plays red
+pub struct PrivateLobby {
+    pub members: Vec<LobbyMember>,
+    // position chosen by the creator, the game starts from the initial position if none
+    pub start: Option<Fen4>,
+}
+
+// Connections, games and reconnect ids are sharded maps, entries are cloned out of them
+// and never locked while a shard is held. Other locks are taken in the order
+// lobbies, game, peer and the rest last.
+// Several peers are locked at once only by the matchmaking and the full lobby.
+// Idle and queued players are not kept apart, the state of the peer is the only record of it.
+// Handlers which only read answer with reply, the peer is locked only to change it.
 pub struct Vault {
-    peers: Mutex<PeerMap>,
-    idle: Mutex<PeerMap>,
-    mm_queue: Mutex<PeerMap>,
-    hb_wait: Mutex<PeerMap>,
-    hb_ready: Mutex<PeerMap>,
-    games: Mutex<GameMap>,
-    reconnect: Mutex<ReconnectMap>,
+    tenant: Tenant,
+    peers: ConnectionMap,
+    senders: SenderMap,
+    games: GameMap,
+    game_tasks: GameTaskMap,
+    reconnect: ReconnectMap,
+    lobbies: Mutex<LobbyMap>,
+    presence: PresenceMap,
+    // players muted by an admin until the instant
+    global_mutes: DashMap<String, Instant>,
+    challenges: Mutex<ChallengeMap>,
+    server_meta: RwLock<Option<ServerMeta>>,
+    ratings: Mutex<Ratings>,
+    accounts: Mutex<Accounts>,
+    records: Mutex<GameRecords>,
+    tournaments: Mutex<Tournaments>,
+    fair_play: Mutex<FairPlay>,
+    chat_filter: Mutex<ChatFilter>,
+    metrics: Mutex<MetricsHistory>,
+    flags: Mutex<Flags>,
+    next_game_id: AtomicU64,
+    next_connection_id: AtomicU64,
+    next_challenge_id: AtomicU64,
+    dead_games: AtomicU64,
+    canary_passed: AtomicU64,
+    canary_failed: AtomicU64,
+    // players are accepted, the state is restored and the dispatchers run
+    listening: AtomicBool,
+    // unix seconds of the last matchmaking round, 0 before the first one
+    matchmaking_tick: AtomicU64,
 }
 
-#[derive(PartialEq, Clone, Copy, Debug)]
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum Color {
     Red,
     Green,
@@ -100,38 +381,599 @@ impl ToString for Color {
     }
 }
 
-#[derive(PartialEq, Clone)]
+#[derive(PartialEq, Clone, Serialize, Deserialize)]
 pub enum PlayerState {
     NoState,
     Check,
     Checkmate,
     Stalemate,
     Lost,
+    // game ended in a draw with the player still in it
+    Draw,
 }
 
 impl PlayerState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlayerState::NoState => "no_state",
+            PlayerState::Check => "check",
+            PlayerState::Checkmate => "checkmate",
+            PlayerState::Stalemate => "stalemate",
+            PlayerState::Lost => "lost",
+            PlayerState::Draw => "draw",
+        }
+    }
     /*pub fn is_idle(&self) -> bool {
         matches!(self, PlayerState::Idle)
     }*/
+    pub fn in_game(&self) -> bool {
+        !matches!(self, PlayerState::Lost | PlayerState::Draw)
+    }
 }
 
 pub struct Player {
+    pub name: String,
     pub color: Color,
     pub reconnect_id: String,
     pub time_remaining: Duration,
     pub state: PlayerState,
     pub peer: Arc<Mutex<Peer>>,
+    // turns in a row skipped without any input
+    pub afk_count: usize,
+    // moved by the server, the peer is a placeholder
+    pub bot: bool,
+    // scored in games of the points variant
+    pub points: u32,
 }
 
 pub struct Complete {
     pub mv: Move,
     pub at: tokio::time::Instant,
+    // handling of the move message, the broadcast of the turn is traced in it
+    pub span: tracing::Span,
 }
 
 pub struct WhoMove {
     pub color: Color,
     pub since: tokio::time::Instant,
     pub complete: Option<Complete>,
+    pub had_input: bool,
+}
+
+// what happens to the player who has no legal move and is not in check
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StalemateRule {
+    // player loses as if checkmated
+    Eliminate,
+    // player passes the turn and stays in the game
+    Skip,
+    // game ends, every remaining player draws
+    Draw,
+}
+
+impl FromStr for StalemateRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "eliminate" => Ok(StalemateRule::Eliminate),
+            "skip" => Ok(StalemateRule::Skip),
+            "draw" => Ok(StalemateRule::Draw),
+            _ => bail!("unknown stalemate rule \"{}\"", s),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum SubscriberKind {
+    Engine,
+    Spectator,
+}
+
+// messages published to the subscribers of a game which the slowest of them may fall behind,
+// it skips them and gets the whole state of the game instead
+const FANOUT_CAPACITY: usize = 64;
+
+// subscribers of the game the message is published to
+enum Audience {
+    All { except: Option<ConnectionId> },
+    Kind(SubscriberKind),
+    // the subscriber is disconnected after the message if close
+    One { conn_id: ConnectionId, close: bool },
+}
+
+// message published once for the subscribers of the game
+struct Published {
+    // published ones are counted by the game
+    seq: u64,
+    message: Message,
+    // for the downgraded spectators, they skip the message without it
+    lite: Option<Message>,
+    to: Audience,
+}
+
+// Publisher of the game, every subscriber receives from it at its own pace.
+// Latest state of the game is kept for the subscribers which fall behind.
+pub struct Fanout {
+    tx: broadcast::Sender<Arc<Published>>,
+    state: watch::Sender<Option<GameState>>,
+    published: Arc<AtomicU64>,
+}
+
+impl Default for Fanout {
+    fn default() -> Fanout {
+        Fanout {
+            tx: broadcast::channel(FANOUT_CAPACITY).0,
+            state: watch::channel(None).0,
+            published: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+// Subscriber forwards what the game publishes to its connection from its own task.
+// Once the subscriber is dropped the task forwards what was published before and stops.
+pub struct Subscriber {
+    pub tx: Tx,
+    pub kind: SubscriberKind,
+    // slow spectator receives clocks instead of full updates
+    clocks_only: Arc<AtomicBool>,
+    forward: JoinHandle<()>,
+    // gets the count of messages published until the subscriber left
+    left: Option<oneshot::Sender<u64>>,
+    published: Arc<AtomicU64>,
+}
+
+impl Subscriber {
+    pub fn clocks_only(&self) -> bool {
+        self.clocks_only.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        if let Some(left) = self.left.take() {
+            let _ = left.send(self.published.load(Ordering::Relaxed));
+        }
+    }
+}
+
+struct Forward {
+    conn_id: ConnectionId,
+    kind: SubscriberKind,
+    seat_labels: bool,
+    tx: Tx,
+    clocks_only: Arc<AtomicBool>,
+}
+
+impl Forward {
+    async fn run(
+        self,
+        mut rx: broadcast::Receiver<Arc<Published>>,
+        state: watch::Receiver<Option<GameState>>,
+        mut left: oneshot::Receiver<u64>,
+    ) {
+        loop {
+            let published = tokio::select! {
+                biased;
+                last = &mut left => {
+                    let last = last.unwrap_or(0);
+                    while let Ok(published) = rx.try_recv() {
+                        if published.seq > last || !self.deliver(&published) {
+                            break;
+                        }
+                    }
+                    return;
+                }
+                published = rx.recv() => published,
+            };
+            match published {
+                Ok(published) => {
+                    if !self.deliver(&published) {
+                        return;
+                    }
+                }
+                // skipped updates are replaced by the whole state, the next ones follow it
+                Err(RecvError::Lagged(_)) => {
+                    rx = rx.resubscribe();
+                    if !self.resync(&state) {
+                        return;
+                    }
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+
+    // false once the connection is gone or closed
+    fn deliver(&self, published: &Published) -> bool {
+        // only updates for everyone are downgraded
+        let (downgraded, close) = match published.to {
+            Audience::All { except } if except != Some(self.conn_id) => {
+                (self.clocks_only.load(Ordering::Relaxed), false)
+            }
+            Audience::Kind(kind) if kind == self.kind => (false, false),
+            Audience::One { conn_id, close } if conn_id == self.conn_id => (false, close),
+            _ => return true,
+        };
+        let message = match (downgraded, &published.lite) {
+            (false, _) => &published.message,
+            (true, Some(lite)) => lite,
+            (true, None) => return true,
+        };
+        if self.tx.send(message.clone()).is_err() {
+            return false;
+        }
+        if close {
+            self.tx.close_channel();
+        }
+        !close
+    }
+
+    // false if the connection is gone
+    fn resync(&self, state: &watch::Receiver<Option<GameState>>) -> bool {
+        let state = match state.borrow().clone() {
+            Some(state) if self.seat_labels => state.with_seats(),
+            Some(state) => state,
+            None => return true,
+        };
+        match Pdu::GameSession(GameSession::StateSync(StateSync::State(state))).to_message() {
+            Ok(message) => self.tx.send(message).is_ok(),
+            Err(_) => true,
+        }
+    }
+}
+
+// slowest spectators are downgraded or disconnected once a game has many of them,
+// queue depths are messages waiting to be written to the socket
+#[derive(Clone)]
+pub struct ShedPolicy {
+    pub min_spectators: usize,
+    pub downgrade_queued: usize,
+    pub disconnect_queued: usize,
+}
+
+// incoming messages of every peer, burst is the bucket size
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: f64,
+    // peer is disconnected after so many dropped messages before the bucket refills
+    pub disconnect_after: usize,
+}
+
+pub enum Admission {
+    Accept,
+    // the first dropped message of the flood is reported to the client
+    Drop { first: bool },
+    Disconnect,
+}
+
+pub struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+    dropped: usize,
+}
+
+impl TokenBucket {
+    pub fn new(limit: &RateLimit) -> TokenBucket {
+        TokenBucket {
+            tokens: limit.burst,
+            updated: Instant::now(),
+            dropped: 0,
+        }
+    }
+
+    pub fn admit(&mut self, limit: &RateLimit, now: Instant) -> Admission {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst);
+        self.updated = now;
+        // flood is over once the bucket is full again
+        if self.tokens >= limit.burst {
+            self.dropped = 0;
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Admission::Accept;
+        }
+        self.dropped += 1;
+        if self.dropped > limit.disconnect_after {
+            Admission::Disconnect
+        } else {
+            Admission::Drop {
+                first: self.dropped == 1,
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AfkPolicy {
+    // turn without any input is skipped after this time
+    pub turn_timeout: Duration,
+    pub warn_after: usize,
+    pub resign_after: usize,
+}
+
+// What happens to the seat of the disconnected player, tournaments hold it longer
+#[derive(Clone)]
+pub struct DisconnectPolicy {
+    // reconnect id stays valid this long after the disconnect, then the player has lost
+    pub grace: Duration,
+    // the clock of the disconnected player to move stops until the grace is over,
+    // otherwise his turns are skipped meanwhile
+    pub hold_seat: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PlayerSnapshot {
+    pub name: String,
+    pub reconnect_id: String,
+    pub time_remaining: Duration,
+    pub state: PlayerState,
+    #[serde(default)]
+    pub bot: bool,
+    #[serde(default)]
+    pub points: u32,
+}
+
+// piece removed from the board by capture
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Captured {
+    pub figure: Figure,
+    pub color: Color,
+    pub by: Color,
+}
+
+// applied move, record of the game event log
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MoveRecord {
+    pub color: Color,
+    pub mv: Move,
+    pub time_used: Duration,
+    #[serde(default)]
+    pub captured: Option<Figure>,
+    // players put in check by the move
+    pub checks: Vec<Color>,
+    // move matched the analysis engine best move, none if not evaluated
+    pub best: Option<bool>,
+    // moved piece, none in records made before it was stored
+    #[serde(default)]
+    pub figure: Option<Figure>,
+    // unix milliseconds when the move was applied
+    #[serde(default)]
+    pub at: u64,
+}
+
+impl MoveRecord {
+    pub fn effects(&self) -> MoveEffects {
+        MoveEffects {
+            captured: self.captured,
+            promoted: match &self.mv {
+                Move::Promotion { into, .. } => Some(*into),
+                _ => None,
+            },
+            checks: self.checks.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+}
+
+// game state at the beginning of a turn, used to recover a crashed game session
+// and persisted to restore active games after server restart
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GameSnapshot {
+    pub id: u64,
+    pub board: Board,
+    pub red: PlayerSnapshot,
+    pub green: PlayerSnapshot,
+    pub blue: PlayerSnapshot,
+    pub yellow: PlayerSnapshot,
+    pub who_move: Option<Color>,
+    #[serde(default)]
+    pub captured: Vec<Captured>,
+    #[serde(default)]
+    pub moves: Vec<MoveRecord>,
+    #[serde(default)]
+    pub eliminated: Vec<Color>,
+    #[serde(default)]
+    pub chat: Vec<ChatMessage>,
+    #[serde(default)]
+    pub chat_unrecorded: Vec<Color>,
+    // number of turns played, write-ahead log continues from it
+    #[serde(default)]
+    pub turn: u64,
+    // unix seconds, 0 for games started before it was stored
+    #[serde(default)]
+    pub started: u64,
+    #[serde(default)]
+    pub casual: bool,
+    #[serde(default)]
+    pub bot_level: u8,
+    #[serde(default)]
+    pub draw_odds: Option<Color>,
+    #[serde(default)]
+    pub pool: Option<String>,
+    #[serde(default)]
+    pub variant: Variant,
+    #[serde(default)]
+    pub features: Vec<String>,
+    // zero for games started before it was stored
+    #[serde(default)]
+    pub time_control: Duration,
+    #[serde(default)]
+    pub end: GameEnd,
+    #[serde(default)]
+    pub private: bool,
+    // FEN4 of the custom start position, none for the initial position
+    #[serde(default)]
+    pub start: Option<String>,
+    #[serde(default = "default_first_move")]
+    pub first_move: Color,
+    // reconnection window of the tournament game holding the seats, none for other games
+    #[serde(default)]
+    pub held_seat_grace: Option<Duration>,
+}
+
+fn default_first_move() -> Color {
+    Color::Red
+}
+
+// clock and state of the player after the turn
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PlayerTurn {
+    pub time_remaining: Duration,
+    pub state: PlayerState,
+    #[serde(default)]
+    pub points: u32,
+}
+
+// Whether the clock of the player to move runs, the survivors pause the game together
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum GamePause {
+    #[default]
+    Running,
+    Paused,
+    // counting down to go on, moves are still rejected
+    Resuming,
+}
+
+// How the finished game ended, the standings decide unless the survivors agreed to a draw
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GameEnd {
+    #[default]
+    Standings,
+    DrawAgreed,
+    // decided by material while players were timing out
+    Adjudicated,
+}
+
+// Changes of one turn, appended to the write-ahead log before the Update is broadcast.
+// Replayed on top of the snapshot of the previous turn.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TurnDelta {
+    pub turn: u64,
+    // none when the turn ended by timeout
+    pub mv: Option<MoveRecord>,
+    // players eliminated during the turn, in order
+    pub eliminated: Vec<Color>,
+    // red, green, blue and yellow
+    pub players: Vec<PlayerTurn>,
+    pub who_move: Option<Color>,
+    #[serde(default)]
+    pub end: GameEnd,
+}
+
+impl GameSnapshot {
+    pub fn player_color(&self, name: &str) -> Option<Color> {
+        [
+            (&self.red, Color::Red),
+            (&self.green, Color::Green),
+            (&self.blue, Color::Blue),
+            (&self.yellow, Color::Yellow),
+        ]
+        .iter()
+        .find(|(p, _)| p.name == name)
+        .map(|(_, color)| *color)
+    }
+}
+
+// redacted message keeps its place in the record without text
+pub fn redact_chat(chat: &mut [ChatMessage], message_id: u64) -> Option<ChatMessage> {
+    let message = chat.iter_mut().find(|m| m.id == message_id)?;
+    message.text = None;
+    message.redacted = true;
+    Some(message.clone())
+}
+
+// records of finished games for replays, persisted by storage
+#[derive(Default)]
+pub struct GameRecords {
+    records: RecordMap,
+    // ids of not yet persisted records
+    changed: Vec<u64>,
+}
+
+impl GameRecords {
+    pub fn insert(&mut self, record: GameSnapshot) {
+        self.changed.push(record.id);
+        self.records.insert(record.id, record);
+    }
+    pub fn get(&self, game_id: u64) -> Option<&GameSnapshot> {
+        self.records.get(&game_id)
+    }
+    pub fn get_mut(&mut self, game_id: u64) -> Option<&mut GameSnapshot> {
+        let record = self.records.get_mut(&game_id)?;
+        self.changed.push(game_id);
+        Some(record)
+    }
+    // records of games the player took part in, oldest first
+    pub fn of_player(&self, name: &str) -> Vec<&GameSnapshot> {
+        let mut records = self
+            .records
+            .values()
+            .filter(|r| r.player_color(name).is_some())
+            .collect::<Vec<_>>();
+        records.sort_by_key(|r| r.id);
+        records
+    }
+
+    // Replace the name of the player by alias and redact their chat, moves stay intact
+    pub fn anonymize(&mut self, name: &str, alias: &str) {
+        for record in self.records.values_mut() {
+            let color = match record.player_color(name) {
+                Some(color) => color,
+                None => continue,
+            };
+            for player in [
+                &mut record.red,
+                &mut record.green,
+                &mut record.blue,
+                &mut record.yellow,
+            ] {
+                if player.name == name {
+                    player.name = alias.to_string();
+                }
+            }
+            let ids = record
+                .chat
+                .iter()
+                .filter(|m| m.player == color.to_string())
+                .map(|m| m.id)
+                .collect::<Vec<_>>();
+            for id in ids {
+                redact_chat(&mut record.chat, id);
+            }
+            self.changed.push(record.id);
+        }
+    }
+    pub fn take_changed(&mut self) -> Vec<GameSnapshot> {
+        let mut ids = std::mem::take(&mut self.changed);
+        ids.sort_unstable();
+        ids.dedup();
+        ids.iter()
+            .filter_map(|id| self.records.get(id).cloned())
+            .collect()
+    }
+    pub fn mark_changed(&mut self, game_id: u64) {
+        self.changed.push(game_id);
+    }
+    pub fn values(&self) -> impl Iterator<Item = &GameSnapshot> {
+        self.records.values()
+    }
+}
+
+// points of the points variant
+const CHECKMATE_POINTS: u32 = 20;
+const SURVIVOR_POINTS: u32 = 20;
+
+fn capture_points(figure: Figure) -> u32 {
+    match figure {
+        Figure::Pawn => 1,
+        Figure::Knight => 3,
+        Figure::Bishop | Figure::Rook => 5,
+        Figure::Queen => 9,
+        Figure::King => 20,
+    }
 }
 
 pub struct Game {
@@ -143,6 +985,68 @@ pub struct Game {
     pub yellow: Player,
     pub who_move: Option<WhoMove>,
     pub move_happen_signal: UnboundedSender<()>,
+    // colors which allowed analysis engines to follow the game
+    pub analysis_consent: Vec<Color>,
+    // analysis engines and spectators receiving moves of the game, never the players
+    pub subscribers: SubscriberMap,
+    pub fanout: Fanout,
+    pub snapshot: Option<GameSnapshot>,
+    pub afk_policy: AfkPolicy,
+    pub disconnect_policy: DisconnectPolicy,
+    pub shed_policy: ShedPolicy,
+    pub stalemate_rule: StalemateRule,
+    pub remaining_pieces: RemainingPieces,
+    // in order of captures
+    pub captured: Vec<Captured>,
+    pub moves: Vec<MoveRecord>,
+    // lost players in order of elimination
+    pub eliminated: Vec<Color>,
+    // analysis engine suggestion for the current position
+    pub best_move: Option<Move>,
+    // recorded chat messages and reactions
+    pub chat: Vec<ChatMessage>,
+    // colors which turned chat recording off
+    pub chat_unrecorded: Vec<Color>,
+    // player who muted and the player muted by them, for this game only
+    pub chat_mutes: Vec<(Color, Color)>,
+    pub next_chat_id: u64,
+    // turns played, every turn ends with a snapshot
+    pub turn: u64,
+    // unix seconds
+    pub started: u64,
+    // not rated, players came from casual pools
+    pub casual: bool,
+    // strength of the engine playing the bots, built-in bots on 0
+    pub bot_level: u8,
+    // armageddon tiebreak, drawn results are the win of this player
+    pub draw_odds: Option<Color>,
+    // pool the players were matched in, none for lobby games
+    pub pool: Option<String>,
+    // kept out of public history, a player keeps games of the pool private
+    pub private: bool,
+    pub variant: Variant,
+    // feature flags enabled for the game at the start
+    pub features: Vec<String>,
+    // clock of the players at the start
+    pub time_control: Duration,
+    // ended by the operators, the session stops on the next wake up
+    pub terminated: bool,
+    // player who left before the first move, the others go back to their queue
+    pub aborted_by: Option<Color>,
+    pub end: GameEnd,
+    // survivors who agreed to end the game in a draw, votes lapse with every turn
+    pub draw_votes: Vec<Color>,
+    pub pause: GamePause,
+    // survivors who agreed to pause the running game or to resume the paused one
+    pub pause_votes: Vec<Color>,
+    // players who confirmed they are ready during the countdown, none once it is over
+    pub ready: Option<Vec<Color>>,
+    // players who consented to the offered adjudication, none unless it is offered
+    pub adjudication: Option<Vec<Color>>,
+    // FEN4 of the custom start position of lobby games, none for the initial position
+    pub start: Option<String>,
+    // player of the first turn, red unless the start position tells otherwise
+    pub first_move: Color,
 }
 
 impl Game {
@@ -182,18 +1086,15 @@ impl Game {
                 | PlayerState::Check
                 | PlayerState::Checkmate
                 | PlayerState::Stalemate => return Some(self.player_mut(color)),
-                PlayerState::Lost => continue,
+                PlayerState::Lost | PlayerState::Draw => continue,
             }
         }
         None
     }
 
     pub fn next_moved_player_mut(&mut self) -> Option<&mut Player> {
-        let no_lost_state_players_count = self
-            .players()
-            .iter()
-            .filter(|p| p.state != PlayerState::Lost)
-            .count();
+        let no_lost_state_players_count =
+            self.players().iter().filter(|p| p.state.in_game()).count();
 
         return match &self.who_move {
             Some(wm) => {
@@ -222,7 +1123,8 @@ impl Game {
             }
             None => {
                 if no_lost_state_players_count == 4 {
-                    Some(self.player_mut(&Color::Red))
+                    let first_move = self.first_move;
+                    Some(self.player_mut(&first_move))
                 } else {
                     None
                 }
@@ -230,128 +1132,957 @@ impl Game {
         };
     }
 
+    // players whose connection is gone or too slow are skipped, they get the state on reconnect
     pub async fn broadcast(&self, message: Message) -> Result<()> {
-        for player in self.players() {
-            player
-                .peer
-                .lock()
-                .await
-                .tx
-                .unbounded_send(message.clone())?
+        for player in self.players().into_iter().filter(|p| !p.bot) {
+            let _ = player.peer.lock().await.tx.send(message.clone());
         }
         Ok(())
     }
 
-    pub fn current_move_player(&self) -> Option<&Player> {
-        let color = self.who_move.as_ref()?.color.clone();
-        Some(self.player(&color))
+    pub fn take_snapshot(&mut self) {
+        self.snapshot = Some(self.record());
     }
 
-    pub fn current_move_player_mut(&mut self) -> Option<&mut Player> {
-        let color = self.who_move.as_ref()?.color.clone();
-        Some(self.player_mut(&color))
+    // Close the turn with the snapshot, returns what changed since the previous one
+    pub fn end_turn(&mut self) -> TurnDelta {
+        let (moves, eliminated) = self
+            .snapshot
+            .as_ref()
+            .map_or((0, 0), |s| (s.moves.len(), s.eliminated.len()));
+        self.turn += 1;
+        let delta = TurnDelta {
+            turn: self.turn,
+            mv: self.moves.get(moves).cloned(),
+            eliminated: self.eliminated[eliminated.min(self.eliminated.len())..].to_vec(),
+            players: self
+                .players()
+                .iter()
+                .map(|p| PlayerTurn {
+                    time_remaining: p.time_remaining,
+                    state: p.state.clone(),
+                    points: p.points,
+                })
+                .collect(),
+            who_move: self.who_move.as_ref().map(|wm| wm.color),
+            end: self.end,
+        };
+        self.draw_votes.clear();
+        self.pause_votes.clear();
+        self.take_snapshot();
+        delta
     }
 
-    pub fn validate_player_move(&self, mv: &Move, color: &Color) -> bool {
-        if let Some(wm) = &self.who_move {
-            if wm.color == *color {
-                return true;
+    // apply the logged turn after restart, the move is validated again
+    pub fn replay_turn(&mut self, delta: &TurnDelta) -> Result<()> {
+        if let Some(record) = &delta.mv {
+            self.who_move = Some(WhoMove {
+                color: record.color,
+                since: tokio::time::Instant::now(),
+                complete: None,
+                had_input: false,
+            });
+            if let Err(e) = self.apply_move(&record.mv) {
+                bail!("turn {} move not replayed {:?}", delta.turn, e);
             }
+            self.moves.push(record.clone());
         }
-        false
-    }
-
-    pub fn validate_move(&self, mv: &Move) -> Result<(), MoveError> {
+        for color in &delta.eliminated {
+            self.bury(*color);
+        }
+        for (player, turn) in self.players_mut().into_iter().zip(&delta.players) {
+            player.time_remaining = turn.time_remaining;
+            player.state = turn.state.clone();
+            player.points = turn.points;
+        }
+        self.who_move = delta.who_move.map(|color| WhoMove {
+            color,
+            since: tokio::time::Instant::now(),
+            complete: None,
+            had_input: false,
+        });
+        self.turn = delta.turn;
+        self.end = delta.end;
+        self.take_snapshot();
         Ok(())
     }
 
-    fn apply_castling(&mut self, rook_pos: Position) -> Result<(), MoveError> {
-        let rook = self.board.piece(rook_pos);
-        if rook.is_none() {
-            return Err(MoveError::ForbiddenMove {
-                description: "empty rook cell".to_string(),
-            });
+    // current state of the game, also kept as the record of the finished game
+    pub fn record(&self) -> GameSnapshot {
+        let player_snapshot = |p: &Player| PlayerSnapshot {
+            name: p.name.clone(),
+            reconnect_id: p.reconnect_id.clone(),
+            time_remaining: p.time_remaining,
+            state: p.state.clone(),
+            bot: p.bot,
+            points: p.points,
+        };
+        GameSnapshot {
+            id: self.id,
+            board: self.board.clone(),
+            red: player_snapshot(&self.red),
+            green: player_snapshot(&self.green),
+            blue: player_snapshot(&self.blue),
+            yellow: player_snapshot(&self.yellow),
+            who_move: self.who_move.as_ref().map(|wm| wm.color),
+            captured: self.captured.clone(),
+            moves: self.moves.clone(),
+            eliminated: self.eliminated.clone(),
+            chat: self.chat.clone(),
+            chat_unrecorded: self.chat_unrecorded.clone(),
+            turn: self.turn,
+            started: self.started,
+            casual: self.casual,
+            bot_level: self.bot_level,
+            draw_odds: self.draw_odds,
+            pool: self.pool.clone(),
+            variant: self.variant,
+            features: self.features.clone(),
+            time_control: self.time_control,
+            end: self.end,
+            private: self.private,
+            start: self.start.clone(),
+            first_move: self.first_move,
+            held_seat_grace: self
+                .disconnect_policy
+                .hold_seat
+                .then_some(self.disconnect_policy.grace),
         }
+    }
 
-        let rook = rook.unwrap();
-        if rook.already_move() {
-            return Err(MoveError::ForbiddenMove {
-                description: "rook already move".to_string(),
-            });
+    // Message to broadcast to players, recorded unless the sender turned recording off
+    pub fn post_chat(&mut self, color: Color, kind: ChatKind, text: &str) -> ChatMessage {
+        let message = ChatMessage {
+            id: self.next_chat_id,
+            player: color.to_string(),
+            move_number: self.moves.len(),
+            kind,
+            text: Some(text.to_string()),
+            redacted: false,
+        };
+        self.next_chat_id += 1;
+        if !self.chat_unrecorded.contains(&color) {
+            self.chat.push(message.clone());
         }
+        message
+    }
 
-        let king = self.board.find_king(rook.color);
-        if king.is_none() {
-            return Err(MoveError::ForbiddenMove {
-                description: "empty king cell".to_string(),
-            });
+    pub fn redact_chat(&mut self, message_id: u64) -> Option<ChatMessage> {
+        if let Some(snapshot) = &mut self.snapshot {
+            redact_chat(&mut snapshot.chat, message_id);
         }
+        redact_chat(&mut self.chat, message_id)
+    }
 
-        let (king, king_pos) = king.unwrap().piece_pos();
-        if king.already_move() {
-            return Err(MoveError::ForbiddenMove {
-                description: "king already move".to_string(),
-            });
+    // roll back to the last snapshot, the move in progress is lost
+    pub fn restore_snapshot(&mut self) -> bool {
+        let snapshot = match self.snapshot.clone() {
+            Some(snapshot) => snapshot,
+            None => return false,
+        };
+        self.board = snapshot.board;
+        self.captured = snapshot.captured;
+        self.moves = snapshot.moves;
+        self.eliminated = snapshot.eliminated;
+        self.chat_unrecorded = snapshot.chat_unrecorded;
+        // messages after the snapshot are lost, ids of the broadcast ones are not reused
+        self.next_chat_id = self
+            .next_chat_id
+            .max(snapshot.chat.last().map_or(0, |m| m.id + 1));
+        self.chat = snapshot.chat;
+        self.turn = snapshot.turn;
+        self.started = snapshot.started;
+        self.casual = snapshot.casual;
+        self.bot_level = snapshot.bot_level;
+        self.draw_odds = snapshot.draw_odds;
+        self.pool = snapshot.pool;
+        self.private = snapshot.private;
+        self.variant = snapshot.variant;
+        self.features = snapshot.features;
+        self.time_control = snapshot.time_control;
+        self.end = snapshot.end;
+        self.start = snapshot.start;
+        self.first_move = snapshot.first_move;
+        for (player, player_snapshot) in [
+            (&mut self.red, snapshot.red),
+            (&mut self.green, snapshot.green),
+            (&mut self.blue, snapshot.blue),
+            (&mut self.yellow, snapshot.yellow),
+        ] {
+            player.time_remaining = player_snapshot.time_remaining;
+            player.state = player_snapshot.state;
+            player.points = player_snapshot.points;
         }
+        self.who_move = snapshot.who_move.map(|color| WhoMove {
+            color,
+            since: tokio::time::Instant::now(),
+            complete: None,
+            had_input: false,
+        });
+        true
+    }
 
-        let castling_pattern = CASTLING_PATTERNS.get(&(rook_pos, king_pos)).unwrap();
+    // zobrist key of the board with the player of the turn, equal for repeated positions
+    pub fn position_key(&self) -> u64 {
+        let to_move = self
+            .who_move
+            .as_ref()
+            .map_or(0, |w| zobrist::to_move_key(w.color));
+        self.board.key() ^ to_move
+    }
 
-        if castling_pattern
-            .space_between
-            .iter()
-            .any(|pos| self.board.piece(*pos).is_some())
-        {
-            return Err(MoveError::ForbiddenMove {
-                description: "cells between rook and king not empty".to_string(),
-            });
+    pub fn fen4(&self) -> Fen4 {
+        Fen4 {
+            board: self.board.clone(),
+            to_move: self
+                .who_move
+                .as_ref()
+                .map_or(self.first_move, |wm| wm.color),
+            dead: self
+                .players()
+                .iter()
+                .filter(|p| p.state == PlayerState::Lost)
+                .map(|p| p.color)
+                .collect(),
+            points: [0; 4],
+            halfmove_clock: 0,
         }
+    }
 
-        let current_move_player = self.current_move_player().unwrap();
-        if current_move_player.state == PlayerState::Check {
-            return Err(MoveError::ForbiddenMove {
-                description: "player under check".to_string(),
-            });
+    // humans in game confirmed the start, bots are always ready
+    pub fn all_ready(&self) -> bool {
+        let ready = match &self.ready {
+            Some(ready) => ready,
+            None => return false,
+        };
+        self.players()
+            .iter()
+            .filter(|p| !p.bot && p.state.in_game())
+            .all(|p| ready.contains(&p.color))
+    }
+
+    pub fn mark_input(&mut self, color: &Color) {
+        if let Some(wm) = &mut self.who_move {
+            if wm.color == *color {
+                wm.had_input = true;
+            }
         }
+    }
 
-        let king_path_attackers = castling_pattern
-            .king_path
+    pub fn analysis_allowed(&self) -> bool {
+        self.players()
             .iter()
-            .map(|path_pos| self.board.attackers_on_position(*path_pos))
-            .filter(|attackers| attackers.is_some())
-            .map(|attackers| attackers.unwrap())
-            .flatten()
-            .filter(|attacker| attacker.piece().color != current_move_player.color);
-
-        if king_path_attackers.count() > 0 {
-            return Err(MoveError::ForbiddenMove {
-                description: "king castling path is under attack".to_string(),
-            });
-        }
+            .all(|p| self.analysis_consent.contains(&p.color))
+    }
 
-        self.board
-            .piece_move(rook_pos, castling_pattern.rook_end_pos);
-        self.board
-            .piece_move(rook_pos, castling_pattern.rook_end_pos);
-        Ok(())
+    // the connection receives what the game publishes from now on
+    pub fn subscribe(
+        &mut self,
+        conn_id: ConnectionId,
+        tx: Tx,
+        kind: SubscriberKind,
+        seat_labels: bool,
+    ) {
+        let clocks_only = Arc::new(AtomicBool::new(false));
+        let (left, left_rx) = oneshot::channel();
+        let forward = Forward {
+            conn_id,
+            kind,
+            seat_labels,
+            tx: tx.clone(),
+            clocks_only: clocks_only.clone(),
+        };
+        let forward = tokio::spawn(forward.run(
+            self.fanout.tx.subscribe(),
+            self.fanout.state.subscribe(),
+            left_rx,
+        ));
+        let subscriber = Subscriber {
+            tx,
+            kind,
+            clocks_only,
+            forward,
+            left: Some(left),
+            published: self.fanout.published.clone(),
+        };
+        self.subscribers.insert(conn_id, subscriber);
+    }
+
+    // state of the game the subscribers which fall behind get, kept up with every turn
+    pub fn set_state(&mut self, state: GameState) {
+        self.fanout.state.send_replace(Some(state));
+    }
+
+    // no receivers without subscribers
+    fn fan_out(&self, message: Message, lite: Option<Message>, to: Audience) {
+        let seq = self.fanout.published.fetch_add(1, Ordering::Relaxed) + 1;
+        let _ = self.fanout.tx.send(Arc::new(Published {
+            seq,
+            message,
+            lite,
+            to,
+        }));
+    }
+
+    // Publish the message once for every subscriber, downgraded spectators get lite message
+    // instead, nothing if it is none. Subscribers whose connection is gone are dropped.
+    pub async fn publish(
+        &mut self,
+        message: Message,
+        lite: Option<Message>,
+        except: Option<&ConnectionId>,
+    ) {
+        let except = except.copied();
+        self.fan_out(message, lite, Audience::All { except });
+        self.subscribers.retain(|_, s| !s.forward.is_finished());
+        self.shed_spectators().await;
+    }
+
+    // players latency first: slowest spectators are switched to clocks, then disconnected
+    async fn shed_spectators(&mut self) {
+        let policy = &self.shed_policy;
+        let mut spectators = Vec::new();
+        for (conn_id, subscriber) in self.subscribers.iter() {
+            if subscriber.kind == SubscriberKind::Spectator {
+                spectators.push((*conn_id, subscriber.tx.queued()));
+            }
+        }
+        if spectators.len() < policy.min_spectators {
+            return;
+        }
+        spectators.sort_by_key(|(_, queued)| std::cmp::Reverse(*queued));
+
+        let mut shed = Vec::new();
+        for (conn_id, queued) in spectators {
+            let downgraded = self.subscribers[&conn_id].clocks_only();
+            let (clocks_only, description) = if queued >= policy.disconnect_queued {
+                (false, "connection is too slow, disconnected")
+            } else if queued >= policy.downgrade_queued && !downgraded {
+                (true, "connection is too slow, switched to clocks only")
+            } else {
+                continue;
+            };
+            let notice = Pdu::Analysis(Analysis::Shed {
+                game_id: self.id,
+                clocks_only,
+                description: description.to_string(),
+            })
+            .to_message();
+            // updates still queued for the spectator go before the notice
+            let close = !clocks_only;
+            if let Ok(notice) = notice {
+                self.fan_out(notice, None, Audience::One { conn_id, close });
+            }
+            if clocks_only {
+                self.subscribers[&conn_id]
+                    .clocks_only
+                    .store(true, Ordering::Relaxed);
+            } else {
+                shed.push(conn_id);
+            }
+        }
+        for conn_id in shed {
+            self.subscribers.remove(&conn_id);
+        }
+    }
+
+    // analysis engines leave when consent is revoked, spectators stay
+    pub async fn drop_engines(&mut self, message: Message) {
+        self.fan_out(message, None, Audience::Kind(SubscriberKind::Engine));
+        self.subscribers
+            .retain(|_, s| s.kind != SubscriberKind::Engine);
+    }
+
+    // player is out of the game, his pieces are removed or turned to stone
+    pub fn eliminate(&mut self, color: Color) {
+        self.player_mut(&color).state = PlayerState::Lost;
+        self.bury(color);
+        self.update_players_states();
+    }
+
+    fn bury(&mut self, color: Color) {
+        self.eliminated.push(color);
+        match self.remaining_pieces {
+            RemainingPieces::Clear => self.board.clear(color),
+            RemainingPieces::TurnToStone => self.board.turn_to_stone(color),
+        }
+    }
+
+    // place of the player in the final standings, lower is better,
+    // players still in game or drawn share the first place
+    // in the points variant players with more points are placed higher
+    pub fn place(&self, color: Color) -> usize {
+        if self.variant == Variant::Points {
+            let points = self.player(&color).points;
+            return self.players().iter().filter(|p| p.points > points).count();
+        }
+        match self.eliminated.iter().position(|c| *c == color) {
+            Some(i) => self.eliminated.len() - i,
+            None if self.player(&color).state == PlayerState::Lost => self.eliminated.len() + 1,
+            None => 0,
+        }
+    }
+
+    // Recalculate check and checkmate of every player still in game,
+    // player whose king was captured loses immediately
+    pub fn update_players_states(&mut self) {
+        for color in [Color::Red, Color::Green, Color::Blue, Color::Yellow] {
+            if self.player(&color).state.in_game() && self.board.find_king(color).is_none() {
+                self.player_mut(&color).state = PlayerState::Lost;
+                self.bury(color);
+            }
+        }
+        for color in [Color::Red, Color::Green, Color::Blue, Color::Yellow] {
+            if !self.player(&color).state.in_game() {
+                continue;
+            }
+            let state = {
+                match self.board.is_checkmate(color) {
+                    CheckMate::No if self.board.is_stalemate(color) => PlayerState::Stalemate,
+                    CheckMate::No => PlayerState::NoState,
+                    CheckMate::Check => PlayerState::Check,
+                    CheckMate::Checkmate => PlayerState::Checkmate,
+                }
+            };
+            self.player_mut(&color).state = state;
+        }
+    }
+
+    // game ends, players still in it share the draw
+    pub fn declare_draw(&mut self) {
+        // armageddon is never drawn, the others lose from the weakest one by material
+        if let Some(odds) = self.draw_odds.filter(|c| self.player(c).state.in_game()) {
+            let mut others = self
+                .players()
+                .iter()
+                .filter(|p| p.color != odds && p.state.in_game())
+                .map(|p| (crate::bot::material(self, p.color), p.color))
+                .collect::<Vec<_>>();
+            others.sort_by_key(|(material, _)| *material);
+            for (_, color) in others {
+                self.eliminate(color);
+            }
+            return;
+        }
+        for player in self.players_mut() {
+            if player.state.in_game() {
+                player.state = PlayerState::Draw;
+            }
+        }
+    }
+
+    // Survivors agreed to the draw. In the points variant they split the points still to be
+    // captured evenly, those of their pieces on the board, the remainder is dropped.
+    pub fn agree_draw(&mut self) {
+        let survivors = self
+            .players()
+            .iter()
+            .filter(|p| p.state.in_game())
+            .map(|p| p.color)
+            .collect::<Vec<_>>();
+        // armageddon is decided by the draw odds
+        if self.draw_odds.is_none() && !survivors.is_empty() {
+            let remaining: u32 = Position::into_enum_iter()
+                .filter_map(|pos| self.board.piece(pos))
+                .filter(|piece| !piece.stone && survivors.contains(&piece.color))
+                .map(|piece| capture_points(piece.figure))
+                .sum();
+            let share = remaining / survivors.len() as u32;
+            for color in survivors {
+                self.award(color, share);
+            }
+        }
+        self.declare_draw();
+    }
+
+    // Decide the game by material, players are eliminated from the weakest one,
+    // the strongest one wins or the equally strong share the draw
+    pub fn adjudicate(&mut self) {
+        let mut standing = self
+            .players()
+            .iter()
+            .filter(|p| p.state.in_game())
+            .map(|p| (crate::bot::material(self, p.color), p.color))
+            .collect::<Vec<_>>();
+        standing.sort_by_key(|(material, _)| *material);
+        let best = standing.last().map_or(0, |(material, _)| *material);
+        for (material, color) in standing {
+            if material < best {
+                self.eliminate(color);
+            }
+        }
+        if self.players().iter().filter(|p| p.state.in_game()).count() > 1 {
+            self.declare_draw();
+        }
+    }
+
+    pub fn current_move_player(&self) -> Option<&Player> {
+        let color = self.who_move.as_ref()?.color.clone();
+        Some(self.player(&color))
+    }
+
+    pub fn current_move_player_mut(&mut self) -> Option<&mut Player> {
+        let color = self.who_move.as_ref()?.color.clone();
+        Some(self.player_mut(&color))
+    }
+
+    pub fn validate_player_move(&self, mv: &Move, color: &Color) -> bool {
+        if let Some(wm) = &self.who_move {
+            if wm.color == *color {
+                return true;
+            }
+        }
+        false
+    }
+
+    // check the move of the current player without changing the board
+    pub fn validate_move(&mut self, mv: &Move) -> Result<(), MoveError> {
+        let color = self.current_move_color()?;
+        match mv {
+            Move::Basic { from, to } => self.check_step(color, *from, *to, false, false),
+            Move::Capture { from, to } => self.check_step(color, *from, *to, true, false),
+            Move::Promotion { from, to, into } => self.check_promotion(color, *from, *to, *into),
+            _ => Ok(()),
+        }
+    }
+
+    fn current_move_color(&self) -> Result<Color, MoveError> {
+        match &self.who_move {
+            Some(wm) => Ok(wm.color),
+            None => Err(illegal(IllegalMoveReason::NotYourTurn, "nobody moves now")),
+        }
     }
 
-    fn apply_capture(&self, from: Position, to: Position) -> Result<(), MoveError> {
+    // plain step to the empty cell or capture of enemy piece,
+    // pawn reaching promotion line has to be promoted
+    fn check_step(
+        &mut self,
+        color: Color,
+        from: Position,
+        to: Position,
+        capture: bool,
+        promotion: bool,
+    ) -> Result<(), MoveError> {
+        let piece = match self.board.piece(from) {
+            None => return Err(illegal(IllegalMoveReason::EmptyCell, "empty from cell")),
+            Some(piece) if piece.color != color => {
+                return Err(illegal(
+                    IllegalMoveReason::PieceOfAnotherPlayer,
+                    "piece of another player",
+                ))
+            }
+            Some(piece) => piece,
+        };
+        let is_king = piece.figure.is(Figure::King);
+        let is_pawn = piece.figure.is(Figure::Pawn);
+        let promotes = is_pawn && piece.promotion_line().is_some_and(|line| line.contains(to));
+        if promotion && !is_pawn {
+            return Err(illegal(
+                IllegalMoveReason::NotPromotable,
+                "only pawn can be promoted",
+            ));
+        }
+        if promotion && !promotes {
+            return Err(illegal(
+                IllegalMoveReason::PromotionLineNotReached,
+                "pawn does not reach promotion line",
+            ));
+        }
+        if !promotion && promotes {
+            return Err(illegal(
+                IllegalMoveReason::PromotionRequired,
+                "pawn reaching promotion line must be promoted",
+            ));
+        }
+        match self.board.piece(to) {
+            Some(piece) if !capture && piece.color == color => {
+                return Err(illegal(
+                    IllegalMoveReason::DestinationOccupiedByOwnPiece,
+                    "destination occupied by own piece",
+                ))
+            }
+            Some(_) if !capture => {
+                return Err(illegal(
+                    IllegalMoveReason::DestinationOccupied,
+                    "destination cell is not empty",
+                ))
+            }
+            None if capture => {
+                return Err(illegal(
+                    IllegalMoveReason::NothingToCapture,
+                    "nothing to capture",
+                ))
+            }
+            Some(piece) if piece.color == color => {
+                return Err(illegal(
+                    IllegalMoveReason::DestinationOccupiedByOwnPiece,
+                    "capture of own piece",
+                ))
+            }
+            Some(piece) if piece.stone => {
+                return Err(illegal(
+                    IllegalMoveReason::StoneCapture,
+                    "stone can not be captured",
+                ))
+            }
+            _ => (),
+        }
+        if !self.board.is_reachable(from, to) {
+            return Err(illegal(
+                IllegalMoveReason::Unreachable,
+                "piece can not reach destination cell",
+            ));
+        }
+
+        let in_check = self.board.is_check(color);
+        self.board.restorable_piece_move(from, to);
+        let checker = self.board.checkers(color).first().map(|checker| {
+            let (piece, position) = checker.piece_pos();
+            format!(
+                "{} {} at {:?}",
+                piece.color.to_string(),
+                format!("{:?}", piece.figure).to_lowercase(),
+                position
+            )
+        });
+        self.board.restore_move();
+        if let Some(checker) = checker {
+            return Err(if in_check {
+                illegal(
+                    IllegalMoveReason::KingRemainsInCheck,
+                    format!("king would remain in check from {}", checker),
+                )
+            } else if is_king {
+                illegal(
+                    IllegalMoveReason::KingIntoCheck,
+                    format!("king would be in check from {}", checker),
+                )
+            } else {
+                illegal(
+                    IllegalMoveReason::PinnedPiece,
+                    format!("pinned piece, king would be in check from {}", checker),
+                )
+            });
+        }
         Ok(())
     }
 
-    pub fn apply_move(&mut self, mv: &Move) -> Result<(), MoveError> {
+    fn check_promotion(
+        &mut self,
+        color: Color,
+        from: Position,
+        to: Position,
+        into: Figure,
+    ) -> Result<(), MoveError> {
+        if !into.is_promotion() {
+            return Err(illegal(
+                IllegalMoveReason::BadPromotionFigure,
+                format!("pawn can not be promoted into {:?}", into),
+            ));
+        }
+        let capture = self.board.piece(to).is_some();
+        self.check_step(color, from, to, capture, true)
+    }
+
+    // move the piece, remember the captured one
+    fn step(&mut self, color: Color, from: Position, to: Position) -> Option<Figure> {
+        let piece = self.board.play(from, to)?;
+        self.captured.push(Captured {
+            figure: piece.figure,
+            color: piece.color,
+            by: color,
+        });
+        self.award(color, capture_points(piece.figure));
+        Some(piece.figure)
+    }
+
+    fn award(&mut self, color: Color, points: u32) {
+        if self.variant == Variant::Points {
+            self.player_mut(&color).points += points;
+        }
+    }
+
+    // the player of the turn mated the one found checkmated
+    pub fn award_checkmate(&mut self, mated: Color) {
+        match &self.who_move {
+            Some(wm) if wm.color != mated => self.award(wm.color, CHECKMATE_POINTS),
+            _ => (),
+        }
+    }
+
+    // the game is over and only one player is left in it
+    pub fn award_survivor(&mut self) {
+        let survivors = self
+            .players()
+            .iter()
+            .filter(|p| p.state.in_game())
+            .map(|p| p.color)
+            .collect::<Vec<_>>();
+        if let [survivor] = survivors[..] {
+            self.award(survivor, SURVIVOR_POINTS);
+        }
+    }
+
+    fn apply_basic(&mut self, from: Position, to: Position) -> Result<Option<Figure>, MoveError> {
+        let color = self.current_move_color()?;
+        self.check_step(color, from, to, false, false)?;
+        Ok(self.step(color, from, to))
+    }
+
+    fn apply_capture(&mut self, from: Position, to: Position) -> Result<Option<Figure>, MoveError> {
+        let color = self.current_move_color()?;
+        self.check_step(color, from, to, true, false)?;
+        Ok(self.step(color, from, to))
+    }
+
+    // promotion captures implicitly when the destination is occupied
+    fn apply_promotion(
+        &mut self,
+        from: Position,
+        to: Position,
+        into: Figure,
+    ) -> Result<Option<Figure>, MoveError> {
+        let color = self.current_move_color()?;
+        self.check_promotion(color, from, to, into)?;
+        let captured = self.step(color, from, to);
+        self.board.promote(to, into);
+        Ok(captured)
+    }
+
+    // called after the move applied and players states updated
+    pub fn record_move(&mut self, mv: Move, captured: Option<Figure>, at: tokio::time::Instant) {
+        let (color, since) = match &self.who_move {
+            Some(wm) => (wm.color, wm.since),
+            None => return,
+        };
+        let checks = self
+            .players()
+            .iter()
+            .filter(|p| p.color != color)
+            .filter(|p| matches!(p.state, PlayerState::Check | PlayerState::Checkmate))
+            .map(|p| p.color)
+            .collect();
+        let best = self.best_move.take().map(|best| best == mv);
+        let figure = match &mv {
+            Move::Basic { to, .. } | Move::Capture { to, .. } => {
+                self.board.piece(*to).map(|p| p.figure)
+            }
+            Move::Promotion { .. } => Some(Figure::Pawn),
+            Move::Castling { .. } => Some(Figure::King),
+            Move::NoMove {} | Move::Ok { .. } | Move::Error(_) => None,
+        };
+        self.moves.push(MoveRecord {
+            color,
+            mv,
+            time_used: at.duration_since(since),
+            captured,
+            checks,
+            best,
+            figure,
+            at: SystemTime::now()
+                .checked_sub(at.elapsed())
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_millis() as u64),
+        });
+    }
+
+    // pieces captured by color
+    pub fn captures_by(&self, color: Color) -> Vec<&Captured> {
+        self.captured.iter().filter(|c| c.by == color).collect()
+    }
+
+    fn check_castling(
+        &mut self,
+        color: Color,
+        rook_pos: Position,
+    ) -> Result<&'static CastlingPattern, MoveError> {
+        let rook = self.board.piece(rook_pos);
+        if rook.is_none() {
+            return Err(illegal(IllegalMoveReason::NoRook, "empty rook cell"));
+        }
+
+        let rook = rook.unwrap();
+        if rook.color != color {
+            return Err(illegal(
+                IllegalMoveReason::PieceOfAnotherPlayer,
+                "rook of another player",
+            ));
+        }
+        if rook.already_move() {
+            return Err(illegal(
+                IllegalMoveReason::RookAlreadyMoved,
+                "rook already move",
+            ));
+        }
+
+        let king = self.board.find_king(rook.color);
+        if king.is_none() {
+            return Err(illegal(IllegalMoveReason::NoKing, "empty king cell"));
+        }
+
+        let (king, king_pos) = king.unwrap().piece_pos();
+        if king.already_move() {
+            return Err(illegal(
+                IllegalMoveReason::KingAlreadyMoved,
+                "king already move",
+            ));
+        }
+
+        let castling_pattern = match CASTLING_PATTERNS.get(&(rook_pos, king_pos)) {
+            Some(pattern) => pattern,
+            None => {
+                return Err(illegal(
+                    IllegalMoveReason::NoRook,
+                    "no castling with the rook on this cell",
+                ))
+            }
+        };
+
+        if castling_pattern
+            .space_between
+            .iter()
+            .any(|pos| self.board.piece(*pos).is_some())
+        {
+            return Err(illegal(
+                IllegalMoveReason::CastlingBlocked,
+                "cells between rook and king not empty",
+            ));
+        }
+
+        if self.board.is_check(color) {
+            return Err(illegal(
+                IllegalMoveReason::CastlingUnderCheck,
+                "player under check",
+            ));
+        }
+
+        // the king is moved over the path, it no longer covers the lines through its cell
+        let mut from = king_pos;
+        let mut moved = 0;
+        let mut path_attacked = false;
+        for path_pos in castling_pattern.king_path.iter() {
+            self.board.restorable_piece_move(from, *path_pos);
+            moved += 1;
+            from = *path_pos;
+            if self.board.is_check(color) {
+                path_attacked = true;
+                break;
+            }
+        }
+        for _ in 0..moved {
+            self.board.restore_move();
+        }
+        if !path_attacked {
+            // the rook leaves its cell as well, lines it covered are open at the end
+            self.board.restorable_piece_moves(&[
+                (rook_pos, castling_pattern.rook_end_pos),
+                (king_pos, castling_pattern.king_end_pos),
+            ]);
+            path_attacked = self.board.is_check(color);
+            self.board.restore_move();
+        }
+        if path_attacked {
+            return Err(illegal(
+                IllegalMoveReason::CastlingPathAttacked,
+                "king castling path is under attack",
+            ));
+        }
+        Ok(castling_pattern)
+    }
+
+    fn apply_castling(&mut self, rook_pos: Position) -> Result<Option<Figure>, MoveError> {
+        let color = self.current_move_color()?;
+        let castling_pattern = self.check_castling(color, rook_pos)?;
+        let king_pos = self
+            .board
+            .find_king(color)
+            .map(|king| king.position())
+            .ok_or_else(|| illegal(IllegalMoveReason::NoKing, "empty king cell"))?;
+        self.board
+            .piece_move(rook_pos, castling_pattern.rook_end_pos);
+        self.board
+            .piece_move(king_pos, castling_pattern.king_end_pos);
+        self.board.set_en_passant(color, None);
+        Ok(None)
+    }
+
+    // Moves of the color's piece on from passing the same checks as the made move,
+    // promotion is listed for every figure, castling as the move of the king
+    pub fn legal_moves(&mut self, color: Color, from: Position) -> Vec<Move> {
+        let is_king = match self.board.piece(from) {
+            Some(piece) if piece.color == color => piece.figure.is(Figure::King),
+            _ => return Vec::new(),
+        };
+        let steps = self.board.legal_moves_from(from);
+        let mut moves = self.moves_of_steps(steps);
+        if is_king {
+            moves.extend(self.castlings(color));
+        }
+        moves
+    }
+
+    // legal moves of all pieces of the color, as legal_moves
+    pub fn all_legal_moves(&mut self, color: Color) -> Vec<Move> {
+        let steps = self.board.legal_moves(color);
+        let mut moves = self.moves_of_steps(steps);
+        moves.extend(self.castlings(color));
+        moves
+    }
+
+    fn moves_of_steps(&self, steps: Vec<RawMove>) -> Vec<Move> {
+        let mut moves = Vec::new();
+        for RawMove { from, to } in steps {
+            let promotes = self.board.piece(from).is_some_and(|piece| {
+                piece.figure.is(Figure::Pawn)
+                    && piece.promotion_line().is_some_and(|line| line.contains(to))
+            });
+            if promotes {
+                for into in [Figure::Queen, Figure::Rook, Figure::Bishop, Figure::Knight] {
+                    moves.push(Move::Promotion { from, to, into });
+                }
+            } else if self.board.piece(to).is_some() {
+                moves.push(Move::Capture { from, to });
+            } else {
+                moves.push(Move::Basic { from, to });
+            }
+        }
+        moves
+    }
+
+    fn castlings(&mut self, color: Color) -> Vec<Move> {
+        let squares = castling_squares(color);
+        [squares.king_side_rook, squares.queen_side_rook]
+            .iter()
+            .filter(|rook| self.check_castling(color, **rook).is_ok())
+            .map(|rook| Move::Castling { rook: *rook })
+            .collect()
+    }
+
+    // returns the captured figure
+    pub fn apply_move(&mut self, mv: &Move) -> Result<Option<Figure>, MoveError> {
         match mv {
-            Move::Basic { from, to } => {}
+            Move::Basic { from, to } => {
+                return self.apply_basic(*from, *to);
+            }
             Move::Capture { from, to } => {
                 return self.apply_capture(*from, *to);
             }
             Move::Castling { rook } => {
                 return self.apply_castling(*rook);
             }
-            Move::Promotion { from, to, into } => {}
-            Move::NoMove {} | Move::Error(_) => (),
+            Move::Promotion { from, to, into } => {
+                return self.apply_promotion(*from, *to, *into);
+            }
+            Move::NoMove {} | Move::Ok { .. } | Move::Error(_) => (),
         }
-        Ok(())
+        Ok(None)
+    }
+}
+
+fn illegal(reason: IllegalMoveReason, description: impl Into<String>) -> MoveError {
+    MoveError::IllegalMove {
+        reason,
+        description: description.into(),
     }
 }
 
@@ -361,54 +2092,785 @@ impl Game {
 
 impl<'a> Vault {
     pub fn new() -> Vault {
+        Vault::with_tenant(Tenant::default())
+    }
+    // state of the tenant is kept apart from the other tenants of the server
+    pub fn with_tenant(tenant: Tenant) -> Vault {
         Vault {
-            peers: Mutex::new(PeerMap::new()),
-            idle: Mutex::new(PeerMap::new()),
-            mm_queue: Mutex::new(PeerMap::new()),
-            hb_wait: Mutex::new(PeerMap::new()),
-            hb_ready: Mutex::new(PeerMap::new()),
-            games: Mutex::new(GameMap::new()),
-            reconnect: Mutex::new(ReconnectMap::new()),
-        }
-    }
-    pub async fn try_insert_peer(&self, sock_addr: SocketAddr, peer: Peer) -> Result<(), ()> {
-        let mut peers = self.peers.lock().await;
-        match peers.contains_key(&sock_addr) {
-            true => Err(()),
-            false => {
-                peers.insert(sock_addr, Arc::new(Mutex::new(peer)));
+            tenant,
+            peers: ConnectionMap::new(),
+            senders: SenderMap::new(),
+            games: GameMap::new(),
+            game_tasks: GameTaskMap::new(),
+            reconnect: ReconnectMap::new(),
+            lobbies: Mutex::new(LobbyMap::new()),
+            presence: PresenceMap::new(),
+            global_mutes: DashMap::new(),
+            challenges: Mutex::new(ChallengeMap::new()),
+            server_meta: RwLock::new(None),
+            ratings: Mutex::new(Ratings::default()),
+            accounts: Mutex::new(Accounts::default()),
+            records: Mutex::new(GameRecords::default()),
+            tournaments: Mutex::new(Tournaments::default()),
+            fair_play: Mutex::new(FairPlay::default()),
+            chat_filter: Mutex::new(ChatFilter::default()),
+            metrics: Mutex::new(MetricsHistory::default()),
+            flags: Mutex::new(Flags::default()),
+            next_game_id: AtomicU64::new(0),
+            next_connection_id: AtomicU64::new(0),
+            next_challenge_id: AtomicU64::new(0),
+            dead_games: AtomicU64::new(0),
+            canary_passed: AtomicU64::new(0),
+            canary_failed: AtomicU64::new(0),
+            listening: AtomicBool::new(false),
+            matchmaking_tick: AtomicU64::new(0),
+        }
+    }
+    pub fn tenant(&self) -> &Tenant {
+        &self.tenant
+    }
+    // connection ids are never reused
+    pub fn next_connection_id(&self) -> ConnectionId {
+        ConnectionId(self.next_connection_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn try_insert_peer(&self, conn_id: ConnectionId, peer: Peer) -> Result<(), ()> {
+        match self.peers.entry(conn_id) {
+            Entry::Occupied(_) => Err(()),
+            Entry::Vacant(entry) => {
+                self.senders.insert(conn_id, peer.tx.clone());
+                entry.insert(Arc::new(Mutex::new(peer)));
                 Ok(())
             }
         }
     }
 
-    pub async fn remove_peer(&self, sock_addr: &SocketAddr) {
-        let mut peers = self.peers.lock().await;
-        if let Some(peer) = peers.remove(sock_addr) {
-            // change state to Unknown, gc will clean it later
-            peer.lock().await.state = PeerState::Unknown(Instant::now())
+    // pongs keep the connection alive, requests keep the idle peer too
+    pub async fn touch_peer(&self, conn_id: &ConnectionId, request: bool, pong: bool) {
+        if let Some(peer) = self.get_peer(conn_id) {
+            let mut peer_lock = peer.lock().await;
+            peer_lock.last_activity = Instant::now();
+            if request {
+                peer_lock.last_request = peer_lock.last_activity;
+            }
+            if pong {
+                let now = peer_lock.last_activity;
+                peer_lock.pong(now);
+            }
+        }
+    }
+
+    pub async fn remove_peer(&self, conn_id: &ConnectionId) {
+        self.senders.remove(conn_id);
+        if let Some((_, peer)) = self.peers.remove(conn_id) {
+            let mut peer_lock = peer.lock().await;
+            // games keep the peer, Unknown tells since when the player is disconnected
+            peer_lock.state = PeerState::Unknown(Instant::now());
+            if let Some(account) = &peer_lock.account {
+                self.set_offline(account.id, conn_id);
+            }
         }
     }
 
-    pub async fn get_peers(&'a self) -> MutexGuard<'a, PeerMap> {
-        self.peers.lock().await
+    // connection logged in to the account
+    pub fn set_online(&self, account_id: u64, conn_id: ConnectionId) {
+        let mut connections = self.presence.entry(account_id).or_default();
+        if !connections.contains(&conn_id) {
+            connections.push(conn_id);
+        }
+    }
+    // connection closed or logged out of the account
+    pub fn set_offline(&self, account_id: u64, conn_id: &ConnectionId) {
+        if let Entry::Occupied(mut entry) = self.presence.entry(account_id) {
+            entry.get_mut().retain(|c| c != conn_id);
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        }
     }
-    pub async fn get_idle(&'a self) -> MutexGuard<'a, PeerMap> {
-        self.idle.lock().await
+    // connections logged in to the account at the moment
+    pub fn connections_of(&self, account_id: u64) -> Vec<ConnectionId> {
+        self.presence
+            .get(&account_id)
+            .map_or_else(Vec::new, |connections| connections.clone())
     }
-    pub async fn get_mm_queue(&'a self) -> MutexGuard<'a, PeerMap> {
-        self.mm_queue.lock().await
+    pub fn is_online(&self, account_id: u64) -> bool {
+        self.presence.contains_key(&account_id)
     }
-    pub async fn get_hb_wait(&'a self) -> MutexGuard<'a, PeerMap> {
-        self.hb_wait.lock().await
+
+    // chat of the player is dropped until the instant, none lifts the mute
+    pub fn mute(&self, name: &str, until: Option<Instant>) {
+        match until {
+            Some(until) => {
+                self.global_mutes.insert(name.to_string(), until);
+            }
+            None => {
+                self.global_mutes.remove(name);
+            }
+        }
+    }
+    pub fn is_muted(&self, name: &str, now: Instant) -> bool {
+        self.global_mutes
+            .get(name)
+            .is_some_and(|until| now < *until)
+    }
+
+    pub fn get_peer(&self, conn_id: &ConnectionId) -> Option<Arc<Mutex<Peer>>> {
+        self.peers.get(conn_id).map(|peer| peer.clone())
+    }
+    // send to the connected peer without locking it
+    pub fn reply(&self, conn_id: &ConnectionId, message: Message) -> Result<()> {
+        let tx = self
+            .senders
+            .get(conn_id)
+            .map(|tx| tx.clone())
+            .context(format!("get({}) from peer_map failed", conn_id))?;
+        tx.send(message)?;
+        Ok(())
+    }
+    // connected peers at the moment, peers may be locked while iterating over them
+    pub fn get_peers(&self) -> Vec<(ConnectionId, Arc<Mutex<Peer>>)> {
+        self.peers
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect()
+    }
+    pub fn peer_count(&self) -> usize {
+        self.peers.len()
+    }
+    // connected peers by the name of their state, every peer is counted once
+    pub async fn count_states(&self) -> HashMap<&'static str, usize> {
+        let mut counts = HashMap::new();
+        for (_, peer) in self.get_peers() {
+            *counts.entry(peer.lock().await.state.name()).or_insert(0) += 1;
+        }
+        counts
     }
-    pub async fn get_hb_ready(&'a self) -> MutexGuard<'a, PeerMap> {
-        self.hb_ready.lock().await
+    pub fn get_game(&self, game_id: u64) -> Option<Arc<Mutex<Game>>> {
+        self.games.get(&game_id).map(|game| game.clone())
+    }
+    // active games at the moment, games may be locked while iterating over them
+    pub fn get_games(&self) -> Vec<Arc<Mutex<Game>>> {
+        self.games.iter().map(|game| game.clone()).collect()
+    }
+    pub fn insert_game(&self, game_id: u64, game: Arc<Mutex<Game>>) {
+        self.games.insert(game_id, game);
+    }
+    pub fn remove_game(&self, game_id: u64) -> Option<Arc<Mutex<Game>>> {
+        self.game_tasks.remove(&game_id);
+        self.games.remove(&game_id).map(|(_, game)| game)
+    }
+    pub fn insert_game_task(&self, game_id: u64, task: JoinHandle<()>) {
+        self.game_tasks.insert(game_id, task);
+    }
+    // games still kept whose supervisor ended without releasing them
+    pub fn dead_games(&self) -> Vec<u64> {
+        // supervisor of the game released before it was inserted
+        self.game_tasks
+            .retain(|game_id, task| !task.is_finished() || self.games.contains_key(game_id));
+        self.game_tasks
+            .iter()
+            .filter(|task| task.value().is_finished() && self.games.contains_key(task.key()))
+            .map(|task| *task.key())
+            .collect()
+    }
+    pub fn count_dead_game(&self) {
+        self.dead_games.fetch_add(1, Ordering::Relaxed);
+    }
+    // games reaped since the previous call
+    pub fn take_dead_game_count(&self) -> u64 {
+        self.dead_games.swap(0, Ordering::Relaxed)
+    }
+    pub fn set_listening(&self) {
+        self.listening.store(true, Ordering::Relaxed)
+    }
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::Relaxed)
+    }
+    pub fn matchmaking_ticked(&self, now: u64) {
+        self.matchmaking_tick.store(now, Ordering::Relaxed)
+    }
+    pub fn matchmaking_tick(&self) -> u64 {
+        self.matchmaking_tick.load(Ordering::Relaxed)
+    }
+
+    pub fn count_canary_probe(&self, passed: bool) {
+        match passed {
+            true => self.canary_passed.fetch_add(1, Ordering::Relaxed),
+            false => self.canary_failed.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+    // passed and failed canary probes since the previous call
+    pub fn take_canary_counts(&self) -> (u64, u64) {
+        (
+            self.canary_passed.swap(0, Ordering::Relaxed),
+            self.canary_failed.swap(0, Ordering::Relaxed),
+        )
+    }
+    pub fn get_reconnect(&self, reconnect_id: &str) -> Option<Arc<Mutex<Game>>> {
+        self.reconnect.get(reconnect_id).map(|game| game.clone())
+    }
+    pub fn insert_reconnect(&self, reconnect_id: String, game: Arc<Mutex<Game>>) {
+        self.reconnect.insert(reconnect_id, game);
+    }
+    pub fn get_reconnects(&self) -> Vec<(String, Arc<Mutex<Game>>)> {
+        self.reconnect
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
     }
-    pub async fn get_games(&'a self) -> MutexGuard<'a, GameMap> {
-        self.games.lock().await
+    pub fn remove_reconnect(&self, reconnect_id: &str) {
+        self.reconnect.remove(reconnect_id);
     }
-    pub async fn get_reconnect(&'a self) -> MutexGuard<'a, ReconnectMap> {
-        self.reconnect.lock().await
+    pub fn reconnect_count(&self) -> usize {
+        self.reconnect.len()
+    }
+    pub async fn get_lobbies(&'a self) -> MutexGuard<'a, LobbyMap> {
+        self.lobbies.lock().await
+    }
+    // locked before peers like lobbies
+    pub async fn get_challenges(&'a self) -> MutexGuard<'a, ChallengeMap> {
+        self.challenges.lock().await
+    }
+    pub async fn get_flags(&'a self) -> MutexGuard<'a, Flags> {
+        self.flags.lock().await
+    }
+    pub async fn get_server_meta(&'a self) -> RwLockReadGuard<'a, Option<ServerMeta>> {
+        self.server_meta.read().await
+    }
+    pub async fn set_server_meta(&'a self) -> RwLockWriteGuard<'a, Option<ServerMeta>> {
+        self.server_meta.write().await
+    }
+    pub async fn get_ratings(&'a self) -> MutexGuard<'a, Ratings> {
+        self.ratings.lock().await
+    }
+    pub async fn get_accounts(&'a self) -> MutexGuard<'a, Accounts> {
+        self.accounts.lock().await
+    }
+    pub async fn get_records(&'a self) -> MutexGuard<'a, GameRecords> {
+        self.records.lock().await
+    }
+    pub async fn get_tournaments(&'a self) -> MutexGuard<'a, Tournaments> {
+        self.tournaments.lock().await
+    }
+    pub async fn get_fair_play(&'a self) -> MutexGuard<'a, FairPlay> {
+        self.fair_play.lock().await
+    }
+    pub async fn get_chat_filter(&'a self) -> MutexGuard<'a, ChatFilter> {
+        self.chat_filter.lock().await
+    }
+    pub async fn get_metrics(&'a self) -> MutexGuard<'a, MetricsHistory> {
+        self.metrics.lock().await
+    }
+    pub fn next_game_id(&self) -> u64 {
+        self.next_game_id.fetch_add(1, Ordering::Relaxed)
+    }
+    pub fn next_challenge_id(&self) -> u64 {
+        self.next_challenge_id.fetch_add(1, Ordering::Relaxed)
+    }
+    pub fn set_next_game_id(&self, game_id: u64) {
+        self.next_game_id.store(game_id, Ordering::Relaxed)
+    }
+}
+
+// Games of placeholder players outside of any session, for tests and benchmarks
+#[cfg(any(test, feature = "fixtures"))]
+pub(crate) mod fixtures {
+    use super::*;
+
+    fn player(color: Color) -> Player {
+        let (tx, _) = peer_channel(16);
+        Player {
+            name: color.to_string(),
+            color,
+            reconnect_id: String::new(),
+            time_remaining: Duration::from_secs(60),
+            state: PlayerState::NoState,
+            points: 0,
+            peer: Arc::new(Mutex::new(Peer {
+                tx,
+                addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+                player_name: None,
+                state: PeerState::Idle,
+                client_info: None,
+                queue_priority_until: None,
+                last_activity: Instant::now(),
+                last_request: Instant::now(),
+                pool: None,
+                variant: Variant::Ffa,
+                time_control: 60,
+                account: None,
+                bucket: TokenBucket::new(&RateLimit {
+                    per_second: 1.0,
+                    burst: 1.0,
+                    disconnect_after: 1,
+                }),
+                queued_at: None,
+                merged: None,
+                bot_fill: false,
+                filter_strikes: 0,
+                muted_until: None,
+                ping_sent: None,
+                rtt: None,
+            })),
+            afk_count: 0,
+            bot: false,
+        }
+    }
+
+    // red moves first on the given board
+    pub fn game(board: Board) -> Game {
+        Game {
+            id: 0,
+            board,
+            red: player(Color::Red),
+            green: player(Color::Green),
+            blue: player(Color::Blue),
+            yellow: player(Color::Yellow),
+            who_move: Some(WhoMove {
+                color: Color::Red,
+                since: tokio::time::Instant::now(),
+                complete: None,
+                had_input: false,
+            }),
+            move_happen_signal: futures::channel::mpsc::unbounded().0,
+            analysis_consent: Vec::new(),
+            subscribers: SubscriberMap::new(),
+            fanout: Fanout::default(),
+            snapshot: None,
+            afk_policy: AfkPolicy {
+                turn_timeout: Duration::from_secs(30),
+                warn_after: 2,
+                resign_after: 3,
+            },
+            disconnect_policy: DisconnectPolicy {
+                grace: Duration::from_secs(600),
+                hold_seat: false,
+            },
+            shed_policy: ShedPolicy {
+                min_spectators: 16,
+                downgrade_queued: 32,
+                disconnect_queued: 128,
+            },
+            stalemate_rule: StalemateRule::Eliminate,
+            remaining_pieces: RemainingPieces::Clear,
+            captured: Vec::new(),
+            moves: Vec::new(),
+            eliminated: Vec::new(),
+            best_move: None,
+            chat: Vec::new(),
+            chat_unrecorded: Vec::new(),
+            chat_mutes: Vec::new(),
+            next_chat_id: 0,
+            turn: 0,
+            started: 0,
+            casual: false,
+            bot_level: 0,
+            draw_odds: None,
+            pool: None,
+            private: false,
+            variant: Variant::Ffa,
+            features: Vec::new(),
+            time_control: Duration::from_secs(60),
+            terminated: false,
+            aborted_by: None,
+            end: GameEnd::Standings,
+            draw_votes: Vec::new(),
+            pause: GamePause::Running,
+            pause_votes: Vec::new(),
+            ready: None,
+            adjudication: None,
+            start: None,
+            first_move: Color::Red,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::game;
+    use super::*;
+
+    // the same steps the game session does with the complete move
+    fn play(game: &mut Game, mv: Move) -> MoveEffects {
+        let captured = game.apply_move(&mv).unwrap();
+        game.update_players_states();
+        game.record_move(mv, captured, tokio::time::Instant::now());
+        game.moves.last().unwrap().effects()
+    }
+
+    #[test]
+    fn promotion_with_capture() {
+        let mut board = Board::new();
+        board.piece_move(Position::e2, Position::e7);
+        board.piece_move(Position::f13, Position::f8);
+        let mut game = game(board);
+
+        let effects = play(
+            &mut game,
+            Move::Promotion {
+                from: Position::e7,
+                to: Position::f8,
+                into: Figure::Knight,
+            },
+        );
+        assert_eq!(effects.captured, Some(Figure::Pawn));
+        assert_eq!(effects.promoted, Some(Figure::Knight));
+        assert!(effects.checks.is_empty());
+        let piece = game.board.piece(Position::f8).unwrap();
+        assert_eq!((piece.figure, piece.color), (Figure::Knight, Color::Red));
+        assert_eq!(game.captures_by(Color::Red).len(), 1);
+        assert!(matches!(
+            game.captured[..],
+            [Captured {
+                color: Color::Yellow,
+                figure: Figure::Pawn,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn capture_with_check() {
+        let mut board = Board::new();
+        board.piece_move(Position::d1, Position::d8);
+        let mut game = game(board);
+
+        let effects = play(
+            &mut game,
+            Move::Capture {
+                from: Position::d8,
+                to: Position::b8,
+            },
+        );
+        assert_eq!(effects.captured, Some(Figure::Pawn));
+        assert_eq!(effects.promoted, None);
+        assert_eq!(effects.checks, vec![Color::Blue.to_string()]);
+        assert!(game.player(&Color::Blue).state == PlayerState::Check);
+    }
+
+    #[test]
+    fn promotion_with_capture_and_double_check() {
+        let mut board = Board::new();
+        board.piece_move(Position::e2, Position::l7);
+        board.piece_move(Position::b8, Position::c5);
+        let mut game = game(board);
+
+        let effects = play(
+            &mut game,
+            Move::Promotion {
+                from: Position::l7,
+                to: Position::m8,
+                into: Figure::Queen,
+            },
+        );
+        assert_eq!(effects.captured, Some(Figure::Pawn));
+        assert_eq!(effects.promoted, Some(Figure::Queen));
+        assert_eq!(
+            effects.checks,
+            vec![Color::Green.to_string(), Color::Blue.to_string()]
+        );
+        let record = game.moves.last().unwrap();
+        assert_eq!(record.checks, vec![Color::Green, Color::Blue]);
+        assert_eq!(record.captured, Some(Figure::Pawn));
+    }
+
+    #[test]
+    fn legal_moves_of_pinned_and_promoting_pieces() {
+        let mut board = Board::new();
+        // blue queen pins the red pawn on i2 to the king on h1
+        board.piece_move(Position::a7, Position::k4);
+        board.piece_move(Position::e2, Position::e7);
+        let mut game = game(board);
+
+        assert!(game.legal_moves(Color::Red, Position::i2).is_empty());
+        assert!(game.legal_moves(Color::Blue, Position::e7).is_empty());
+        let moves = game.legal_moves(Color::Red, Position::e7);
+        assert_eq!(moves.len(), 4);
+        assert!(moves.contains(&Move::Promotion {
+            from: Position::e7,
+            to: Position::e8,
+            into: Figure::Knight,
+        }));
+        let moves = game.legal_moves(Color::Red, Position::e1);
+        assert_eq!(moves.len(), 2);
+        assert!(moves.contains(&Move::Basic {
+            from: Position::e1,
+            to: Position::f3,
+        }));
+    }
+
+    #[test]
+    fn adjudication_eliminates_the_weakest_first() {
+        let mut board = Board::new();
+        // yellow lost the queen, blue a knight, red and green are equal
+        board.piece_move(Position::e2, Position::h14);
+        board.piece_move(Position::f2, Position::a5);
+        let mut game = game(board);
+
+        game.adjudicate();
+        assert_eq!(game.eliminated, vec![Color::Yellow, Color::Blue]);
+        assert_eq!(game.place(Color::Yellow), 2);
+        assert_eq!(game.place(Color::Blue), 1);
+        assert!(game.red.state == PlayerState::Draw && game.green.state == PlayerState::Draw);
+    }
+
+    #[test]
+    fn armageddon_draw_is_won_by_draw_odds() {
+        let mut board = Board::new();
+        // blue lost a knight
+        board.piece_move(Position::f2, Position::a5);
+        let mut game = game(board);
+        game.draw_odds = Some(Color::Yellow);
+
+        game.declare_draw();
+        assert_eq!(game.eliminated[0], Color::Blue);
+        assert_eq!(game.eliminated.len(), 3);
+        assert_eq!(game.place(Color::Yellow), 0);
+    }
+
+    #[test]
+    fn castling_moves_king_and_rook() {
+        let mut board = Board::new();
+        for pos in [Position::e1, Position::f1, Position::g1] {
+            board.piece_move(pos, Position::h7);
+        }
+        let mut castled = game(board.clone());
+        castled
+            .apply_move(&Move::Castling { rook: Position::d1 })
+            .unwrap();
+        let king = castled.board.piece(Position::f1).unwrap();
+        assert!(king.figure.is(Figure::King) && king.already_move());
+        let rook = castled.board.piece(Position::g1).unwrap();
+        assert!(rook.figure.is(Figure::Rook) && rook.already_move());
+        assert!(
+            castled.board.piece(Position::d1).is_none()
+                && castled.board.piece(Position::h1).is_none()
+        );
+        assert!(!castled.board.castling_rights(Color::Red).queen_side);
+
+        // yellow rook covers f1 the king passes
+        board.piece_move(Position::f2, Position::h8);
+        board.piece_move(Position::k14, Position::f9);
+        let mut blocked = game(board);
+        assert!(matches!(
+            blocked.apply_move(&Move::Castling { rook: Position::d1 }),
+            Err(MoveError::IllegalMove {
+                reason: IllegalMoveReason::CastlingPathAttacked,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn points_variant_scores_and_places_by_points() {
+        let mut board = Board::new();
+        board.piece_move(Position::a5, Position::f3);
+        let mut game = game(board);
+        game.variant = Variant::Points;
+
+        game.apply_move(&Move::Capture {
+            from: Position::e2,
+            to: Position::f3,
+        })
+        .unwrap();
+        assert_eq!(game.red.points, 3);
+        game.award_checkmate(Color::Blue);
+        game.award_checkmate(Color::Red);
+        assert_eq!(game.red.points, 23);
+
+        game.blue.points = 30;
+        for color in [Color::Blue, Color::Yellow, Color::Green] {
+            game.player_mut(&color).state = PlayerState::Lost;
+        }
+        game.award_survivor();
+        assert_eq!(game.red.points, 43);
+        assert_eq!(game.place(Color::Red), 0);
+        assert_eq!(game.place(Color::Blue), 1);
+        assert_eq!(game.place(Color::Green), 2);
+
+        game.variant = Variant::Ffa;
+        assert_eq!(game.place(Color::Blue), 1);
+    }
+
+    #[test]
+    fn composite_effects_serialization() {
+        let effects = MoveEffects {
+            captured: Some(Figure::Pawn),
+            promoted: Some(Figure::Queen),
+            checks: vec![Color::Green.to_string(), Color::Blue.to_string()],
+        };
+        let json = serde_json::to_string(&effects).unwrap();
+        assert_eq!(
+            json,
+            r#"{"captured":"Pawn","promoted":"Queen","checks":["Green","Blue"]}"#
+        );
+        let plain: MoveEffects = serde_json::from_str("{}").unwrap();
+        assert_eq!(plain, MoveEffects::default());
+    }
+
+    #[test]
+    fn unrecorded_and_redacted_chat() {
+        let mut game = game(Board::new());
+        game.chat_unrecorded.push(Color::Blue);
+        let hello = game.post_chat(Color::Red, ChatKind::Text, "hello");
+        let secret = game.post_chat(Color::Blue, ChatKind::Text, "secret");
+        game.post_chat(Color::Green, ChatKind::Reaction, "gg");
+        assert_ne!(hello.id, secret.id);
+        assert_eq!(game.chat.len(), 2);
+        assert!(game
+            .chat
+            .iter()
+            .all(|m| m.player != Color::Blue.to_string()));
+
+        game.take_snapshot();
+        let redacted = game.redact_chat(hello.id).unwrap();
+        assert!(redacted.redacted && redacted.text.is_none());
+        let snapshot_message = &game.snapshot.as_ref().unwrap().chat[0];
+        assert!(snapshot_message.redacted && snapshot_message.text.is_none());
+        assert!(game.redact_chat(secret.id).is_none());
+    }
+
+    #[test]
+    fn anonymized_record_keeps_moves() {
+        let mut game = game(Board::new());
+        play(
+            &mut game,
+            Move::Basic {
+                from: Position::e2,
+                to: Position::e4,
+            },
+        );
+        game.red.name = "alice".to_string();
+        game.post_chat(Color::Red, ChatKind::Text, "my phone number");
+        game.post_chat(Color::Blue, ChatKind::Text, "hi");
+        let mut records = GameRecords::default();
+        records.insert(game.record());
+        records.take_changed();
+
+        records.anonymize("alice", "deleted-0");
+        assert!(records.of_player("alice").is_empty());
+        let record = records.get(game.id).unwrap();
+        assert_eq!(record.red.name, "deleted-0");
+        assert_eq!(record.moves.len(), 1);
+        assert!(record.chat[0].redacted && record.chat[0].text.is_none());
+        assert_eq!(record.chat[1].text.as_deref(), Some("hi"));
+        assert_eq!(records.take_changed().len(), 1);
+    }
+
+    #[test]
+    fn logged_turns_replay_onto_snapshot() {
+        let mut live = game(Board::new());
+        live.take_snapshot();
+        let start = live.record();
+        play(
+            &mut live,
+            Move::Basic {
+                from: Position::e2,
+                to: Position::e4,
+            },
+        );
+        live.eliminate(Color::Green);
+        live.who_move.as_mut().unwrap().color = Color::Blue;
+        live.blue.time_remaining = Duration::from_secs(42);
+        let first = live.end_turn();
+        // timed out turn without a move
+        let second = live.end_turn();
+        assert!(second.mv.is_none() && second.eliminated.is_empty());
+
+        let mut restored = game(Board::new());
+        restored.snapshot = Some(start);
+        restored.restore_snapshot();
+        restored.replay_turn(&first).unwrap();
+        restored.replay_turn(&second).unwrap();
+        assert_eq!(restored.fen4().checksum(), live.fen4().checksum());
+        assert_eq!(restored.moves.len(), 1);
+        assert_eq!(restored.eliminated, vec![Color::Green]);
+        assert_eq!(restored.blue.time_remaining, Duration::from_secs(42));
+        assert_eq!(restored.turn, 2);
+        assert_eq!(restored.snapshot.as_ref().unwrap().turn, 2);
+    }
+
+    #[test]
+    fn token_bucket_drops_then_disconnects_flood() {
+        let limit = RateLimit {
+            per_second: 10.0,
+            burst: 3.0,
+            disconnect_after: 2,
+        };
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(&limit);
+        for _ in 0..3 {
+            assert!(matches!(bucket.admit(&limit, start), Admission::Accept));
+        }
+        assert!(matches!(
+            bucket.admit(&limit, start),
+            Admission::Drop { first: true }
+        ));
+        // one token refilled after 100ms
+        let later = start + Duration::from_millis(100);
+        assert!(matches!(bucket.admit(&limit, later), Admission::Accept));
+        assert!(matches!(
+            bucket.admit(&limit, later),
+            Admission::Drop { first: false }
+        ));
+        assert!(matches!(bucket.admit(&limit, later), Admission::Disconnect));
+        // calm client starts over once the bucket is full
+        let calm = later + Duration::from_secs(1);
+        assert!(matches!(bucket.admit(&limit, calm), Admission::Accept));
+        assert!(matches!(bucket.admit(&limit, calm), Admission::Accept));
+    }
+
+    #[test]
+    fn full_peer_queue_closes_slow_peer() {
+        let (tx, _rx) = peer_channel(2);
+        assert!(tx.send(Message::Text("a".to_string())).is_ok());
+        assert!(tx.send(Message::Text("b".to_string())).is_ok());
+        assert!(tx.send(Message::Ping(Vec::new())).is_err());
+        assert!(!tx.is_closed());
+        assert!(tx.send(Message::Text("c".to_string())).is_err());
+        assert!(tx.is_closed());
+        assert_eq!(tx.queued(), 2);
+    }
+
+    #[tokio::test]
+    async fn published_once_for_every_spectator() {
+        let mut game = game(Board::new());
+        let mut spectators = Vec::new();
+        for id in 0..20 {
+            let (tx, rx) = peer_channel(8);
+            game.subscribe(ConnectionId(id), tx, SubscriberKind::Spectator, false);
+            spectators.push(Box::pin(rx));
+        }
+        let text = |t: &str| Message::Text(t.to_string());
+        game.publish(text("update"), None, Some(&ConnectionId(0)))
+            .await;
+        game.publish(text("clocks"), None, None).await;
+        for (id, rx) in spectators.iter_mut().enumerate() {
+            if id > 0 {
+                assert_eq!(rx.next().await, Some(text("update")));
+            }
+            assert_eq!(rx.next().await, Some(text("clocks")));
+        }
+
+        // gone once unsubscribed
+        game.subscribers.remove(&ConnectionId(1));
+        game.publish(text("next"), None, None).await;
+        assert_eq!(spectators[2].next().await, Some(text("next")));
+        assert_eq!(spectators[1].next().await, None);
+
+        // engines are told after the updates queued for them
+        let (tx, engine) = peer_channel(8);
+        let mut engine = Box::pin(engine);
+        game.subscribe(ConnectionId(99), tx, SubscriberKind::Engine, false);
+        game.publish(text("update"), None, None).await;
+        game.drop_engines(text("unsubscribe")).await;
+        assert_eq!(engine.next().await, Some(text("update")));
+        assert_eq!(engine.next().await, Some(text("unsubscribe")));
+        assert_eq!(engine.next().await, None);
+        assert!(game
+            .subscribers
+            .values()
+            .all(|s| s.kind == SubscriberKind::Spectator));
     }
 }